@@ -0,0 +1,113 @@
+// builds statements out of KivQL's tokens rather than raw bytes, so most
+// inputs get past the tokenizer and exercise the parser. statements that
+// parse should format and parse back to themselves
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use kivql::formatter::format_script;
+use kivql::parser::{Parser, ValueMut};
+use kivql::tokenizer::Tokenizer;
+use libfuzzer_sys::fuzz_target;
+
+const KEYWORDS: [&str; 32] = [
+    "SET",
+    "TO",
+    "DELETE",
+    "GET",
+    "ZADD",
+    "ZREM",
+    "ZSCORE",
+    "ZRANGE",
+    "ZRANK",
+    "BY",
+    "SCORE",
+    "SADD",
+    "SREM",
+    "SISMEMBER",
+    "SMEMBERS",
+    "SCARD",
+    "SUNION",
+    "SINTER",
+    "SDIFF",
+    "CREATE",
+    "DROP",
+    "USE",
+    "SHOW",
+    "NAMESPACE",
+    "NAMESPACES",
+    "FLUSH",
+    "TRUNCATE",
+    "INFO",
+    "STATS",
+    "EXPLAIN",
+    "BACKUP",
+    "COMPACT",
+];
+
+#[derive(Arbitrary, Debug)]
+enum Fragment {
+    Keyword(u8, bool),
+    String(String),
+    Number(f64),
+    Placeholder(u8),
+    NamedPlaceholder(u8),
+    Semicolon,
+    Other(char),
+}
+
+impl Fragment {
+    fn render(&self) -> String {
+        match self {
+            Fragment::Keyword(index, lowercase) => {
+                let keyword = KEYWORDS[*index as usize % KEYWORDS.len()];
+                if *lowercase {
+                    keyword.to_lowercase()
+                } else {
+                    keyword.to_owned()
+                }
+            }
+            Fragment::String(string) => format!("'{}'", string.replace('\'', "''")),
+            Fragment::Number(number) => number.to_string(),
+            Fragment::Placeholder(0) => String::from("?"),
+            Fragment::Placeholder(index) => format!("${}", index),
+            Fragment::NamedPlaceholder(name) => format!(":p{}", name),
+            Fragment::Semicolon => String::from(";"),
+            Fragment::Other(char) => char.to_string(),
+        }
+    }
+}
+
+fuzz_target!(|fragments: Vec<Fragment>| {
+    let script = fragments
+        .iter()
+        .map(Fragment::render)
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    let tokens = match Tokenizer::new().tokenize(script) {
+        Ok(tokens) => tokens,
+        Err(_) => return,
+    };
+    let mut operations = match Parser::parse_script(tokens) {
+        Ok(operations) => operations,
+        Err(_) => return,
+    };
+
+    // infinite numbers have no KivQL spelling
+    let finite = operations.iter_mut().all(|operation| {
+        operation.values_mut().iter().all(|value| match value {
+            ValueMut::Number(number) => number.is_finite(),
+            ValueMut::String(_) => true,
+        })
+    });
+    if !finite {
+        return;
+    }
+
+    let tokens = Tokenizer::new()
+        .tokenize(format_script(&operations))
+        .expect("formatted script should tokenize");
+    let reparsed = Parser::parse_script(tokens).expect("formatted script should parse");
+    assert_eq!(operations, reparsed);
+});
@@ -0,0 +1,41 @@
+// reads every kind of entry out of a file with arbitrary contents, and
+// checks it. corrupt entries should come back as errors, never panics
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Write;
+use storage::Storage;
+
+// a valid header, so the entries are decoded instead of the file being
+// reinitialized
+const HEADER: [u8; 8] = [0, 104, 105, 107, 105, 118, 0, 7];
+// saying the file isn't encrypted
+const KEY_BLOCK: [u8; 45] = [0; 45];
+
+fuzz_target!(|entries: &[u8]| {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&HEADER).unwrap();
+    file.write_all(&KEY_BLOCK).unwrap();
+    file.write_all(entries).unwrap();
+
+    let _ = storage::check::check(file.path());
+
+    let path = file.path().to_string_lossy().into_owned();
+    let mut storage = Storage::open(path).unwrap();
+
+    let _ = storage.get_stats();
+    let _ = storage.get_entry_count();
+    let _ = storage.get_namespace_stats();
+    let _ = storage.scan_data_entries(b"");
+    let _ = storage.get_data_entry(b"key");
+    let _ = storage.get_sorted_set_range("key", f64::MIN, f64::MAX);
+    let _ = storage.get_set_members("key");
+
+    if let Ok(namespaces) = storage.get_namespaces() {
+        for (id, _) in namespaces {
+            storage.use_namespace(id);
+            let _ = storage.scan_data_entries(b"");
+        }
+    }
+});
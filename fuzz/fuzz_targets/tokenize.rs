@@ -0,0 +1,10 @@
+// the tokenizer should return an error for bad input, never panic
+
+#![no_main]
+
+use kivql::tokenizer::Tokenizer;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|statement: String| {
+    let _ = Tokenizer::new().tokenize(statement);
+});
@@ -0,0 +1,89 @@
+// `kiv backup`: copies a database, or just the changes made to it since an
+// earlier backup
+
+use crate::display::{format_error, format_open_error};
+use clap::Args;
+use kiv_core::{Kiv, KivOpenError};
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use storage::changes::{changes_path, export};
+
+#[derive(Args)]
+pub struct BackupArgs {
+    /// Database file to back up
+    db_path: PathBuf,
+    /// Where to write the backup
+    output: PathBuf,
+    /// Only write the changes made since an earlier backup
+    #[arg(long, requires = "since")]
+    incremental: bool,
+    /// The change the earlier backup was taken at
+    #[arg(long, value_name = "SEQ", requires = "incremental")]
+    since: Option<u64>,
+}
+
+pub fn run(args: BackupArgs) -> io::Result<ExitCode> {
+    match args.since {
+        Some(since) => incremental(args, since),
+        None => full(args),
+    }
+}
+
+/// Copies the whole database, and starts a change log if it doesn't keep one
+/// yet so the next backup can be incremental.
+fn full(args: BackupArgs) -> io::Result<ExitCode> {
+    let kiv = match Kiv::open(args.db_path) {
+        Ok(kiv) => kiv,
+        Err(error) => {
+            eprintln!("failed to open database: {}", format_open_error(&error));
+            if let KivOpenError::Locked = error {
+                eprintln!("to back up a database a server has open, run BACKUP TO through it");
+            }
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+
+    let result = kiv
+        .enable_change_log()
+        .and_then(|_| kiv.backup(&args.output));
+
+    match result {
+        Ok(bytes) => {
+            println!(
+                "wrote {} bytes to {} at change {}",
+                bytes,
+                args.output.display(),
+                kiv.sequence().unwrap_or(0)
+            );
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(error) => {
+            eprintln!("error: {}", format_error(&error));
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Copies the change log from `since` on. Only the log is read, so this is
+/// safe to run while a server has the database open.
+fn incremental(args: BackupArgs, since: u64) -> io::Result<ExitCode> {
+    let log = changes_path(&args.db_path);
+    if !log.exists() {
+        eprintln!(
+            "{} has no change log, take a full backup first",
+            args.db_path.display()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let changes = export(&log, since, &args.output)?;
+    println!(
+        "wrote {} changes since change {} to {}",
+        changes,
+        since,
+        args.output.display()
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
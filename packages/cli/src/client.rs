@@ -0,0 +1,112 @@
+// runs operations against a database file or a running kiv-json-server
+
+use crate::display::{format_error, format_open_error, format_result};
+use kiv_core::{Kiv, DEFAULT_NAMESPACE};
+use kivql::parser::Operation;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub enum Client {
+    Local(Kiv),
+    Remote {
+        agent: ureq::Agent,
+        url: String,
+        /// The server doesn't keep sessions, so `USE` is tracked here and
+        /// sent with every request.
+        namespace: String,
+    },
+}
+
+/// The printable result of an operation.
+pub struct Response {
+    pub time: Duration,
+    pub output: String,
+}
+
+impl Client {
+    pub fn open(path: PathBuf, read_only: bool) -> Result<Self, String> {
+        let kiv = if read_only {
+            Kiv::open_read_only(path)
+        } else {
+            Kiv::open(path)
+        };
+
+        kiv.map(Client::Local)
+            .map_err(|error| format!("failed to open database: {}", format_open_error(&error)))
+    }
+
+    pub fn connect(url: &str) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+
+        Client::Remote {
+            agent,
+            url: format!("{}/exec", url.trim_end_matches('/')),
+            namespace: DEFAULT_NAMESPACE.to_string(),
+        }
+    }
+
+    /// Returns the open database, or `None` when connected to a server.
+    pub fn kiv(&mut self) -> Option<&mut Kiv> {
+        match self {
+            Client::Local(kiv) => Some(kiv),
+            Client::Remote { .. } => None,
+        }
+    }
+
+    pub fn execute(&mut self, operation: Operation) -> Result<Response, String> {
+        match self {
+            Client::Local(kiv) => {
+                let result = kiv
+                    .execute(operation)
+                    .map_err(|error| format_error(&error))?;
+
+                Ok(Response {
+                    time: result.time,
+                    output: format_result(&result.result),
+                })
+            }
+            Client::Remote {
+                agent,
+                url,
+                namespace,
+            } => {
+                let mut response = agent
+                    .post(url.as_str())
+                    .query("namespace", namespace.as_str())
+                    .header("content-type", "text/plain")
+                    .send(operation.to_string())
+                    .map_err(|error| format!("request failed: {}", error))?;
+                let status = response.status();
+                let body = response
+                    .body_mut()
+                    .read_to_string()
+                    .map_err(|error| format!("request failed: {}", error))?;
+
+                if !status.is_success() {
+                    return Err(format!("server returned {}: {}", status, body));
+                }
+
+                if let Operation::USE(r#use) = operation {
+                    *namespace = r#use.namespace;
+                }
+
+                let body: serde_json::Value = serde_json::from_str(&body)
+                    .map_err(|error| format!("invalid response: {}", error))?;
+                let time = Duration::new(
+                    body["time"]["secs"].as_u64().unwrap_or(0),
+                    body["time"]["nanos"].as_u64().unwrap_or(0) as u32,
+                );
+                let output = match &body["result"] {
+                    // results without a value are serialized as their name
+                    serde_json::Value::String(_) => String::from("OK"),
+                    result => serde_json::to_string_pretty(result).unwrap(),
+                };
+
+                Ok(Response { time, output })
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// human readable output for results and errors
+
+use kiv_core::{AccessPath, KivError, KivOpenError, OperationResultResult};
+
+pub fn format_result(result: &OperationResultResult) -> String {
+    match result {
+        OperationResultResult::Get(get) => match &get.value {
+            Some(value) => kivql::formatter::quote(value),
+            None => String::from("(nil)"),
+        },
+        OperationResultResult::ZScore(zscore) => match zscore.score {
+            Some(score) => score.to_string(),
+            None => String::from("(nil)"),
+        },
+        OperationResultResult::ZRange(zrange) => numbered(
+            zrange
+                .members
+                .iter()
+                .map(|scored| {
+                    format!(
+                        "{} {}",
+                        kivql::formatter::quote(&scored.member),
+                        scored.score
+                    )
+                })
+                .collect(),
+        ),
+        OperationResultResult::ZRank(zrank) => match zrank.rank {
+            Some(rank) => rank.to_string(),
+            None => String::from("(nil)"),
+        },
+        OperationResultResult::SIsMember(sismember) => sismember.is_member.to_string(),
+        OperationResultResult::SMembers(smembers)
+        | OperationResultResult::SUnion(smembers)
+        | OperationResultResult::SInter(smembers)
+        | OperationResultResult::SDiff(smembers) => numbered(
+            smembers
+                .members
+                .iter()
+                .map(|member| kivql::formatter::quote(member))
+                .collect(),
+        ),
+        OperationResultResult::SCard(scard) => scard.cardinality.to_string(),
+        OperationResultResult::Namespaces(namespaces) => {
+            let mut lines = vec![format!("{:<20} {:>10} {:>12}", "name", "entries", "bytes")];
+            for namespace in &namespaces.namespaces {
+                lines.push(format!(
+                    "{:<20} {:>10} {:>12}",
+                    namespace.name, namespace.entries, namespace.bytes
+                ));
+            }
+            lines.join("\n")
+        }
+        OperationResultResult::Info(info) => [
+            format!("keys: {}", info.keys),
+            format!("file size: {}", info.file_size),
+            format!("live bytes: {}", info.live_bytes),
+            format!("dead bytes: {}", info.dead_bytes),
+            format!("version: {}", info.version),
+            format!("uptime: {:.0?}", info.uptime),
+            format!("bloom filter hits: {}", info.bloom_hits),
+            format!("bloom filter misses: {}", info.bloom_misses),
+            format!("compression ratio: {:.2}", info.compression_ratio),
+        ]
+        .join("\n"),
+        OperationResultResult::Explain(explain) => [
+            explain.operation.clone(),
+            format!(
+                "access path: {}",
+                match explain.access_path {
+                    AccessPath::None => "none",
+                    AccessPath::KeyScan => "key scan",
+                    AccessPath::FullScan => "full scan",
+                    AccessPath::Rewrite => "rewrite",
+                    AccessPath::Namespaces => "namespaces",
+                    AccessPath::SortedSet => "sorted set",
+                }
+            ),
+            format!("estimated rows: {}", explain.estimated_rows),
+            format!("estimated bytes: {}", explain.estimated_bytes),
+        ]
+        .join("\n"),
+        OperationResultResult::Backup(backup) => match backup.sequence {
+            Some(sequence) => format!(
+                "wrote {} bytes to {} at change {}",
+                backup.bytes, backup.path, sequence
+            ),
+            None => format!("wrote {} bytes to {}", backup.bytes, backup.path),
+        },
+        OperationResultResult::Compact(compact) => format!("freed {} bytes", compact.bytes),
+        // everything else either worked or returned an error
+        _ => String::from("OK"),
+    }
+}
+
+/// Describes an error along with what caused it, since `KivError`'s own
+/// messages leave out the tokenizer and parser details.
+pub fn format_error(error: &KivError) -> String {
+    match error {
+        KivError::TokenizerError(error) => format!("tokenizer error: {}", error),
+        KivError::ParserError(error) => format!("parser error: {}", error),
+        KivError::IoError(error) => format!("io error: {}", error),
+        KivError::NamespaceExists(name)
+        | KivError::NamespaceNotFound(name)
+        | KivError::UnboundParameter(name)
+        | KivError::ParameterTypeMismatch(name) => format!("{}: {}", error, name),
+        error => error.to_string(),
+    }
+}
+
+pub fn format_open_error(error: &KivOpenError) -> String {
+    match error {
+        KivOpenError::IoError(error) => format!("io error: {}", error),
+        error => error.to_string(),
+    }
+}
+
+fn numbered(lines: Vec<String>) -> String {
+    if lines.is_empty() {
+        return String::from("(empty)");
+    }
+
+    lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| format!("{}) {}", index + 1, line))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
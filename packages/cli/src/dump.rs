@@ -0,0 +1,174 @@
+// `kiv dump`: streams a database out as JSON Lines, CSV or a KivQL script
+
+use crate::display::{format_error, format_open_error};
+use crate::records::{for_each_record, Format, Record, Row};
+use clap::Args;
+use kiv_core::{Kiv, KivError, DEFAULT_NAMESPACE};
+use kivql::parser::{CreateNamespace, Operation, SAdd, Set, Use, ZAdd};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Args)]
+pub struct DumpArgs {
+    /// Database file to dump
+    db_path: PathBuf,
+    #[arg(short, long, value_enum, default_value_t = Format::Jsonl)]
+    format: Format,
+    /// File to write to instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+pub fn run(args: DumpArgs) -> io::Result<ExitCode> {
+    let mut kiv = match Kiv::open_read_only(args.db_path) {
+        Ok(kiv) => kiv,
+        Err(error) => {
+            eprintln!("failed to open database: {}", format_open_error(&error));
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    let result = match args.format {
+        Format::Jsonl => write_jsonl(&mut kiv, &mut out),
+        Format::Csv => write_csv(&mut kiv, &mut out),
+        Format::Kivql => write_script(&mut kiv, &mut out),
+    };
+    out.flush()?;
+
+    match result {
+        Ok(records) => {
+            eprintln!("dumped {} records", records);
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(error) => {
+            eprintln!("error: {}", format_error(&error));
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Writes one JSON object per record, leaving out empty fields. Returns how
+/// many records were written.
+pub fn write_jsonl(kiv: &mut Kiv, out: &mut impl Write) -> Result<u64, KivError> {
+    let mut records = 0;
+
+    for_each_record(kiv, |record| {
+        let mut row = serde_json::to_value(Row::from(record))?;
+        if let serde_json::Value::Object(fields) = &mut row {
+            fields.retain(|_, value| !value.is_null());
+        }
+
+        serde_json::to_writer(&mut *out, &row)?;
+        writeln!(out)?;
+        records += 1;
+        Ok(())
+    })?;
+
+    Ok(records)
+}
+
+/// Writes a header row and then a row per record. Returns how many records
+/// were written.
+pub fn write_csv(kiv: &mut Kiv, out: &mut impl Write) -> Result<u64, KivError> {
+    let mut writer = csv::Writer::from_writer(out);
+    let mut records = 0;
+
+    for_each_record(kiv, |record| {
+        writer.serialize(Row::from(record))?;
+        records += 1;
+        Ok(())
+    })?;
+    writer.flush()?;
+
+    Ok(records)
+}
+
+/// Writes one statement per record, switching namespaces with `USE` as
+/// needed. Data that isn't UTF-8 can't be written as a KivQL string, so
+/// it's written lossily. Returns how many records were written.
+pub fn write_script(kiv: &mut Kiv, out: &mut impl Write) -> Result<u64, KivError> {
+    let mut current = DEFAULT_NAMESPACE.to_string();
+    let mut records = 0;
+    let mut lossy = 0;
+
+    for_each_record(kiv, |record| {
+        let (namespace, operation) = match record {
+            Record::Namespace { name } => {
+                let operation = Operation::CREATENAMESPACE(CreateNamespace { name });
+                writeln!(out, "{};", operation)?;
+                records += 1;
+                return Ok(());
+            }
+            Record::Data {
+                namespace,
+                key,
+                value,
+            } => {
+                if std::str::from_utf8(&key).is_err() || std::str::from_utf8(&value).is_err() {
+                    lossy += 1;
+                }
+
+                let operation = Operation::SET(Set {
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                    value: String::from_utf8_lossy(&value).into_owned(),
+                });
+                (namespace, operation)
+            }
+            Record::SortedSetMember {
+                namespace,
+                key,
+                member,
+                score,
+            } => (namespace, Operation::ZADD(ZAdd { key, score, member })),
+            Record::SetMember {
+                namespace,
+                key,
+                member,
+            } => {
+                let operation = Operation::SADD(SAdd {
+                    key,
+                    members: vec![member],
+                });
+                (namespace, operation)
+            }
+        };
+
+        if namespace != current {
+            writeln!(
+                out,
+                "{};",
+                Operation::USE(Use {
+                    namespace: namespace.clone()
+                })
+            )?;
+            current = namespace;
+        }
+
+        writeln!(out, "{};", operation)?;
+        records += 1;
+        Ok(())
+    })?;
+
+    if current != DEFAULT_NAMESPACE {
+        let operation = Operation::USE(Use {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+        });
+        writeln!(out, "{};", operation)?;
+    }
+
+    if lossy > 0 {
+        eprintln!(
+            "warning: {} entries weren't UTF-8 and were written lossily",
+            lossy
+        );
+    }
+
+    Ok(records)
+}
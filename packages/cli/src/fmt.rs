@@ -0,0 +1,91 @@
+// `kiv fmt`: uppercases keywords, puts one statement per line and
+// re-quotes strings, checking that every statement parses
+
+use clap::Args;
+use kivql::formatter::format_tokens;
+use kivql::parser::Parser;
+use kivql::tokenizer::Tokenizer;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Args)]
+pub struct FmtArgs {
+    /// Scripts to format in place. Reads stdin and writes stdout if none
+    /// are given
+    files: Vec<PathBuf>,
+    /// Don't write anything, fail if a script isn't formatted
+    #[arg(long)]
+    check: bool,
+}
+
+pub fn run(args: FmtArgs) -> io::Result<ExitCode> {
+    if args.files.is_empty() {
+        let mut script = String::new();
+        io::stdin().read_to_string(&mut script)?;
+
+        let formatted = match format(&script) {
+            Ok(formatted) => formatted,
+            Err(error) => {
+                eprintln!("<stdin>: {}", error);
+                return Ok(ExitCode::FAILURE);
+            }
+        };
+
+        if args.check {
+            return Ok(if formatted == script {
+                ExitCode::SUCCESS
+            } else {
+                eprintln!("<stdin> is not formatted");
+                ExitCode::FAILURE
+            });
+        }
+
+        io::stdout().write_all(formatted.as_bytes())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut code = ExitCode::SUCCESS;
+
+    for path in args.files {
+        let script = std::fs::read_to_string(&path)?;
+
+        let formatted = match format(&script) {
+            Ok(formatted) => formatted,
+            Err(error) => {
+                eprintln!("{}: {}", path.display(), error);
+                code = ExitCode::FAILURE;
+                continue;
+            }
+        };
+
+        if formatted == script {
+            continue;
+        }
+
+        if args.check {
+            eprintln!("{} is not formatted", path.display());
+            code = ExitCode::FAILURE;
+        } else {
+            std::fs::write(&path, formatted)?;
+        }
+    }
+
+    Ok(code)
+}
+
+/// Formats a script, failing with the first tokenizer or parser error.
+fn format(script: &str) -> Result<String, String> {
+    let tokens = Tokenizer::new()
+        .tokenize(script.to_owned())
+        .map_err(|error| error.to_string())?;
+    Parser::parse_script(tokens.clone()).map_err(|error| error.to_string())?;
+
+    let mut formatted = format_tokens(&tokens);
+    // the last statement doesn't need a `;`, but give it one
+    if !formatted.is_empty() && !formatted.ends_with('\n') {
+        formatted.push_str(";\n");
+    }
+
+    Ok(formatted)
+}
@@ -0,0 +1,74 @@
+// `kiv fsck`: checks a database file without opening it, and can copy what
+// survives into a new file
+
+use clap::Args;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use storage::check::{check, repair, CheckReport};
+
+#[derive(Args)]
+pub struct FsckArgs {
+    /// Database file to check. It is never modified
+    db_path: PathBuf,
+    /// Copy every readable entry into a new file
+    #[arg(long, value_name = "OUTPUT")]
+    repair: Option<PathBuf>,
+    /// Where to write the list of what a repair left out, OUTPUT.report by
+    /// default
+    #[arg(long, value_name = "FILE", requires = "repair")]
+    report: Option<PathBuf>,
+}
+
+pub fn run(args: FsckArgs) -> io::Result<ExitCode> {
+    let report = match &args.repair {
+        Some(output) => repair(&args.db_path, output)?,
+        None => check(&args.db_path)?,
+    };
+
+    write_report(&mut io::stdout().lock(), &report)?;
+
+    if let Some(output) = &args.repair {
+        let path = args.report.unwrap_or_else(|| {
+            let mut path = output.clone().into_os_string();
+            path.push(".report");
+            PathBuf::from(path)
+        });
+        let mut file = BufWriter::new(File::create(&path)?);
+        write_report(&mut file, &report)?;
+        file.flush()?;
+
+        println!(
+            "wrote {} entries to {}, report in {}",
+            report.entries,
+            output.display(),
+            path.display()
+        );
+    }
+
+    Ok(if report.is_ok() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn write_report(out: &mut impl Write, report: &CheckReport) -> io::Result<()> {
+    match report.version {
+        Some(version) => writeln!(out, "file version {}", version)?,
+        None => writeln!(out, "no valid file header")?,
+    }
+
+    for problem in &report.problems {
+        writeln!(out, "{}", problem)?;
+    }
+
+    writeln!(
+        out,
+        "{} readable entries, {} problems, {} bytes affected",
+        report.entries,
+        report.problems.len(),
+        report.lost_bytes()
+    )
+}
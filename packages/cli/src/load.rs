@@ -0,0 +1,475 @@
+// `kiv load`: reads a dump back into a database
+
+use crate::display::{format_error, format_open_error};
+use crate::records::{Format, Record, Row};
+use clap::Args;
+use kiv_core::{Entry, Kiv, KivError, DEFAULT_NAMESPACE};
+use kivql::parser::Parser;
+use kivql::tokenizer::Tokenizer;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Entries written to the file at a time by the fast path.
+const BATCH_SIZE: usize = 10_000;
+
+/// Size of the filter the fast path spots repeats with, in bits.
+const SEEN_BITS: usize = 1 << 24;
+
+/// Bits set in the filter per record.
+const SEEN_HASHES: u64 = 4;
+
+#[derive(Args)]
+pub struct LoadArgs {
+    /// Database file to load into
+    db_path: PathBuf,
+    /// Dump to read, stdin by default
+    input: Option<PathBuf>,
+    /// Format of the dump. Guessed from the input's extension if not given,
+    /// otherwise JSON Lines
+    #[arg(short, long, value_enum)]
+    format: Option<Format>,
+}
+
+pub fn run(args: LoadArgs) -> io::Result<ExitCode> {
+    let format = args
+        .format
+        .or_else(|| args.input.as_deref().and_then(Format::from_extension))
+        .unwrap_or(Format::Jsonl);
+
+    let mut kiv = match Kiv::open(args.db_path) {
+        Ok(kiv) => kiv,
+        Err(error) => {
+            eprintln!("failed to open database: {}", format_open_error(&error));
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+
+    let input: Box<dyn Read> = match &args.input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin().lock()),
+    };
+    let input = BufReader::new(input);
+
+    let result = match format {
+        Format::Jsonl => load_jsonl(&mut kiv, input),
+        Format::Csv => load_csv(&mut kiv, input),
+        Format::Kivql => load_script(&mut kiv, input),
+    };
+
+    match result {
+        Ok(records) => {
+            eprintln!("loaded {} records", records);
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(error) => {
+            eprintln!("error: {}", error);
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn load_jsonl(kiv: &mut Kiv, input: impl BufRead) -> Result<u64, String> {
+    let mut loader = Loader::new(kiv).map_err(|error| format_error(&error))?;
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line.map_err(|error| error.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record = serde_json::from_str::<Row>(&line)
+            .map_err(|error| error.to_string())
+            .and_then(Record::try_from)
+            .map_err(|error| format!("line {}: {}", index + 1, error))?;
+        loader.add(record).map_err(|error| format_error(&error))?;
+    }
+
+    loader.finish().map_err(|error| format_error(&error))
+}
+
+fn load_csv(kiv: &mut Kiv, input: impl Read) -> Result<u64, String> {
+    let mut loader = Loader::new(kiv).map_err(|error| format_error(&error))?;
+
+    for (index, row) in csv::Reader::from_reader(input).deserialize().enumerate() {
+        // the header is line 1
+        let record = row
+            .map_err(|error: csv::Error| error.to_string())
+            .and_then(|row: Row| Record::try_from(row))
+            .map_err(|error| format!("row {}: {}", index + 2, error))?;
+        loader.add(record).map_err(|error| format_error(&error))?;
+    }
+
+    loader.finish().map_err(|error| format_error(&error))
+}
+
+/// Scripts can hold any statement, so they are run one by one. The whole
+/// script is parsed before anything runs.
+fn load_script(kiv: &mut Kiv, mut input: impl Read) -> Result<u64, String> {
+    let mut script = String::new();
+    input
+        .read_to_string(&mut script)
+        .map_err(|error| error.to_string())?;
+
+    let tokens = Tokenizer::new()
+        .tokenize(script)
+        .map_err(|error| format!("tokenizer error: {}", error))?;
+    let operations =
+        Parser::parse_script(tokens).map_err(|error| format!("parser error: {}", error))?;
+
+    let count = operations.len();
+    for (index, operation) in operations.into_iter().enumerate() {
+        kiv.execute(operation).map_err(|error| {
+            format!(
+                "statement {} of {}: {}",
+                index + 1,
+                count,
+                format_error(&error)
+            )
+        })?;
+    }
+
+    kiv.use_namespace(DEFAULT_NAMESPACE)
+        .map_err(|error| format_error(&error))?;
+    Ok(count as u64)
+}
+
+/// A bloom filter over the keys and members the fast path has written. It
+/// takes the same memory however long the load is, and never misses a
+/// repeat, but can take a new record for one.
+struct Seen {
+    bits: Vec<u64>,
+    hasher: RandomState,
+}
+
+impl Seen {
+    fn new() -> Self {
+        Self {
+            bits: vec![0; SEEN_BITS / 64],
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Adds a record, returning whether it may have been added before.
+    fn insert(&mut self, record: (u16, u8, &[u8], &str)) -> bool {
+        let hash = self.hasher.hash_one(record);
+        let (h1, h2) = (hash & u32::MAX as u64, hash >> 32);
+
+        let mut seen = true;
+        for i in 0..SEEN_HASHES {
+            let bit = (h1.wrapping_add(i.wrapping_mul(h2)) % SEEN_BITS as u64) as usize;
+            seen &= self.bits[bit / 64] & (1 << (bit % 64)) != 0;
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        seen
+    }
+}
+
+/// Writes records into a database.
+///
+/// Loading into an empty database takes a fast path: records become entries
+/// that are appended in batches, without looking for existing keys first.
+/// A record that may repeat a key or member goes through the normal write
+/// path instead, which updates in place.
+struct Loader<'a> {
+    kiv: &'a mut Kiv,
+    fast: bool,
+    /// Namespace ids handed out by the fast path.
+    ids: HashMap<String, u16>,
+    /// Keys and members the fast path has written, by namespace.
+    seen: Seen,
+    batch: Vec<Entry>,
+    /// Namespace the normal write path is in.
+    current: String,
+    records: u64,
+}
+
+impl<'a> Loader<'a> {
+    fn new(kiv: &'a mut Kiv) -> Result<Self, KivError> {
+        let fast = kiv.info()?.keys == 0 && kiv.namespaces()?.len() == 1;
+
+        Ok(Self {
+            kiv,
+            fast,
+            ids: HashMap::from([(DEFAULT_NAMESPACE.to_string(), 0)]),
+            seen: Seen::new(),
+            batch: vec![],
+            current: DEFAULT_NAMESPACE.to_string(),
+            records: 0,
+        })
+    }
+
+    fn add(&mut self, record: Record) -> Result<(), KivError> {
+        self.records += 1;
+
+        if self.fast {
+            let (namespace, kind, key, member) = match &record {
+                Record::Namespace { name } => {
+                    self.namespace_id(name)?;
+                    return Ok(());
+                }
+                Record::Data { namespace, key, .. } => (namespace, 0, key.as_slice(), ""),
+                Record::SortedSetMember {
+                    namespace,
+                    key,
+                    member,
+                    ..
+                } => (namespace, 1, key.as_bytes(), member.as_str()),
+                Record::SetMember {
+                    namespace,
+                    key,
+                    member,
+                } => (namespace, 2, key.as_bytes(), member.as_str()),
+            };
+            let id = self.namespace_id(namespace)?;
+
+            if !self.seen.insert((id, kind, key, member)) {
+                self.batch.push(match record {
+                    Record::Data { key, value, .. } => Entry::Data {
+                        namespace: id,
+                        key,
+                        value,
+                    },
+                    Record::SortedSetMember {
+                        key, member, score, ..
+                    } => Entry::SortedSetMember {
+                        namespace: id,
+                        key,
+                        member,
+                        score,
+                    },
+                    Record::SetMember { key, member, .. } => Entry::SetMember {
+                        namespace: id,
+                        key,
+                        member,
+                    },
+                    Record::Namespace { .. } => unreachable!("handled above"),
+                });
+                if self.batch.len() >= BATCH_SIZE {
+                    self.flush_batch()?;
+                }
+                return Ok(());
+            }
+
+            // a possible repeat, which only an update can handle once what
+            // came before it is written
+            self.flush_batch()?;
+        }
+
+        match record {
+            Record::Namespace { name } => {
+                self.ensure_namespace(&name)?;
+            }
+            Record::Data {
+                namespace,
+                key,
+                value,
+            } => {
+                self.use_namespace(namespace)?;
+                self.kiv.set(key, value)?;
+            }
+            Record::SortedSetMember {
+                namespace,
+                key,
+                member,
+                score,
+            } => {
+                self.use_namespace(namespace)?;
+                self.kiv.zadd(key, score, member)?;
+            }
+            Record::SetMember {
+                namespace,
+                key,
+                member,
+            } => {
+                self.use_namespace(namespace)?;
+                self.kiv.sadd(key, [member])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes what's left and returns how many records were loaded.
+    fn finish(mut self) -> Result<u64, KivError> {
+        self.flush_batch()?;
+        self.kiv.use_namespace(DEFAULT_NAMESPACE)?;
+        Ok(self.records)
+    }
+
+    /// Returns the fast path's id for a namespace, registering new ones in
+    /// the batch.
+    fn namespace_id(&mut self, name: &str) -> Result<u16, KivError> {
+        if let Some(id) = self.ids.get(name) {
+            return Ok(*id);
+        }
+
+        let id = u16::try_from(self.ids.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("too many namespaces to create {:?}", name),
+            )
+        })?;
+        self.ids.insert(name.to_string(), id);
+        self.batch.push(Entry::Namespace {
+            id,
+            name: name.to_string(),
+        });
+        Ok(id)
+    }
+
+    fn flush_batch(&mut self) -> Result<(), KivError> {
+        if !self.batch.is_empty() {
+            self.kiv.append_entries(&self.batch)?;
+            self.batch.clear();
+        }
+        Ok(())
+    }
+
+    fn ensure_namespace(&mut self, name: &str) -> Result<(), KivError> {
+        match self.kiv.create_namespace(name) {
+            Ok(()) | Err(KivError::NamespaceExists(_)) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn use_namespace(&mut self, name: String) -> Result<(), KivError> {
+        if name != self.current {
+            self.ensure_namespace(&name)?;
+            self.kiv.use_namespace(&name)?;
+            self.current = name;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dump::{write_csv, write_jsonl, write_script};
+    use crate::records::for_each_record;
+
+    fn open(dir: &tempfile::TempDir, name: &str) -> Kiv {
+        Kiv::open(dir.path().join(name)).unwrap()
+    }
+
+    /// Every record, written out so NaN scores compare equal.
+    fn records(kiv: &mut Kiv) -> Vec<String> {
+        let mut records = vec![];
+        for_each_record(kiv, |record| {
+            records.push(format!("{:?}", record));
+            Ok(())
+        })
+        .unwrap();
+        records
+    }
+
+    fn fill(kiv: &Kiv) {
+        kiv.set("a", "1").unwrap();
+        kiv.set([0xff, 0x00], [0xc3, 0x28]).unwrap();
+        kiv.zadd("z", f64::NAN, "nan").unwrap();
+        kiv.zadd("z", f64::INFINITY, "inf").unwrap();
+        kiv.zadd("z", f64::NEG_INFINITY, "-inf").unwrap();
+        kiv.zadd("z", -0.5, "half").unwrap();
+        kiv.sadd("s", ["x", "it's"]).unwrap();
+        kiv.create_namespace("team").unwrap();
+        kiv.use_namespace("team").unwrap();
+        kiv.set("a", "team").unwrap();
+        kiv.use_namespace(DEFAULT_NAMESPACE).unwrap();
+    }
+
+    fn round_trip(
+        dump: fn(&mut Kiv, &mut Vec<u8>) -> Result<u64, KivError>,
+        load: fn(&mut Kiv, &[u8]) -> Result<u64, String>,
+    ) -> (Vec<String>, Vec<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut source = open(&dir, "source.kiv");
+        fill(&source);
+        let mut dumped = vec![];
+        dump(&mut source, &mut dumped).unwrap();
+
+        let mut loaded = open(&dir, "loaded.kiv");
+        load(&mut loaded, &dumped).unwrap();
+        (records(&mut source), records(&mut loaded))
+    }
+
+    #[test]
+    fn jsonl_dumps_load_back() {
+        let (source, loaded) = round_trip(write_jsonl, |kiv, input| load_jsonl(kiv, input));
+        assert_eq!(source, loaded);
+    }
+
+    #[test]
+    fn csv_dumps_load_back() {
+        let (source, loaded) = round_trip(write_csv, |kiv, input| load_csv(kiv, input));
+        assert_eq!(source, loaded);
+    }
+
+    #[test]
+    fn kivql_dumps_load_back() {
+        let (source, loaded) = round_trip(write_script, |kiv, input| load_script(kiv, input));
+
+        // data that isn't UTF-8 is written lossily, everything else loads
+        // back as it was
+        let lossy = format!(
+            "{:?}",
+            Record::Data {
+                namespace: DEFAULT_NAMESPACE.to_string(),
+                key: "\u{fffd}\0".into(),
+                value: "\u{fffd}(".into(),
+            }
+        );
+        let expected: Vec<_> = source
+            .into_iter()
+            .map(|record| match record.contains("[255, 0]") {
+                true => lossy.clone(),
+                false => record,
+            })
+            .collect();
+        assert_eq!(expected, loaded);
+    }
+
+    #[test]
+    fn repeated_records_are_updated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kiv = open(&dir, "test.kiv");
+        let input = [
+            r#"{"type":"data","namespace":"default","key":"a","value":"1"}"#,
+            r#"{"type":"zset","namespace":"default","key":"z","member":"m","score":1}"#,
+            r#"{"type":"data","namespace":"default","key":"a","value":"2"}"#,
+            r#"{"type":"zset","namespace":"default","key":"z","member":"m","score":"NaN"}"#,
+            r#"{"type":"data","namespace":"default","key":"b","value":"3"}"#,
+        ]
+        .join("\n");
+
+        assert_eq!(load_jsonl(&mut kiv, input.as_bytes()).unwrap(), 5);
+        assert_eq!(kiv.get("a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(kiv.get("b").unwrap(), Some(b"3".to_vec()));
+        assert!(kiv.zscore("z", "m").unwrap().unwrap().is_nan());
+        assert_eq!(records(&mut kiv).len(), 3);
+    }
+
+    #[test]
+    fn namespace_ids_run_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kiv = open(&dir, "test.kiv");
+        let mut loader = Loader::new(&mut kiv).unwrap();
+
+        // the default namespace takes id 0
+        for name in 1..=u16::MAX {
+            loader
+                .add(Record::Namespace {
+                    name: name.to_string(),
+                })
+                .unwrap();
+        }
+        assert!(loader
+            .add(Record::Namespace {
+                name: String::from("one too many"),
+            })
+            .is_err());
+    }
+}
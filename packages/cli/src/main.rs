@@ -0,0 +1,62 @@
+// command line tools for kiv
+
+mod backup;
+mod client;
+mod display;
+mod dump;
+mod fmt;
+mod fsck;
+mod load;
+mod records;
+mod restore;
+mod shell;
+
+use clap::{Parser, Subcommand};
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(name = "kiv", author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Back up a database, in full or as the changes since an earlier backup
+    Backup(backup::BackupArgs),
+    /// Rewrite KivQL scripts in canonical form
+    Fmt(fmt::FmtArgs),
+    /// Write every entry in a database out as JSON Lines, CSV or KivQL
+    Dump(dump::DumpArgs),
+    /// Check a database file for damage, optionally salvaging what's left
+    Fsck(fsck::FsckArgs),
+    /// Load a dump into a database
+    Load(load::LoadArgs),
+    /// Replace a database with a snapshot, after checking the snapshot
+    Restore(restore::RestoreArgs),
+    /// Run statements interactively against a database file or a server
+    Shell(shell::ShellArgs),
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let result = match args.command {
+        Command::Backup(args) => backup::run(args),
+        Command::Fmt(args) => fmt::run(args),
+        Command::Dump(args) => dump::run(args),
+        Command::Fsck(args) => fsck::run(args),
+        Command::Load(args) => load::run(args),
+        Command::Restore(args) => restore::run(args),
+        Command::Shell(args) => shell::run(args),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,297 @@
+// the entries of a database with namespaces resolved to names, and the flat
+// rows they're written as in JSON Lines and CSV dumps
+
+use clap::ValueEnum;
+use kiv_core::{Entry, Kiv, KivError, DEFAULT_NAMESPACE};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::io;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    /// One JSON object per line
+    Jsonl,
+    /// A header row, then one row per entry
+    Csv,
+    /// A KivQL script
+    Kivql,
+}
+
+impl Format {
+    /// Guesses a format from a file extension.
+    pub fn from_extension(path: &std::path::Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "jsonl" | "ndjson" => Some(Format::Jsonl),
+            "csv" => Some(Format::Csv),
+            "kivql" | "kql" => Some(Format::Kivql),
+            _ => None,
+        }
+    }
+}
+
+/// A database entry, with sorted sets and sets split into one record per
+/// member the same way they are stored.
+#[derive(Debug, PartialEq)]
+pub enum Record {
+    Namespace {
+        name: String,
+    },
+    Data {
+        namespace: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SortedSetMember {
+        namespace: String,
+        key: String,
+        member: String,
+        score: f64,
+    },
+    SetMember {
+        namespace: String,
+        key: String,
+        member: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RowType {
+    Namespace,
+    Data,
+    Zset,
+    Set,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// The key and value are hex, because one of them isn't UTF-8.
+    Hex,
+}
+
+/// A record as a row of optional fields, which suits both JSON and CSV.
+/// `namespace` holds the namespace's own name in namespace rows.
+#[derive(Serialize, Deserialize)]
+pub struct Row {
+    #[serde(rename = "type")]
+    pub row_type: RowType,
+    pub namespace: String,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub member: Option<String>,
+    #[serde(
+        default,
+        serialize_with = "serialize_score",
+        deserialize_with = "deserialize_score"
+    )]
+    pub score: Option<f64>,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub encoding: Option<Encoding>,
+}
+
+impl From<Record> for Row {
+    fn from(record: Record) -> Self {
+        let row = |row_type, namespace| Row {
+            row_type,
+            namespace,
+            key: None,
+            member: None,
+            score: None,
+            value: None,
+            encoding: None,
+        };
+
+        match record {
+            Record::Namespace { name } => row(RowType::Namespace, name),
+            Record::Data {
+                namespace,
+                key,
+                value,
+            } => match (String::from_utf8(key), String::from_utf8(value)) {
+                (Ok(key), Ok(value)) => Row {
+                    key: Some(key),
+                    value: Some(value),
+                    ..row(RowType::Data, namespace)
+                },
+                (key, value) => Row {
+                    key: Some(to_hex(
+                        &key.map_or_else(|error| error.into_bytes(), String::into_bytes),
+                    )),
+                    value: Some(to_hex(
+                        &value.map_or_else(|error| error.into_bytes(), String::into_bytes),
+                    )),
+                    encoding: Some(Encoding::Hex),
+                    ..row(RowType::Data, namespace)
+                },
+            },
+            Record::SortedSetMember {
+                namespace,
+                key,
+                member,
+                score,
+            } => Row {
+                key: Some(key),
+                member: Some(member),
+                score: Some(score),
+                ..row(RowType::Zset, namespace)
+            },
+            Record::SetMember {
+                namespace,
+                key,
+                member,
+            } => Row {
+                key: Some(key),
+                member: Some(member),
+                ..row(RowType::Set, namespace)
+            },
+        }
+    }
+}
+
+impl TryFrom<Row> for Record {
+    type Error = String;
+
+    fn try_from(row: Row) -> Result<Self, Self::Error> {
+        let namespace = row.namespace;
+        let key = || row.key.clone().ok_or("missing key");
+        let member = || row.member.clone().ok_or("missing member");
+
+        Ok(match row.row_type {
+            RowType::Namespace => Record::Namespace { name: namespace },
+            RowType::Data => {
+                let value = row.value.clone().ok_or("missing value")?;
+                match row.encoding {
+                    Some(Encoding::Hex) => Record::Data {
+                        namespace,
+                        key: from_hex(&key()?)?,
+                        value: from_hex(&value)?,
+                    },
+                    None => Record::Data {
+                        namespace,
+                        key: key()?.into_bytes(),
+                        value: value.into_bytes(),
+                    },
+                }
+            }
+            RowType::Zset => Record::SortedSetMember {
+                namespace,
+                key: key()?,
+                member: member()?,
+                score: row.score.ok_or("missing score")?,
+            },
+            RowType::Set => Record::SetMember {
+                namespace,
+                key: key()?,
+                member: member()?,
+            },
+        })
+    }
+}
+
+/// Writes inf and NaN scores, which JSON has no numbers for, as strings.
+fn serialize_score<S: Serializer>(score: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match score {
+        Some(score) if !score.is_finite() => serializer.serialize_some(&score.to_string()),
+        score => score.serialize(serializer),
+    }
+}
+
+/// Reads scores written as numbers or, like [`serialize_score`] writes inf
+/// and NaN, as strings.
+fn deserialize_score<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Score {
+        Number(f64),
+        String(String),
+    }
+
+    match Option::<Score>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Score::Number(score)) => Ok(Some(score)),
+        Some(Score::String(score)) => score
+            .parse()
+            .map(Some)
+            .map_err(|_| de::Error::custom(format!("invalid score {:?}", score))),
+    }
+}
+
+/// Streams through every entry as a record, in file order, so namespace
+/// records come before the records stored in them.
+pub fn for_each_record<F>(kiv: &mut Kiv, mut f: F) -> Result<(), KivError>
+where
+    F: FnMut(Record) -> io::Result<()>,
+{
+    let mut names = HashMap::from([(0, DEFAULT_NAMESPACE.to_string())]);
+
+    kiv.for_each_entry(|entry| {
+        let name = |names: &HashMap<u16, String>, id| {
+            names.get(&id).cloned().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("entry in unknown namespace {}", id),
+                )
+            })
+        };
+
+        let record = match entry {
+            Entry::Namespace { id, name } => {
+                names.insert(id, name.clone());
+                Record::Namespace { name }
+            }
+            Entry::Data {
+                namespace,
+                key,
+                value,
+            } => Record::Data {
+                namespace: name(&names, namespace)?,
+                key,
+                value,
+            },
+            Entry::SortedSetMember {
+                namespace,
+                key,
+                member,
+                score,
+            } => Record::SortedSetMember {
+                namespace: name(&names, namespace)?,
+                key,
+                member,
+                score,
+            },
+            Entry::SetMember {
+                namespace,
+                key,
+                member,
+            } => Record::SetMember {
+                namespace: name(&names, namespace)?,
+                key,
+                member,
+            },
+        };
+
+        f(record)
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("invalid hex {:?}", hex));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&hex[index..index + 2], 16)
+                .map_err(|_| format!("invalid hex {:?}", hex))
+        })
+        .collect()
+}
@@ -0,0 +1,263 @@
+// `kiv restore`: replaces a database file with a snapshot taken by
+// `BACKUP TO`, `kiv backup` or the server's snapshot endpoint, optionally
+// replaying incremental backups on top of it
+
+use clap::Args;
+use std::fs::{self, File, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::UNIX_EPOCH;
+use storage::changes::{changes_path, ChangeLog, ChangeReader};
+use storage::check::check;
+use storage::{bloom_path, Storage};
+
+#[derive(Args)]
+pub struct RestoreArgs {
+    /// Snapshot to restore from. It is never modified
+    snapshot: PathBuf,
+    /// Database file to restore into
+    db_path: PathBuf,
+    /// Replace the database if it already exists
+    #[arg(long)]
+    force: bool,
+    /// Incremental backup to replay after the snapshot. Can be given more
+    /// than once, in any order
+    #[arg(short, long, value_name = "FILE")]
+    incremental: Vec<PathBuf>,
+    /// The change the snapshot was taken at, for snapshots that don't have a
+    /// change log next to them
+    #[arg(long, value_name = "SEQ")]
+    sequence: Option<u64>,
+    /// Stop replaying after this change
+    #[arg(long, value_name = "SEQ")]
+    until: Option<u64>,
+    /// Stop replaying at changes made after this time, e.g.
+    /// 2024-05-01T12:00:00Z
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    until_time: Option<u64>,
+}
+
+/// Parses an RFC 3339 time into milliseconds since the Unix epoch.
+fn parse_time(time: &str) -> Result<u64, String> {
+    let time = humantime::parse_rfc3339_weak(time).map_err(|error| error.to_string())?;
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .map_err(|error| error.to_string())
+}
+
+pub fn run(args: RestoreArgs) -> io::Result<ExitCode> {
+    if args.db_path.exists() && !args.force {
+        eprintln!(
+            "{} already exists, pass --force to replace it",
+            args.db_path.display()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+
+    // a damaged snapshot would silently lose whatever it can't read, so
+    // refuse it and leave the database alone
+    let report = check(&args.snapshot)?;
+    if !report.is_ok() {
+        for problem in &report.problems {
+            eprintln!("{}", problem);
+        }
+        eprintln!(
+            "{} is damaged ({} problems), run kiv fsck on it first",
+            args.snapshot.display(),
+            report.problems.len()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let base = match args.sequence {
+        Some(sequence) => Some(sequence),
+        None => snapshot_sequence(&args.snapshot)?,
+    };
+    if base.is_none() && !args.incremental.is_empty() {
+        eprintln!(
+            "{} has no change log, pass --sequence with the change it was taken at",
+            args.snapshot.display()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+
+    // hold the lock on the database being replaced, so a process that has it
+    // open doesn't carry on writing to a file that is no longer there
+    let _lock = match lock(&args.db_path) {
+        Ok(lock) => lock,
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+            eprintln!(
+                "{} is open in another process, stop it first",
+                args.db_path.display()
+            );
+            return Ok(ExitCode::FAILURE);
+        }
+        Err(error) => return Err(error),
+    };
+
+    // restore next to the database and rename, so it is never half restored
+    let mut temp_path = args.db_path.clone().into_os_string();
+    temp_path.push(".restore");
+    let temp_path = PathBuf::from(temp_path);
+
+    let result = restore(&args, base, &temp_path);
+    let (bytes, replayed) = match result {
+        Ok(restored) => restored,
+        Err(error) => {
+            let _ = fs::remove_file(&temp_path);
+            let _ = fs::remove_file(changes_path(&temp_path));
+            let _ = fs::remove_file(bloom_path(&temp_path));
+            return Err(error);
+        }
+    };
+
+    println!(
+        "restored {} entries ({} bytes) to {}",
+        report.entries,
+        bytes,
+        args.db_path.display()
+    );
+    if let Some((changes, sequence)) = replayed {
+        println!("replayed {} changes, now at change {}", changes, sequence);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Locks the database at `path`, if there is one.
+fn lock(path: &Path) -> io::Result<Option<File>> {
+    let file = match File::options().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+        Err(TryLockError::Error(error)) => Err(error),
+    }
+}
+
+/// The change a snapshot was taken at, from the change log next to it.
+fn snapshot_sequence(snapshot: &Path) -> io::Result<Option<u64>> {
+    let log = changes_path(snapshot);
+    if !log.exists() {
+        return Ok(None);
+    }
+
+    let mut reader = ChangeReader::open(&log)?;
+    let mut sequence = reader.base();
+    for record in &mut reader {
+        sequence = record?.sequence;
+    }
+
+    Ok(Some(sequence))
+}
+
+/// Copies the snapshot to `temp_path`, replays the incremental backups onto
+/// it and moves it into place. Returns the size of the snapshot and, if the
+/// database keeps a change log, how many changes were replayed and the
+/// change it ended up at.
+fn restore(
+    args: &RestoreArgs,
+    base: Option<u64>,
+    temp_path: &Path,
+) -> io::Result<(u64, Option<(u64, u64)>)> {
+    let mut temp = File::create(temp_path)?;
+    let bytes = io::copy(&mut File::open(&args.snapshot)?, &mut temp)?;
+    temp.flush()?;
+    temp.sync_all()?;
+    drop(temp);
+
+    let replayed = match base {
+        Some(base) => {
+            ChangeLog::create(changes_path(temp_path), base)?;
+            Some(replay(args, temp_path)?)
+        }
+        None => None,
+    };
+
+    fs::rename(temp_path, &args.db_path)?;
+    match replayed {
+        Some(_) => fs::rename(changes_path(temp_path), changes_path(&args.db_path))?,
+        // the old database's log doesn't match the restored file
+        None => match fs::remove_file(changes_path(&args.db_path)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        },
+    }
+    // neither bloom filter is for the restored file, the next open builds one
+    for path in [bloom_path(temp_path), bloom_path(&args.db_path)] {
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+
+    Ok((bytes, replayed))
+}
+
+/// Applies the changes in the incremental backups, oldest first, stopping at
+/// `--until` and `--until-time`.
+fn replay(args: &RestoreArgs, path: &Path) -> io::Result<(u64, u64)> {
+    let mut readers = vec![];
+    for incremental in &args.incremental {
+        let reader = ChangeReader::open(incremental).map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("{}: {}", incremental.display(), error),
+            )
+        })?;
+        readers.push((incremental, reader));
+    }
+    readers.sort_by_key(|(_, reader)| reader.base());
+
+    let mut storage = Storage::open(path.to_string_lossy())?;
+    let mut sequence = storage.sequence().unwrap_or(0);
+    let mut replayed = 0;
+
+    'replay: for (incremental, reader) in readers {
+        if reader.base() > sequence {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} starts after change {}, the changes in between are missing",
+                    incremental.display(),
+                    sequence
+                ),
+            ));
+        }
+
+        for record in reader {
+            let record = record?;
+            if record.sequence <= sequence {
+                continue;
+            }
+            if args.until.is_some_and(|until| record.sequence > until)
+                || args
+                    .until_time
+                    .is_some_and(|until| record.timestamp > until)
+            {
+                break 'replay;
+            }
+
+            storage.apply_change(&record)?;
+            sequence = record.sequence;
+            replayed += 1;
+        }
+    }
+
+    if let Some(until) = args.until.filter(|until| *until > sequence) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the backups only go up to change {}, not {}",
+                sequence, until
+            ),
+        ));
+    }
+
+    Ok((replayed, sequence))
+}
@@ -0,0 +1,382 @@
+// `kiv shell`: an interactive prompt for a database file or a server
+
+use crate::client::Client;
+use crate::display::format_error;
+use crate::dump::write_script;
+use clap::Args;
+use kivql::parser::{Operation, Parser};
+use kivql::tokenizer::{Keyword, Token, Tokenizer, TokenizerError};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const META_COMMANDS: [&str; 6] = [".dump", ".import", ".stats", ".timer", ".help", ".quit"];
+
+const HELP: &str = "\
+statements run once they're complete, or when a line ends with ;
+
+.dump [FILE]     write the database as a KivQL script, to stdout by default
+.import FILE     run a KivQL script
+.stats           show database statistics and namespaces
+.timer on|off    show how long each statement took
+.help            show this message
+.quit            exit";
+
+#[derive(Args)]
+pub struct ShellArgs {
+    /// Database file to open
+    #[arg(required_unless_present = "connect", conflicts_with = "connect")]
+    db_path: Option<PathBuf>,
+    /// Connect to a kiv-json-server instead, e.g. http://127.0.0.1:7312
+    #[arg(short, long, value_name = "URL")]
+    connect: Option<String>,
+    /// File to keep history in, ~/.kiv_history by default
+    #[arg(long, value_name = "FILE")]
+    history: Option<PathBuf>,
+    /// Open the database file for reading only, so it can be shared with
+    /// other readers
+    #[arg(long, conflicts_with = "connect")]
+    read_only: bool,
+}
+
+struct ShellHelper;
+
+impl Helper for ShellHelper {}
+impl Highlighter for ShellHelper {}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|char: char| !char.is_ascii_alphabetic() && char != '.')
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        let word = &line[start..pos];
+        if word.is_empty() {
+            return Ok((pos, vec![]));
+        }
+
+        let candidates = if word.starts_with('.') {
+            META_COMMANDS
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| command.to_string())
+                .collect()
+        } else {
+            // complete in the case the word was started in
+            let lowercase = word.chars().all(|char| char.is_ascii_lowercase());
+            Keyword::ALL
+                .iter()
+                .map(Keyword::name)
+                .filter(|keyword| keyword.starts_with(&word.to_ascii_uppercase()))
+                .map(|keyword| {
+                    if lowercase {
+                        keyword.to_ascii_lowercase()
+                    } else {
+                        keyword.to_string()
+                    }
+                })
+                .collect()
+        };
+
+        Ok((start, candidates))
+    }
+}
+
+impl Validator for ShellHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(if is_complete(ctx.input()) {
+            ValidationResult::Valid(None)
+        } else {
+            ValidationResult::Incomplete
+        })
+    }
+}
+
+/// Whether the input should run now rather than wait for another line. Only
+/// an unterminated string or a statement that stops before it is finished
+/// waits, anything else that can't be parsed runs to have its error shown.
+fn is_complete(input: &str) -> bool {
+    let input = input.trim();
+    if input.is_empty() || input.starts_with('.') || input.ends_with(';') {
+        return true;
+    }
+
+    let tokens = match Tokenizer::new().tokenize(input.to_owned()) {
+        Ok(tokens) => tokens,
+        Err(TokenizerError::UnterminatedString) => return false,
+        Err(_) => return true,
+    };
+    let error = match Parser::parse_script(tokens.clone()) {
+        Ok(_) => return true,
+        Err(error) => error,
+    };
+
+    // the last statement stopped early if another token could have taken
+    // its parsing further, whereas an error earlier on stays the same
+    // whatever follows
+    let next = [Token::String(String::new()), Token::Number(0.0)]
+        .into_iter()
+        .chain(Keyword::ALL.iter().cloned().map(Token::Keyword));
+    !next.into_iter().any(|token| {
+        let mut tokens = tokens.clone();
+        tokens.extend([Token::Whitespace, token]);
+        Parser::parse_script(tokens).err().as_ref() != Some(&error)
+    })
+}
+
+pub fn run(args: ShellArgs) -> io::Result<ExitCode> {
+    let mut client = match (args.db_path, args.connect) {
+        (Some(path), _) => match Client::open(path, args.read_only) {
+            Ok(client) => client,
+            Err(error) => {
+                eprintln!("{}", error);
+                return Ok(ExitCode::FAILURE);
+            }
+        },
+        (None, Some(url)) => Client::connect(&url),
+        (None, None) => unreachable!("clap requires one of them"),
+    };
+
+    let mut editor: Editor<ShellHelper, DefaultHistory> =
+        Editor::new().map_err(io::Error::other)?;
+    editor.set_helper(Some(ShellHelper));
+
+    let history = args
+        .history
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kiv_history")));
+    if let Some(history) = &history {
+        // there's no history the first time
+        let _ = editor.load_history(history);
+    }
+
+    println!("kiv {}, .help for help", env!("CARGO_PKG_VERSION"));
+
+    let mut timer = true;
+    loop {
+        let input = match editor.readline("kiv> ") {
+            Ok(input) => input,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(io::Error::other(error)),
+        };
+
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(input);
+
+        if input.starts_with('.') {
+            let mut words = input.split_whitespace();
+            match (words.next().unwrap(), words.next()) {
+                (".quit" | ".exit", _) => break,
+                (".help", _) => println!("{}", HELP),
+                (".timer", Some("on")) => timer = true,
+                (".timer", Some("off")) => timer = false,
+                (".dump", file) => dump(&mut client, file),
+                (".import", Some(file)) => import(&mut client, file),
+                (".stats", _) => run_script(
+                    &mut client,
+                    vec![Operation::INFO, Operation::SHOWNAMESPACES],
+                    false,
+                ),
+                _ => eprintln!("unknown command, .help for help"),
+            }
+            continue;
+        }
+
+        match parse(input) {
+            Ok(operations) => run_script(&mut client, operations, timer),
+            Err(error) => eprintln!("error: {}", error),
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(error) = editor.save_history(history) {
+            eprintln!("failed to save history: {}", error);
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn parse(script: &str) -> Result<Vec<Operation>, String> {
+    let tokens = Tokenizer::new()
+        .tokenize(script.to_owned())
+        .map_err(|error| format!("tokenizer error: {}", error))?;
+
+    Parser::parse_script(tokens).map_err(|error| format!("parser error: {}", error))
+}
+
+/// Runs operations in order, stopping at the first error.
+fn run_script(client: &mut Client, operations: Vec<Operation>, timer: bool) {
+    for operation in operations {
+        match client.execute(operation) {
+            Ok(response) => {
+                println!("{}", response.output);
+                if timer {
+                    println!("({:.2?})", response.time);
+                }
+            }
+            Err(error) => {
+                eprintln!("error: {}", error);
+                return;
+            }
+        }
+    }
+}
+
+fn dump(client: &mut Client, file: Option<&str>) {
+    let kiv = match client.kiv() {
+        Some(kiv) => kiv,
+        None => {
+            eprintln!("error: .dump needs a database file, the server can't list entries");
+            return;
+        }
+    };
+
+    let result = match file {
+        Some(file) => std::fs::File::create(file)
+            .map_err(kiv_core::KivError::from)
+            .and_then(|file| {
+                let mut out = BufWriter::new(file);
+                let entries = write_script(kiv, &mut out)?;
+                out.flush()?;
+                Ok(entries)
+            }),
+        None => write_script(kiv, &mut io::stdout().lock()),
+    };
+
+    match result {
+        Ok(entries) => {
+            if file.is_some() {
+                println!("dumped {} records", entries);
+            }
+        }
+        Err(error) => eprintln!("error: {}", format_error(&error)),
+    }
+}
+
+fn import(client: &mut Client, file: &str) {
+    let script = match std::fs::read_to_string(file) {
+        Ok(script) => script,
+        Err(error) => {
+            eprintln!("error: failed to read {}: {}", file, error);
+            return;
+        }
+    };
+    let operations = match parse(&script) {
+        Ok(operations) => operations,
+        Err(error) => {
+            eprintln!("error: {}: {}", file, error);
+            return;
+        }
+    };
+
+    let count = operations.len();
+    for (index, operation) in operations.into_iter().enumerate() {
+        if let Err(error) = client.execute(operation) {
+            eprintln!("error: statement {} of {}: {}", index + 1, count, error);
+            return;
+        }
+    }
+
+    println!("ran {} statements", count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustyline::history::DefaultHistory;
+
+    #[test]
+    fn statements_that_stop_early_wait_for_more() {
+        for input in [
+            "SET 'a'",
+            "SET 'a' TO",
+            "SET 'a' TO 'b",
+            "ZADD 'z' 1",
+            "ZRANGE 'z'",
+            "ZRANGE 'z' BY",
+            "ZRANGE 'z' BY SCORE 0",
+            "SADD 's'",
+            "GET",
+            "CREATE",
+            "EXPLAIN",
+            "EXPLAIN GET",
+            "GET 'a'; GET",
+        ] {
+            assert!(!is_complete(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn statements_that_can_run_or_fail_now_are_complete() {
+        for input in [
+            "",
+            ".help",
+            "GET 'a'",
+            "SADD 's' 'a' 'b'",
+            "SET 'a';",
+            // every other error is shown at once
+            "GET 'a' 'b'",
+            "SET 'a' 'b'",
+            "ZRANGE 'z' 0 1",
+            "SADD 's' 1",
+            "TO 'a'",
+            "'a'",
+            "GET 'a' BOGUS",
+            "GET 1; GET",
+        ] {
+            assert!(is_complete(input), "{}", input);
+        }
+    }
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        ShellHelper
+            .complete(line, line.len(), &Context::new(&history))
+            .unwrap()
+    }
+
+    #[test]
+    fn keywords_and_commands_are_completed() {
+        assert_eq!(complete("SM"), (0, vec!["SMEMBERS".to_string()]));
+        assert_eq!(
+            complete("GET 'a'; z"),
+            (
+                9,
+                ["zadd", "zrem", "zscore", "zrange", "zrank"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+        assert_eq!(complete("SHOW NAMESPACE").1, ["NAMESPACE", "NAMESPACES"]);
+        assert_eq!(complete(".st"), (0, vec![".stats".to_string()]));
+        assert_eq!(complete("GET "), (4, vec![]));
+        // every keyword can be completed
+        for keyword in Keyword::ALL {
+            assert!(complete(keyword.name())
+                .1
+                .contains(&keyword.name().to_string()));
+        }
+    }
+}
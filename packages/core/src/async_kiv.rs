@@ -0,0 +1,205 @@
+// an async handle for use inside a tokio runtime
+
+use crate::{KeyValue, Kiv, KivError, OperationResult, PreparedStatement};
+use std::{io, sync::Arc};
+
+/// A handle on an open database for async code, available with the `tokio`
+/// feature.
+///
+/// Every call runs on tokio's blocking thread pool, so scanning the file
+/// never holds up a runtime worker. Like [`Kiv`], clones work on the same
+/// database but each keeps its own current namespace.
+///
+/// The futures are cancellation-safe: an operation that has started runs to
+/// completion even if its future is dropped, and only its result is lost.
+/// A write is either made in full or, if the future was dropped before it
+/// was first polled, not at all.
+pub struct AsyncKiv {
+    // shared with the blocking task, so `USE` sticks to this handle
+    kiv: Arc<Kiv>,
+}
+
+impl Clone for AsyncKiv {
+    fn clone(&self) -> Self {
+        Self {
+            kiv: Arc::new(Kiv::clone(&self.kiv)),
+        }
+    }
+}
+
+impl From<Kiv> for AsyncKiv {
+    fn from(kiv: Kiv) -> Self {
+        Self { kiv: Arc::new(kiv) }
+    }
+}
+
+impl AsyncKiv {
+    /// The synchronous handle underneath, for calls that don't touch the
+    /// file such as [`Kiv::allows_admin`].
+    pub fn blocking(&self) -> &Kiv {
+        &self.kiv
+    }
+
+    /// Runs `f` on the blocking thread pool with the synchronous handle, for
+    /// anything the other methods don't cover. A panic in `f` comes back as
+    /// an io error; the handle can still be used afterwards.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, KivError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Kiv) -> Result<T, KivError> + Send + 'static,
+    {
+        let kiv = Arc::clone(&self.kiv);
+
+        tokio::task::spawn_blocking(move || f(&kiv))
+            .await
+            .unwrap_or_else(|err| Err(KivError::IoError(io::Error::other(err))))
+    }
+
+    pub async fn exec(&self, statement: impl Into<String>) -> Result<OperationResult, KivError> {
+        let statement = statement.into();
+        self.run(move |kiv| kiv.exec(statement)).await
+    }
+
+    /// Executes a prepared statement with its currently bound parameters.
+    pub async fn exec_prepared(
+        &self,
+        statement: PreparedStatement,
+    ) -> Result<OperationResult, KivError> {
+        self.run(move |kiv| kiv.exec_prepared(&statement)).await
+    }
+
+    pub async fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>, KivError> {
+        let key = key.into();
+        self.run(move |kiv| kiv.get(key)).await
+    }
+
+    pub async fn set(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(), KivError> {
+        let (key, value) = (key.into(), value.into());
+        self.run(move |kiv| kiv.set(key, value)).await
+    }
+
+    pub async fn delete(&self, key: impl Into<Vec<u8>>) -> Result<(), KivError> {
+        let key = key.into();
+        self.run(move |kiv| kiv.delete(key)).await
+    }
+
+    /// Returns every key and value whose key starts with `prefix`, ordered by
+    /// key.
+    pub async fn scan(&self, prefix: impl Into<Vec<u8>>) -> Result<Vec<KeyValue>, KivError> {
+        let prefix = prefix.into();
+        self.run(move |kiv| kiv.scan(prefix)).await
+    }
+
+    /// Switches the namespace that following statements on this handle
+    /// operate on.
+    pub async fn use_namespace(&self, name: impl Into<String>) -> Result<(), KivError> {
+        let name = name.into();
+        self.run(move |kiv| kiv.use_namespace(&name)).await
+    }
+
+    /// A read-only handle on the database as it is now. See
+    /// [`Kiv::snapshot`].
+    pub async fn snapshot(&self) -> Result<AsyncKiv, KivError> {
+        self.run(|kiv| Ok(AsyncKiv::from(kiv.snapshot()))).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    fn open() -> (tempfile::TempDir, AsyncKiv) {
+        let dir = tempfile::tempdir().unwrap();
+        let kiv = Kiv::open(dir.path().join("test.kiv")).unwrap();
+        (dir, AsyncKiv::from(kiv))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dropped_writes_are_made_in_full_or_not_at_all() {
+        let (_dir, kiv) = open();
+        let value = vec![7; 1 << 20];
+
+        // never polled, so never started
+        drop(kiv.set("unpolled", value.clone()));
+
+        // polled once, which starts the write, and then dropped
+        let keys: Vec<_> = (0..16).map(|i| format!("key {}", i)).collect();
+        for key in &keys {
+            let _ = timeout(Duration::ZERO, kiv.set(key.as_str(), value.clone())).await;
+        }
+
+        for key in &keys {
+            let read = timeout(Duration::from_secs(10), async {
+                loop {
+                    match kiv.get(key.as_str()).await.unwrap() {
+                        Some(read) => break read,
+                        None => sleep(Duration::from_millis(1)).await,
+                    }
+                }
+            })
+            .await
+            .expect("a started write was never made");
+            assert!(read == value, "{} was written in part", key);
+        }
+        assert_eq!(kiv.get("unpolled").await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clones_keep_their_own_namespace() {
+        let (_dir, kiv) = open();
+        kiv.exec("CREATE NAMESPACE 'a'").await.unwrap();
+        kiv.exec("CREATE NAMESPACE 'b'").await.unwrap();
+        let (a, b) = (kiv.clone(), kiv.clone());
+
+        for i in 0..32 {
+            let value = i.to_string();
+            let (left, right) = tokio::join!(
+                async {
+                    a.use_namespace("a").await?;
+                    a.set("k", format!("a{}", value)).await
+                },
+                async {
+                    b.use_namespace("b").await?;
+                    b.set("k", format!("b{}", value)).await
+                },
+            );
+            left.unwrap();
+            right.unwrap();
+
+            assert_eq!(a.get("k").await.unwrap(), Some(format!("a{}", i).into()));
+            assert_eq!(b.get("k").await.unwrap(), Some(format!("b{}", i).into()));
+        }
+
+        // the original never left the default namespace
+        assert_eq!(kiv.get("k").await.unwrap(), None);
+        kiv.set("k", "default").await.unwrap();
+        assert_eq!(a.get("k").await.unwrap(), Some(b"a31".to_vec()));
+
+        // and a clone starts out where the original is
+        kiv.use_namespace("b").await.unwrap();
+        assert_eq!(kiv.clone().get("k").await.unwrap(), Some(b"b31".to_vec()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handles_still_work_after_a_panic() {
+        let (_dir, kiv) = open();
+        kiv.set("a", "1").await.unwrap();
+
+        let result: Result<(), _> = kiv.run(|_| panic!("in a read")).await;
+        assert!(matches!(result, Err(KivError::IoError(_))));
+
+        // panicking while holding the write lock poisons it
+        let result: Result<(), _> = kiv.run(|kiv| kiv.write(|_| panic!("in a write"))).await;
+        assert!(matches!(result, Err(KivError::IoError(_))));
+
+        assert_eq!(kiv.get("a").await.unwrap(), Some(b"1".to_vec()));
+        kiv.set("b", "2").await.unwrap();
+        assert_eq!(kiv.clone().get("b").await.unwrap(), Some(b"2".to_vec()));
+    }
+}
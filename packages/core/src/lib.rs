@@ -1,15 +1,34 @@
 // core kiv implementation
 
+#[cfg(feature = "tokio")]
+pub mod async_kiv;
+pub mod prepared;
+
+#[cfg(feature = "tokio")]
+pub use async_kiv::AsyncKiv;
 use kivql::{
     parser::{Operation, Parser, ParserError},
     tokenizer::{Tokenizer, TokenizerError},
 };
+pub use prepared::{Param, PreparedStatement};
 use std::{
-    io,
-    path::PathBuf,
+    collections::BTreeSet,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, PoisonError, RwLock,
+    },
     time::{Duration, Instant},
 };
-use storage::Storage;
+use storage::{
+    engine::{Locked, StorageEngine},
+    BTreeEngine, KeyError, LockError, LsmEngine, MemoryEngine, Storage,
+};
+pub use storage::{
+    BTreeOptions, Cipher, Codec, CompressionOptions, EncryptionKey, EncryptionOptions, Entry,
+    LsmOptions, StorageOptions, DEFAULT_NAMESPACE,
+};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -18,12 +37,111 @@ pub enum KivError {
     TokenizerError(#[from] TokenizerError),
     #[error("parser error")]
     ParserError(#[from] ParserError),
+    #[error("io error")]
+    IoError(#[from] io::Error),
+    #[error("namespace already exists")]
+    NamespaceExists(String),
+    #[error("namespace not found")]
+    NamespaceNotFound(String),
+    #[error("the default namespace can't be dropped")]
+    DropDefaultNamespace,
+    #[error("admin statements are disabled")]
+    AdminDisabled,
+    #[error("no value bound to parameter")]
+    UnboundParameter(String),
+    #[error("value bound to parameter has the wrong type")]
+    ParameterTypeMismatch(String),
+    #[error("placeholders don't line up with the statement's values")]
+    PlaceholderMismatch,
+    #[error("snapshots are read-only")]
+    SnapshotReadOnly,
 }
 
 #[derive(Error, Debug)]
 pub enum KivOpenError {
     #[error("io error")]
     IoError(#[from] io::Error),
+    #[error("database is locked by another process")]
+    Locked,
+    #[error("database is encrypted, and no key was given")]
+    MissingKey,
+    #[error("database is encrypted with a different key")]
+    WrongKey,
+    #[error("database isn't encrypted, but a key was given")]
+    NotEncrypted,
+}
+
+impl KivOpenError {
+    fn from_storage(err: io::Error) -> Self {
+        let inner = err.get_ref();
+        let locked = inner.is_some_and(|inner| inner.is::<LockError>());
+        match (
+            locked,
+            inner.and_then(|inner| inner.downcast_ref::<KeyError>()),
+        ) {
+            (true, _) => KivOpenError::Locked,
+            (_, Some(KeyError::Missing)) => KivOpenError::MissingKey,
+            (_, Some(KeyError::Wrong)) => KivOpenError::WrongKey,
+            (_, Some(KeyError::NotEncrypted)) => KivOpenError::NotEncrypted,
+            _ => KivOpenError::IoError(err),
+        }
+    }
+}
+
+/// Which storage engine a database keeps its entries in.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Engine {
+    /// A single kiv file.
+    #[default]
+    File,
+    /// Memory only. Everything is lost when the last handle is dropped,
+    /// and the path is ignored.
+    Memory,
+    /// A log-structured merge tree in the directory at the path, tuned by
+    /// [`KivConfig::lsm`]. Suited to write-heavy use.
+    Lsm,
+    /// A copy-on-write B+tree of fixed-size pages in the file at the path,
+    /// tuned by [`KivConfig::btree`]. Suited to reads and range scans.
+    BTree,
+}
+
+/// How to open a database, for [`Kiv::open_with`].
+#[derive(Debug, Clone)]
+pub struct KivConfig {
+    pub path: PathBuf,
+    pub engine: Engine,
+    /// Opens an existing database for reading only, as with
+    /// [`Kiv::open_read_only`].
+    pub read_only: bool,
+    /// Tuning for [`Engine::File`], including how its values are compressed
+    /// and encrypted. The other engines don't encrypt.
+    pub file: StorageOptions,
+    /// Tuning for [`Engine::Lsm`].
+    pub lsm: LsmOptions,
+    /// Tuning for [`Engine::BTree`].
+    pub btree: BTreeOptions,
+}
+
+impl KivConfig {
+    /// A database file at `path`, opened for reading and writing.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            engine: Engine::File,
+            read_only: false,
+            file: StorageOptions::default(),
+            lsm: LsmOptions::default(),
+            btree: BTreeOptions::default(),
+        }
+    }
+
+    /// A database that only lives in memory.
+    pub fn memory() -> Self {
+        Self {
+            engine: Engine::Memory,
+            ..Self::new(PathBuf::new())
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -37,6 +155,29 @@ pub enum OperationResultResult {
     Set,
     Delete,
     Get(GetResult),
+    ZAdd,
+    ZRem,
+    ZScore(ZScoreResult),
+    ZRange(ZRangeResult),
+    ZRank(ZRankResult),
+    SAdd,
+    SRem,
+    SIsMember(SIsMemberResult),
+    SMembers(SMembersResult),
+    SCard(SCardResult),
+    SUnion(SMembersResult),
+    SInter(SMembersResult),
+    SDiff(SMembersResult),
+    CreateNamespace,
+    DropNamespace,
+    Use,
+    Namespaces(NamespacesResult),
+    Flush,
+    Truncate,
+    Info(InfoResult),
+    Explain(ExplainResult),
+    Backup(BackupResult),
+    Compact(CompactResult),
 }
 
 #[derive(Debug)]
@@ -44,67 +185,1610 @@ pub struct GetResult {
     pub value: Option<String>,
 }
 
+/// A key and value returned by [`Kiv::scan`].
+#[derive(Debug, PartialEq)]
+pub struct KeyValue {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct ZScoreResult {
+    pub score: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct ZRangeResult {
+    pub members: Vec<ScoredMember>,
+}
+
+#[derive(Debug)]
+pub struct ScoredMember {
+    pub member: String,
+    pub score: f64,
+}
+
+#[derive(Debug)]
+pub struct ZRankResult {
+    pub rank: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct SIsMemberResult {
+    pub is_member: bool,
+}
+
+#[derive(Debug)]
+pub struct SMembersResult {
+    pub members: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct SCardResult {
+    pub cardinality: u64,
+}
+
+#[derive(Debug)]
+pub struct NamespacesResult {
+    pub namespaces: Vec<NamespaceInfo>,
+}
+
+#[derive(Debug)]
+pub struct NamespaceInfo {
+    pub name: String,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct InfoResult {
+    pub keys: u64,
+    pub file_size: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub version: u16,
+    pub uptime: Duration,
+    /// Lookups for missing keys answered without reading from disk, since
+    /// the database was opened.
+    pub bloom_hits: u64,
+    /// Lookups that had to read from disk despite the bloom filters.
+    pub bloom_misses: u64,
+    /// How many times bigger values would be without compression.
+    pub compression_ratio: f64,
+}
+
+#[derive(Debug)]
+pub struct BackupResult {
+    pub path: String,
+    pub bytes: u64,
+    /// The change the backup was taken at, if the database keeps a change
+    /// log.
+    pub sequence: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct CompactResult {
+    /// How much smaller the file got.
+    pub bytes: u64,
+}
+
+/// How an operation reaches the data it needs.
+#[derive(Debug, PartialEq)]
+pub enum AccessPath {
+    /// Doesn't touch storage.
+    None,
+    /// Scans entries in file order, stopping at the first one that matches.
+    KeyScan,
+    /// Reads every entry in the file.
+    FullScan,
+    /// Reads every entry and rewrites the file.
+    Rewrite,
+    /// Looks a name up among the namespaces.
+    Namespaces,
+    /// Reads the members of one sorted set, in score order.
+    SortedSet,
+}
+
+#[derive(Debug)]
+pub struct ExplainResult {
+    /// The parsed operation, as canonical KivQL.
+    pub operation: String,
+    pub access_path: AccessPath,
+    /// How many entries the operation reads at most, and how many bytes.
+    /// Both are taken from counters the storage engine keeps, without
+    /// reading what they count.
+    pub estimated_rows: u64,
+    pub estimated_bytes: u64,
+}
+
+enum SetAlgebra {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/// A handle on an open database.
+///
+/// Handles can be shared between threads, and cloning one is cheap: every
+/// clone works on the same database. Reads run in parallel, each on its own
+/// view of the file, while writes take turns. Each handle keeps its own
+/// current namespace, so a clone starts out where the original was but
+/// `USE` on one doesn't affect the other.
+///
+/// [`Kiv::snapshot`] makes a handle that keeps reading the database as it
+/// was when the snapshot was taken.
 pub struct Kiv {
-    tokenizer: Tokenizer,
-    storage: Storage,
+    shared: Arc<Shared>,
+    namespace: AtomicU16,
+    allow_admin: bool,
+    /// The view reads go through, on snapshot handles.
+    snapshot: Option<Box<dyn StorageEngine>>,
 }
+
+struct Shared {
+    storage: RwLock<Box<dyn StorageEngine>>,
+    opened_at: Instant,
+}
+
+// handles are meant to be shared between threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Kiv>();
+};
+
+impl Clone for Kiv {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+            namespace: AtomicU16::new(self.namespace.load(Ordering::Relaxed)),
+            allow_admin: self.allow_admin,
+            snapshot: self.snapshot.as_ref().map(|snapshot| snapshot.reader()),
+        }
+    }
+}
+
 impl Kiv {
+    /// Opens a database for reading and writing, creating it if it doesn't
+    /// exist. Only one process can have a database open this way.
     pub fn open(path: PathBuf) -> Result<Self, KivOpenError> {
-        Ok(Self {
-            tokenizer: Tokenizer::new(),
-            storage: Storage::open(path.to_string_lossy().to_string())
-                .expect("Failed to initialize storage"),
+        Self::open_with(KivConfig::new(path))
+    }
+
+    /// Opens an existing database for reading only. Any number of processes
+    /// can do this at once, as long as none has it open for writing.
+    pub fn open_read_only(path: PathBuf) -> Result<Self, KivOpenError> {
+        Self::open_with(KivConfig {
+            read_only: true,
+            ..KivConfig::new(path)
         })
     }
 
-    pub fn exec(&mut self, statement: String) -> Result<OperationResult, KivError> {
-        let tokens = self.tokenizer.tokenize(statement)?;
-        let operation = Parser::parse(tokens)?;
+    /// Opens a database with the engine and options in `config`.
+    pub fn open_with(config: KivConfig) -> Result<Self, KivOpenError> {
+        let path = config.path.to_string_lossy().to_string();
+        let storage: Box<dyn StorageEngine> = match (config.engine, config.read_only) {
+            (Engine::File, false) => Box::new(
+                Storage::open_with_options(path, config.file)
+                    .map_err(KivOpenError::from_storage)?,
+            ),
+            (Engine::File, true) => Box::new(
+                Storage::open_read_only_with_options(path, config.file)
+                    .map_err(KivOpenError::from_storage)?,
+            ),
+            (Engine::Memory, false) => Box::new(MemoryEngine::new()),
+            (Engine::Memory, true) => {
+                return Err(KivOpenError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "an in-memory database can't be opened read-only",
+                )))
+            }
+            (Engine::Lsm, false) => Box::new(
+                LsmEngine::open(&config.path, config.lsm).map_err(KivOpenError::from_storage)?,
+            ),
+            (Engine::Lsm, true) => Box::new(
+                LsmEngine::open_read_only(&config.path, config.lsm)
+                    .map_err(KivOpenError::from_storage)?,
+            ),
+            (Engine::BTree, false) => Box::new(
+                BTreeEngine::open(&config.path, config.btree)
+                    .map_err(KivOpenError::from_storage)?,
+            ),
+            (Engine::BTree, true) => Box::new(
+                BTreeEngine::open_read_only(&config.path, config.btree)
+                    .map_err(KivOpenError::from_storage)?,
+            ),
+        };
+
+        Ok(Self::with_storage(storage))
+    }
+
+    fn with_storage(storage: Box<dyn StorageEngine>) -> Self {
+        Self {
+            namespace: AtomicU16::new(storage.current_namespace()),
+            shared: Arc::new(Shared {
+                storage: RwLock::new(storage),
+                opened_at: Instant::now(),
+            }),
+            allow_admin: true,
+            snapshot: None,
+        }
+    }
+
+    /// A read-only handle on the database as it is now. Reads through it
+    /// keep seeing the same data while writes carry on through other
+    /// handles, and writes through it fail with
+    /// [`KivError::SnapshotReadOnly`]. The old versions it reads are kept
+    /// until it and all its clones are dropped, after which `COMPACT`
+    /// removes them.
+    pub fn snapshot(&self) -> Kiv {
+        let storage = self
+            .shared
+            .storage
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // a snapshot of a snapshot reads where the first one does
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot.reader(),
+            None => storage.snapshot_reader(),
+        };
+
+        Kiv {
+            snapshot: Some(snapshot),
+            ..self.clone()
+        }
+    }
+
+    /// Whether this handle is a snapshot made by [`Kiv::snapshot`].
+    pub fn is_snapshot(&self) -> bool {
+        self.snapshot.is_some()
+    }
+
+    /// Runs `f` on a view of the database that can read alongside any
+    /// number of other views.
+    fn read<T>(
+        &self,
+        f: impl FnOnce(&mut dyn StorageEngine) -> Result<T, KivError>,
+    ) -> Result<T, KivError> {
+        // a panic elsewhere doesn't leave the file any different from an
+        // error would, so a poisoned lock is still safe to use
+        let storage = self
+            .shared
+            .storage
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut view = match &self.snapshot {
+            // the lock is still needed, as a write could be halfway through
+            // changing what the snapshot reads
+            Some(snapshot) => snapshot.reader(),
+            None => storage.reader(),
+        };
+        view.use_namespace(self.namespace.load(Ordering::Relaxed));
+
+        f(view.as_mut())
+    }
+
+    /// Runs `f` with the database to itself, waiting for reads and other
+    /// writes to finish first.
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut dyn StorageEngine) -> Result<T, KivError>,
+    ) -> Result<T, KivError> {
+        if self.snapshot.is_some() {
+            return Err(KivError::SnapshotReadOnly);
+        }
+
+        let mut storage = self
+            .shared
+            .storage
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        storage.use_namespace(self.namespace.load(Ordering::Relaxed));
+        let result = f(storage.as_mut());
+        // dropping the current namespace or flushing moves to the default
+        self.namespace
+            .store(storage.current_namespace(), Ordering::Relaxed);
+
+        result
+    }
+
+    /// Allows or denies admin statements such as `FLUSH` and `INFO`. They are
+    /// allowed by default.
+    pub fn set_allow_admin(&mut self, allow_admin: bool) {
+        self.allow_admin = allow_admin;
+    }
+
+    /// Whether admin statements are allowed.
+    pub fn allows_admin(&self) -> bool {
+        self.allow_admin
+    }
+
+    pub fn exec(&self, statement: String) -> Result<OperationResult, KivError> {
+        let tokens = Tokenizer::new().tokenize(statement)?;
+        self.execute(Parser::parse(tokens)?)
+    }
+
+    /// Tokenizes and parses a statement once so it can be executed many times
+    /// with different parameters bound to its placeholders.
+    pub fn prepare(&self, statement: impl Into<String>) -> Result<PreparedStatement, KivError> {
+        let tokens = Tokenizer::new().tokenize(statement.into())?;
+        let slots = PreparedStatement::slots(&tokens);
+        let operation = Parser::parse_prepared(tokens)?;
+
+        PreparedStatement::new(operation, slots)
+    }
+
+    /// Executes a prepared statement with its currently bound parameters.
+    pub fn exec_prepared(
+        &self,
+        statement: &PreparedStatement,
+    ) -> Result<OperationResult, KivError> {
+        self.execute(statement.operation()?)
+    }
+
+    /// Executes an already parsed operation.
+    pub fn execute(&self, operation: Operation) -> Result<OperationResult, KivError> {
+        if operation.is_admin() && !self.allow_admin {
+            return Err(KivError::AdminDisabled);
+        }
 
         let start = Instant::now();
-        #[allow(unused_assignments)]
-        let mut result: OperationResultResult = OperationResultResult::Set;
 
-        match &operation {
+        let result = match operation {
             Operation::SET(set) => {
-                // see if we need to write or update entry
-                let entry = self
-                    .storage
-                    .get_data_entry(&set.key)
-                    .expect("unknown error");
-                if entry.is_some() {
-                    // update
-                    self.storage
-                        .update_data_entry(&set.key, &set.value)
-                        .expect("unknown error");
-                } else {
-                    // write
-                    self.storage
-                        .write_data_entry(&set.key, &set.value)
-                        .expect("unknown error");
-                }
-                result = OperationResultResult::Set;
+                self.set(set.key, set.value)?;
+                OperationResultResult::Set
             }
             Operation::DELETE(delete) => {
-                self.storage
-                    .delete_data_entry(&delete.key)
-                    .expect("unknown error");
-                result = OperationResultResult::Delete;
+                self.delete(delete.key)?;
+                OperationResultResult::Delete
             }
             Operation::GET(get) => {
                 let value = self
-                    .storage
-                    .get_data_entry(&get.key)
-                    .expect("unknown error");
-                result = OperationResultResult::Get(GetResult { value });
+                    .get(get.key)?
+                    .map(|value| String::from_utf8_lossy(&value).into_owned());
+                OperationResultResult::Get(GetResult { value })
             }
-        }
+            Operation::ZADD(zadd) => {
+                self.zadd(zadd.key, zadd.score, zadd.member)?;
+                OperationResultResult::ZAdd
+            }
+            Operation::ZREM(zrem) => {
+                self.zrem(zrem.key, zrem.member)?;
+                OperationResultResult::ZRem
+            }
+            Operation::ZSCORE(zscore) => {
+                let score = self.zscore(zscore.key, zscore.member)?;
+                OperationResultResult::ZScore(ZScoreResult { score })
+            }
+            Operation::ZRANGE(zrange) => {
+                let members = self.zrange_by_score(zrange.key, zrange.min, zrange.max)?;
+                OperationResultResult::ZRange(ZRangeResult { members })
+            }
+            Operation::ZRANK(zrank) => {
+                let rank = self.zrank(zrank.key, zrank.member)?;
+                OperationResultResult::ZRank(ZRankResult { rank })
+            }
+            Operation::SADD(sadd) => {
+                self.sadd(sadd.key, sadd.members)?;
+                OperationResultResult::SAdd
+            }
+            Operation::SREM(srem) => {
+                self.srem(srem.key, srem.members)?;
+                OperationResultResult::SRem
+            }
+            Operation::SISMEMBER(sismember) => {
+                let is_member = self.sismember(sismember.key, sismember.member)?;
+                OperationResultResult::SIsMember(SIsMemberResult { is_member })
+            }
+            Operation::SMEMBERS(smembers) => {
+                let members = self.smembers(smembers.key)?;
+                OperationResultResult::SMembers(SMembersResult { members })
+            }
+            Operation::SCARD(scard) => {
+                let cardinality = self.scard(scard.key)?;
+                OperationResultResult::SCard(SCardResult { cardinality })
+            }
+            Operation::SUNION(sunion) => {
+                let members = self.sunion(sunion.keys)?;
+                OperationResultResult::SUnion(SMembersResult { members })
+            }
+            Operation::SINTER(sinter) => {
+                let members = self.sinter(sinter.keys)?;
+                OperationResultResult::SInter(SMembersResult { members })
+            }
+            Operation::SDIFF(sdiff) => {
+                let members = self.sdiff(sdiff.keys)?;
+                OperationResultResult::SDiff(SMembersResult { members })
+            }
+            Operation::CREATENAMESPACE(create) => {
+                self.create_namespace(create.name)?;
+                OperationResultResult::CreateNamespace
+            }
+            Operation::DROPNAMESPACE(drop) => {
+                self.drop_namespace(drop.name)?;
+                OperationResultResult::DropNamespace
+            }
+            Operation::USE(use_namespace) => {
+                self.use_namespace(&use_namespace.namespace)?;
+                OperationResultResult::Use
+            }
+            Operation::SHOWNAMESPACES => {
+                let namespaces = self.namespaces()?;
+                OperationResultResult::Namespaces(NamespacesResult { namespaces })
+            }
+            Operation::FLUSH => {
+                self.flush()?;
+                OperationResultResult::Flush
+            }
+            Operation::TRUNCATE => {
+                self.truncate()?;
+                OperationResultResult::Truncate
+            }
+            Operation::EXPLAIN(explained) => {
+                OperationResultResult::Explain(self.explain_operation(&explained)?)
+            }
+            Operation::INFO => OperationResultResult::Info(self.info()?),
+            Operation::BACKUP(backup) => {
+                let (bytes, sequence) =
+                    self.backup_view(|view, locked| view.backup(Path::new(&backup.path), locked))?;
+                OperationResultResult::Backup(BackupResult {
+                    path: backup.path,
+                    bytes,
+                    sequence,
+                })
+            }
+            Operation::COMPACT => OperationResultResult::Compact(CompactResult {
+                bytes: self.compact()?,
+            }),
+        };
 
         let elapsed = start.elapsed();
 
-        return Ok(OperationResult {
+        Ok(OperationResult {
             time: elapsed,
             result,
-        });
+        })
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, KivError> {
+        self.read(|storage| Ok(storage.get(key.as_ref())?))
+    }
+
+    pub fn set(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), KivError> {
+        self.write(|storage| Ok(storage.put(key.as_ref(), value.as_ref())?))
+    }
+
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<(), KivError> {
+        self.write(|storage| Ok(storage.delete(key.as_ref())?))
+    }
+
+    /// Returns every key and value whose key starts with `prefix`, ordered by
+    /// key.
+    pub fn scan(&self, prefix: impl AsRef<[u8]>) -> Result<Vec<KeyValue>, KivError> {
+        self.read(|storage| {
+            Ok(storage
+                .scan(prefix.as_ref())?
+                .into_iter()
+                .map(|(key, value)| KeyValue { key, value })
+                .collect())
+        })
+    }
+
+    /// Adds a member to a sorted set, or updates its score.
+    pub fn zadd(
+        &self,
+        key: impl AsRef<str>,
+        score: f64,
+        member: impl AsRef<str>,
+    ) -> Result<(), KivError> {
+        self.write(|storage| Ok(storage.zadd(key.as_ref(), member.as_ref(), score)?))
+    }
+
+    pub fn zrem(&self, key: impl AsRef<str>, member: impl AsRef<str>) -> Result<(), KivError> {
+        self.write(|storage| Ok(storage.zrem(key.as_ref(), member.as_ref())?))
+    }
+
+    pub fn zscore(
+        &self,
+        key: impl AsRef<str>,
+        member: impl AsRef<str>,
+    ) -> Result<Option<f64>, KivError> {
+        self.read(|storage| Ok(storage.zscore(key.as_ref(), member.as_ref())?))
+    }
+
+    /// Returns the members of a sorted set with a score between `min` and
+    /// `max` (inclusive), ordered by score.
+    pub fn zrange_by_score(
+        &self,
+        key: impl AsRef<str>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KivError> {
+        self.read(|storage| {
+            Ok(storage
+                .zrange(key.as_ref(), min, max)?
+                .into_iter()
+                .map(|(member, score)| ScoredMember { member, score })
+                .collect())
+        })
+    }
+
+    pub fn zrank(
+        &self,
+        key: impl AsRef<str>,
+        member: impl AsRef<str>,
+    ) -> Result<Option<u64>, KivError> {
+        self.read(|storage| Ok(storage.zrank(key.as_ref(), member.as_ref())?))
+    }
+
+    pub fn sadd<M>(&self, key: impl AsRef<str>, members: M) -> Result<(), KivError>
+    where
+        M: IntoIterator,
+        M::Item: AsRef<str>,
+    {
+        self.write(|storage| {
+            for member in members {
+                storage.sadd(key.as_ref(), member.as_ref())?;
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn srem<M>(&self, key: impl AsRef<str>, members: M) -> Result<(), KivError>
+    where
+        M: IntoIterator,
+        M::Item: AsRef<str>,
+    {
+        self.write(|storage| {
+            for member in members {
+                storage.srem(key.as_ref(), member.as_ref())?;
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn sismember(
+        &self,
+        key: impl AsRef<str>,
+        member: impl AsRef<str>,
+    ) -> Result<bool, KivError> {
+        self.read(|storage| Ok(storage.sismember(key.as_ref(), member.as_ref())?))
+    }
+
+    /// Returns every member of a set, in sorted order.
+    pub fn smembers(&self, key: impl AsRef<str>) -> Result<Vec<String>, KivError> {
+        self.read(|storage| Ok(storage.smembers(key.as_ref())?))
+    }
+
+    pub fn scard(&self, key: impl AsRef<str>) -> Result<u64, KivError> {
+        self.read(|storage| Ok(storage.scard(key.as_ref())?))
+    }
+
+    pub fn sunion<K>(&self, keys: K) -> Result<Vec<String>, KivError>
+    where
+        K: IntoIterator,
+        K::Item: AsRef<str>,
+    {
+        self.combine_sets(keys, SetAlgebra::Union)
+    }
+
+    pub fn sinter<K>(&self, keys: K) -> Result<Vec<String>, KivError>
+    where
+        K: IntoIterator,
+        K::Item: AsRef<str>,
+    {
+        self.combine_sets(keys, SetAlgebra::Intersection)
+    }
+
+    pub fn sdiff<K>(&self, keys: K) -> Result<Vec<String>, KivError>
+    where
+        K: IntoIterator,
+        K::Item: AsRef<str>,
+    {
+        self.combine_sets(keys, SetAlgebra::Difference)
+    }
+
+    pub fn create_namespace(&self, name: impl Into<String>) -> Result<(), KivError> {
+        let name = name.into();
+        self.write(|storage| match storage.create_namespace(&name)? {
+            Some(_) => Ok(()),
+            None => Err(KivError::NamespaceExists(name)),
+        })
+    }
+
+    /// Removes a namespace and everything in it.
+    pub fn drop_namespace(&self, name: impl Into<String>) -> Result<(), KivError> {
+        let name = name.into();
+        if name == DEFAULT_NAMESPACE {
+            return Err(KivError::DropDefaultNamespace);
+        }
+        self.write(|storage| match storage.drop_namespace(&name)? {
+            true => Ok(()),
+            false => Err(KivError::NamespaceNotFound(name)),
+        })
+    }
+
+    /// Returns every namespace along with the size of its contents.
+    pub fn namespaces(&self) -> Result<Vec<NamespaceInfo>, KivError> {
+        self.read(|storage| {
+            Ok(storage
+                .namespace_stats()?
+                .into_iter()
+                .map(|stats| NamespaceInfo {
+                    name: stats.name,
+                    entries: stats.entries,
+                    bytes: stats.bytes,
+                })
+                .collect())
+        })
+    }
+
+    /// Removes every entry and namespace.
+    pub fn flush(&self) -> Result<(), KivError> {
+        self.write(|storage| Ok(storage.flush()?))
+    }
+
+    /// Removes every entry in the current namespace.
+    pub fn truncate(&self) -> Result<(), KivError> {
+        self.write(|storage| Ok(storage.truncate()?))
+    }
+
+    pub fn info(&self) -> Result<InfoResult, KivError> {
+        let stats = self.read(|storage| Ok(storage.stats()?))?;
+
+        Ok(InfoResult {
+            keys: stats.keys,
+            file_size: stats.file_size,
+            live_bytes: stats.live_bytes,
+            dead_bytes: stats.dead_bytes,
+            version: stats.version,
+            uptime: self.shared.opened_at.elapsed(),
+            bloom_hits: stats.bloom_hits,
+            bloom_misses: stats.bloom_misses,
+            compression_ratio: stats.compression_ratio,
+        })
+    }
+
+    /// Writes a consistent copy of the database to `path` and returns its
+    /// size in bytes. The copy can be opened like any other database file.
+    /// It holds the database as it was when the backup started, while
+    /// writes made in the meantime go ahead.
+    pub fn backup(&self, path: impl AsRef<Path>) -> Result<u64, KivError> {
+        let (bytes, _) = self.backup_view(|view, locked| view.backup(path.as_ref(), locked))?;
+        Ok(bytes)
+    }
+
+    /// Like [`Kiv::backup`], but writes the copy to `out`. Returns its size
+    /// along with the change it was taken at, if the database keeps a change
+    /// log.
+    pub fn backup_to(&self, out: &mut impl Write) -> Result<(u64, Option<u64>), KivError> {
+        self.backup_view(|view, locked| view.backup_to(out, locked))
+    }
+
+    /// Runs `backup` on a snapshot of the database, along with the change
+    /// the snapshot was taken at. The read lock is only held while each part
+    /// of the copy is read, so writes can be made between parts.
+    fn backup_view<T>(
+        &self,
+        backup: impl FnOnce(&mut dyn StorageEngine, Locked) -> io::Result<T>,
+    ) -> Result<(T, Option<u64>), KivError> {
+        let mut view = {
+            let storage = self
+                .shared
+                .storage
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            match &self.snapshot {
+                Some(snapshot) => snapshot.reader(),
+                None => storage.snapshot_reader(),
+            }
+        };
+
+        let locked: Locked = &|part| {
+            let _storage = self
+                .shared
+                .storage
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            part()
+        };
+        let written = backup(view.as_mut(), locked)?;
+
+        Ok((written, view.sequence()))
+    }
+
+    /// Rewrites the file without the old versions no snapshot can read any
+    /// more, returning how many bytes that freed.
+    pub fn compact(&self) -> Result<u64, KivError> {
+        self.write(|storage| Ok(storage.compact()?))
+    }
+
+    /// Starts keeping a change log next to the database, so backups can be
+    /// taken incrementally. Returns the current sequence number.
+    pub fn enable_change_log(&self) -> Result<u64, KivError> {
+        self.write(|storage| Ok(storage.enable_change_log()?))
+    }
+
+    /// The sequence number of the latest change, if the database keeps a
+    /// change log.
+    pub fn sequence(&self) -> Option<u64> {
+        self.shared
+            .storage
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .sequence()
+    }
+
+    /// Streams through every entry in every namespace, in file order. A
+    /// namespace's entry always comes before the entries stored in it.
+    pub fn for_each_entry<F>(&self, mut f: F) -> Result<(), KivError>
+    where
+        F: FnMut(Entry) -> io::Result<()>,
+    {
+        self.read(|storage| Ok(storage.for_each_entry(&mut f)?))
+    }
+
+    /// Writes entries straight to the end of the file, keeping their
+    /// namespace ids. Nothing is checked against what is already stored, so
+    /// this is only for loading into an empty database.
+    pub fn append_entries(&self, entries: &[Entry]) -> Result<(), KivError> {
+        self.write(|storage| Ok(storage.append_entries(entries)?))
+    }
+
+    /// Parses a statement and describes how it would be executed, without
+    /// executing it. A leading `EXPLAIN` is optional.
+    pub fn explain(&self, statement: String) -> Result<ExplainResult, KivError> {
+        let tokens = Tokenizer::new().tokenize(statement)?;
+        let operation = match Parser::parse(tokens)? {
+            Operation::EXPLAIN(explained) => *explained,
+            operation => operation,
+        };
+
+        self.explain_operation(&operation)
+    }
+
+    fn explain_operation(&self, operation: &Operation) -> Result<ExplainResult, KivError> {
+        let access_path = match operation {
+            Operation::EXPLAIN(_) => AccessPath::None,
+            Operation::USE(_) | Operation::CREATENAMESPACE(_) => AccessPath::Namespaces,
+            Operation::ZRANGE(_) | Operation::ZRANK(_) => AccessPath::SortedSet,
+            Operation::SET(_)
+            | Operation::DELETE(_)
+            | Operation::GET(_)
+            | Operation::ZADD(_)
+            | Operation::ZREM(_)
+            | Operation::ZSCORE(_)
+            | Operation::SADD(_)
+            | Operation::SREM(_)
+            | Operation::SISMEMBER(_) => AccessPath::KeyScan,
+            Operation::SMEMBERS(_)
+            | Operation::SCARD(_)
+            | Operation::SUNION(_)
+            | Operation::SINTER(_)
+            | Operation::SDIFF(_)
+            | Operation::SHOWNAMESPACES
+            | Operation::INFO
+            | Operation::BACKUP(_) => AccessPath::FullScan,
+            Operation::DROPNAMESPACE(_)
+            | Operation::FLUSH
+            | Operation::TRUNCATE
+            | Operation::COMPACT => AccessPath::Rewrite,
+        };
+
+        let sorted_set = match operation {
+            Operation::ZRANGE(zrange) => Some(&zrange.key),
+            Operation::ZRANK(zrank) => Some(&zrank.key),
+            _ => None,
+        };
+        let (estimated_rows, estimated_bytes) = self.read(|storage| {
+            Ok(match access_path {
+                AccessPath::None => (0, 0),
+                AccessPath::Namespaces => {
+                    let namespaces = storage.namespaces()?;
+                    let names = namespaces.iter().map(|(_, name)| name.len() as u64);
+                    (namespaces.len() as u64, names.sum())
+                }
+                AccessPath::SortedSet => {
+                    let key = sorted_set.map_or("", String::as_str);
+                    let members = storage.zrange(key, f64::NEG_INFINITY, f64::INFINITY)?;
+                    // each member is read along with its score
+                    let bytes = members.iter().map(|(member, _)| member.len() as u64 + 8);
+                    (members.len() as u64, bytes.sum())
+                }
+                // a key scan stops early, but can't be told where
+                AccessPath::KeyScan | AccessPath::FullScan | AccessPath::Rewrite => {
+                    storage.size()?
+                }
+            })
+        })?;
+
+        Ok(ExplainResult {
+            operation: operation.to_string(),
+            access_path,
+            estimated_rows,
+            estimated_bytes,
+        })
+    }
+
+    /// Switches the namespace that following statements operate on.
+    pub fn use_namespace(&self, name: &str) -> Result<(), KivError> {
+        let id = self
+            .read(|storage| Ok(storage.namespace_id(name)?))?
+            .ok_or_else(|| KivError::NamespaceNotFound(name.to_string()))?;
+        self.namespace.store(id, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Folds the sets at `keys` together, left to right, returning the
+    /// resulting members in sorted order.
+    fn combine_sets<K>(&self, keys: K, algebra: SetAlgebra) -> Result<Vec<String>, KivError>
+    where
+        K: IntoIterator,
+        K::Item: AsRef<str>,
+    {
+        self.read(|storage| {
+            let mut keys = keys.into_iter();
+            let mut combined: BTreeSet<String> = match keys.next() {
+                Some(key) => storage.smembers(key.as_ref())?.into_iter().collect(),
+                None => return Ok(vec![]),
+            };
+
+            for key in keys {
+                let members: BTreeSet<String> =
+                    storage.smembers(key.as_ref())?.into_iter().collect();
+
+                match algebra {
+                    SetAlgebra::Union => combined.extend(members),
+                    SetAlgebra::Intersection => combined.retain(|m| members.contains(m)),
+                    SetAlgebra::Difference => combined.retain(|m| !members.contains(m)),
+                }
+            }
+
+            Ok(combined.into_iter().collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> (tempfile::TempDir, Kiv) {
+        let dir = tempfile::tempdir().unwrap();
+        let kiv = Kiv::open(dir.path().join("test.kiv")).unwrap();
+        (dir, kiv)
+    }
+
+    fn exec(kiv: &Kiv, statement: &str) -> Result<OperationResultResult, KivError> {
+        kiv.exec(statement.to_string()).map(|result| result.result)
+    }
+
+    #[test]
+    fn prepared_statements_fill_in_their_placeholders() {
+        let (_dir, kiv) = open();
+        let mut statement = kiv.prepare("SADD :key 'a' ?").unwrap();
+        statement.bind_named("key", "k").bind(1, "b");
+        kiv.exec_prepared(&statement).unwrap();
+        assert_eq!(kiv.smembers("k").unwrap(), vec!["a", "b"]);
+
+        statement.bind(1, 5);
+        assert!(matches!(
+            kiv.exec_prepared(&statement),
+            Err(KivError::ParameterTypeMismatch(_))
+        ));
+        statement.clear_bindings();
+        assert!(matches!(
+            kiv.exec_prepared(&statement),
+            Err(KivError::UnboundParameter(_))
+        ));
+    }
+
+    #[test]
+    fn placeholders_are_rejected_outside_prepared_statements() {
+        let (_dir, kiv) = open();
+        for statement in ["SET ? TO 'a'", "SADD 'k' :member", "EXPLAIN GET ?"] {
+            assert!(matches!(
+                exec(&kiv, statement),
+                Err(KivError::ParserError(ParserError::UnexpectedPlaceholder(_)))
+            ));
+        }
+        assert!(matches!(
+            kiv.explain(String::from("GET ?")),
+            Err(KivError::ParserError(ParserError::UnexpectedPlaceholder(_)))
+        ));
+
+        // nothing was written
+        assert_eq!(kiv.get("").unwrap(), None);
+        assert_eq!(kiv.scard("k").unwrap(), 0);
+    }
+
+    #[test]
+    fn trailing_tokens_are_rejected() {
+        let (_dir, kiv) = open();
+        for statement in ["GET ? ?", "SADD 'k' 'a' 5 ?", "SET 'a' TO 'b' 'c'"] {
+            assert!(matches!(
+                kiv.prepare(statement),
+                Err(KivError::ParserError(ParserError::TrailingToken(_)))
+            ));
+            assert!(matches!(
+                exec(&kiv, statement),
+                Err(KivError::ParserError(ParserError::TrailingToken(_)))
+            ));
+        }
+
+        // nothing was written
+        assert_eq!(kiv.get("a").unwrap(), None);
+        assert_eq!(kiv.scard("k").unwrap(), 0);
+    }
+
+    fn zrange(kiv: &Kiv, statement: &str) -> Vec<(String, f64)> {
+        match exec(kiv, statement).unwrap() {
+            OperationResultResult::ZRange(ZRangeResult { members }) => members
+                .into_iter()
+                .map(|scored| (scored.member, scored.score))
+                .collect(),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn sorted_sets() {
+        let (_dir, kiv) = open();
+        exec(&kiv, "ZADD 'board' 30 'carol'").unwrap();
+        exec(&kiv, "ZADD 'board' 10 'alice'").unwrap();
+        kiv.zadd("board", 20.0, "bob").unwrap();
+        kiv.zadd("board", -5.5, "dave").unwrap();
+
+        assert!(matches!(
+            exec(&kiv, "ZSCORE 'board' 'carol'").unwrap(),
+            OperationResultResult::ZScore(ZScoreResult { score: Some(s) }) if s == 30.0
+        ));
+        assert_eq!(kiv.zscore("board", "dave").unwrap(), Some(-5.5));
+        assert!(matches!(
+            exec(&kiv, "ZRANK 'board' 'bob'").unwrap(),
+            OperationResultResult::ZRank(ZRankResult { rank: Some(2) })
+        ));
+        assert_eq!(kiv.zrank("board", "dave").unwrap(), Some(0));
+
+        // updating a score moves the member
+        exec(&kiv, "ZADD 'board' 40 'alice'").unwrap();
+        assert_eq!(kiv.zscore("board", "alice").unwrap(), Some(40.0));
+        assert_eq!(kiv.zrank("board", "alice").unwrap(), Some(3));
+
+        exec(&kiv, "ZREM 'board' 'carol'").unwrap();
+        kiv.zrem("board", "dave").unwrap();
+        assert_eq!(kiv.zscore("board", "carol").unwrap(), None);
+        assert_eq!(kiv.zrank("board", "dave").unwrap(), None);
+        assert_eq!(
+            zrange(&kiv, "ZRANGE 'board' BY SCORE -100 100"),
+            vec![("bob".to_string(), 20.0), ("alice".to_string(), 40.0)]
+        );
+    }
+
+    #[test]
+    fn sorted_set_ranges_include_their_bounds() {
+        let (_dir, kiv) = open();
+        for (score, member) in [(1.0, "a"), (2.0, "b"), (2.0, "c"), (3.0, "d"), (-1.0, "e")] {
+            kiv.zadd("z", score, member).unwrap();
+        }
+        let typed: Vec<String> = kiv
+            .zrange_by_score("z", 1.5, 3.0)
+            .unwrap()
+            .into_iter()
+            .map(|scored| scored.member)
+            .collect();
+        let members = |statement| -> Vec<String> {
+            zrange(&kiv, statement)
+                .into_iter()
+                .map(|(member, _)| member)
+                .collect()
+        };
+
+        assert_eq!(members("ZRANGE 'z' BY SCORE 2 3"), vec!["b", "c", "d"]);
+        assert_eq!(members("ZRANGE 'z' BY SCORE 2 2"), vec!["b", "c"]);
+        assert_eq!(members("ZRANGE 'z' BY SCORE -1 1"), vec!["e", "a"]);
+        assert_eq!(members("ZRANGE 'z' BY SCORE -10 -2"), Vec::<String>::new());
+        assert_eq!(members("ZRANGE 'z' BY SCORE 3 1"), Vec::<String>::new());
+        assert_eq!(members("ZRANGE 'z' BY SCORE 3.5 100"), Vec::<String>::new());
+
+        // the typed method reads the same range
+        assert_eq!(typed, members("ZRANGE 'z' BY SCORE 1.5 3"));
+    }
+
+    #[test]
+    fn missing_and_mistyped_sorted_sets_are_empty() {
+        let (_dir, kiv) = open();
+        kiv.set("plain", "value").unwrap();
+        kiv.sadd("tags", ["a"]).unwrap();
+
+        for key in ["missing", "plain", "tags"] {
+            assert_eq!(kiv.zscore(key, "a").unwrap(), None);
+            assert_eq!(kiv.zrank(key, "a").unwrap(), None);
+            assert!(kiv
+                .zrange_by_score(key, f64::MIN, f64::MAX)
+                .unwrap()
+                .is_empty());
+            assert!(matches!(
+                exec(&kiv, &format!("ZSCORE '{}' 'a'", key)).unwrap(),
+                OperationResultResult::ZScore(ZScoreResult { score: None })
+            ));
+        }
+        // removing from a missing set does nothing
+        exec(&kiv, "ZREM 'missing' 'a'").unwrap();
+
+        // a sorted set doesn't replace a value stored under the same key
+        kiv.zadd("plain", 1.0, "a").unwrap();
+        assert_eq!(kiv.get("plain").unwrap(), Some(b"value".to_vec()));
+        assert!(kiv.sismember("tags", "a").unwrap());
+        assert!(matches!(
+            exec(&kiv, "GET 'plain'").unwrap(),
+            OperationResultResult::Get(GetResult { value: Some(v) }) if v == "value"
+        ));
+        assert_eq!(kiv.zscore("plain", "a").unwrap(), Some(1.0));
+    }
+
+    fn members(kiv: &Kiv, statement: &str) -> Vec<String> {
+        match exec(kiv, statement).unwrap() {
+            OperationResultResult::SMembers(SMembersResult { members })
+            | OperationResultResult::SUnion(SMembersResult { members })
+            | OperationResultResult::SInter(SMembersResult { members })
+            | OperationResultResult::SDiff(SMembersResult { members }) => members,
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn sets() {
+        let (_dir, kiv) = open();
+        exec(&kiv, "SADD 'flags' 'dark' 'beta' 'dark'").unwrap();
+        kiv.sadd("flags", ["new"]).unwrap();
+
+        assert_eq!(
+            members(&kiv, "SMEMBERS 'flags'"),
+            vec!["beta", "dark", "new"]
+        );
+        assert_eq!(kiv.smembers("flags").unwrap(), vec!["beta", "dark", "new"]);
+        assert!(matches!(
+            exec(&kiv, "SCARD 'flags'").unwrap(),
+            OperationResultResult::SCard(SCardResult { cardinality: 3 })
+        ));
+        assert!(matches!(
+            exec(&kiv, "SISMEMBER 'flags' 'beta'").unwrap(),
+            OperationResultResult::SIsMember(SIsMemberResult { is_member: true })
+        ));
+
+        exec(&kiv, "SREM 'flags' 'beta' 'gone'").unwrap();
+        kiv.srem("flags", ["new"]).unwrap();
+        assert!(!kiv.sismember("flags", "beta").unwrap());
+        assert_eq!(kiv.scard("flags").unwrap(), 1);
+        assert_eq!(kiv.smembers("flags").unwrap(), vec!["dark"]);
+    }
+
+    #[test]
+    fn set_algebra() {
+        let (_dir, kiv) = open();
+        kiv.sadd("a", ["1", "2", "3"]).unwrap();
+        kiv.sadd("b", ["2", "3", "4"]).unwrap();
+        kiv.sadd("c", ["3", "5"]).unwrap();
+
+        assert_eq!(
+            members(&kiv, "SUNION 'a' 'b' 'c'"),
+            vec!["1", "2", "3", "4", "5"]
+        );
+        assert_eq!(members(&kiv, "SINTER 'a' 'b' 'c'"), vec!["3"]);
+        assert_eq!(members(&kiv, "SDIFF 'a' 'b'"), vec!["1"]);
+        assert_eq!(members(&kiv, "SDIFF 'b' 'a' 'c'"), vec!["4"]);
+        assert_eq!(kiv.sunion(["a", "c"]).unwrap(), vec!["1", "2", "3", "5"]);
+        assert_eq!(kiv.sinter(["b", "c"]).unwrap(), vec!["3"]);
+        assert_eq!(kiv.sdiff(["c", "a"]).unwrap(), vec!["5"]);
+
+        // a missing key is an empty set
+        assert_eq!(members(&kiv, "SUNION 'a' 'missing'"), vec!["1", "2", "3"]);
+        assert!(members(&kiv, "SINTER 'a' 'missing'").is_empty());
+        assert_eq!(members(&kiv, "SDIFF 'a' 'missing'"), vec!["1", "2", "3"]);
+        assert!(kiv.sdiff(["missing", "a"]).unwrap().is_empty());
+        assert!(kiv.sunion(Vec::<String>::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing_and_mistyped_sets_are_empty() {
+        let (_dir, kiv) = open();
+        kiv.set("plain", "value").unwrap();
+        kiv.zadd("board", 1.0, "a").unwrap();
+
+        for key in ["missing", "plain", "board"] {
+            assert!(kiv.smembers(key).unwrap().is_empty());
+            assert_eq!(kiv.scard(key).unwrap(), 0);
+            assert!(!kiv.sismember(key, "a").unwrap());
+            assert!(members(&kiv, &format!("SMEMBERS '{}'", key)).is_empty());
+        }
+        exec(&kiv, "SREM 'missing' 'a'").unwrap();
+
+        // a set doesn't replace what's stored under the same key
+        kiv.sadd("plain", ["a"]).unwrap();
+        kiv.sadd("board", ["a"]).unwrap();
+        assert_eq!(kiv.get("plain").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(kiv.zscore("board", "a").unwrap(), Some(1.0));
+        assert_eq!(kiv.smembers("plain").unwrap(), vec!["a"]);
+    }
+
+    fn namespaces(kiv: &Kiv) -> Vec<(String, u64)> {
+        match exec(kiv, "SHOW NAMESPACES").unwrap() {
+            OperationResultResult::Namespaces(NamespacesResult { namespaces }) => namespaces
+                .into_iter()
+                .map(|namespace| (namespace.name, namespace.entries))
+                .collect(),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn namespaces_keep_their_keys_apart() {
+        let (_dir, kiv) = open();
+        exec(&kiv, "SET 'a' TO 'default'").unwrap();
+        exec(&kiv, "CREATE NAMESPACE 'team'").unwrap();
+        kiv.create_namespace("other").unwrap();
+
+        exec(&kiv, "USE 'team'").unwrap();
+        assert_eq!(kiv.get("a").unwrap(), None);
+        exec(&kiv, "SET 'a' TO 'team'").unwrap();
+        kiv.sadd("s", ["x"]).unwrap();
+        kiv.zadd("z", 1.0, "m").unwrap();
+
+        // each handle has its own namespace
+        let other = kiv.clone();
+        exec(&other, "USE 'other'").unwrap();
+        assert_eq!(other.get("a").unwrap(), None);
+        assert!(other.smembers("s").unwrap().is_empty());
+        assert_eq!(other.zscore("z", "m").unwrap(), None);
+        assert_eq!(kiv.get("a").unwrap(), Some(b"team".to_vec()));
+
+        kiv.use_namespace(DEFAULT_NAMESPACE).unwrap();
+        assert!(matches!(
+            exec(&kiv, "GET 'a'").unwrap(),
+            OperationResultResult::Get(GetResult { value: Some(v) }) if v == "default"
+        ));
+        assert!(!kiv.sismember("s", "x").unwrap());
+
+        // truncating only empties the current namespace
+        exec(&kiv, "TRUNCATE").unwrap();
+        assert_eq!(kiv.get("a").unwrap(), None);
+        kiv.use_namespace("team").unwrap();
+        assert_eq!(kiv.get("a").unwrap(), Some(b"team".to_vec()));
+
+        let listed = namespaces(&kiv);
+        assert_eq!(listed.len(), 3);
+        assert!(listed.contains(&("team".to_string(), 3)));
+        assert!(listed.contains(&("other".to_string(), 0)));
+        assert_eq!(kiv.namespaces().unwrap().len(), listed.len());
+    }
+
+    #[test]
+    fn namespace_errors() {
+        let (_dir, kiv) = open();
+        exec(&kiv, "CREATE NAMESPACE 'team'").unwrap();
+
+        assert!(matches!(
+            exec(&kiv, "CREATE NAMESPACE 'team'"),
+            Err(KivError::NamespaceExists(name)) if name == "team"
+        ));
+        assert!(matches!(
+            kiv.create_namespace(DEFAULT_NAMESPACE),
+            Err(KivError::NamespaceExists(_))
+        ));
+        assert!(matches!(
+            exec(&kiv, "USE 'missing'"),
+            Err(KivError::NamespaceNotFound(name)) if name == "missing"
+        ));
+        assert!(matches!(
+            kiv.drop_namespace("missing"),
+            Err(KivError::NamespaceNotFound(_))
+        ));
+        assert!(matches!(
+            exec(&kiv, "DROP NAMESPACE 'default'"),
+            Err(KivError::DropDefaultNamespace)
+        ));
+    }
+
+    #[test]
+    fn dropped_namespaces_take_their_keys_with_them() {
+        let (_dir, kiv) = open();
+        kiv.set("a", "default").unwrap();
+        kiv.create_namespace("team").unwrap();
+        kiv.use_namespace("team").unwrap();
+        kiv.set("a", "team").unwrap();
+        kiv.sadd("s", ["x"]).unwrap();
+
+        // dropping the namespace in use moves back to the default one
+        exec(&kiv, "DROP NAMESPACE 'team'").unwrap();
+        assert_eq!(kiv.get("a").unwrap(), Some(b"default".to_vec()));
+        assert!(matches!(
+            exec(&kiv, "USE 'team'"),
+            Err(KivError::NamespaceNotFound(_))
+        ));
+        assert!(!namespaces(&kiv).iter().any(|(name, _)| name == "team"));
+
+        // and a namespace made under the same name starts out empty
+        exec(&kiv, "CREATE NAMESPACE 'team'").unwrap();
+        exec(&kiv, "USE 'team'").unwrap();
+        assert_eq!(kiv.get("a").unwrap(), None);
+        assert!(kiv.smembers("s").unwrap().is_empty());
+        kiv.set("b", "new").unwrap();
+        assert!(namespaces(&kiv).contains(&("team".to_string(), 1)));
+
+        kiv.drop_namespace("team").unwrap();
+        assert_eq!(kiv.get("b").unwrap(), None);
+        assert_eq!(kiv.get("a").unwrap(), Some(b"default".to_vec()));
+    }
+
+    fn info(kiv: &Kiv, statement: &str) -> InfoResult {
+        match exec(kiv, statement).unwrap() {
+            OperationResultResult::Info(info) => info,
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn truncate_empties_the_current_namespace() {
+        let (_dir, kiv) = open();
+        kiv.set("a", "default").unwrap();
+        kiv.create_namespace("team").unwrap();
+        kiv.use_namespace("team").unwrap();
+        kiv.set("a", "team").unwrap();
+        kiv.sadd("s", ["x"]).unwrap();
+        kiv.zadd("z", 1.0, "m").unwrap();
+
+        assert!(matches!(
+            exec(&kiv, "TRUNCATE").unwrap(),
+            OperationResultResult::Truncate
+        ));
+        // the namespace stays in use and in the list, with nothing in it
+        assert_eq!(kiv.get("a").unwrap(), None);
+        assert!(kiv.smembers("s").unwrap().is_empty());
+        assert_eq!(kiv.zscore("z", "m").unwrap(), None);
+        assert!(namespaces(&kiv).contains(&("team".to_string(), 0)));
+        kiv.set("b", "after").unwrap();
+        assert!(namespaces(&kiv).contains(&("team".to_string(), 1)));
+
+        kiv.use_namespace(DEFAULT_NAMESPACE).unwrap();
+        assert_eq!(kiv.get("a").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(kiv.get("b").unwrap(), None);
+    }
+
+    #[test]
+    fn flush_removes_every_namespace_and_moves_to_the_default_one() {
+        let (_dir, kiv) = open();
+        let empty = info(&kiv, "INFO");
+        kiv.set("a", "default").unwrap();
+        kiv.create_namespace("team").unwrap();
+        kiv.use_namespace("team").unwrap();
+        kiv.set("a", "team").unwrap();
+        kiv.zadd("z", 1.0, "m").unwrap();
+
+        assert!(matches!(
+            exec(&kiv, "FLUSH").unwrap(),
+            OperationResultResult::Flush
+        ));
+        // writes after a flush go to the default namespace, which is all
+        // that is left
+        kiv.set("b", "after").unwrap();
+        assert_eq!(namespaces(&kiv), vec![(DEFAULT_NAMESPACE.to_string(), 1)]);
+        assert_eq!(kiv.get("a").unwrap(), None);
+        assert_eq!(kiv.zscore("z", "m").unwrap(), None);
+        assert!(matches!(
+            exec(&kiv, "USE 'team'"),
+            Err(KivError::NamespaceNotFound(_))
+        ));
+
+        kiv.delete("b").unwrap();
+        exec(&kiv, "FLUSH").unwrap();
+        let flushed = info(&kiv, "INFO");
+        assert_eq!(flushed.keys, 0);
+        assert_eq!(flushed.file_size, empty.file_size);
+        assert_eq!(flushed.version, empty.version);
+    }
+
+    #[test]
+    fn info_and_stats_report_the_file() {
+        let (_dir, kiv) = open();
+        let empty = info(&kiv, "INFO");
+        assert_eq!(empty.keys, 0);
+        assert_eq!(empty.live_bytes, empty.file_size);
+        assert_eq!(empty.dead_bytes, 0);
+
+        kiv.set("a", "1").unwrap();
+        kiv.set("b", "2").unwrap();
+        kiv.sadd("a", ["x", "y"]).unwrap();
+        kiv.zadd("z", 1.0, "m").unwrap();
+        kiv.create_namespace("team").unwrap();
+        kiv.use_namespace("team").unwrap();
+        kiv.set("a", "team").unwrap();
+
+        // STATS is another name for INFO
+        for statement in ["INFO", "STATS"] {
+            let info = info(&kiv, statement);
+            // keys are counted once per namespace, whatever is stored
+            // under them
+            assert_eq!(info.keys, 4);
+            assert!(info.file_size > empty.file_size);
+            assert_eq!(info.live_bytes + info.dead_bytes, info.file_size);
+            assert_eq!(info.version, empty.version);
+            assert_eq!(info.compression_ratio, 1.0);
+        }
+        assert_eq!(kiv.info().unwrap().keys, 4);
+
+        let before = info(&kiv, "INFO");
+        kiv.delete("a").unwrap();
+        let after = info(&kiv, "INFO");
+        assert_eq!(after.keys, 3);
+        assert!(after.file_size <= before.file_size);
+        assert!(after.uptime >= before.uptime);
+    }
+
+    #[test]
+    fn typed_methods_match_kivql() {
+        let (_dir, kiv) = open();
+        kiv.set("a", "typed").unwrap();
+        assert!(matches!(
+            exec(&kiv, "GET 'a'").unwrap(),
+            OperationResultResult::Get(GetResult { value: Some(v) }) if v == "typed"
+        ));
+        exec(&kiv, "SET 'b' TO 'kivql'").unwrap();
+        assert_eq!(kiv.get("b").unwrap(), Some(b"kivql".to_vec()));
+
+        // setting again replaces the value
+        kiv.set("a", "again").unwrap();
+        assert_eq!(kiv.get(b"a").unwrap(), Some(b"again".to_vec()));
+
+        exec(&kiv, "DELETE 'a'").unwrap();
+        kiv.delete("b").unwrap();
+        assert_eq!(kiv.get("a").unwrap(), None);
+        assert!(matches!(
+            exec(&kiv, "GET 'b'").unwrap(),
+            OperationResultResult::Get(GetResult { value: None })
+        ));
+        // deleting a missing key does nothing
+        kiv.delete("missing").unwrap();
+        exec(&kiv, "DELETE 'missing'").unwrap();
+
+        let result = kiv
+            .execute(Operation::SET(kivql::parser::Set {
+                key: "c".to_string(),
+                value: "ast".to_string(),
+            }))
+            .unwrap();
+        assert!(matches!(result.result, OperationResultResult::Set));
+        assert_eq!(kiv.get("c").unwrap(), Some(b"ast".to_vec()));
+    }
+
+    #[test]
+    fn typed_methods_take_any_bytes() {
+        let (_dir, kiv) = open();
+        let key = [0u8, 255, b'\'', 10];
+        let value = vec![1u8, 0, 200, 0];
+        kiv.set(key, &value).unwrap();
+        assert_eq!(kiv.get(key).unwrap(), Some(value.clone()));
+        assert_eq!(kiv.get(&key[..3]).unwrap(), None);
+
+        // values that aren't utf-8 are read lossily through KivQL
+        kiv.set("bytes", [b'o', 255, b'k']).unwrap();
+        assert!(matches!(
+            exec(&kiv, "GET 'bytes'").unwrap(),
+            OperationResultResult::Get(GetResult { value: Some(v) }) if v == "o\u{fffd}k"
+        ));
+
+        kiv.delete(key).unwrap();
+        assert_eq!(kiv.get(key).unwrap(), None);
+    }
+
+    #[test]
+    fn scans_return_matching_keys_in_order() {
+        let (_dir, kiv) = open();
+        for key in ["user:2", "user:10", "user:1", "users", "team:1"] {
+            kiv.set(key, key.to_uppercase()).unwrap();
+        }
+        kiv.delete("user:10").unwrap();
+        kiv.set("user:1", "updated").unwrap();
+        kiv.sadd("user:set", ["x"]).unwrap();
+
+        let scanned = kiv.scan("user:").unwrap();
+        assert_eq!(
+            scanned,
+            vec![
+                KeyValue {
+                    key: b"user:1".to_vec(),
+                    value: b"updated".to_vec(),
+                },
+                KeyValue {
+                    key: b"user:2".to_vec(),
+                    value: b"USER:2".to_vec(),
+                },
+            ]
+        );
+        assert_eq!(kiv.scan("").unwrap().len(), 4);
+        assert!(kiv.scan("missing").unwrap().is_empty());
+
+        // scans stay in the current namespace
+        kiv.create_namespace("other").unwrap();
+        kiv.use_namespace("other").unwrap();
+        assert!(kiv.scan("").unwrap().is_empty());
+    }
+
+    fn explain(kiv: &Kiv, statement: &str) -> (AccessPath, u64, u64) {
+        let OperationResultResult::Explain(explained) = exec(kiv, statement).unwrap() else {
+            panic!("{} didn't explain", statement);
+        };
+        (
+            explained.access_path,
+            explained.estimated_rows,
+            explained.estimated_bytes,
+        )
+    }
+
+    #[test]
+    fn explain_reports_access_paths_and_estimates() {
+        let (_dir, kiv) = open();
+        // an empty file is only its header
+        let header = kiv.info().unwrap().file_size;
+        assert_eq!(
+            explain(&kiv, "EXPLAIN GET 'a'"),
+            (AccessPath::KeyScan, 0, 0)
+        );
+
+        kiv.set("a", "one").unwrap();
+        kiv.set("b", "two").unwrap();
+        kiv.zadd("z", 1.0, "x").unwrap();
+        kiv.zadd("z", 2.0, "yy").unwrap();
+        kiv.create_namespace("team").unwrap();
+        let bytes = kiv.info().unwrap().file_size - header;
+        assert_eq!(
+            explain(&kiv, "EXPLAIN GET 'a'"),
+            (AccessPath::KeyScan, 5, bytes)
+        );
+        assert_eq!(
+            explain(&kiv, "EXPLAIN SMEMBERS 's'"),
+            (AccessPath::FullScan, 5, bytes)
+        );
+        assert_eq!(
+            explain(&kiv, "EXPLAIN COMPACT"),
+            (AccessPath::Rewrite, 5, bytes)
+        );
+
+        // only the set's members and their scores
+        assert_eq!(
+            explain(&kiv, "EXPLAIN ZRANGE 'z' BY SCORE 0 1"),
+            (AccessPath::SortedSet, 2, 1 + 8 + 2 + 8)
+        );
+        assert_eq!(
+            explain(&kiv, "EXPLAIN ZRANK 'missing' 'x'"),
+            (AccessPath::SortedSet, 0, 0)
+        );
+        // "default" and "team"
+        assert_eq!(
+            explain(&kiv, "EXPLAIN USE 'team'"),
+            (AccessPath::Namespaces, 2, 11)
+        );
+        assert_eq!(
+            explain(&kiv, "EXPLAIN EXPLAIN GET 'a'"),
+            (AccessPath::None, 0, 0)
+        );
+        assert_eq!(
+            kiv.explain("EXPLAIN EXPLAIN GET 'a'".to_string())
+                .unwrap()
+                .operation,
+            "EXPLAIN GET 'a'"
+        );
+
+        // nothing explained was run
+        assert_eq!(kiv.get("b").unwrap(), Some(b"two".to_vec()));
+
+        // the counts follow writes
+        kiv.delete("a").unwrap();
+        let bytes = kiv.info().unwrap().file_size - header;
+        assert_eq!(
+            explain(&kiv, "EXPLAIN GET 'a'"),
+            (AccessPath::KeyScan, 4, bytes)
+        );
+    }
+
+    /// Collects a backup, waiting for a write to be made before each part.
+    struct Interleaved {
+        written: std::sync::mpsc::Receiver<()>,
+        bytes: Vec<u8>,
+    }
+
+    impl Write for Interleaved {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            // the writer may have finished already
+            let _ = self.written.recv();
+            self.bytes.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backups_hold_the_state_they_started_in() {
+        let (dir, kiv) = open();
+        // big enough to be copied in several parts
+        let value = vec![7u8; 64 * 1024];
+        for i in 0..40 {
+            kiv.set(format!("k{}", i), &value).unwrap();
+        }
+        kiv.zadd("z", 1.0, "a").unwrap();
+        let before = kiv.scan("").unwrap();
+
+        let (sender, written) = std::sync::mpsc::channel();
+        let writer = {
+            let kiv = kiv.clone();
+            std::thread::spawn(move || {
+                for i in 0..20 {
+                    kiv.set(format!("k{}", i), "new").unwrap();
+                    kiv.delete(format!("k{}", 39 - i)).unwrap();
+                    kiv.set(format!("new{}", i), "new").unwrap();
+                    kiv.zadd("z", i as f64, "b").unwrap();
+                    if sender.send(()).is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+        let mut out = Interleaved {
+            written,
+            bytes: vec![],
+        };
+        let (bytes, _) = kiv.backup_to(&mut out).unwrap();
+        let Interleaved {
+            written,
+            bytes: copy,
+        } = out;
+        // let the writer finish
+        drop(written);
+        writer.join().unwrap();
+        assert_eq!(bytes, copy.len() as u64);
+        assert_ne!(kiv.scan("").unwrap(), before);
+
+        let path = dir.path().join("backup.kiv");
+        std::fs::write(&path, copy).unwrap();
+        let backup = Kiv::open(path).unwrap();
+        assert_eq!(backup.scan("").unwrap(), before);
+        assert_eq!(backup.zscore("z", "b").unwrap(), None);
+        assert_eq!(backup.zscore("z", "a").unwrap(), Some(1.0));
+    }
+
+    #[test]
+    fn locked_databases_fail_to_open() {
+        let dir = tempfile::tempdir().unwrap();
+        for engine in [Engine::File, Engine::Lsm, Engine::BTree] {
+            let config = KivConfig {
+                engine,
+                ..KivConfig::new(dir.path().join(format!("{:?}", engine)))
+            };
+            let _kiv = Kiv::open_with(config.clone()).unwrap();
+            assert!(matches!(
+                Kiv::open_with(config.clone()),
+                Err(KivOpenError::Locked)
+            ));
+            assert!(matches!(
+                Kiv::open_with(KivConfig {
+                    read_only: true,
+                    ..config
+                }),
+                Err(KivOpenError::Locked)
+            ));
+        }
+
+        // only a lock held elsewhere is reported as one
+        let err = io::Error::from(io::ErrorKind::WouldBlock);
+        assert!(matches!(
+            KivOpenError::from_storage(err),
+            KivOpenError::IoError(_)
+        ));
     }
 }
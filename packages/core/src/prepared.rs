@@ -0,0 +1,130 @@
+// prepared statements with bindable parameters
+
+use crate::KivError;
+use kivql::{
+    parser::{Operation, ValueMut},
+    tokenizer::{Placeholder, Token},
+};
+use std::collections::HashMap;
+
+/// A value bound to a placeholder in a prepared statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Param {
+    String(String),
+    Number(f64),
+}
+
+impl From<&str> for Param {
+    fn from(value: &str) -> Self {
+        Param::String(value.to_string())
+    }
+}
+
+impl From<String> for Param {
+    fn from(value: String) -> Self {
+        Param::String(value)
+    }
+}
+
+impl From<f64> for Param {
+    fn from(value: f64) -> Self {
+        Param::Number(value)
+    }
+}
+
+impl From<i32> for Param {
+    fn from(value: i32) -> Self {
+        Param::Number(value as f64)
+    }
+}
+
+/// A statement that has been tokenized and parsed once, and can be executed
+/// any number of times with different parameters.
+#[derive(Debug, Clone)]
+pub struct PreparedStatement {
+    operation: Operation,
+    /// The placeholder behind each of the operation's literals, in the order
+    /// they appear. `None` for literals written into the statement.
+    slots: Vec<Option<Placeholder>>,
+    params: HashMap<Placeholder, Param>,
+}
+
+impl PreparedStatement {
+    /// Pairs an operation with the placeholder behind each of its literals,
+    /// which have to line up one for one.
+    pub(crate) fn new(
+        mut operation: Operation,
+        slots: Vec<Option<Placeholder>>,
+    ) -> Result<Self, KivError> {
+        if operation.values_mut().len() != slots.len() {
+            return Err(KivError::PlaceholderMismatch);
+        }
+
+        Ok(Self {
+            operation,
+            slots,
+            params: HashMap::new(),
+        })
+    }
+
+    /// Finds the placeholder behind each literal in a statement's tokens.
+    pub(crate) fn slots(tokens: &[Token]) -> Vec<Option<Placeholder>> {
+        tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::String(_) | Token::Number(_) => Some(None),
+                Token::Placeholder(placeholder) => Some(Some(placeholder.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Binds a value to a positional placeholder (`?` or `$1`). Positions
+    /// start at 1.
+    pub fn bind(&mut self, index: usize, value: impl Into<Param>) -> &mut Self {
+        self.params.insert(Placeholder::Index(index), value.into());
+        self
+    }
+
+    /// Binds a value to a named placeholder (`:name`).
+    pub fn bind_named(&mut self, name: impl Into<String>, value: impl Into<Param>) -> &mut Self {
+        self.params
+            .insert(Placeholder::Name(name.into()), value.into());
+        self
+    }
+
+    /// Removes every bound value.
+    pub fn clear_bindings(&mut self) {
+        self.params.clear();
+    }
+
+    /// Returns every placeholder in the statement, in the order they appear.
+    pub fn placeholders(&self) -> Vec<&Placeholder> {
+        self.slots.iter().flatten().collect()
+    }
+
+    /// Builds the operation to execute by filling in the bound values.
+    pub(crate) fn operation(&self) -> Result<Operation, KivError> {
+        let mut operation = self.operation.clone();
+
+        for (value, slot) in operation.values_mut().into_iter().zip(&self.slots) {
+            let placeholder = match slot {
+                Some(placeholder) => placeholder,
+                None => continue,
+            };
+
+            let param = self
+                .params
+                .get(placeholder)
+                .ok_or_else(|| KivError::UnboundParameter(placeholder.to_string()))?;
+
+            match (value, param) {
+                (ValueMut::String(value), Param::String(param)) => *value = param.clone(),
+                (ValueMut::Number(value), Param::Number(param)) => *value = *param,
+                _ => return Err(KivError::ParameterTypeMismatch(placeholder.to_string())),
+            }
+        }
+
+        Ok(operation)
+    }
+}
@@ -1,28 +1,104 @@
 // basic implementation of a JSON server for kiv
 
-use axum::{extract::State, http::StatusCode, routing::post, Router};
-use clap::Parser;
-use core::{GetResult, Kiv, KivError, OperationResult, OperationResultResult};
+use axum::{
+    body::StreamBody,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use kiv_core::{
+    AccessPath, AsyncKiv, BackupResult, Cipher, Codec, CompactResult, EncryptionKey, Engine,
+    ExplainResult, GetResult, InfoResult, Kiv, KivConfig, KivError, NamespaceInfo,
+    NamespacesResult, OperationResult, OperationResultResult, Param, SCardResult, SIsMemberResult,
+    SMembersResult, ScoredMember, ZRangeResult, ZRankResult, ZScoreResult, DEFAULT_NAMESPACE,
+};
 use kivql::parser::ParserError;
 use kivql::tokenizer::TokenizerError;
-use serde::Serialize;
+use serde::{ser::SerializeSeq, Deserialize, Serialize, Serializer};
 use std::{
+    collections::HashMap,
+    io::{Seek, SeekFrom},
     net::SocketAddr,
     path::PathBuf,
-    sync::{Arc, Mutex},
     time::Duration,
 };
+use tokio_util::io::ReaderStream;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    db_path: PathBuf,
+    /// Not needed with the memory engine
+    db_path: Option<PathBuf>,
+    /// Where entries are kept
+    #[arg(long, value_enum, default_value_t = EngineArg::File)]
+    engine: EngineArg,
     #[arg(short, long, value_name = "PORT", default_value_t = 7312)]
     port: u16,
+    /// Reject admin statements such as FLUSH and INFO
+    #[arg(long)]
+    deny_admin: bool,
+    /// Keep a change log next to the database for incremental backups
+    #[arg(long)]
+    change_log: bool,
+    /// How the file engine compresses values
+    #[arg(long, value_enum, default_value_t = CompressionArg::None)]
+    compression: CompressionArg,
+    /// A zstd dictionary to compress values with
+    #[arg(long, value_name = "FILE")]
+    dictionary: Option<PathBuf>,
+    /// A file holding the key the file engine encrypts values with
+    #[arg(long, value_name = "FILE", conflicts_with = "key_env")]
+    key_file: Option<PathBuf>,
+    /// An environment variable holding the key, as 64 hex digits
+    #[arg(long, value_name = "VAR")]
+    key_env: Option<String>,
+    /// A file holding a new key to re-encrypt with on the next COMPACT
+    #[arg(long, value_name = "FILE")]
+    rotate_key_file: Option<PathBuf>,
+    /// The cipher new databases and rotated keys use
+    #[arg(long, value_enum, default_value_t = CipherArg::AesGcm)]
+    cipher: CipherArg,
+    /// Read the file engine's database through a memory map
+    #[arg(long)]
+    mmap: bool,
 }
 
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum CipherArg {
+    AesGcm,
+    #[value(name = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum CompressionArg {
+    None,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum EngineArg {
+    /// A single kiv file at the database path
+    File,
+    /// Memory only, lost when the server stops
+    Memory,
+    /// A log-structured merge tree in a directory at the database path
+    Lsm,
+    /// A B+tree of fixed-size pages in a file at the database path
+    #[value(name = "btree")]
+    BTree,
+}
+
+/// Every request gets its own clone of the handle, so requests don't share
+/// a current namespace and reads run side by side, off the runtime's
+/// workers.
+#[derive(Clone)]
 struct AppState {
-    kiv: Kiv,
+    kiv: AsyncKiv,
 }
 
 #[derive(Serialize)]
@@ -32,16 +108,47 @@ enum KivErrorP {
     TokenizerError(#[serde(with = "TokenizerErrorP")] TokenizerError),
     #[serde(rename = "parserError")]
     ParserError(#[serde(with = "ParserErrorP")] ParserError),
+    #[serde(rename = "ioError")]
+    IoError(#[serde(serialize_with = "serialize_io_error")] std::io::Error),
+    #[serde(rename = "namespaceExists")]
+    NamespaceExists(String),
+    #[serde(rename = "namespaceNotFound")]
+    NamespaceNotFound(String),
+    #[serde(rename = "dropDefaultNamespace")]
+    DropDefaultNamespace,
+    #[serde(rename = "adminDisabled")]
+    AdminDisabled,
+    #[serde(rename = "unboundParameter")]
+    UnboundParameter(String),
+    #[serde(rename = "parameterTypeMismatch")]
+    ParameterTypeMismatch(String),
+    #[serde(rename = "placeholderMismatch")]
+    PlaceholderMismatch,
+    #[serde(rename = "snapshotReadOnly")]
+    SnapshotReadOnly,
 }
 
 #[derive(Serialize)]
 struct KivErrorPW(#[serde(with = "KivErrorP")] KivError);
 
+fn serialize_io_error<S>(err: &std::io::Error, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&err.to_string())
+}
+
 #[derive(Serialize)]
 #[serde(remote = "TokenizerError")]
 enum TokenizerErrorP {
     #[serde(rename = "unknownKeyword")]
     UnknownKeyword(String),
+    #[serde(rename = "invalidNumber")]
+    InvalidNumber(String),
+    #[serde(rename = "invalidPlaceholder")]
+    InvalidPlaceholder(String),
+    #[serde(rename = "unterminatedString")]
+    UnterminatedString,
 }
 
 #[derive(Serialize)]
@@ -63,6 +170,78 @@ enum ParserErrorP {
     DeleteNoKey,
     #[serde(rename = "getNoKey")]
     GetNoKey,
+    #[serde(rename = "zaddNoKey")]
+    ZAddNoKey,
+    #[serde(rename = "zaddNoScore")]
+    ZAddNoScore,
+    #[serde(rename = "zaddNoMember")]
+    ZAddNoMember,
+    #[serde(rename = "zremNoKey")]
+    ZRemNoKey,
+    #[serde(rename = "zremNoMember")]
+    ZRemNoMember,
+    #[serde(rename = "zscoreNoKey")]
+    ZScoreNoKey,
+    #[serde(rename = "zscoreNoMember")]
+    ZScoreNoMember,
+    #[serde(rename = "zrangeNoKey")]
+    ZRangeNoKey,
+    #[serde(rename = "zrangeNoByScore")]
+    ZRangeNoByScore,
+    #[serde(rename = "zrangeNoScore")]
+    ZRangeNoScore,
+    #[serde(rename = "zrangeNoMin")]
+    ZRangeNoMin,
+    #[serde(rename = "zrangeNoMax")]
+    ZRangeNoMax,
+    #[serde(rename = "zrankNoKey")]
+    ZRankNoKey,
+    #[serde(rename = "zrankNoMember")]
+    ZRankNoMember,
+    #[serde(rename = "saddNoKey")]
+    SAddNoKey,
+    #[serde(rename = "saddNoMember")]
+    SAddNoMember,
+    #[serde(rename = "sremNoKey")]
+    SRemNoKey,
+    #[serde(rename = "sremNoMember")]
+    SRemNoMember,
+    #[serde(rename = "sismemberNoKey")]
+    SIsMemberNoKey,
+    #[serde(rename = "sismemberNoMember")]
+    SIsMemberNoMember,
+    #[serde(rename = "smembersNoKey")]
+    SMembersNoKey,
+    #[serde(rename = "scardNoKey")]
+    SCardNoKey,
+    #[serde(rename = "sunionNoKey")]
+    SUnionNoKey,
+    #[serde(rename = "sinterNoKey")]
+    SInterNoKey,
+    #[serde(rename = "sdiffNoKey")]
+    SDiffNoKey,
+    #[serde(rename = "createNoNamespace")]
+    CreateNoNamespace,
+    #[serde(rename = "createNamespaceNoName")]
+    CreateNamespaceNoName,
+    #[serde(rename = "dropNoNamespace")]
+    DropNoNamespace,
+    #[serde(rename = "dropNamespaceNoName")]
+    DropNamespaceNoName,
+    #[serde(rename = "useNoNamespace")]
+    UseNoNamespace,
+    #[serde(rename = "showNoNamespaces")]
+    ShowNoNamespaces,
+    #[serde(rename = "explainNoOperation")]
+    ExplainNoOperation,
+    #[serde(rename = "backupNoTo")]
+    BackupNoTo,
+    #[serde(rename = "backupNoPath")]
+    BackupNoPath,
+    #[serde(rename = "trailingToken")]
+    TrailingToken(String),
+    #[serde(rename = "unexpectedPlaceholder")]
+    UnexpectedPlaceholder(String),
 }
 
 #[derive(Serialize)]
@@ -85,6 +264,52 @@ pub enum OperationResultResultP {
     Delete,
     #[serde(rename = "get")]
     Get(#[serde(with = "GetResultP")] GetResult),
+    #[serde(rename = "zadd")]
+    ZAdd,
+    #[serde(rename = "zrem")]
+    ZRem,
+    #[serde(rename = "zscore")]
+    ZScore(#[serde(with = "ZScoreResultP")] ZScoreResult),
+    #[serde(rename = "zrange")]
+    ZRange(#[serde(with = "ZRangeResultP")] ZRangeResult),
+    #[serde(rename = "zrank")]
+    ZRank(#[serde(with = "ZRankResultP")] ZRankResult),
+    #[serde(rename = "sadd")]
+    SAdd,
+    #[serde(rename = "srem")]
+    SRem,
+    #[serde(rename = "sismember")]
+    SIsMember(#[serde(with = "SIsMemberResultP")] SIsMemberResult),
+    #[serde(rename = "smembers")]
+    SMembers(#[serde(with = "SMembersResultP")] SMembersResult),
+    #[serde(rename = "scard")]
+    SCard(#[serde(with = "SCardResultP")] SCardResult),
+    #[serde(rename = "sunion")]
+    SUnion(#[serde(with = "SMembersResultP")] SMembersResult),
+    #[serde(rename = "sinter")]
+    SInter(#[serde(with = "SMembersResultP")] SMembersResult),
+    #[serde(rename = "sdiff")]
+    SDiff(#[serde(with = "SMembersResultP")] SMembersResult),
+    #[serde(rename = "createNamespace")]
+    CreateNamespace,
+    #[serde(rename = "dropNamespace")]
+    DropNamespace,
+    #[serde(rename = "use")]
+    Use,
+    #[serde(rename = "namespaces")]
+    Namespaces(#[serde(with = "NamespacesResultP")] NamespacesResult),
+    #[serde(rename = "flush")]
+    Flush,
+    #[serde(rename = "truncate")]
+    Truncate,
+    #[serde(rename = "info")]
+    Info(#[serde(with = "InfoResultP")] InfoResult),
+    #[serde(rename = "explain")]
+    Explain(#[serde(with = "ExplainResultP")] ExplainResult),
+    #[serde(rename = "backup")]
+    Backup(#[serde(with = "BackupResultP")] BackupResult),
+    #[serde(rename = "compact")]
+    Compact(#[serde(with = "CompactResultP")] CompactResult),
 }
 
 #[derive(Serialize)]
@@ -93,11 +318,270 @@ pub struct GetResultP {
     pub value: Option<String>,
 }
 
+#[derive(Serialize)]
+#[serde(remote = "ZScoreResult")]
+pub struct ZScoreResultP {
+    pub score: Option<f64>,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "ZRangeResult")]
+pub struct ZRangeResultP {
+    #[serde(serialize_with = "serialize_scored_members")]
+    pub members: Vec<ScoredMember>,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "ScoredMember")]
+pub struct ScoredMemberP {
+    pub member: String,
+    pub score: f64,
+}
+
+#[derive(Serialize)]
+struct ScoredMemberPW<'a>(#[serde(with = "ScoredMemberP")] &'a ScoredMember);
+
+fn serialize_scored_members<S>(members: &[ScoredMember], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut seq = serializer.serialize_seq(Some(members.len()))?;
+    for member in members {
+        seq.serialize_element(&ScoredMemberPW(member))?;
+    }
+    seq.end()
+}
+
+#[derive(Serialize)]
+#[serde(remote = "ZRankResult")]
+pub struct ZRankResultP {
+    pub rank: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "SIsMemberResult")]
+pub struct SIsMemberResultP {
+    #[serde(rename = "isMember")]
+    pub is_member: bool,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "SMembersResult")]
+pub struct SMembersResultP {
+    pub members: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "SCardResult")]
+pub struct SCardResultP {
+    pub cardinality: u64,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "NamespacesResult")]
+pub struct NamespacesResultP {
+    #[serde(serialize_with = "serialize_namespaces")]
+    pub namespaces: Vec<NamespaceInfo>,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "NamespaceInfo")]
+pub struct NamespaceInfoP {
+    pub name: String,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+struct NamespaceInfoPW<'a>(#[serde(with = "NamespaceInfoP")] &'a NamespaceInfo);
+
+fn serialize_namespaces<S>(namespaces: &[NamespaceInfo], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut seq = serializer.serialize_seq(Some(namespaces.len()))?;
+    for namespace in namespaces {
+        seq.serialize_element(&NamespaceInfoPW(namespace))?;
+    }
+    seq.end()
+}
+
+#[derive(Serialize)]
+#[serde(remote = "InfoResult")]
+pub struct InfoResultP {
+    pub keys: u64,
+    #[serde(rename = "fileSize")]
+    pub file_size: u64,
+    #[serde(rename = "liveBytes")]
+    pub live_bytes: u64,
+    #[serde(rename = "deadBytes")]
+    pub dead_bytes: u64,
+    pub version: u16,
+    pub uptime: Duration,
+    #[serde(rename = "bloomHits")]
+    pub bloom_hits: u64,
+    #[serde(rename = "bloomMisses")]
+    pub bloom_misses: u64,
+    #[serde(rename = "compressionRatio")]
+    pub compression_ratio: f64,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "ExplainResult")]
+pub struct ExplainResultP {
+    pub operation: String,
+    #[serde(rename = "accessPath", with = "AccessPathP")]
+    pub access_path: AccessPath,
+    #[serde(rename = "estimatedRows")]
+    pub estimated_rows: u64,
+    #[serde(rename = "estimatedBytes")]
+    pub estimated_bytes: u64,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "BackupResult")]
+pub struct BackupResultP {
+    pub path: String,
+    pub bytes: u64,
+    pub sequence: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "CompactResult")]
+pub struct CompactResultP {
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "AccessPath")]
+pub enum AccessPathP {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "keyScan")]
+    KeyScan,
+    #[serde(rename = "fullScan")]
+    FullScan,
+    #[serde(rename = "rewrite")]
+    Rewrite,
+    #[serde(rename = "namespaces")]
+    Namespaces,
+    #[serde(rename = "sortedSet")]
+    SortedSet,
+}
+
+#[derive(Deserialize)]
+struct ExecParams {
+    namespace: Option<String>,
+}
+
+/// A statement sent as JSON, with values for its placeholders.
+#[derive(Deserialize)]
+struct ExecRequest {
+    query: String,
+    #[serde(default)]
+    params: Option<ExecRequestParams>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExecRequestParams {
+    Positional(Vec<ExecRequestParam>),
+    Named(HashMap<String, ExecRequestParam>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExecRequestParam {
+    String(String),
+    Number(f64),
+}
+
+impl From<ExecRequestParam> for Param {
+    fn from(param: ExecRequestParam) -> Self {
+        match param {
+            ExecRequestParam::String(value) => Param::String(value),
+            ExecRequestParam::Number(value) => Param::Number(value),
+        }
+    }
+}
+
+/// Executes a JSON request, binding its params to the query's placeholders.
+fn exec_request(kiv: &Kiv, request: ExecRequest) -> Result<OperationResult, KivError> {
+    let mut prepared = kiv.prepare(request.query)?;
+    match request.params {
+        Some(ExecRequestParams::Positional(params)) => {
+            for (index, param) in params.into_iter().enumerate() {
+                prepared.bind(index + 1, param);
+            }
+        }
+        Some(ExecRequestParams::Named(params)) => {
+            for (name, param) in params {
+                prepared.bind_named(name, param);
+            }
+        }
+        None => {}
+    }
+
+    kiv.exec_prepared(&prepared)
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let kiv = match Kiv::open(args.db_path) {
+    let mut config = match (args.engine, args.db_path) {
+        (EngineArg::File, Some(path)) => KivConfig::new(path),
+        (EngineArg::Lsm, Some(path)) => KivConfig {
+            engine: Engine::Lsm,
+            ..KivConfig::new(path)
+        },
+        (EngineArg::BTree, Some(path)) => KivConfig {
+            engine: Engine::BTree,
+            ..KivConfig::new(path)
+        },
+        (EngineArg::File | EngineArg::Lsm | EngineArg::BTree, None) => Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "a database path is needed with the file, lsm and btree engines",
+            )
+            .exit(),
+        (EngineArg::Memory, _) => KivConfig::memory(),
+    };
+    config.file.compression.codec = match args.compression {
+        CompressionArg::None => Codec::None,
+        CompressionArg::Lz4 => Codec::Lz4,
+        CompressionArg::Zstd => Codec::Zstd,
+    };
+    if let Some(path) = args.dictionary {
+        match std::fs::read(&path) {
+            Ok(dictionary) => config.file.compression.dictionary = Some(dictionary.into()),
+            Err(err) => {
+                eprintln!("Error reading dictionary {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let key = |key: std::io::Result<EncryptionKey>| {
+        key.unwrap_or_else(|err| {
+            eprintln!("Error reading encryption key: {}", err);
+            std::process::exit(1);
+        })
+    };
+    let encryption = &mut config.file.encryption;
+    encryption.cipher = match args.cipher {
+        CipherArg::AesGcm => Cipher::Aes256Gcm,
+        CipherArg::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305,
+    };
+    encryption.key = match (args.key_file, args.key_env) {
+        (Some(path), _) => Some(key(EncryptionKey::from_file(path))),
+        (_, Some(name)) => Some(key(EncryptionKey::from_env(&name))),
+        (None, None) => None,
+    };
+    encryption.rotate_to = args
+        .rotate_key_file
+        .map(|path| key(EncryptionKey::from_file(path)));
+    config.file.mmap = args.mmap;
+    let mut kiv = match Kiv::open_with(config) {
         Ok(kiv) => kiv,
         Err(err) => {
             eprintln!("Error opening database:");
@@ -106,10 +590,23 @@ async fn main() {
         }
     };
 
-    let shared_state = Arc::new(Mutex::new(AppState { kiv }));
+    kiv.set_allow_admin(!args.deny_admin);
+
+    if args.change_log {
+        if let Err(err) = kiv.enable_change_log() {
+            eprintln!("Error starting change log:");
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let shared_state = AppState {
+        kiv: AsyncKiv::from(kiv),
+    };
 
     let app = Router::new()
         .route("/exec", post(exec))
+        .route("/admin/snapshot", get(snapshot))
         .with_state(shared_state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
@@ -120,23 +617,222 @@ async fn main() {
 }
 
 async fn exec(
-    State(state): State<Arc<Mutex<AppState>>>,
+    State(state): State<AppState>,
+    Query(params): Query<ExecParams>,
+    headers: HeaderMap,
     body: String,
 ) -> axum::http::Response<String> {
-    match state.lock().unwrap().kiv.exec(body) {
-        Ok(res) => {
-            return axum::http::Response::builder()
-                .header("content-type", "application/json")
-                .status(StatusCode::OK)
-                .body(serde_json::to_string(&OperationResultPW(res)).unwrap())
-                .unwrap();
+    let namespace = params
+        .namespace
+        .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string());
+
+    // JSON bodies carry a query and its params, anything else is plain KivQL
+    let is_json = headers
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let request = if is_json {
+        match serde_json::from_str::<ExecRequest>(&body) {
+            Ok(request) => Some(request),
+            Err(err) => {
+                return axum::http::Response::builder()
+                    .header("content-type", "application/json")
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(serde_json::json!({ "invalidRequest": err.to_string() }).to_string())
+                    .unwrap();
+            }
         }
-        Err(err) => {
-            return axum::http::Response::builder()
-                .header("content-type", "application/json")
-                .status(StatusCode::BAD_REQUEST)
-                .body(serde_json::to_string(&KivErrorPW(err)).unwrap())
-                .unwrap();
+    } else {
+        None
+    };
+
+    let result = state
+        .kiv
+        .run(move |kiv| {
+            // every request starts out in the namespace it asked for
+            kiv.use_namespace(&namespace)?;
+            match request {
+                Some(request) => exec_request(kiv, request),
+                None => kiv.exec(body),
+            }
+        })
+        .await;
+
+    exec_response(result)
+}
+
+/// The response to a statement. A request that panicked comes back as an io
+/// error, and gets a 500 like other io errors.
+fn exec_response(result: Result<OperationResult, KivError>) -> axum::http::Response<String> {
+    match result {
+        Ok(res) => axum::http::Response::builder()
+            .header("content-type", "application/json")
+            .status(StatusCode::OK)
+            .body(serde_json::to_string(&OperationResultPW(res)).unwrap())
+            .unwrap(),
+        Err(err) => axum::http::Response::builder()
+            .header("content-type", "application/json")
+            .status(match err {
+                KivError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::BAD_REQUEST,
+            })
+            .body(serde_json::to_string(&KivErrorPW(err)).unwrap())
+            .unwrap(),
+    }
+}
+
+/// Streams a consistent copy of the database as a download. The copy is
+/// taken into a temporary file first, so writes only wait while it is being
+/// copied and not while the client downloads it.
+async fn snapshot(State(state): State<AppState>) -> Response {
+    if !state.kiv.blocking().allows_admin() {
+        return error_response(KivError::AdminDisabled);
+    }
+
+    let result = state
+        .kiv
+        .run(|kiv| {
+            let mut file = tempfile::tempfile()?;
+            let (bytes, sequence) = kiv.backup_to(&mut file)?;
+            Ok((file, bytes, sequence))
+        })
+        .await;
+
+    let (mut file, bytes, sequence) = match result {
+        Ok(snapshot) => snapshot,
+        Err(err) => return error_response(err),
+    };
+
+    if let Err(err) = file.seek(SeekFrom::Start(0)) {
+        return error_response(KivError::IoError(err));
+    }
+
+    let body = StreamBody::new(ReaderStream::new(tokio::fs::File::from_std(file)));
+
+    let mut response = (
+        [
+            ("content-type", "application/octet-stream".to_string()),
+            ("content-length", bytes.to_string()),
+            (
+                "content-disposition",
+                "attachment; filename=\"snapshot.kiv\"".to_string(),
+            ),
+        ],
+        body,
+    )
+        .into_response();
+
+    // lets the download be restored along with incremental backups
+    if let Some(sequence) = sequence {
+        response
+            .headers_mut()
+            .insert("x-kiv-sequence", sequence.into());
+    }
+
+    response
+}
+
+fn error_response(err: KivError) -> Response {
+    axum::http::Response::builder()
+        .header("content-type", "application/json")
+        .status(match err {
+            KivError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            KivError::AdminDisabled => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_REQUEST,
+        })
+        .body(serde_json::to_string(&KivErrorPW(err)).unwrap())
+        .unwrap()
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> (tempfile::TempDir, AppState) {
+        let dir = tempfile::tempdir().unwrap();
+        let kiv = Kiv::open(dir.path().join("test.kiv")).unwrap();
+        let state = AppState {
+            kiv: AsyncKiv::from(kiv),
+        };
+        (dir, state)
+    }
+
+    async fn request(
+        state: &AppState,
+        namespace: Option<&str>,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let params = ExecParams {
+            namespace: namespace.map(String::from),
+        };
+        let response = exec(
+            State(state.clone()),
+            Query(params),
+            HeaderMap::new(),
+            body.to_string(),
+        )
+        .await;
+
+        let status = response.status();
+        (status, serde_json::from_str(response.body()).unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parallel_requests_stay_in_their_namespace() {
+        let (_dir, state) = state();
+        request(&state, None, "CREATE NAMESPACE 'a'").await;
+        request(&state, None, "CREATE NAMESPACE 'b'").await;
+
+        let requests = (0..64).map(|i| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let namespace = ["a", "b", "default"][i % 3];
+                let set = format!("SET 'k{}' TO '{}'", i, namespace);
+                let (status, _) = request(&state, Some(namespace), &set).await;
+                assert_eq!(status, StatusCode::OK);
+
+                let get = format!("GET 'k{}'", i);
+                let (status, body) = request(&state, Some(namespace), &get).await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(body["result"]["get"]["value"], namespace, "{}", body);
+            })
+        });
+        for request in requests.collect::<Vec<_>>() {
+            request.await.unwrap();
         }
+
+        // each key is only in the namespace it was set in
+        let (_, body) = request(&state, Some("b"), "GET 'k0'").await;
+        assert!(body["result"]["get"]["value"].is_null(), "{}", body);
+        let (_, body) = request(&state, None, "GET 'k2'").await;
+        assert_eq!(body["result"]["get"]["value"], "default");
+
+        let (status, body) = request(&state, Some("missing"), "GET 'k0'").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.get("namespaceNotFound").is_some(), "{}", body);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn panicking_requests_get_an_internal_error() {
+        let (_dir, state) = state();
+        request(&state, None, "SET 'a' TO '1'").await;
+
+        let result = state
+            .kiv
+            .run(|kiv| -> Result<OperationResult, KivError> {
+                kiv.use_namespace(DEFAULT_NAMESPACE)?;
+                panic!("in a request")
+            })
+            .await;
+        let response = exec_response(result);
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value = serde_json::from_str(response.body()).unwrap();
+        assert!(body.get("ioError").is_some(), "{}", body);
+
+        // the server carries on
+        let (status, body) = request(&state, None, "GET 'a'").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["get"]["value"], "1");
     }
 }
@@ -0,0 +1,241 @@
+// canonical KivQL text for tokens and operations
+//
+// formatting an operation and parsing the result gives back the same
+// operation, as long as its numbers are finite
+
+use crate::parser::Operation;
+use crate::tokenizer::{Keyword, Token};
+use std::fmt;
+
+/// Quotes a string literal, doubling any single quotes inside it.
+pub fn quote(string: &str) -> String {
+    format!("'{}'", string.replace('\'', "''"))
+}
+
+/// Formats a script with one statement per line, each ending in `;`.
+pub fn format_script(operations: &[Operation]) -> String {
+    operations
+        .iter()
+        .map(|operation| format!("{};\n", operation))
+        .collect()
+}
+
+/// Formats tokens with single spaces between them and a line break after
+/// every `;`. Unlike formatting a parsed operation this keeps placeholders.
+pub fn format_tokens(tokens: &[Token]) -> String {
+    let mut formatted = String::new();
+
+    for token in tokens.iter().filter(|token| token != &&Token::Whitespace) {
+        if token == &Token::Semicolon {
+            formatted.push_str(";\n");
+            continue;
+        }
+
+        if !formatted.is_empty() && !formatted.ends_with('\n') {
+            formatted.push(' ');
+        }
+        formatted.push_str(&token.to_string());
+    }
+
+    formatted
+}
+
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Keyword(keyword) => write!(f, "{}", keyword),
+            Token::String(string) => write!(f, "{}", quote(string)),
+            Token::Number(number) => write!(f, "{}", number),
+            Token::Placeholder(placeholder) => write!(f, "{}", placeholder),
+            Token::Semicolon => write!(f, ";"),
+            Token::Whitespace => write!(f, " "),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::SET(set) => write!(f, "SET {} TO {}", quote(&set.key), quote(&set.value)),
+            Operation::DELETE(delete) => write!(f, "DELETE {}", quote(&delete.key)),
+            Operation::GET(get) => write!(f, "GET {}", quote(&get.key)),
+            Operation::ZADD(zadd) => write!(
+                f,
+                "ZADD {} {} {}",
+                quote(&zadd.key),
+                zadd.score,
+                quote(&zadd.member)
+            ),
+            Operation::ZREM(zrem) => {
+                write!(f, "ZREM {} {}", quote(&zrem.key), quote(&zrem.member))
+            }
+            Operation::ZSCORE(zscore) => {
+                write!(f, "ZSCORE {} {}", quote(&zscore.key), quote(&zscore.member))
+            }
+            Operation::ZRANGE(zrange) => write!(
+                f,
+                "ZRANGE {} BY SCORE {} {}",
+                quote(&zrange.key),
+                zrange.min,
+                zrange.max
+            ),
+            Operation::ZRANK(zrank) => {
+                write!(f, "ZRANK {} {}", quote(&zrank.key), quote(&zrank.member))
+            }
+            Operation::SADD(sadd) => {
+                write!(f, "SADD {} {}", quote(&sadd.key), quote_all(&sadd.members))
+            }
+            Operation::SREM(srem) => {
+                write!(f, "SREM {} {}", quote(&srem.key), quote_all(&srem.members))
+            }
+            Operation::SISMEMBER(sismember) => write!(
+                f,
+                "SISMEMBER {} {}",
+                quote(&sismember.key),
+                quote(&sismember.member)
+            ),
+            Operation::SMEMBERS(smembers) => write!(f, "SMEMBERS {}", quote(&smembers.key)),
+            Operation::SCARD(scard) => write!(f, "SCARD {}", quote(&scard.key)),
+            Operation::SUNION(sunion) => write!(f, "SUNION {}", quote_all(&sunion.keys)),
+            Operation::SINTER(sinter) => write!(f, "SINTER {}", quote_all(&sinter.keys)),
+            Operation::SDIFF(sdiff) => write!(f, "SDIFF {}", quote_all(&sdiff.keys)),
+            Operation::CREATENAMESPACE(create) => {
+                write!(f, "CREATE NAMESPACE {}", quote(&create.name))
+            }
+            Operation::DROPNAMESPACE(drop) => write!(f, "DROP NAMESPACE {}", quote(&drop.name)),
+            Operation::USE(r#use) => write!(f, "USE {}", quote(&r#use.namespace)),
+            Operation::SHOWNAMESPACES => write!(f, "SHOW NAMESPACES"),
+            Operation::FLUSH => write!(f, "FLUSH"),
+            Operation::TRUNCATE => write!(f, "TRUNCATE"),
+            Operation::INFO => write!(f, "INFO"),
+            Operation::EXPLAIN(operation) => write!(f, "EXPLAIN {}", operation),
+            Operation::BACKUP(backup) => write!(f, "BACKUP TO {}", quote(&backup.path)),
+            Operation::COMPACT => write!(f, "COMPACT"),
+        }
+    }
+}
+
+fn quote_all(strings: &[String]) -> String {
+    strings
+        .iter()
+        .map(|string| quote(string))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+    use crate::tokenizer::Tokenizer;
+    use proptest::prelude::*;
+
+    fn string() -> impl Strategy<Value = String> {
+        any::<String>()
+    }
+
+    fn strings() -> impl Strategy<Value = Vec<String>> {
+        prop::collection::vec(string(), 1..4)
+    }
+
+    fn number() -> impl Strategy<Value = f64> {
+        prop::num::f64::NORMAL
+            | prop::num::f64::SUBNORMAL
+            | prop::num::f64::ZERO
+            | prop::num::f64::INFINITE
+    }
+
+    fn operation() -> impl Strategy<Value = Operation> {
+        let leaf =
+            prop_oneof![
+                (string(), string()).prop_map(|(key, value)| Operation::SET(Set { key, value })),
+                string().prop_map(|key| Operation::DELETE(Delete { key })),
+                string().prop_map(|key| Operation::GET(Get { key })),
+                (string(), number(), string())
+                    .prop_map(|(key, score, member)| Operation::ZADD(ZAdd { key, score, member })),
+                (string(), string())
+                    .prop_map(|(key, member)| Operation::ZREM(ZRem { key, member })),
+                (string(), string())
+                    .prop_map(|(key, member)| Operation::ZSCORE(ZScore { key, member })),
+                (string(), number(), number())
+                    .prop_map(|(key, min, max)| Operation::ZRANGE(ZRange { key, min, max })),
+                (string(), string())
+                    .prop_map(|(key, member)| Operation::ZRANK(ZRank { key, member })),
+                (string(), strings())
+                    .prop_map(|(key, members)| Operation::SADD(SAdd { key, members })),
+                (string(), strings())
+                    .prop_map(|(key, members)| Operation::SREM(SRem { key, members })),
+                (string(), string())
+                    .prop_map(|(key, member)| Operation::SISMEMBER(SIsMember { key, member })),
+                string().prop_map(|key| Operation::SMEMBERS(SMembers { key })),
+                string().prop_map(|key| Operation::SCARD(SCard { key })),
+                strings().prop_map(|keys| Operation::SUNION(SUnion { keys })),
+                strings().prop_map(|keys| Operation::SINTER(SInter { keys })),
+                strings().prop_map(|keys| Operation::SDIFF(SDiff { keys })),
+                string().prop_map(|name| Operation::CREATENAMESPACE(CreateNamespace { name })),
+                string().prop_map(|name| Operation::DROPNAMESPACE(DropNamespace { name })),
+                string().prop_map(|namespace| Operation::USE(Use { namespace })),
+                Just(Operation::SHOWNAMESPACES),
+                Just(Operation::FLUSH),
+                Just(Operation::TRUNCATE),
+                Just(Operation::INFO),
+                string().prop_map(|path| Operation::BACKUP(Backup { path })),
+                Just(Operation::COMPACT),
+            ];
+
+        leaf.prop_recursive(2, 2, 1, |inner| {
+            inner.prop_map(|operation| Operation::EXPLAIN(Box::new(operation)))
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn formatted_operations_parse_to_themselves(operation in operation()) {
+            let tokens = Tokenizer::new().tokenize(operation.to_string()).unwrap();
+
+            prop_assert_eq!(operation, Parser::parse(tokens).unwrap());
+        }
+
+        #[test]
+        fn formatted_scripts_parse_to_themselves(operations in prop::collection::vec(operation(), 0..4)) {
+            let tokens = Tokenizer::new().tokenize(format_script(&operations)).unwrap();
+
+            prop_assert_eq!(operations, Parser::parse_script(tokens).unwrap());
+        }
+    }
+
+    #[test]
+    fn nan_scores_parse_back() {
+        let operation = Operation::ZADD(ZAdd {
+            key: String::from("k"),
+            score: f64::NAN,
+            member: String::from("m"),
+        });
+        let tokens = Tokenizer::new().tokenize(operation.to_string()).unwrap();
+
+        assert!(matches!(
+            Parser::parse(tokens).unwrap(),
+            Operation::ZADD(ZAdd { score, .. }) if score.is_nan()
+        ));
+    }
+
+    #[test]
+    fn tokens_are_normalized() {
+        let tokens = Tokenizer::new()
+            .tokenize(String::from(
+                "set  \"it's\"\nto ?;zrange 'k' by score -1 2.50 ;",
+            ))
+            .unwrap();
+
+        assert_eq!(
+            "SET 'it''s' TO $1;\nZRANGE 'k' BY SCORE -1 2.5;\n",
+            format_tokens(&tokens)
+        );
+    }
+}
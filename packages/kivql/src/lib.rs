@@ -1,4 +1,5 @@
 // parsing for kiv's query language (KivQL)
 
+pub mod formatter;
 pub mod parser;
 pub mod tokenizer;
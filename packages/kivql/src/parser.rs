@@ -1,30 +1,221 @@
 use crate::tokenizer::{Keyword, Token};
 use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Operation {
     SET(Set),
     DELETE(Delete),
     GET(Get),
+    ZADD(ZAdd),
+    ZREM(ZRem),
+    ZSCORE(ZScore),
+    ZRANGE(ZRange),
+    ZRANK(ZRank),
+    SADD(SAdd),
+    SREM(SRem),
+    SISMEMBER(SIsMember),
+    SMEMBERS(SMembers),
+    SCARD(SCard),
+    SUNION(SUnion),
+    SINTER(SInter),
+    SDIFF(SDiff),
+    CREATENAMESPACE(CreateNamespace),
+    DROPNAMESPACE(DropNamespace),
+    USE(Use),
+    SHOWNAMESPACES,
+    FLUSH,
+    TRUNCATE,
+    INFO,
+    EXPLAIN(Box<Operation>),
+    BACKUP(Backup),
+    COMPACT,
 }
 
+impl Operation {
+    /// Returns every literal in the operation, in the order they appear in
+    /// the statement.
+    pub fn values_mut(&mut self) -> Vec<ValueMut<'_>> {
+        match self {
+            Operation::SET(set) => vec![
+                ValueMut::String(&mut set.key),
+                ValueMut::String(&mut set.value),
+            ],
+            Operation::DELETE(Delete { key })
+            | Operation::GET(Get { key })
+            | Operation::SMEMBERS(SMembers { key })
+            | Operation::SCARD(SCard { key }) => vec![ValueMut::String(key)],
+            Operation::ZADD(zadd) => vec![
+                ValueMut::String(&mut zadd.key),
+                ValueMut::Number(&mut zadd.score),
+                ValueMut::String(&mut zadd.member),
+            ],
+            Operation::ZREM(ZRem { key, member })
+            | Operation::ZSCORE(ZScore { key, member })
+            | Operation::ZRANK(ZRank { key, member })
+            | Operation::SISMEMBER(SIsMember { key, member }) => {
+                vec![ValueMut::String(key), ValueMut::String(member)]
+            }
+            Operation::ZRANGE(zrange) => vec![
+                ValueMut::String(&mut zrange.key),
+                ValueMut::Number(&mut zrange.min),
+                ValueMut::Number(&mut zrange.max),
+            ],
+            Operation::SADD(SAdd { key, members }) | Operation::SREM(SRem { key, members }) => {
+                let mut values = vec![ValueMut::String(key)];
+                values.extend(members.iter_mut().map(ValueMut::String));
+                values
+            }
+            Operation::SUNION(SUnion { keys })
+            | Operation::SINTER(SInter { keys })
+            | Operation::SDIFF(SDiff { keys }) => keys.iter_mut().map(ValueMut::String).collect(),
+            Operation::CREATENAMESPACE(CreateNamespace { name })
+            | Operation::DROPNAMESPACE(DropNamespace { name })
+            | Operation::USE(Use { namespace: name })
+            | Operation::BACKUP(Backup { path: name }) => vec![ValueMut::String(name)],
+            Operation::EXPLAIN(operation) => operation.values_mut(),
+            Operation::SHOWNAMESPACES
+            | Operation::FLUSH
+            | Operation::TRUNCATE
+            | Operation::INFO
+            | Operation::COMPACT => {
+                vec![]
+            }
+        }
+    }
+
+    /// Whether the operation administers the whole database rather than
+    /// reading or writing data.
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Operation::FLUSH
+                | Operation::TRUNCATE
+                | Operation::INFO
+                | Operation::BACKUP(_)
+                | Operation::COMPACT
+        )
+    }
+}
+
+/// A mutable reference to a literal inside an operation.
 #[derive(Debug)]
+pub enum ValueMut<'a> {
+    String(&'a mut String),
+    Number(&'a mut f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Set {
     pub key: String,
     pub value: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Delete {
     pub key: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Get {
     pub key: String,
 }
 
-#[derive(Error, Debug)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZAdd {
+    pub key: String,
+    pub score: f64,
+    pub member: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZRem {
+    pub key: String,
+    pub member: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZScore {
+    pub key: String,
+    pub member: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZRange {
+    pub key: String,
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZRank {
+    pub key: String,
+    pub member: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SAdd {
+    pub key: String,
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SRem {
+    pub key: String,
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SIsMember {
+    pub key: String,
+    pub member: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SMembers {
+    pub key: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SCard {
+    pub key: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SUnion {
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SInter {
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SDiff {
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateNamespace {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DropNamespace {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Use {
+    pub namespace: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backup {
+    pub path: String,
+}
+
+#[derive(Error, Debug, PartialEq)]
 pub enum ParserError {
     #[error("no key provided for SET operation")]
     SetNoKey,
@@ -42,13 +233,101 @@ pub enum ParserError {
     DeleteNoKey,
     #[error("no key provided for GET operation")]
     GetNoKey,
+    #[error("no key provided for ZADD operation")]
+    ZAddNoKey,
+    #[error("no score provided for ZADD operation")]
+    ZAddNoScore,
+    #[error("no member provided for ZADD operation")]
+    ZAddNoMember,
+    #[error("no key provided for ZREM operation")]
+    ZRemNoKey,
+    #[error("no member provided for ZREM operation")]
+    ZRemNoMember,
+    #[error("no key provided for ZSCORE operation")]
+    ZScoreNoKey,
+    #[error("no member provided for ZSCORE operation")]
+    ZScoreNoMember,
+    #[error("no key provided for ZRANGE operation")]
+    ZRangeNoKey,
+    #[error("no BY SCORE after ZRANGE operation key")]
+    ZRangeNoByScore,
+    #[error("no SCORE after ZRANGE operation BY")]
+    ZRangeNoScore,
+    #[error("no minimum score provided for ZRANGE operation")]
+    ZRangeNoMin,
+    #[error("no maximum score provided for ZRANGE operation")]
+    ZRangeNoMax,
+    #[error("no key provided for ZRANK operation")]
+    ZRankNoKey,
+    #[error("no member provided for ZRANK operation")]
+    ZRankNoMember,
+    #[error("no key provided for SADD operation")]
+    SAddNoKey,
+    #[error("no member provided for SADD operation")]
+    SAddNoMember,
+    #[error("no key provided for SREM operation")]
+    SRemNoKey,
+    #[error("no member provided for SREM operation")]
+    SRemNoMember,
+    #[error("no key provided for SISMEMBER operation")]
+    SIsMemberNoKey,
+    #[error("no member provided for SISMEMBER operation")]
+    SIsMemberNoMember,
+    #[error("no key provided for SMEMBERS operation")]
+    SMembersNoKey,
+    #[error("no key provided for SCARD operation")]
+    SCardNoKey,
+    #[error("no key provided for SUNION operation")]
+    SUnionNoKey,
+    #[error("no key provided for SINTER operation")]
+    SInterNoKey,
+    #[error("no key provided for SDIFF operation")]
+    SDiffNoKey,
+    #[error("no NAMESPACE after CREATE operation")]
+    CreateNoNamespace,
+    #[error("no name provided for CREATE NAMESPACE operation")]
+    CreateNamespaceNoName,
+    #[error("no NAMESPACE after DROP operation")]
+    DropNoNamespace,
+    #[error("no name provided for DROP NAMESPACE operation")]
+    DropNamespaceNoName,
+    #[error("no namespace provided for USE operation")]
+    UseNoNamespace,
+    #[error("no NAMESPACES after SHOW operation")]
+    ShowNoNamespaces,
+    #[error("no operation provided for EXPLAIN")]
+    ExplainNoOperation,
+    #[error("no TO after BACKUP operation")]
+    BackupNoTo,
+    #[error("no path provided for BACKUP operation")]
+    BackupNoPath,
+    #[error("unexpected token after the end of the operation")]
+    TrailingToken(String),
+    #[error("placeholders can only be used in prepared statements")]
+    UnexpectedPlaceholder(String),
 }
 
 pub struct Parser {}
 
 impl Parser {
+    /// Parses a statement that has no placeholders.
     pub fn parse(tokens: Vec<Token>) -> Result<Operation, ParserError> {
-        let operation = if let Some(op) = tokens.get(0) {
+        let placeholder = tokens.iter().find_map(|token| match token {
+            Token::Placeholder(placeholder) => Some(placeholder.to_string()),
+            _ => None,
+        });
+        let operation = Parser::parse_prepared(tokens)?;
+
+        match placeholder {
+            Some(placeholder) => Err(ParserError::UnexpectedPlaceholder(placeholder)),
+            None => Ok(operation),
+        }
+    }
+
+    /// Parses a statement that may have placeholders, to be filled in when
+    /// its parameters are bound.
+    pub fn parse_prepared(tokens: Vec<Token>) -> Result<Operation, ParserError> {
+        let operation = if let Some(op) = tokens.first() {
             op
         } else {
             return Err(ParserError::EmptyStatement);
@@ -62,25 +341,18 @@ impl Parser {
                         .filter(|token| token != &&Token::Whitespace)
                         .collect();
 
-                    let key = match tokens.get(1) {
-                        Some(Token::String(k)) => k,
-                        _ => return Err(ParserError::SetNoKey),
-                    };
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::SetNoKey)?;
 
                     match tokens.get(2) {
                         Some(Token::Keyword(Keyword::TO)) => {}
                         _ => return Err(ParserError::SetNoTo),
                     }
 
-                    let value = match tokens.get(3) {
-                        Some(Token::String(v)) => v,
-                        _ => return Err(ParserError::SetNoValue),
-                    };
+                    let value = Parser::string(tokens.get(3)).ok_or(ParserError::SetNoValue)?;
 
-                    return Ok(Operation::SET(Set {
-                        key: key.to_owned(),
-                        value: value.to_owned(),
-                    }));
+                    Parser::end(&tokens, 4)?;
+
+                    Ok(Operation::SET(Set { key, value }))
                 }
                 Keyword::DELETE => {
                     let tokens: Vec<&Token> = tokens
@@ -88,14 +360,11 @@ impl Parser {
                         .filter(|token| token != &&Token::Whitespace)
                         .collect();
 
-                    let key = match tokens.get(1) {
-                        Some(Token::String(k)) => k,
-                        _ => return Err(ParserError::DeleteNoKey),
-                    };
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::DeleteNoKey)?;
+
+                    Parser::end(&tokens, 2)?;
 
-                    return Ok(Operation::DELETE(Delete {
-                        key: key.to_owned(),
-                    }));
+                    Ok(Operation::DELETE(Delete { key }))
                 }
                 Keyword::GET => {
                     let tokens: Vec<&Token> = tokens
@@ -103,18 +372,491 @@ impl Parser {
                         .filter(|token| token != &&Token::Whitespace)
                         .collect();
 
-                    let key = match tokens.get(1) {
-                        Some(Token::String(k)) => k,
-                        _ => return Err(ParserError::GetNoKey),
-                    };
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::GetNoKey)?;
+
+                    Parser::end(&tokens, 2)?;
+
+                    Ok(Operation::GET(Get { key }))
+                }
+                Keyword::ZADD => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::ZAddNoKey)?;
+
+                    let score = Parser::number(tokens.get(2)).ok_or(ParserError::ZAddNoScore)?;
+
+                    let member = Parser::string(tokens.get(3)).ok_or(ParserError::ZAddNoMember)?;
+
+                    Parser::end(&tokens, 4)?;
+
+                    Ok(Operation::ZADD(ZAdd { key, score, member }))
+                }
+                Keyword::ZREM => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::ZRemNoKey)?;
+
+                    let member = Parser::string(tokens.get(2)).ok_or(ParserError::ZRemNoMember)?;
+
+                    Parser::end(&tokens, 3)?;
+
+                    Ok(Operation::ZREM(ZRem { key, member }))
+                }
+                Keyword::ZSCORE => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::ZScoreNoKey)?;
+
+                    let member =
+                        Parser::string(tokens.get(2)).ok_or(ParserError::ZScoreNoMember)?;
+
+                    Parser::end(&tokens, 3)?;
+
+                    Ok(Operation::ZSCORE(ZScore { key, member }))
+                }
+                Keyword::ZRANGE => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::ZRangeNoKey)?;
+
+                    match tokens.get(2) {
+                        Some(Token::Keyword(Keyword::BY)) => {}
+                        _ => return Err(ParserError::ZRangeNoByScore),
+                    }
+
+                    match tokens.get(3) {
+                        Some(Token::Keyword(Keyword::SCORE)) => {}
+                        _ => return Err(ParserError::ZRangeNoScore),
+                    }
+
+                    let min = Parser::number(tokens.get(4)).ok_or(ParserError::ZRangeNoMin)?;
+
+                    let max = Parser::number(tokens.get(5)).ok_or(ParserError::ZRangeNoMax)?;
+
+                    Parser::end(&tokens, 6)?;
+
+                    Ok(Operation::ZRANGE(ZRange { key, min, max }))
+                }
+                Keyword::ZRANK => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::ZRankNoKey)?;
+
+                    let member = Parser::string(tokens.get(2)).ok_or(ParserError::ZRankNoMember)?;
+
+                    Parser::end(&tokens, 3)?;
+
+                    Ok(Operation::ZRANK(ZRank { key, member }))
+                }
+                Keyword::SADD => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::SAddNoKey)?;
+
+                    let members = Parser::strings(&tokens[2..]);
+                    if members.is_empty() {
+                        return Err(ParserError::SAddNoMember);
+                    }
+
+                    Parser::end(&tokens, 2 + members.len())?;
+
+                    Ok(Operation::SADD(SAdd { key, members }))
+                }
+                Keyword::SREM => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::SRemNoKey)?;
+
+                    let members = Parser::strings(&tokens[2..]);
+                    if members.is_empty() {
+                        return Err(ParserError::SRemNoMember);
+                    }
+
+                    Parser::end(&tokens, 2 + members.len())?;
+
+                    Ok(Operation::SREM(SRem { key, members }))
+                }
+                Keyword::SISMEMBER => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::SIsMemberNoKey)?;
+
+                    let member =
+                        Parser::string(tokens.get(2)).ok_or(ParserError::SIsMemberNoMember)?;
+
+                    Parser::end(&tokens, 3)?;
+
+                    Ok(Operation::SISMEMBER(SIsMember { key, member }))
+                }
+                Keyword::SMEMBERS => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::SMembersNoKey)?;
+
+                    Parser::end(&tokens, 2)?;
+
+                    Ok(Operation::SMEMBERS(SMembers { key }))
+                }
+                Keyword::SCARD => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let key = Parser::string(tokens.get(1)).ok_or(ParserError::SCardNoKey)?;
+
+                    Parser::end(&tokens, 2)?;
+
+                    Ok(Operation::SCARD(SCard { key }))
+                }
+                Keyword::SUNION => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let keys = Parser::strings(&tokens[1..]);
+                    if keys.is_empty() {
+                        return Err(ParserError::SUnionNoKey);
+                    }
+
+                    Parser::end(&tokens, 1 + keys.len())?;
+
+                    Ok(Operation::SUNION(SUnion { keys }))
+                }
+                Keyword::SINTER => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let keys = Parser::strings(&tokens[1..]);
+                    if keys.is_empty() {
+                        return Err(ParserError::SInterNoKey);
+                    }
+
+                    Parser::end(&tokens, 1 + keys.len())?;
+
+                    Ok(Operation::SINTER(SInter { keys }))
+                }
+                Keyword::SDIFF => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let keys = Parser::strings(&tokens[1..]);
+                    if keys.is_empty() {
+                        return Err(ParserError::SDiffNoKey);
+                    }
+
+                    Parser::end(&tokens, 1 + keys.len())?;
+
+                    Ok(Operation::SDIFF(SDiff { keys }))
+                }
+                Keyword::CREATE => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    match tokens.get(1) {
+                        Some(Token::Keyword(Keyword::NAMESPACE)) => {}
+                        _ => return Err(ParserError::CreateNoNamespace),
+                    }
+
+                    let name =
+                        Parser::string(tokens.get(2)).ok_or(ParserError::CreateNamespaceNoName)?;
+
+                    Parser::end(&tokens, 3)?;
+
+                    Ok(Operation::CREATENAMESPACE(CreateNamespace { name }))
+                }
+                Keyword::DROP => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    match tokens.get(1) {
+                        Some(Token::Keyword(Keyword::NAMESPACE)) => {}
+                        _ => return Err(ParserError::DropNoNamespace),
+                    }
+
+                    let name =
+                        Parser::string(tokens.get(2)).ok_or(ParserError::DropNamespaceNoName)?;
+
+                    Parser::end(&tokens, 3)?;
+
+                    Ok(Operation::DROPNAMESPACE(DropNamespace { name }))
+                }
+                Keyword::USE => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    let namespace =
+                        Parser::string(tokens.get(1)).ok_or(ParserError::UseNoNamespace)?;
+
+                    Parser::end(&tokens, 2)?;
+
+                    Ok(Operation::USE(Use { namespace }))
+                }
+                Keyword::SHOW => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    match tokens.get(1) {
+                        Some(Token::Keyword(Keyword::NAMESPACES)) => {}
+                        _ => return Err(ParserError::ShowNoNamespaces),
+                    }
+
+                    Parser::end(&tokens, 2)?;
 
-                    return Ok(Operation::GET(Get {
-                        key: key.to_owned(),
-                    }));
+                    Ok(Operation::SHOWNAMESPACES)
                 }
-                _ => return Err(ParserError::UnexpectedOperation),
+                Keyword::FLUSH => Parser::bare(&tokens, Operation::FLUSH),
+                Keyword::TRUNCATE => Parser::bare(&tokens, Operation::TRUNCATE),
+                Keyword::COMPACT => Parser::bare(&tokens, Operation::COMPACT),
+                // STATS is an alias for INFO
+                Keyword::INFO | Keyword::STATS => Parser::bare(&tokens, Operation::INFO),
+                Keyword::EXPLAIN => {
+                    let tokens: Vec<Token> = tokens
+                        .into_iter()
+                        .skip(1)
+                        .skip_while(|token| token == &Token::Whitespace)
+                        .collect();
+
+                    if tokens.is_empty() {
+                        return Err(ParserError::ExplainNoOperation);
+                    }
+
+                    Ok(Operation::EXPLAIN(Box::new(Parser::parse_prepared(
+                        tokens,
+                    )?)))
+                }
+                Keyword::BACKUP => {
+                    let tokens: Vec<&Token> = tokens
+                        .iter()
+                        .filter(|token| token != &&Token::Whitespace)
+                        .collect();
+
+                    match tokens.get(1) {
+                        Some(Token::Keyword(Keyword::TO)) => {}
+                        _ => return Err(ParserError::BackupNoTo),
+                    }
+
+                    let path = Parser::string(tokens.get(2)).ok_or(ParserError::BackupNoPath)?;
+
+                    Parser::end(&tokens, 3)?;
+
+                    Ok(Operation::BACKUP(Backup { path }))
+                }
+                _ => Err(ParserError::UnexpectedOperation),
             },
-            _ => return Err(ParserError::OperationFirst),
+            _ => Err(ParserError::OperationFirst),
         }
     }
+
+    /// Parses statements separated by `;`. Empty statements are skipped.
+    pub fn parse_script(tokens: Vec<Token>) -> Result<Vec<Operation>, ParserError> {
+        let mut operations = vec![];
+        let mut statement = vec![];
+
+        for token in tokens.into_iter().chain(std::iter::once(Token::Semicolon)) {
+            match token {
+                Token::Semicolon => {
+                    if !statement.is_empty() {
+                        operations.push(Parser::parse(std::mem::take(&mut statement))?);
+                    }
+                }
+                // leading whitespace would be taken for the operation
+                Token::Whitespace if statement.is_empty() => {}
+                token => statement.push(token),
+            }
+        }
+
+        Ok(operations)
+    }
+
+    /// Parses an operation that is only its keyword.
+    fn bare(tokens: &[Token], operation: Operation) -> Result<Operation, ParserError> {
+        let tokens: Vec<&Token> = tokens
+            .iter()
+            .filter(|token| token != &&Token::Whitespace)
+            .collect();
+
+        Parser::end(&tokens, 1)?;
+
+        Ok(operation)
+    }
+
+    /// Checks that nothing but semicolons follows the `used` tokens an
+    /// operation is made of.
+    fn end(tokens: &[&Token], used: usize) -> Result<(), ParserError> {
+        match tokens
+            .iter()
+            .skip(used)
+            .find(|token| token != &&&Token::Semicolon)
+        {
+            Some(token) => Err(ParserError::TrailingToken(token.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Collects the leading run of string tokens, stopping at the first
+    /// token that isn't a string or placeholder.
+    fn strings(tokens: &[&Token]) -> Vec<String> {
+        tokens
+            .iter()
+            .map_while(|token| Parser::string(Some(token)))
+            .collect()
+    }
+
+    /// Reads a string literal. Placeholders are read as empty strings, to be
+    /// filled in when the statement's parameters are bound.
+    fn string(token: Option<&&Token>) -> Option<String> {
+        match token {
+            Some(Token::String(s)) => Some(s.to_owned()),
+            Some(Token::Placeholder(_)) => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    /// Reads a number literal. Placeholders are read as zero, to be filled in
+    /// when the statement's parameters are bound.
+    fn number(token: Option<&&Token>) -> Option<f64> {
+        match token {
+            Some(Token::Number(n)) => Some(*n),
+            Some(Token::Placeholder(_)) => Some(0.0),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{Get, Operation, Parser, ParserError, SAdd};
+    use crate::tokenizer::Tokenizer;
+
+    fn parse(statement: &str) -> Result<Operation, ParserError> {
+        Parser::parse(Tokenizer::new().tokenize(String::from(statement)).unwrap())
+    }
+
+    #[test]
+    fn trailing_tokens_are_rejected() {
+        for statement in [
+            "GET ? ?",
+            "GET 'a' 'b'",
+            "SET 'a' TO 'b' 'c'",
+            "SADD 'k' 'a' 5 ?",
+            "ZRANGE 'k' BY SCORE 1 2 3",
+            "SHOW NAMESPACES 'x'",
+            "FLUSH 'x'",
+            "EXPLAIN GET 'a' TO",
+            "GET 'a'; GET 'b'",
+        ] {
+            assert!(
+                matches!(parse(statement), Err(ParserError::TrailingToken(_))),
+                "{}",
+                statement
+            );
+        }
+    }
+
+    #[test]
+    fn zrange_reports_which_part_is_missing() {
+        assert_eq!(
+            parse("ZRANGE 'k'").unwrap_err(),
+            ParserError::ZRangeNoByScore
+        );
+        assert_eq!(
+            parse("ZRANGE 'k' 1 2").unwrap_err(),
+            ParserError::ZRangeNoByScore
+        );
+        assert_eq!(
+            parse("ZRANGE 'k' BY").unwrap_err(),
+            ParserError::ZRangeNoScore
+        );
+        assert_eq!(
+            parse("ZRANGE 'k' BY 1 2").unwrap_err(),
+            ParserError::ZRangeNoScore
+        );
+        assert_eq!(
+            parse("ZRANGE 'k' BY SCORE").unwrap_err(),
+            ParserError::ZRangeNoMin
+        );
+    }
+
+    #[test]
+    fn statements_can_end_with_semicolons() {
+        assert_eq!(
+            parse("GET 'a' ;").unwrap(),
+            Operation::GET(Get {
+                key: String::from("a")
+            })
+        );
+        assert_eq!(parse("COMPACT;").unwrap(), Operation::COMPACT);
+    }
+
+    #[test]
+    fn placeholders_are_only_parsed_in_prepared_statements() {
+        let tokens = Tokenizer::new()
+            .tokenize(String::from("SADD 'k' 'a' ?"))
+            .unwrap();
+
+        assert_eq!(
+            Parser::parse_prepared(tokens.clone()).unwrap(),
+            Operation::SADD(SAdd {
+                key: String::from("k"),
+                members: vec![String::from("a"), String::new()],
+            })
+        );
+        assert_eq!(
+            Parser::parse(tokens),
+            Err(ParserError::UnexpectedPlaceholder(String::from("$1")))
+        );
+        assert_eq!(
+            parse("EXPLAIN ZADD 'k' :score 'a'"),
+            Err(ParserError::UnexpectedPlaceholder(String::from(":score")))
+        );
+
+        let tokens = Tokenizer::new()
+            .tokenize(String::from("GET 'a'; GET ?"))
+            .unwrap();
+        assert_eq!(
+            Parser::parse_script(tokens),
+            Err(ParserError::UnexpectedPlaceholder(String::from("$1")))
+        );
+    }
 }
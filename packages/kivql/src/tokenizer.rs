@@ -1,42 +1,130 @@
+use std::fmt;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum TokenizerError {
     #[error("unknown keyword")]
     UnknownKeyword(String),
+    #[error("invalid number")]
+    InvalidNumber(String),
+    #[error("invalid placeholder")]
+    InvalidPlaceholder(String),
+    #[error("unterminated string")]
+    UnterminatedString,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Keyword(Keyword),
     String(String),
+    Number(f64),
+    Placeholder(Placeholder),
+    Semicolon,
     Whitespace,
 }
 
-#[derive(Debug, PartialEq)]
-pub enum Keyword {
+/// A parameter to be bound in a prepared statement.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Placeholder {
+    /// `?` or `$1`. `?` placeholders are numbered from 1 in the order they
+    /// appear.
+    Index(usize),
+    /// `:name`
+    Name(String),
+}
+
+impl fmt::Display for Placeholder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Placeholder::Index(index) => write!(f, "${}", index),
+            Placeholder::Name(name) => write!(f, ":{}", name),
+        }
+    }
+}
+
+/// Declares [`Keyword`] along with the list of every keyword, so the
+/// tokenizer and anything completing statements can't miss one.
+macro_rules! keywords {
+    ($($keyword:ident),* $(,)?) => {
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Keyword {
+            $($keyword),*
+        }
+
+        impl Keyword {
+            /// Every keyword, in the order they're declared.
+            pub const ALL: &'static [Keyword] = &[$(Keyword::$keyword),*];
+
+            /// The keyword as it is written, in uppercase.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Keyword::$keyword => stringify!($keyword)),*
+                }
+            }
+        }
+    };
+}
+
+keywords! {
     SET,
     TO,
     DELETE,
     GET,
+    ZADD,
+    ZREM,
+    ZSCORE,
+    ZRANGE,
+    ZRANK,
+    BY,
+    SCORE,
+    SADD,
+    SREM,
+    SISMEMBER,
+    SMEMBERS,
+    SCARD,
+    SUNION,
+    SINTER,
+    SDIFF,
+    CREATE,
+    DROP,
+    USE,
+    SHOW,
+    NAMESPACE,
+    NAMESPACES,
+    FLUSH,
+    TRUNCATE,
+    INFO,
+    STATS,
+    EXPLAIN,
+    BACKUP,
+    COMPACT,
 }
 
 pub struct Tokenizer {
-    input: String,
+    input: Vec<char>,
     position: usize,
+    placeholders: usize,
+}
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tokenizer {
     pub fn new() -> Self {
         Self {
-            input: String::new(),
+            input: vec![],
             position: 0,
+            placeholders: 0,
         }
     }
 
     pub fn tokenize(&mut self, statement: String) -> Result<Vec<Token>, TokenizerError> {
-        self.input = statement;
+        self.input = statement.chars().collect();
         self.position = 0;
+        self.placeholders = 0;
 
         let mut tokens: Vec<Token> = vec![];
 
@@ -48,22 +136,55 @@ impl Tokenizer {
                 tokens.push(Token::Whitespace);
             }
 
-            if Tokenizer::is_alphanumeric(current_char) {
+            if Tokenizer::is_number_start(current_char) {
+                let number = self.read_until(|char| !Tokenizer::is_number(char));
+                match number.parse() {
+                    Ok(number) => tokens.push(Token::Number(number)),
+                    Err(_) => return Err(TokenizerError::InvalidNumber(number)),
+                }
+            }
+
+            if Tokenizer::is_alphabetic(current_char) {
                 let keyword = self.read_until(|char| !Tokenizer::is_alphanumeric(char));
-                let keyword = match &*keyword.to_uppercase() {
-                    "SET" => Token::Keyword(Keyword::SET),
-                    "TO" => Token::Keyword(Keyword::TO),
-                    "DELETE" => Token::Keyword(Keyword::DELETE),
-                    "GET" => Token::Keyword(Keyword::GET),
-                    _ => return Err(TokenizerError::UnknownKeyword(keyword)),
+                let uppercase = keyword.to_uppercase();
+                let keyword = match Keyword::ALL.iter().find(|known| known.name() == uppercase) {
+                    Some(known) => Token::Keyword(known.clone()),
+                    // inf and NaN are written as words
+                    None => match keyword.parse() {
+                        Ok(number) => Token::Number(number),
+                        Err(_) => return Err(TokenizerError::UnknownKeyword(keyword)),
+                    },
                 };
                 tokens.push(keyword);
             }
 
             if Tokenizer::is_quote(current_char) {
-                self.advance();
-                tokens.push(Token::String(self.read_until(Tokenizer::is_quote)));
-                self.advance();
+                tokens.push(Token::String(self.read_string()?));
+            }
+
+            if current_char == ';' {
+                tokens.push(Token::Semicolon);
+            }
+
+            if current_char == '?' {
+                self.placeholders += 1;
+                tokens.push(Token::Placeholder(Placeholder::Index(self.placeholders)));
+            }
+
+            if current_char == '$' {
+                let index = self.read_placeholder(|char| char.is_ascii_digit())?;
+                match index.parse() {
+                    Ok(index) if index > 0 => {
+                        tokens.push(Token::Placeholder(Placeholder::Index(index)))
+                    }
+                    _ => return Err(TokenizerError::InvalidPlaceholder(format!("${}", index))),
+                }
+            }
+
+            if current_char == ':' {
+                let name =
+                    self.read_placeholder(|char| char.is_ascii_alphanumeric() || char == '_')?;
+                tokens.push(Token::Placeholder(Placeholder::Name(name)));
             }
 
             self.advance();
@@ -73,7 +194,7 @@ impl Tokenizer {
     }
 
     fn current_char(&self) -> Option<char> {
-        self.input.chars().nth(self.position)
+        self.input.get(self.position).copied()
     }
 
     fn advance(&mut self) {
@@ -86,13 +207,7 @@ impl Tokenizer {
     {
         let mut read = String::new();
 
-        loop {
-            let current_char = if let Some(char) = self.current_char() {
-                char
-            } else {
-                break;
-            };
-
+        while let Some(current_char) = self.current_char() {
             read.push(current_char);
 
             let next_char = if let Some(char) = self.peek_next() {
@@ -111,8 +226,47 @@ impl Tokenizer {
         read
     }
 
+    /// Reads a string starting at its opening quote, leaving the position on
+    /// the closing quote. The opening quote can be written twice to include
+    /// it in the string.
+    fn read_string(&mut self) -> Result<String, TokenizerError> {
+        let quote = self.current_char().unwrap();
+        let mut read = String::new();
+
+        loop {
+            self.advance();
+            match self.current_char() {
+                Some(char) if char == quote => {
+                    if self.peek_next() != Some(quote) {
+                        return Ok(read);
+                    }
+                    // doubled quote
+                    self.advance();
+                    read.push(quote);
+                }
+                Some(char) => read.push(char),
+                None => return Err(TokenizerError::UnterminatedString),
+            }
+        }
+    }
+
+    /// Reads the part of a placeholder after its sigil.
+    fn read_placeholder<P>(&mut self, predicate: P) -> Result<String, TokenizerError>
+    where
+        P: Fn(char) -> bool,
+    {
+        let sigil = self.current_char().unwrap();
+        match self.peek_next() {
+            Some(char) if predicate(char) => {}
+            _ => return Err(TokenizerError::InvalidPlaceholder(sigil.to_string())),
+        }
+
+        self.advance();
+        Ok(self.read_until(|char| !predicate(char)))
+    }
+
     fn peek_next(&self) -> Option<char> {
-        self.input.chars().nth(self.position + 1)
+        self.input.get(self.position + 1).copied()
     }
 
     fn input_finished(&self) -> bool {
@@ -127,6 +281,19 @@ impl Tokenizer {
         char == '\'' || char == '"'
     }
 
+    fn is_alphabetic(char: char) -> bool {
+        char.is_ascii_alphabetic()
+    }
+
+    fn is_number_start(char: char) -> bool {
+        char.is_ascii_digit() || char == '-'
+    }
+
+    fn is_number(char: char) -> bool {
+        // letters for -inf and exponents
+        char.is_ascii_alphanumeric() || char == '-' || char == '.'
+    }
+
     fn is_alphanumeric(char: char) -> bool {
         // i like letters :D
         let chars = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
@@ -136,7 +303,7 @@ impl Tokenizer {
 
 #[cfg(test)]
 mod tests {
-    use crate::tokenizer::{Keyword, Token, Tokenizer};
+    use crate::tokenizer::{Keyword, Placeholder, Token, Tokenizer, TokenizerError};
 
     #[test]
     fn strings_are_detected() {
@@ -167,4 +334,97 @@ mod tests {
 
         assert_eq!(expected, tokens);
     }
+
+    #[test]
+    fn every_keyword_is_detected_in_any_case() {
+        for keyword in Keyword::ALL {
+            for written in [keyword.name().to_string(), keyword.name().to_lowercase()] {
+                let tokens = Tokenizer::new().tokenize(written).unwrap();
+                assert_eq!(tokens, vec![Token::Keyword(keyword.clone())]);
+            }
+        }
+    }
+
+    #[test]
+    fn numbers_are_detected() {
+        let expected = vec![
+            Token::Keyword(Keyword::ZADD),
+            Token::Whitespace,
+            Token::String(String::from("board")),
+            Token::Whitespace,
+            Token::Number(-1.5),
+            Token::Whitespace,
+            Token::Number(10.0),
+        ];
+
+        let statement = String::from("ZADD 'board' -1.5 10");
+
+        let tokens = Tokenizer::new().tokenize(statement).unwrap();
+
+        assert_eq!(expected, tokens);
+    }
+
+    #[test]
+    fn non_finite_numbers_are_detected() {
+        let tokens = Tokenizer::new()
+            .tokenize(String::from("inf -inf NaN 1e3"))
+            .unwrap();
+
+        assert_eq!(tokens[0], Token::Number(f64::INFINITY));
+        assert_eq!(tokens[2], Token::Number(f64::NEG_INFINITY));
+        assert!(matches!(tokens[4], Token::Number(number) if number.is_nan()));
+        assert_eq!(tokens[6], Token::Number(1000.0));
+        assert!(matches!(
+            Tokenizer::new().tokenize(String::from("infinite")),
+            Err(TokenizerError::UnknownKeyword(_))
+        ));
+    }
+
+    #[test]
+    fn placeholders_are_detected() {
+        let expected = vec![
+            Token::Placeholder(Placeholder::Index(1)),
+            Token::Whitespace,
+            Token::Placeholder(Placeholder::Index(7)),
+            Token::Whitespace,
+            Token::Placeholder(Placeholder::Name(String::from("user_id"))),
+            Token::Whitespace,
+            Token::Placeholder(Placeholder::Index(2)),
+        ];
+
+        let statement = String::from("? $7 :user_id ?");
+
+        let tokens = Tokenizer::new().tokenize(statement).unwrap();
+
+        assert_eq!(expected, tokens);
+    }
+
+    #[test]
+    fn strings_can_contain_quotes() {
+        let expected = vec![
+            Token::String(String::from("it's")),
+            Token::Whitespace,
+            Token::String(String::from("say \"hi\"")),
+            Token::Whitespace,
+            Token::String(String::from("")),
+            Token::Semicolon,
+        ];
+
+        let statement = String::from("'it''s' \"say \"\"hi\"\"\" '';");
+
+        let tokens = Tokenizer::new().tokenize(statement).unwrap();
+
+        assert_eq!(expected, tokens);
+    }
+
+    #[test]
+    fn fuzz_corpus_does_not_panic() {
+        let corpus =
+            std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../fuzz/corpus/tokenize");
+
+        for input in std::fs::read_dir(corpus).unwrap() {
+            let statement = std::fs::read_to_string(input.unwrap().path()).unwrap();
+            let _ = Tokenizer::new().tokenize(statement);
+        }
+    }
 }
@@ -0,0 +1,58 @@
+// compares reading a file through read calls with reading it through a
+// memory map, for lookups and for scans
+//
+// run with `cargo bench -p storage`
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use storage::{Storage, StorageOptions};
+
+const ENTRIES: usize = 1000;
+
+fn key(i: usize) -> String {
+    format!("key{:04}", i)
+}
+
+fn read(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("read.kiv");
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    for i in 0..ENTRIES {
+        storage.write_data_entry(key(i), "v".repeat(100)).unwrap();
+    }
+    drop(storage);
+
+    // read-only opens share the file, and get values as slices of the map
+    let mut paths = [("read calls", false), ("mmap", true)].map(|(name, mmap)| {
+        let options = StorageOptions {
+            mmap,
+            ..StorageOptions::default()
+        };
+        let storage =
+            Storage::open_read_only_with_options(path.to_string_lossy(), options).unwrap();
+        (name, storage)
+    });
+
+    let mut group = c.benchmark_group("get");
+    for (name, storage) in &mut paths {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            let mut i = 0;
+            b.iter(|| {
+                // lookups read every entry before the one they find
+                i = (i + 7919) % ENTRIES;
+                black_box(storage.get_data_entry_bytes(key(i)).unwrap())
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("scan");
+    for (name, storage) in &mut paths {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| black_box(storage.scan_data_entries("key").unwrap()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read);
+criterion_main!(benches);
@@ -0,0 +1,127 @@
+// bloom filters: a compact set of key hashes that can say for certain that
+// a key was never added, so lookups for missing keys skip reading entries
+//
+// keys are hashed once into two 64-bit halves, and the bits they set are
+// picked from combinations of the two (Kirsch and Mitzenmacher). the hash
+// is written out here rather than taken from std, as filters are persisted
+// and have to hash the same way in every build
+
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::invalid_data;
+
+pub(crate) struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u8,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `items` keys, which wrongly reports about
+    /// `false_positive_rate` of the keys it doesn't hold as present.
+    pub(crate) fn new(items: usize, false_positive_rate: f64) -> Self {
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let items = items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-items * rate.ln() / (ln2 * ln2)).ceil().max(8.0);
+        let hashes = (bits / items * ln2).round().clamp(1.0, 30.0);
+
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(8)],
+            hashes: hashes as u8,
+        }
+    }
+
+    /// Adds a key hashed with [`hash`].
+    pub(crate) fn insert_hash(&mut self, hash: (u64, u64)) {
+        for bit in self.bit_positions(hash) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Whether `key` may have been added. `false` means it certainly wasn't.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.may_contain_hash(hash(key))
+    }
+
+    /// Like [`BloomFilter::may_contain`], for a key hashed with [`hash`].
+    pub(crate) fn may_contain_hash(&self, hash: (u64, u64)) -> bool {
+        self.bit_positions(hash)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn bit_positions(&self, (h1, h2): (u64, u64)) -> impl Iterator<Item = usize> {
+        let bits = (self.bits.len() * 8) as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    /// The number of hashes, followed by the bits.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.bits.len());
+        bytes.push(self.hashes);
+        bytes.extend_from_slice(&self.bits);
+
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        match bytes.split_first() {
+            Some((&hashes, bits)) if hashes > 0 && !bits.is_empty() => Ok(Self {
+                bits: bits.to_vec(),
+                hashes,
+            }),
+            _ => Err(invalid_data("bloom filter is malformed")),
+        }
+    }
+}
+
+/// How lookups went for the filters they were checked against. A hit is a
+/// lookup a filter answered on its own, a miss one it let through to be
+/// read from disk.
+#[derive(Default)]
+pub(crate) struct BloomCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BloomCounters {
+    /// Counts `may_contain`, the answer a filter gave, and passes it on.
+    pub(crate) fn record(&self, may_contain: bool) -> bool {
+        let counter = if may_contain {
+            &self.misses
+        } else {
+            &self.hits
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        may_contain
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Hashes a key for [`BloomFilter::insert_hash`]: FNV-1a, with the second
+/// half mixed from the first by the SplitMix64 finalizer.
+pub(crate) fn hash(key: &[u8]) -> (u64, u64) {
+    let mut h1: u64 = 0xcbf29ce484222325;
+    for byte in key {
+        h1 ^= *byte as u64;
+        h1 = h1.wrapping_mul(0x100000001b3);
+    }
+
+    let mut h2 = h1.wrapping_add(0x9e3779b97f4a7c15);
+    h2 = (h2 ^ (h2 >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h2 = (h2 ^ (h2 >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h2 ^= h2 >> 31;
+
+    // an odd step visits more distinct bits before repeating
+    (h1, h2 | 1)
+}
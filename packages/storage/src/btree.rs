@@ -0,0 +1,1040 @@
+// a storage engine built as a b+tree of fixed-size pages, for read-heavy use
+// with range scans
+//
+// the file is a run of pages. the first holds two copies of the meta
+// header, which names the root page, and every other page is a tree node,
+// part of a value too big for a leaf, or part of the free list. pages are
+// copied on write: a write puts every node it changes on a free page, syncs
+// them, and only then writes a meta header naming the new root, over the
+// older of the two copies. a crash at any point leaves the other copy
+// naming a complete tree
+//
+// pages a write stops using go on the free list, but can't be reused until
+// the meta header that names them is overwritten and no view is still
+// reading a tree that uses them. the free list is written out with every
+// write, on pages taken from the list itself
+//
+// entries are laid out in a single sorted key space, see `sorted`
+
+mod node;
+mod pager;
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs::OpenOptions,
+    io::{self, Read},
+    ops::Range,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use node::{
+    branch_entry_length, decode_free_list, encode_free_list, free_list_capacity,
+    inline_entry_length, leaf_entry_length, overflow_capacity, overflow_entry_length, Node, Slot,
+    NODE_HEADER_LENGTH,
+};
+use pager::Pager;
+
+use crate::{
+    check_length,
+    engine::StorageEngine,
+    file::PositionedFile,
+    invalid_data,
+    lock::try_lock,
+    sorted::{self, Batch, SortedStore, Value, Visit},
+    Entry, NamespaceStats, StorageStats, DEFAULT_NAMESPACE_ID,
+};
+
+const MAGIC_BYTES: [u8; 6] = [0, 107, 105, 118, 98, 116];
+const VERSION: u16 = 1;
+// CRC32, magic, version, page size, then the commit, root page, page
+// count, first free list page and entry count
+const META_LENGTH: usize = 4 + 6 + 2 + 4 + 8 * 5;
+// the copies are a sector apart, so a torn write can only damage one
+const META_SLOT_LENGTH: u64 = 512;
+
+const MIN_PAGE_SIZE: usize = 1024;
+const MAX_PAGE_SIZE: usize = 64 << 10;
+
+/// Tuning for [`BTreeEngine`].
+#[derive(Debug, Clone)]
+pub struct BTreeOptions {
+    /// The size of every page, for new files. Existing files keep the size
+    /// they were made with. Between 1 KiB and 64 KiB. Keys, with their
+    /// namespace, can take up to about a quarter of a page.
+    pub page_size: usize,
+    /// How many tree nodes the buffer pool keeps decoded in memory.
+    pub cache_pages: usize,
+}
+
+impl Default for BTreeOptions {
+    fn default() -> Self {
+        Self {
+            page_size: 4 << 10,
+            cache_pages: 1024,
+        }
+    }
+}
+
+/// Keeps entries in a b+tree in a single file, see [`BTreeOptions`].
+///
+/// The file is locked while it is open, like a kiv file. Views made with
+/// [`StorageEngine::reader`] and [`StorageEngine::snapshot_reader`] each
+/// read a complete tree, and the pages of a snapshot's tree aren't reused
+/// until it is dropped.
+pub struct BTreeEngine {
+    shared: Arc<Shared>,
+    namespace: u16,
+    read_only: bool,
+    /// The tree views made by `snapshot_reader` read.
+    snapshot: Option<Pin>,
+}
+
+#[derive(Clone, Copy)]
+struct Meta {
+    /// Counts up with every write. Even commits go in the first copy of the
+    /// header, odd ones in the second.
+    commit: u64,
+    /// 0 for an empty tree.
+    root: u64,
+    page_count: u64,
+    free_list: u64,
+    entries: u64,
+}
+
+impl Meta {
+    fn encode(&self, page_size: usize) -> Vec<u8> {
+        let mut bytes = BytesMut::with_capacity(META_LENGTH);
+        bytes.put_u32(0);
+        bytes.extend_from_slice(&MAGIC_BYTES);
+        bytes.put_u16(VERSION);
+        bytes.put_u32(page_size as u32);
+        bytes.put_u64(self.commit);
+        bytes.put_u64(self.root);
+        bytes.put_u64(self.page_count);
+        bytes.put_u64(self.free_list);
+        bytes.put_u64(self.entries);
+        let crc = crc32fast::hash(&bytes[4..]);
+        BigEndian::write_u32(&mut bytes[..4], crc);
+
+        bytes.to_vec()
+    }
+
+    /// The header and page size in a copy, if it is intact.
+    fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        if crc32fast::hash(&bytes[4..META_LENGTH]) != BigEndian::read_u32(bytes)
+            || bytes[4..10] != MAGIC_BYTES
+            || BigEndian::read_u16(&bytes[10..12]) != VERSION
+        {
+            return None;
+        }
+
+        let page_size = BigEndian::read_u32(&bytes[12..16]) as usize;
+        let meta = Meta {
+            commit: BigEndian::read_u64(&bytes[16..24]),
+            root: BigEndian::read_u64(&bytes[24..32]),
+            page_count: BigEndian::read_u64(&bytes[32..40]),
+            free_list: BigEndian::read_u64(&bytes[40..48]),
+            entries: BigEndian::read_u64(&bytes[48..56]),
+        };
+        Some((meta, page_size))
+    }
+}
+
+struct Shared {
+    pager: Pager,
+    readers: Mutex<Readers>,
+    /// Held for the whole of every write.
+    writer: Mutex<Writer>,
+}
+
+struct Readers {
+    /// The last commit.
+    meta: Meta,
+    /// How many views are reading the tree of each commit.
+    pins: BTreeMap<u64, usize>,
+}
+
+struct Writer {
+    /// Pages that can be reused now.
+    free: BTreeSet<u64>,
+    /// Pages each commit stopped using, kept until no view reads a tree
+    /// that uses them.
+    pending: Vec<(u64, Vec<u64>)>,
+    /// The pages the last commit's free list is on.
+    free_list_pages: Vec<u64>,
+}
+
+impl Shared {
+    fn readers(&self) -> MutexGuard<'_, Readers> {
+        self.readers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn writer(&self) -> MutexGuard<'_, Writer> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn value(&self, slot: &Slot) -> io::Result<Vec<u8>> {
+        match slot {
+            Slot::Inline(value) => Ok(value.clone()),
+            Slot::Overflow { length, page } => self.pager.read_overflow(*page, *length),
+        }
+    }
+
+    fn get(&self, root: u64, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut page = root;
+        while page != 0 {
+            let node = self.pager.read_node(page)?;
+            match &*node {
+                Node::Leaf(entries) => {
+                    return match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                        Ok(index) => self.value(&entries[index].1).map(Some),
+                        Err(_) => Ok(None),
+                    };
+                }
+                Node::Branch { keys, children } => {
+                    page = children[keys.partition_point(|k| k.as_slice() <= key)];
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Calls `f` with every entry under `page` starting with `prefix`,
+    /// returning `false` once it has passed them all.
+    fn walk(&self, page: u64, prefix: &[u8], f: &mut Visit) -> io::Result<bool> {
+        let node = self.pager.read_node(page)?;
+        match &*node {
+            Node::Leaf(entries) => {
+                let start = entries.partition_point(|(k, _)| k.as_slice() < prefix);
+                for (key, slot) in &entries[start..] {
+                    if !key.starts_with(prefix) {
+                        return Ok(false);
+                    }
+                    f(key, self.value(slot)?)?;
+                }
+            }
+            Node::Branch { keys, children } => {
+                let start = keys.partition_point(|k| k.as_slice() <= prefix);
+                for (index, child) in children.iter().enumerate().skip(start) {
+                    let past = index > 0 && {
+                        let separator = &keys[index - 1];
+                        separator.as_slice() > prefix && !separator.starts_with(prefix)
+                    };
+                    if past || !self.walk(*child, prefix, f)? {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Adds the pages of every node under `page` to `nodes`, and the leaf
+    /// entries to `entries` if it is given.
+    fn collect(
+        &self,
+        page: u64,
+        nodes: &mut Vec<u64>,
+        mut entries: Option<&mut Vec<(Vec<u8>, Slot)>>,
+    ) -> io::Result<()> {
+        nodes.push(page);
+        match &*self.pager.read_node(page)? {
+            Node::Leaf(leaf) => {
+                if let Some(entries) = entries {
+                    entries.extend(leaf.iter().cloned());
+                }
+            }
+            Node::Branch { children, .. } => {
+                for child in children {
+                    self.collect(*child, nodes, entries.as_deref_mut())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pin(self: &Arc<Self>) -> Pin {
+        let mut readers = self.readers();
+        let meta = readers.meta;
+        *readers.pins.entry(meta.commit).or_default() += 1;
+
+        Pin {
+            shared: Arc::clone(self),
+            commit: meta.commit,
+            root: meta.root,
+        }
+    }
+}
+
+/// Keeps the pages of a commit's tree from being reused while it is read.
+struct Pin {
+    shared: Arc<Shared>,
+    commit: u64,
+    root: u64,
+}
+
+impl Clone for Pin {
+    fn clone(&self) -> Self {
+        *self.shared.readers().pins.entry(self.commit).or_default() += 1;
+
+        Self {
+            shared: Arc::clone(&self.shared),
+            commit: self.commit,
+            root: self.root,
+        }
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut readers = self.shared.readers();
+        if let Some(count) = readers.pins.get_mut(&self.commit) {
+            *count -= 1;
+            if *count == 0 {
+                readers.pins.remove(&self.commit);
+            }
+        }
+    }
+}
+
+/// The pages that replace a node, each with the first key under it.
+type Parts = Vec<(Vec<u8>, u64)>;
+
+/// A write in progress. Nothing it does is seen until it commits.
+struct Txn<'a> {
+    shared: &'a Shared,
+    writer: &'a mut Writer,
+    meta: Meta,
+    /// Pages this write took from the free list or the end of the file.
+    taken: HashSet<u64>,
+    /// Pages this write stopped using that an older tree still uses.
+    freed: Vec<u64>,
+    /// Free pages cut off the end of the file.
+    trimmed: Vec<u64>,
+}
+
+impl<'a> Txn<'a> {
+    fn begin(shared: &'a Shared, writer: &'a mut Writer) -> Self {
+        let mut meta = {
+            let readers = shared.readers();
+            // a view pinning commit `n` reads a tree made of pages that
+            // were only freed by later commits
+            let oldest = readers.pins.keys().next().copied();
+            let Writer { free, pending, .. } = writer;
+            pending.retain(|(commit, pages)| {
+                let released = oldest.is_none_or(|oldest| *commit <= oldest);
+                if released {
+                    free.extend(pages);
+                }
+                !released
+            });
+            readers.meta
+        };
+        meta.commit += 1;
+
+        Txn {
+            shared,
+            writer,
+            meta,
+            taken: HashSet::new(),
+            freed: vec![],
+            trimmed: vec![],
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        self.shared.pager.page_size
+    }
+
+    /// The most a leaf entry takes up, so every node holds a few.
+    fn max_entry_length(&self) -> usize {
+        (self.page_size() - NODE_HEADER_LENGTH - 8) / 4
+    }
+
+    fn max_key_length(&self) -> usize {
+        self.max_entry_length() - overflow_entry_length(&[])
+    }
+
+    fn alloc(&mut self) -> u64 {
+        let page = self.writer.free.pop_first().unwrap_or_else(|| {
+            self.meta.page_count += 1;
+            self.meta.page_count - 1
+        });
+        self.taken.insert(page);
+
+        page
+    }
+
+    fn free(&mut self, page: u64) {
+        // no committed tree uses pages this write took, so they can be
+        // reused straight away
+        if self.taken.contains(&page) {
+            self.writer.free.insert(page);
+        } else {
+            self.freed.push(page);
+        }
+    }
+
+    fn slot(&mut self, key: &[u8], value: Vec<u8>) -> io::Result<Slot> {
+        if inline_entry_length(key, &value) <= self.max_entry_length() {
+            return Ok(Slot::Inline(value));
+        }
+
+        let pages: Vec<u64> = (0..value.len().div_ceil(overflow_capacity(self.page_size())))
+            .map(|_| self.alloc())
+            .collect();
+        self.shared.pager.write_overflow(&pages, &value)?;
+
+        Ok(Slot::Overflow {
+            length: value.len() as u32,
+            page: pages[0],
+        })
+    }
+
+    fn free_slot(&mut self, slot: &Slot) -> io::Result<()> {
+        if let Slot::Overflow { page, .. } = slot {
+            for page in self.shared.pager.overflow_pages(*page)? {
+                self.free(page);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, key: Vec<u8>, value: Value) -> io::Result<()> {
+        check_length("key", key.len(), self.max_key_length())?;
+        let parts = if self.meta.root == 0 {
+            let Some(value) = value else {
+                return Ok(());
+            };
+            let slot = self.slot(&key, value)?;
+            self.meta.entries += 1;
+            self.write_leaves(vec![(key, slot)], false)?
+        } else {
+            match self.update(self.meta.root, &key, value)? {
+                Some(parts) => parts,
+                None => return Ok(()),
+            }
+        };
+
+        self.meta.root = self.new_root(parts)?;
+        Ok(())
+    }
+
+    /// Puts or deletes `key` under `page`, returning the pages that replace
+    /// it, or `None` if nothing changed.
+    fn update(&mut self, page: u64, key: &[u8], value: Value) -> io::Result<Option<Parts>> {
+        let node = self.shared.pager.read_node(page)?;
+        match &*node {
+            Node::Leaf(entries) => {
+                let mut entries = entries.clone();
+                match (
+                    entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)),
+                    value,
+                ) {
+                    (Err(_), None) => return Ok(None),
+                    (Ok(index), None) => {
+                        let (_, slot) = entries.remove(index);
+                        self.free_slot(&slot)?;
+                        self.meta.entries -= 1;
+                    }
+                    (Ok(index), Some(value)) => {
+                        let slot = self.slot(key, value)?;
+                        let old = std::mem::replace(&mut entries[index].1, slot);
+                        self.free_slot(&old)?;
+                    }
+                    (Err(index), Some(value)) => {
+                        let slot = self.slot(key, value)?;
+                        entries.insert(index, (key.to_vec(), slot));
+                        self.meta.entries += 1;
+                    }
+                }
+
+                self.free(page);
+                self.write_leaves(entries, false).map(Some)
+            }
+            Node::Branch { keys, children } => {
+                let index = keys.partition_point(|k| k.as_slice() <= key);
+                let Some(parts) = self.update(children[index], key, value)? else {
+                    return Ok(None);
+                };
+
+                let mut keys = keys.clone();
+                let mut children = children.clone();
+                children.splice(index..=index, parts.iter().map(|(_, page)| *page));
+                if children.is_empty() {
+                    self.free(page);
+                    return Ok(Some(vec![]));
+                }
+                if parts.is_empty() {
+                    // the separator goes with the child, or with the next
+                    // one if the first child went
+                    if !keys.is_empty() {
+                        keys.remove(index.saturating_sub(1));
+                    }
+                } else {
+                    keys.splice(index..index, parts[1..].iter().map(|(key, _)| key.clone()));
+                }
+
+                self.free(page);
+                self.write_branches(keys, children).map(Some)
+            }
+        }
+    }
+
+    /// Writes entries out to as many leaves as they need, filling them
+    /// completely if `pack` is set and evenly otherwise.
+    fn write_leaves(&mut self, entries: Vec<(Vec<u8>, Slot)>, pack: bool) -> io::Result<Parts> {
+        let lengths: Vec<usize> = entries
+            .iter()
+            .map(|(key, slot)| leaf_entry_length(key, slot))
+            .collect();
+        let mut parts = vec![];
+        let mut entries = entries.into_iter();
+        for run in split(&lengths, self.page_size(), pack) {
+            let leaf: Vec<_> = entries.by_ref().take(run.len()).collect();
+            let page = self.alloc();
+            parts.push((leaf[0].0.clone(), page));
+            self.shared.pager.write_node(page, Node::Leaf(leaf))?;
+        }
+
+        Ok(parts)
+    }
+
+    /// Writes a branch out to as many pages as it needs. The first key of
+    /// every page after the first is the separator in front of its first
+    /// child.
+    fn write_branches(&mut self, keys: Vec<Vec<u8>>, children: Vec<u64>) -> io::Result<Parts> {
+        let lengths: Vec<usize> = std::iter::once(8)
+            .chain(keys.iter().map(|key| branch_entry_length(key)))
+            .collect();
+        let mut parts = vec![];
+        let mut separators = std::iter::once(vec![]).chain(keys);
+        let mut children = children.into_iter();
+        for run in split(&lengths, self.page_size(), false) {
+            let mut keys: Vec<Vec<u8>> = separators.by_ref().take(run.len()).collect();
+            let first = keys.remove(0);
+            let page = self.alloc();
+            parts.push((first, page));
+            let node = Node::Branch {
+                keys,
+                children: children.by_ref().take(run.len()).collect(),
+            };
+            self.shared.pager.write_node(page, node)?;
+        }
+
+        Ok(parts)
+    }
+
+    /// Builds branches over `parts` until a single page holds them, and
+    /// drops branches at the top with a single child.
+    fn new_root(&mut self, mut parts: Parts) -> io::Result<u64> {
+        while parts.len() > 1 {
+            let keys = parts[1..].iter().map(|(key, _)| key.clone()).collect();
+            let children = parts.iter().map(|(_, page)| *page).collect();
+            parts = self.write_branches(keys, children)?;
+        }
+
+        let Some((_, mut root)) = parts.pop() else {
+            return Ok(0);
+        };
+        while let Node::Branch { children, .. } = &*self.shared.pager.read_node(root)? {
+            if children.len() > 1 {
+                break;
+            }
+            self.free(root);
+            root = children[0];
+        }
+
+        Ok(root)
+    }
+
+    /// Drops every entry.
+    fn clear(&mut self) -> io::Result<()> {
+        if self.meta.root == 0 {
+            return Ok(());
+        }
+
+        let mut nodes = vec![];
+        let mut entries = vec![];
+        self.shared
+            .collect(self.meta.root, &mut nodes, Some(&mut entries))?;
+        for (_, slot) in &entries {
+            self.free_slot(slot)?;
+        }
+        for page in nodes {
+            self.free(page);
+        }
+        self.meta.root = 0;
+        self.meta.entries = 0;
+
+        Ok(())
+    }
+
+    /// Rewrites the tree with every node as full as it can be.
+    fn rebuild(&mut self) -> io::Result<()> {
+        if self.meta.root == 0 {
+            return Ok(());
+        }
+
+        let mut nodes = vec![];
+        let mut entries = vec![];
+        self.shared
+            .collect(self.meta.root, &mut nodes, Some(&mut entries))?;
+        for page in nodes {
+            self.free(page);
+        }
+        let parts = self.write_leaves(entries, true)?;
+        self.meta.root = self.new_root(parts)?;
+
+        Ok(())
+    }
+
+    /// Cuts free pages off the end of the file.
+    fn trim(&mut self) {
+        while self.meta.page_count > 1 && self.writer.free.remove(&(self.meta.page_count - 1)) {
+            self.meta.page_count -= 1;
+            self.trimmed.push(self.meta.page_count);
+        }
+    }
+
+    fn commit(mut self) -> io::Result<()> {
+        let result = self.write_out();
+        if result.is_err() {
+            self.abort();
+        }
+
+        result
+    }
+
+    fn write_out(&mut self) -> io::Result<()> {
+        let page_size = self.page_size();
+        for page in self.writer.free_list_pages.clone() {
+            self.free(page);
+        }
+
+        // the free list goes on pages taken from itself
+        let capacity = free_list_capacity(page_size);
+        let mut list_pages = vec![];
+        loop {
+            let pending: usize = self
+                .writer
+                .pending
+                .iter()
+                .map(|(_, pages)| pages.len())
+                .sum();
+            let count = self.writer.free.len() + pending + self.freed.len();
+            if list_pages.len() * capacity >= count {
+                break;
+            }
+            list_pages.push(self.alloc());
+        }
+
+        // once the file is reopened, nothing reads older trees
+        let mut ids: Vec<u64> = self.writer.free.iter().copied().collect();
+        ids.extend(self.writer.pending.iter().flat_map(|(_, pages)| pages));
+        ids.extend(&self.freed);
+        let mut chunks = ids.chunks(capacity);
+        for (index, page) in list_pages.iter().enumerate() {
+            let next = list_pages.get(index + 1).copied().unwrap_or(0);
+            let chunk = chunks.next().unwrap_or_default();
+            self.shared
+                .pager
+                .write_page(*page, &encode_free_list(next, chunk, page_size))?;
+        }
+        self.meta.free_list = list_pages.first().copied().unwrap_or(0);
+
+        // the new tree has to be on disk before a header names it
+        self.shared.pager.sync()?;
+        let slot = self.meta.commit % 2 * META_SLOT_LENGTH;
+        self.shared
+            .pager
+            .write_at(slot, &self.meta.encode(page_size))?;
+        self.shared.pager.sync()?;
+
+        self.writer.free_list_pages = list_pages;
+        if !self.freed.is_empty() {
+            let freed = std::mem::take(&mut self.freed);
+            self.writer.pending.push((self.meta.commit, freed));
+        }
+        self.shared.readers().meta = self.meta;
+
+        Ok(())
+    }
+
+    /// Gives back the pages this write took, leaving out any past the end
+    /// of the last commit.
+    fn abort(self) {
+        let page_count = self.shared.readers().meta.page_count;
+        let free = &mut self.writer.free;
+        free.extend(self.taken.iter().chain(&self.trimmed));
+        free.retain(|page| *page < page_count);
+    }
+}
+
+/// Splits items of the given lengths into runs that each fit on a page.
+/// Runs are filled completely if `pack` is set, and made about the same
+/// length otherwise, so both halves of a split node have room to grow.
+fn split(lengths: &[usize], page_size: usize, pack: bool) -> Vec<Range<usize>> {
+    let room = page_size - NODE_HEADER_LENGTH;
+    let total: usize = lengths.iter().sum();
+    let target = if pack {
+        room
+    } else {
+        total.div_ceil(total.div_ceil(room).max(1))
+    };
+
+    if lengths.is_empty() {
+        return vec![];
+    }
+
+    let mut runs = vec![];
+    let mut start = 0;
+    let mut length = 0;
+    for (index, item) in lengths.iter().enumerate() {
+        if length > 0 && (length >= target || length + item > room) {
+            runs.push(start..index);
+            start = index;
+            length = 0;
+        }
+        length += item;
+    }
+    runs.push(start..lengths.len());
+
+    runs
+}
+
+impl BTreeEngine {
+    /// Opens the database file at `path` for reading and writing, creating
+    /// it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>, options: BTreeOptions) -> io::Result<Self> {
+        Self::open_with(path.as_ref(), options, false)
+    }
+
+    /// Opens an existing database file for reading only. Any number of
+    /// processes can do this at once, but not while it is open for writing.
+    pub fn open_read_only(path: impl AsRef<Path>, options: BTreeOptions) -> io::Result<Self> {
+        Self::open_with(path.as_ref(), options, true)
+    }
+
+    fn open_with(path: &Path, options: BTreeOptions, read_only: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(!read_only)
+            .truncate(false)
+            .write(!read_only)
+            .read(true)
+            .open(path)?;
+        try_lock(&file, read_only)?;
+
+        let mut file = PositionedFile::new(file);
+        if file.metadata()?.len() == 0 && !read_only {
+            let page_size = options.page_size.clamp(MIN_PAGE_SIZE, MAX_PAGE_SIZE);
+            let meta = Meta {
+                commit: 0,
+                root: 0,
+                page_count: 1,
+                free_list: 0,
+                entries: 0,
+            };
+            let mut page = vec![0u8; page_size];
+            page[..META_LENGTH].copy_from_slice(&meta.encode(page_size));
+            std::io::Write::write_all(&mut file, &page)?;
+            file.sync_all()?;
+        }
+
+        let mut header = [0u8; META_SLOT_LENGTH as usize + META_LENGTH];
+        file.reader()
+            .read_exact(&mut header)
+            .map_err(|_| invalid_data("the file is too short to be a b+tree file"))?;
+        let (meta, page_size) = [0, META_SLOT_LENGTH as usize]
+            .into_iter()
+            .filter_map(|offset| Meta::decode(&header[offset..offset + META_LENGTH]))
+            .max_by_key(|(meta, _)| meta.commit)
+            .ok_or_else(|| {
+                invalid_data("the file isn't a b+tree file, or both its headers are damaged")
+            })?;
+        if !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+            return Err(invalid_data(format!(
+                "page size {} is unsupported",
+                page_size
+            )));
+        }
+
+        let pager = Pager::new(file, page_size, options.cache_pages);
+        if !read_only && pager.file_size()? > meta.page_count * page_size as u64 {
+            // pages past the end were written by a write that didn't commit
+            pager.truncate(meta.page_count)?;
+        }
+
+        let mut free = BTreeSet::new();
+        let mut free_list_pages = vec![];
+        let mut page = meta.free_list;
+        while page != 0 {
+            free_list_pages.push(page);
+            let (next, ids) = decode_free_list(page, &pager.read_page(page)?)?;
+            free.extend(ids);
+            page = next;
+        }
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                pager,
+                readers: Mutex::new(Readers {
+                    meta,
+                    pins: BTreeMap::new(),
+                }),
+                writer: Mutex::new(Writer {
+                    free,
+                    pending: vec![],
+                    free_list_pages,
+                }),
+            }),
+            namespace: DEFAULT_NAMESPACE_ID,
+            read_only,
+            snapshot: None,
+        })
+    }
+
+    /// The size of every page in the file.
+    pub fn page_size(&self) -> usize {
+        self.shared.pager.page_size
+    }
+
+    /// A read-only engine reading what this one does.
+    fn view(&self) -> BTreeEngine {
+        BTreeEngine {
+            shared: Arc::clone(&self.shared),
+            namespace: self.namespace,
+            read_only: true,
+            snapshot: self.snapshot.clone(),
+        }
+    }
+
+    fn pin(&self) -> Pin {
+        match &self.snapshot {
+            Some(pin) => pin.clone(),
+            None => self.shared.pin(),
+        }
+    }
+
+    /// Runs a write, committing it if `f` succeeds.
+    fn transaction(&self, f: impl FnOnce(&mut Txn) -> io::Result<()>) -> io::Result<()> {
+        self.check_writable()?;
+        let mut writer = self.shared.writer();
+        let mut txn = Txn::begin(&self.shared, &mut writer);
+        match f(&mut txn) {
+            Ok(()) => txn.commit(),
+            Err(err) => {
+                txn.abort();
+                Err(err)
+            }
+        }
+    }
+}
+
+impl SortedStore for BTreeEngine {
+    fn lookup(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let pin = self.pin();
+        self.shared.get(pin.root, key)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8], f: &mut Visit) -> io::Result<()> {
+        let pin = self.pin();
+        if pin.root != 0 {
+            self.shared.walk(pin.root, prefix, f)?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, batch: Batch) -> io::Result<()> {
+        if batch.is_empty() {
+            return self.check_writable();
+        }
+
+        self.transaction(|txn| {
+            batch
+                .into_iter()
+                .try_for_each(|(key, value)| txn.apply(key, value))
+        })
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::ReadOnlyFilesystem,
+                "the file is open read-only",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn namespace(&self) -> u16 {
+        self.namespace
+    }
+
+    fn set_namespace(&mut self, id: u16) {
+        self.namespace = id;
+    }
+}
+
+impl StorageEngine for BTreeEngine {
+    fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        sorted::get(self, key)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        sorted::put(self, key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> io::Result<()> {
+        sorted::delete(self, key)
+    }
+
+    fn scan(&mut self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        sorted::scan(self, prefix)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transaction(|txn| txn.clear())?;
+        self.namespace = DEFAULT_NAMESPACE_ID;
+
+        Ok(())
+    }
+
+    fn zadd(&mut self, key: &str, member: &str, score: f64) -> io::Result<()> {
+        sorted::zadd(self, key, member, score)
+    }
+
+    fn zrem(&mut self, key: &str, member: &str) -> io::Result<()> {
+        sorted::zrem(self, key, member)
+    }
+
+    fn zscore(&mut self, key: &str, member: &str) -> io::Result<Option<f64>> {
+        sorted::zscore(self, key, member)
+    }
+
+    fn zrange(&mut self, key: &str, min: f64, max: f64) -> io::Result<Vec<(String, f64)>> {
+        sorted::zrange(self, key, min, max)
+    }
+
+    fn zrank(&mut self, key: &str, member: &str) -> io::Result<Option<u64>> {
+        sorted::zrank(self, key, member)
+    }
+
+    fn sadd(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        sorted::sadd(self, key, member)
+    }
+
+    fn srem(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        sorted::srem(self, key, member)
+    }
+
+    fn sismember(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        sorted::sismember(self, key, member)
+    }
+
+    fn smembers(&mut self, key: &str) -> io::Result<Vec<String>> {
+        sorted::smembers(self, key)
+    }
+
+    fn scard(&mut self, key: &str) -> io::Result<u64> {
+        sorted::scard(self, key)
+    }
+
+    fn create_namespace(&mut self, name: &str) -> io::Result<Option<u16>> {
+        sorted::create_namespace(self, name)
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> io::Result<bool> {
+        sorted::drop_namespace(self, name)
+    }
+
+    fn namespaces(&mut self) -> io::Result<Vec<(u16, String)>> {
+        sorted::namespaces(self)
+    }
+
+    fn namespace_stats(&mut self) -> io::Result<Vec<NamespaceStats>> {
+        sorted::namespace_stats(self)
+    }
+
+    fn use_namespace(&mut self, id: u16) {
+        self.namespace = id;
+    }
+
+    fn current_namespace(&self) -> u16 {
+        self.namespace
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        sorted::truncate(self)
+    }
+
+    /// Free pages and the unused ends of pages count as dead.
+    fn stats(&mut self) -> io::Result<StorageStats> {
+        sorted::stats(self, self.shared.pager.file_size()?, VERSION)
+    }
+
+    fn size(&mut self) -> io::Result<(u64, u64)> {
+        let entries = match &self.snapshot {
+            Some(_) => {
+                let mut entries = 0;
+                self.scan_prefix(&[], &mut |_, _| {
+                    entries += 1;
+                    Ok(())
+                })?;
+                entries
+            }
+            None => self.shared.readers().meta.entries,
+        };
+
+        Ok((entries, self.shared.pager.file_size()?))
+    }
+
+    fn for_each_entry(&mut self, f: &mut dyn FnMut(Entry) -> io::Result<()>) -> io::Result<()> {
+        sorted::for_each_entry(self, f)
+    }
+
+    fn append_entries(&mut self, entries: &[Entry]) -> io::Result<()> {
+        sorted::append_entries(self, entries)
+    }
+
+    fn reader(&self) -> Box<dyn StorageEngine> {
+        Box::new(self.view())
+    }
+
+    fn snapshot_reader(&self) -> Box<dyn StorageEngine> {
+        Box::new(BTreeEngine {
+            snapshot: Some(self.pin()),
+            ..self.view()
+        })
+    }
+
+    /// Rewrites the tree with every node full, then cuts the free pages at
+    /// the end off the file. Pages a snapshot is still reading can't be
+    /// cut off until it is dropped.
+    fn compact(&mut self) -> io::Result<u64> {
+        let before = self.shared.pager.file_size()?;
+        self.transaction(|txn| txn.rebuild())?;
+        // a second commit, now nothing reads the old tree's pages
+        self.transaction(|txn| {
+            txn.trim();
+            Ok(())
+        })?;
+        let page_count = self.shared.readers().meta.page_count;
+        self.shared.pager.truncate(page_count)?;
+
+        Ok(before.saturating_sub(self.shared.pager.file_size()?))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
@@ -0,0 +1,261 @@
+// the pages a b+tree file is made of, and how they're laid out
+//
+// every page starts with a CRC32 of the rest of the page and its kind.
+// leaves hold entries in key order, each with its value inline or, if it
+// is too big to share a page, the length of the value and the first of a
+// chain of overflow pages holding it. branches hold the page of their first
+// child, then a separator key and a page for every child after it. every
+// key in a child is at least the separator in front of it
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use std::io;
+
+use crate::invalid_data;
+
+const LEAF: u8 = 1;
+const BRANCH: u8 = 2;
+const OVERFLOW: u8 = 3;
+const FREE_LIST: u8 = 4;
+
+const INLINE: u8 = 0;
+const OVERFLOWED: u8 = 1;
+
+// CRC32 and kind
+const PAGE_HEADER_LENGTH: usize = 4 + 1;
+// entry or child count
+pub(super) const NODE_HEADER_LENGTH: usize = PAGE_HEADER_LENGTH + 2;
+// next page and data length
+const OVERFLOW_HEADER_LENGTH: usize = PAGE_HEADER_LENGTH + 8 + 4;
+// next page and id count
+const FREE_LIST_HEADER_LENGTH: usize = PAGE_HEADER_LENGTH + 8 + 4;
+
+/// Where a value is kept.
+#[derive(Clone)]
+pub(super) enum Slot {
+    Inline(Vec<u8>),
+    /// In a chain of overflow pages starting at `page`.
+    Overflow {
+        length: u32,
+        page: u64,
+    },
+}
+
+#[derive(Clone)]
+pub(super) enum Node {
+    Leaf(Vec<(Vec<u8>, Slot)>),
+    /// `children` has one more page than `keys` has separators.
+    Branch {
+        keys: Vec<Vec<u8>>,
+        children: Vec<u64>,
+    },
+}
+
+/// Bytes a leaf entry takes up.
+pub(super) fn leaf_entry_length(key: &[u8], slot: &Slot) -> usize {
+    match slot {
+        Slot::Inline(value) => inline_entry_length(key, value),
+        Slot::Overflow { .. } => overflow_entry_length(key),
+    }
+}
+
+pub(super) fn inline_entry_length(key: &[u8], value: &[u8]) -> usize {
+    2 + key.len() + 1 + 4 + value.len()
+}
+
+pub(super) fn overflow_entry_length(key: &[u8]) -> usize {
+    2 + key.len() + 1 + 4 + 8
+}
+
+/// Bytes a branch's separator and the child after it take up.
+pub(super) fn branch_entry_length(key: &[u8]) -> usize {
+    2 + key.len() + 8
+}
+
+impl Node {
+    pub(super) fn encode(&self, page_size: usize) -> Vec<u8> {
+        let mut body = BytesMut::with_capacity(page_size);
+        match self {
+            Node::Leaf(entries) => {
+                body.put_u8(LEAF);
+                body.put_u16(entries.len() as u16);
+                for (key, slot) in entries {
+                    body.put_u16(key.len() as u16);
+                    body.extend_from_slice(key);
+                    match slot {
+                        Slot::Inline(value) => {
+                            body.put_u8(INLINE);
+                            body.put_u32(value.len() as u32);
+                            body.extend_from_slice(value);
+                        }
+                        Slot::Overflow { length, page } => {
+                            body.put_u8(OVERFLOWED);
+                            body.put_u32(*length);
+                            body.put_u64(*page);
+                        }
+                    }
+                }
+            }
+            Node::Branch { keys, children } => {
+                body.put_u8(BRANCH);
+                body.put_u16(keys.len() as u16);
+                body.put_u64(children[0]);
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    body.put_u16(key.len() as u16);
+                    body.extend_from_slice(key);
+                    body.put_u64(*child);
+                }
+            }
+        }
+
+        seal(body, page_size)
+    }
+
+    pub(super) fn decode(id: u64, page: &[u8]) -> io::Result<Self> {
+        let mut body = open(id, page)?;
+        let malformed = || invalid_data(format!("page {} is malformed", id));
+        let kind = take(&mut body, 1).ok_or_else(malformed)?[0];
+        let count = BigEndian::read_u16(take(&mut body, 2).ok_or_else(malformed)?) as usize;
+
+        match kind {
+            LEAF => {
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let entry = (|| {
+                        let key_length = BigEndian::read_u16(take(&mut body, 2)?) as usize;
+                        let key = take(&mut body, key_length)?.to_vec();
+                        let flag = take(&mut body, 1)?[0];
+                        let length = BigEndian::read_u32(take(&mut body, 4)?);
+                        let slot = match flag {
+                            INLINE => Slot::Inline(take(&mut body, length as usize)?.to_vec()),
+                            OVERFLOWED => Slot::Overflow {
+                                length,
+                                page: BigEndian::read_u64(take(&mut body, 8)?),
+                            },
+                            _ => return None,
+                        };
+                        Some((key, slot))
+                    })();
+                    entries.push(entry.ok_or_else(malformed)?);
+                }
+
+                Ok(Node::Leaf(entries))
+            }
+            BRANCH => {
+                let mut keys = Vec::with_capacity(count);
+                let mut children = Vec::with_capacity(count + 1);
+                children.push(BigEndian::read_u64(
+                    take(&mut body, 8).ok_or_else(malformed)?,
+                ));
+                for _ in 0..count {
+                    let entry = (|| {
+                        let key_length = BigEndian::read_u16(take(&mut body, 2)?) as usize;
+                        let key = take(&mut body, key_length)?.to_vec();
+                        let child = BigEndian::read_u64(take(&mut body, 8)?);
+                        Some((key, child))
+                    })();
+                    let (key, child) = entry.ok_or_else(malformed)?;
+                    keys.push(key);
+                    children.push(child);
+                }
+
+                Ok(Node::Branch { keys, children })
+            }
+            _ => Err(invalid_data(format!(
+                "page {} is not a tree node, it has kind {}",
+                id, kind
+            ))),
+        }
+    }
+}
+
+/// How many bytes of a value fit in each overflow page.
+pub(super) fn overflow_capacity(page_size: usize) -> usize {
+    page_size - OVERFLOW_HEADER_LENGTH
+}
+
+pub(super) fn encode_overflow(next: u64, data: &[u8], page_size: usize) -> Vec<u8> {
+    let mut body = BytesMut::with_capacity(page_size);
+    body.put_u8(OVERFLOW);
+    body.put_u64(next);
+    body.put_u32(data.len() as u32);
+    body.extend_from_slice(data);
+
+    seal(body, page_size)
+}
+
+/// The next page in the chain, and the part of the value on this one.
+pub(super) fn decode_overflow(id: u64, page: &[u8]) -> io::Result<(u64, &[u8])> {
+    let mut body = open(id, page)?;
+    let fields = (|| {
+        if take(&mut body, 1)?[0] != OVERFLOW {
+            return None;
+        }
+        let next = BigEndian::read_u64(take(&mut body, 8)?);
+        let length = BigEndian::read_u32(take(&mut body, 4)?) as usize;
+        Some((next, take(&mut body, length)?))
+    })();
+
+    fields.ok_or_else(|| invalid_data(format!("overflow page {} is malformed", id)))
+}
+
+/// How many page ids fit in each free list page.
+pub(super) fn free_list_capacity(page_size: usize) -> usize {
+    (page_size - FREE_LIST_HEADER_LENGTH) / 8
+}
+
+pub(super) fn encode_free_list(next: u64, ids: &[u64], page_size: usize) -> Vec<u8> {
+    let mut body = BytesMut::with_capacity(page_size);
+    body.put_u8(FREE_LIST);
+    body.put_u64(next);
+    body.put_u32(ids.len() as u32);
+    for id in ids {
+        body.put_u64(*id);
+    }
+
+    seal(body, page_size)
+}
+
+/// The next page in the list, and the free pages this one names.
+pub(super) fn decode_free_list(id: u64, page: &[u8]) -> io::Result<(u64, Vec<u64>)> {
+    let mut body = open(id, page)?;
+    let fields = (|| {
+        if take(&mut body, 1)?[0] != FREE_LIST {
+            return None;
+        }
+        let next = BigEndian::read_u64(take(&mut body, 8)?);
+        let count = BigEndian::read_u32(take(&mut body, 4)?) as usize;
+        let ids = take(&mut body, count * 8)?
+            .chunks(8)
+            .map(BigEndian::read_u64)
+            .collect();
+        Some((next, ids))
+    })();
+
+    fields.ok_or_else(|| invalid_data(format!("free list page {} is malformed", id)))
+}
+
+/// Pads a page body out to a full page, with its checksum in front.
+fn seal(body: BytesMut, page_size: usize) -> Vec<u8> {
+    let mut page = vec![0u8; page_size];
+    page[4..4 + body.len()].copy_from_slice(&body);
+    let crc = crc32fast::hash(&page[4..]);
+    BigEndian::write_u32(&mut page[..4], crc);
+
+    page
+}
+
+/// Checks a page's checksum and returns what follows it.
+fn open(id: u64, page: &[u8]) -> io::Result<&[u8]> {
+    if page.len() < PAGE_HEADER_LENGTH || crc32fast::hash(&page[4..]) != BigEndian::read_u32(page) {
+        return Err(invalid_data(format!("page {} has a bad checksum", id)));
+    }
+
+    Ok(&page[4..])
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    let field = bytes.get(..length)?;
+    *bytes = &bytes[length..];
+    Some(field)
+}
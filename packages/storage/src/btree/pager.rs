@@ -0,0 +1,166 @@
+// reads and writes whole pages, keeping recently used tree nodes decoded in
+// a buffer pool
+//
+// pages are never changed while a tree that uses them can still be read,
+// so cached nodes stay valid until their page is freed and written again,
+// which replaces them in the pool. the pool evicts the least recently used
+// node once it holds `capacity` of them
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::file::PositionedFile;
+
+use super::node::{decode_overflow, encode_overflow, overflow_capacity, Node};
+
+pub(super) struct Pager {
+    file: PositionedFile,
+    pub(super) page_size: usize,
+    pool: Mutex<Pool>,
+}
+
+struct Pool {
+    capacity: usize,
+    /// Bumped on every use, so the smallest tick is the least recently used.
+    tick: u64,
+    nodes: HashMap<u64, (Arc<Node>, u64)>,
+    by_tick: BTreeMap<u64, u64>,
+}
+
+impl Pool {
+    fn get(&mut self, id: u64) -> Option<Arc<Node>> {
+        let tick = self.tick;
+        let (node, used) = self.nodes.get_mut(&id)?;
+        self.by_tick.remove(used);
+        self.by_tick.insert(tick, id);
+        *used = tick;
+        self.tick += 1;
+
+        Some(Arc::clone(node))
+    }
+
+    fn insert(&mut self, id: u64, node: Arc<Node>) {
+        if let Some((_, used)) = self.nodes.insert(id, (node, self.tick)) {
+            self.by_tick.remove(&used);
+        }
+        self.by_tick.insert(self.tick, id);
+        self.tick += 1;
+
+        while self.nodes.len() > self.capacity {
+            let Some((_, oldest)) = self.by_tick.pop_first() else {
+                break;
+            };
+            self.nodes.remove(&oldest);
+        }
+    }
+}
+
+impl Pager {
+    pub(super) fn new(file: PositionedFile, page_size: usize, capacity: usize) -> Self {
+        Self {
+            file,
+            page_size,
+            pool: Mutex::new(Pool {
+                capacity: capacity.max(1),
+                tick: 0,
+                nodes: HashMap::new(),
+                by_tick: BTreeMap::new(),
+            }),
+        }
+    }
+
+    fn pool(&self) -> std::sync::MutexGuard<'_, Pool> {
+        self.pool.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(super) fn read_at(&self, offset: u64, bytes: &mut [u8]) -> io::Result<()> {
+        let mut file = self.file.reader();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(bytes)
+    }
+
+    pub(super) fn write_at(&self, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        let mut file = self.file.reader();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(bytes)
+    }
+
+    pub(super) fn read_page(&self, id: u64) -> io::Result<Vec<u8>> {
+        let mut page = vec![0u8; self.page_size];
+        self.read_at(id * self.page_size as u64, &mut page)?;
+
+        Ok(page)
+    }
+
+    pub(super) fn write_page(&self, id: u64, page: &[u8]) -> io::Result<()> {
+        self.write_at(id * self.page_size as u64, page)
+    }
+
+    pub(super) fn read_node(&self, id: u64) -> io::Result<Arc<Node>> {
+        if let Some(node) = self.pool().get(id) {
+            return Ok(node);
+        }
+
+        let node = Arc::new(Node::decode(id, &self.read_page(id)?)?);
+        self.pool().insert(id, Arc::clone(&node));
+
+        Ok(node)
+    }
+
+    pub(super) fn write_node(&self, id: u64, node: Node) -> io::Result<()> {
+        self.write_page(id, &node.encode(self.page_size))?;
+        self.pool().insert(id, Arc::new(node));
+
+        Ok(())
+    }
+
+    /// Reads a value kept in a chain of overflow pages.
+    pub(super) fn read_overflow(&self, mut page: u64, length: u32) -> io::Result<Vec<u8>> {
+        let mut value = Vec::with_capacity(length as usize);
+        while value.len() < length as usize {
+            let bytes = self.read_page(page)?;
+            let (next, data) = decode_overflow(page, &bytes)?;
+            value.extend_from_slice(data);
+            page = next;
+        }
+
+        Ok(value)
+    }
+
+    /// Writes a value out to the given overflow pages, one after another.
+    pub(super) fn write_overflow(&self, pages: &[u64], value: &[u8]) -> io::Result<()> {
+        let chunks = value.chunks(overflow_capacity(self.page_size));
+        for (index, (page, data)) in pages.iter().zip(chunks).enumerate() {
+            let next = pages.get(index + 1).copied().unwrap_or(0);
+            self.write_page(*page, &encode_overflow(next, data, self.page_size))?;
+        }
+
+        Ok(())
+    }
+
+    /// The pages of a chain of overflow pages.
+    pub(super) fn overflow_pages(&self, mut page: u64) -> io::Result<Vec<u64>> {
+        let mut pages = vec![];
+        while page != 0 {
+            pages.push(page);
+            page = decode_overflow(page, &self.read_page(page)?)?.0;
+        }
+
+        Ok(pages)
+    }
+
+    pub(super) fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    pub(super) fn file_size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    pub(super) fn truncate(&self, pages: u64) -> io::Result<()> {
+        self.file.set_len(pages * self.page_size as u64)
+    }
+}
@@ -0,0 +1,530 @@
+// the change log: an append-only record of every change made to a file,
+// kept next to it in `<file>.changes`
+//
+// the log starts at a base sequence number, the point the data file was at
+// when the log was created. every change after that gets the next sequence
+// number, so a copy of the data file at sequence `n` plus the changes after
+// `n` gives back the file as it is now. changes are logical (a key was set,
+// a member removed) rather than byte ranges, so they can be replayed onto
+// any copy of the file
+
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{BufReader, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+
+use super::{check_length, invalid_data, Entry};
+
+const MAGIC_BYTES: [u8; 6] = [0, 107, 105, 118, 108, 103];
+const VERSION: u16 = 1;
+// magic, version and base sequence number
+const HEADER_LENGTH: u64 = 6 + 2 + 8;
+// sequence number, timestamp, payload length and a CRC32 of everything else
+const RECORD_HEADER_LENGTH: usize = 8 + 8 + 4 + 4;
+
+const PUT_DATA: u8 = 0;
+const PUT_SORTED_SET_MEMBER: u8 = 1;
+const PUT_SET_MEMBER: u8 = 2;
+const PUT_NAMESPACE: u8 = 3;
+const DELETE_DATA: u8 = 4;
+const DELETE_SORTED_SET_MEMBER: u8 = 5;
+const DELETE_SET_MEMBER: u8 = 6;
+const DROP_NAMESPACE: u8 = 7;
+const TRUNCATE: u8 = 8;
+const FLUSH: u8 = 9;
+
+/// A single change to a file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// An entry was written, replacing any entry with the same key (and
+    /// member, for sets) in the same namespace.
+    Put(Entry),
+    DeleteData {
+        namespace: u16,
+        key: Vec<u8>,
+    },
+    DeleteSortedSetMember {
+        namespace: u16,
+        key: String,
+        member: String,
+    },
+    DeleteSetMember {
+        namespace: u16,
+        key: String,
+        member: String,
+    },
+    DropNamespace {
+        id: u16,
+    },
+    Truncate {
+        namespace: u16,
+    },
+    Flush,
+}
+
+/// A change along with its place in the log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeRecord {
+    pub sequence: u64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub change: Change,
+}
+
+/// Where the change log for the file at `path` is kept.
+pub fn changes_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut changes_path = OsString::from(path.as_ref().as_os_str());
+    changes_path.push(".changes");
+    PathBuf::from(changes_path)
+}
+
+/// A change log that is being appended to.
+pub struct ChangeLog {
+    file: File,
+    base: u64,
+    last: u64,
+}
+
+impl ChangeLog {
+    /// Creates an empty log starting at `base`, replacing any log already
+    /// at `path`.
+    pub fn create(path: impl AsRef<Path>, base: u64) -> std::io::Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(&header(base))?;
+        file.sync_all()?;
+
+        Ok(Self {
+            file,
+            base,
+            last: base,
+        })
+    }
+
+    /// Opens an existing log for appending. A record cut short by a crash is
+    /// removed; any other damage is an error.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut reader = ChangeReader::new(file.try_clone()?)?;
+        let base = reader.base();
+        let mut last = base;
+        for record in &mut reader {
+            last = record?.sequence;
+        }
+
+        let end = reader.position;
+        if end < file.metadata()?.len() {
+            file.set_len(end)?;
+        }
+
+        Ok(Self { file, base, last })
+    }
+
+    /// The sequence number the log starts after.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// The sequence number of the latest change, or the base if there are
+    /// none.
+    pub fn last_sequence(&self) -> u64 {
+        self.last
+    }
+
+    /// Records a change, giving it the next sequence number.
+    pub fn append(&mut self, change: Change) -> std::io::Result<u64> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+
+        self.append_record(&ChangeRecord {
+            sequence: self.last + 1,
+            timestamp,
+            change,
+        })?;
+
+        Ok(self.last)
+    }
+
+    /// Records a change that already has a sequence number, e.g. one being
+    /// replayed from another log.
+    pub fn append_record(&mut self, record: &ChangeRecord) -> std::io::Result<()> {
+        if record.sequence <= self.last {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "change {} is not after the last change {}",
+                    record.sequence, self.last
+                ),
+            ));
+        }
+
+        let bytes = encode_record(record)?;
+        self.file.seek(std::io::SeekFrom::End(0))?;
+        self.file.write_all(&bytes)?;
+        self.last = record.sequence;
+
+        Ok(())
+    }
+}
+
+/// Reads the records in a log, oldest first. It never writes to the file,
+/// so it is safe to use on a log that is still being appended to: a record
+/// that is only partly written is treated as the end of the log.
+pub struct ChangeReader {
+    reader: BufReader<File>,
+    base: u64,
+    position: u64,
+    length: u64,
+}
+
+impl ChangeReader {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+
+    fn new(mut file: File) -> std::io::Result<Self> {
+        let length = file.metadata()?.len();
+        let mut header = [0u8; HEADER_LENGTH as usize];
+        file.seek(std::io::SeekFrom::Start(0))?;
+        file.read_exact(&mut header)
+            .map_err(|_| invalid_data("not a kiv change log"))?;
+        if header[..6] != MAGIC_BYTES {
+            return Err(invalid_data("not a kiv change log"));
+        }
+
+        let version = BigEndian::read_u16(&header[6..8]);
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported change log version {}",
+                version
+            )));
+        }
+
+        Ok(Self {
+            reader: BufReader::new(file),
+            base: BigEndian::read_u64(&header[8..]),
+            position: HEADER_LENGTH,
+            length,
+        })
+    }
+
+    /// The sequence number the log starts after.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    fn read_record(&mut self) -> std::io::Result<Option<ChangeRecord>> {
+        let remaining = self.length - self.position;
+        if remaining < RECORD_HEADER_LENGTH as u64 {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; RECORD_HEADER_LENGTH];
+        self.reader.read_exact(&mut header)?;
+        let payload_length = BigEndian::read_u32(&header[16..20]) as u64;
+        if remaining - (RECORD_HEADER_LENGTH as u64) < payload_length {
+            return Ok(None);
+        }
+
+        let mut payload = vec![0u8; payload_length as usize];
+        self.reader.read_exact(&mut payload)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[..20]);
+        hasher.update(&payload);
+        if hasher.finalize() != BigEndian::read_u32(&header[20..24]) {
+            return Err(invalid_data(format!(
+                "change log record at offset {} is damaged",
+                self.position
+            )));
+        }
+
+        let record = ChangeRecord {
+            sequence: BigEndian::read_u64(&header[..8]),
+            timestamp: BigEndian::read_u64(&header[8..16]),
+            change: decode_change(&payload).map_err(|reason| {
+                invalid_data(format!(
+                    "change log record at offset {}: {}",
+                    self.position, reason
+                ))
+            })?,
+        };
+        self.position += RECORD_HEADER_LENGTH as u64 + payload_length;
+
+        Ok(Some(record))
+    }
+}
+
+impl Iterator for ChangeReader {
+    type Item = std::io::Result<ChangeRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.read_record();
+        if !matches!(record, Ok(Some(_))) {
+            // don't read past damage or a record that is still being written
+            self.length = self.position;
+        }
+
+        record.transpose()
+    }
+}
+
+/// Copies the changes after `since` from the log at `path` into a new log at
+/// `output`, which starts at `since`. Returns how many changes were copied.
+pub fn export(
+    path: impl AsRef<Path>,
+    since: u64,
+    output: impl AsRef<Path>,
+) -> std::io::Result<u64> {
+    let reader = ChangeReader::open(path)?;
+    if since < reader.base() {
+        return Err(invalid_data(format!(
+            "the change log starts at {}, changes since {} are gone",
+            reader.base(),
+            since
+        )));
+    }
+
+    let mut out = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)?;
+    out.write_all(&header(since))?;
+
+    let mut last = reader.base();
+    let mut copied = 0;
+    for record in reader {
+        let record = record?;
+        last = record.sequence;
+        if record.sequence > since {
+            out.write_all(&encode_record(&record)?)?;
+            copied += 1;
+        }
+    }
+
+    if since > last {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("the latest change is {}, not {}", last, since),
+        ));
+    }
+
+    out.sync_all()?;
+
+    Ok(copied)
+}
+
+fn header(base: u64) -> BytesMut {
+    let mut bytes = BytesMut::new();
+    bytes.extend_from_slice(&MAGIC_BYTES);
+    bytes.put_u16(VERSION);
+    bytes.put_u64(base);
+    bytes
+}
+
+fn encode_record(record: &ChangeRecord) -> std::io::Result<BytesMut> {
+    let payload = encode_change(&record.change)?;
+    check_length("change", payload.len(), u32::MAX as usize)?;
+
+    let mut bytes = BytesMut::new();
+    bytes.put_u64(record.sequence);
+    bytes.put_u64(record.timestamp);
+    bytes.put_u32(payload.len() as u32);
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&bytes);
+    hasher.update(&payload);
+    bytes.put_u32(hasher.finalize());
+    bytes.put(payload);
+
+    Ok(bytes)
+}
+
+fn put_short(bytes: &mut BytesMut, field: &str, value: &[u8]) -> std::io::Result<()> {
+    check_length(field, value.len(), u16::MAX as usize)?;
+    bytes.put_u16(value.len() as u16);
+    bytes.put(value);
+    Ok(())
+}
+
+fn encode_change(change: &Change) -> std::io::Result<BytesMut> {
+    let mut bytes = BytesMut::new();
+
+    match change {
+        Change::Put(Entry::Data {
+            namespace,
+            key,
+            value,
+        }) => {
+            bytes.put_u8(PUT_DATA);
+            bytes.put_u16(*namespace);
+            put_short(&mut bytes, "key", key)?;
+            check_length("value", value.len(), u32::MAX as usize)?;
+            bytes.put_u32(value.len() as u32);
+            bytes.put(&value[..]);
+        }
+        Change::Put(Entry::SortedSetMember {
+            namespace,
+            key,
+            member,
+            score,
+        }) => {
+            bytes.put_u8(PUT_SORTED_SET_MEMBER);
+            bytes.put_u16(*namespace);
+            put_short(&mut bytes, "key", key.as_bytes())?;
+            put_short(&mut bytes, "member", member.as_bytes())?;
+            bytes.put_f64(*score);
+        }
+        Change::Put(Entry::SetMember {
+            namespace,
+            key,
+            member,
+        }) => {
+            bytes.put_u8(PUT_SET_MEMBER);
+            bytes.put_u16(*namespace);
+            put_short(&mut bytes, "key", key.as_bytes())?;
+            put_short(&mut bytes, "member", member.as_bytes())?;
+        }
+        Change::Put(Entry::Namespace { id, name }) => {
+            bytes.put_u8(PUT_NAMESPACE);
+            bytes.put_u16(*id);
+            put_short(&mut bytes, "namespace name", name.as_bytes())?;
+        }
+        Change::DeleteData { namespace, key } => {
+            bytes.put_u8(DELETE_DATA);
+            bytes.put_u16(*namespace);
+            put_short(&mut bytes, "key", key)?;
+        }
+        Change::DeleteSortedSetMember {
+            namespace,
+            key,
+            member,
+        } => {
+            bytes.put_u8(DELETE_SORTED_SET_MEMBER);
+            bytes.put_u16(*namespace);
+            put_short(&mut bytes, "key", key.as_bytes())?;
+            put_short(&mut bytes, "member", member.as_bytes())?;
+        }
+        Change::DeleteSetMember {
+            namespace,
+            key,
+            member,
+        } => {
+            bytes.put_u8(DELETE_SET_MEMBER);
+            bytes.put_u16(*namespace);
+            put_short(&mut bytes, "key", key.as_bytes())?;
+            put_short(&mut bytes, "member", member.as_bytes())?;
+        }
+        Change::DropNamespace { id } => {
+            bytes.put_u8(DROP_NAMESPACE);
+            bytes.put_u16(*id);
+        }
+        Change::Truncate { namespace } => {
+            bytes.put_u8(TRUNCATE);
+            bytes.put_u16(*namespace);
+        }
+        Change::Flush => bytes.put_u8(FLUSH),
+    }
+
+    Ok(bytes)
+}
+
+/// Reads fields out of a change's payload.
+struct Fields<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("change is cut short")?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(BigEndian::read_u16(self.take(2)?))
+    }
+
+    fn short_bytes(&mut self) -> Result<Vec<u8>, String> {
+        let length = self.u16()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    fn short_string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.short_bytes()?).map_err(|_| String::from("invalid UTF-8"))
+    }
+}
+
+fn decode_change(payload: &[u8]) -> Result<Change, String> {
+    let mut fields = Fields {
+        bytes: payload,
+        position: 0,
+    };
+
+    let kind = fields.take(1)?[0];
+    let change = match kind {
+        PUT_DATA => {
+            let namespace = fields.u16()?;
+            let key = fields.short_bytes()?;
+            let value_length = BigEndian::read_u32(fields.take(4)?) as usize;
+            Change::Put(Entry::Data {
+                namespace,
+                key,
+                value: fields.take(value_length)?.to_vec(),
+            })
+        }
+        PUT_SORTED_SET_MEMBER => Change::Put(Entry::SortedSetMember {
+            namespace: fields.u16()?,
+            key: fields.short_string()?,
+            member: fields.short_string()?,
+            score: BigEndian::read_f64(fields.take(8)?),
+        }),
+        PUT_SET_MEMBER => Change::Put(Entry::SetMember {
+            namespace: fields.u16()?,
+            key: fields.short_string()?,
+            member: fields.short_string()?,
+        }),
+        PUT_NAMESPACE => Change::Put(Entry::Namespace {
+            id: fields.u16()?,
+            name: fields.short_string()?,
+        }),
+        DELETE_DATA => Change::DeleteData {
+            namespace: fields.u16()?,
+            key: fields.short_bytes()?,
+        },
+        DELETE_SORTED_SET_MEMBER => Change::DeleteSortedSetMember {
+            namespace: fields.u16()?,
+            key: fields.short_string()?,
+            member: fields.short_string()?,
+        },
+        DELETE_SET_MEMBER => Change::DeleteSetMember {
+            namespace: fields.u16()?,
+            key: fields.short_string()?,
+            member: fields.short_string()?,
+        },
+        DROP_NAMESPACE => Change::DropNamespace { id: fields.u16()? },
+        TRUNCATE => Change::Truncate {
+            namespace: fields.u16()?,
+        },
+        FLUSH => Change::Flush,
+        _ => return Err(format!("unknown change type {}", kind)),
+    };
+
+    if fields.position != payload.len() {
+        return Err(String::from("change has trailing bytes"));
+    }
+
+    Ok(change)
+}
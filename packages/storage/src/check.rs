@@ -0,0 +1,468 @@
+// offline checking and salvaging of kiv files
+//
+// unlike `Storage`, nothing here trusts the file: every entry is bounds
+// checked and, from version 4 on, checksummed. when an entry can't be read
+// the checker moves forward a byte at a time until it finds one that can.
+// old versions of entries, kept around for snapshots, aren't checked for
+// duplicates and are left out of repairs
+
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+
+use super::{
+    checksum, encrypt, entry_header, with_checksum, CREATED_OFFSET, CURRENT_VERSION,
+    DATA_ENTRY_TYPE, DEFAULT_NAMESPACE_ID, DELETED_OFFSET, ENTRY_FLAGS, ENTRY_HEADER_LENGTH,
+    HEADER_LENGTH, MAGIC_BYTES, NAMESPACE_ENTRY_TYPE, SET_ENTRY_TYPE, SORTED_SET_ENTRY_TYPE,
+    V2_ENTRY_HEADER_LENGTH, V3_ENTRY_HEADER_LENGTH, V4_ENTRY_HEADER_LENGTH, V6_HEADER_LENGTH,
+};
+
+/// What checking a file found.
+#[derive(Debug)]
+pub struct CheckReport {
+    /// The version in the file header, if it has a valid one.
+    pub version: Option<u16>,
+    /// Entries that can be read and would be kept by a repair.
+    pub entries: u64,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Bytes that a repair would leave out.
+    pub fn lost_bytes(&self) -> u64 {
+        self.problems.iter().map(|problem| problem.length).sum()
+    }
+}
+
+/// A range of the file that is damaged or shadowed by another entry.
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub offset: u64,
+    pub length: u64,
+    pub kind: ProblemKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProblemKind {
+    /// The file doesn't start with a kiv header.
+    BadHeader,
+    UnsupportedVersion(u16),
+    /// Bytes between entries that don't form an entry. `reason` says why
+    /// the first of them couldn't be read.
+    Unreadable {
+        reason: String,
+    },
+    /// Bytes at the end of the file that don't form an entry, usually from
+    /// a write that didn't finish.
+    TrailingGarbage {
+        reason: String,
+    },
+    /// A sorted set, set or namespace entry with a string that isn't UTF-8.
+    InvalidUtf8,
+    /// An entry in a namespace that was never created.
+    UnknownNamespace(u16),
+    /// A data entry whose key already appeared earlier in its namespace.
+    /// Reads only ever see the first one.
+    DuplicateKey {
+        namespace: u16,
+        key: Vec<u8>,
+    },
+    /// A set or sorted set member that already appeared earlier.
+    DuplicateMember {
+        namespace: u16,
+        key: String,
+        member: String,
+    },
+    /// A namespace entry reusing an id or name.
+    DuplicateNamespace {
+        id: u16,
+        name: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "offset {} ({} bytes): ", self.offset, self.length)?;
+        match &self.kind {
+            ProblemKind::BadHeader => write!(f, "not a kiv file header"),
+            ProblemKind::UnsupportedVersion(version) => {
+                write!(f, "unsupported file version {}", version)
+            }
+            ProblemKind::Unreadable { reason } => write!(f, "unreadable bytes, {}", reason),
+            ProblemKind::TrailingGarbage { reason } => write!(f, "trailing garbage, {}", reason),
+            ProblemKind::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            ProblemKind::UnknownNamespace(id) => write!(f, "entry in unknown namespace {}", id),
+            ProblemKind::DuplicateKey { namespace, key } => write!(
+                f,
+                "duplicate key {:?} in namespace {}",
+                String::from_utf8_lossy(key),
+                namespace
+            ),
+            ProblemKind::DuplicateMember {
+                namespace,
+                key,
+                member,
+            } => write!(
+                f,
+                "duplicate member {:?} of {:?} in namespace {}",
+                member, key, namespace
+            ),
+            ProblemKind::DuplicateNamespace { id, name } => {
+                write!(f, "duplicate namespace {:?} with id {}", name, id)
+            }
+        }
+    }
+}
+
+/// An entry that could be read, borrowing from the file's bytes.
+struct RawEntry<'a> {
+    offset: usize,
+    /// Along with how a data entry's value is compressed.
+    entry_type: u8,
+    namespace: u16,
+    /// Sequence numbers, 0 before version 5.
+    created: u64,
+    deleted: u64,
+    /// Everything after the entry header.
+    body: &'a [u8],
+    fields: Fields<'a>,
+}
+
+enum Fields<'a> {
+    Data { key: &'a [u8] },
+    SortedSet { key: &'a [u8], member: &'a [u8] },
+    Set { key: &'a [u8], member: &'a [u8] },
+    Namespace { name: &'a [u8] },
+}
+
+impl RawEntry<'_> {
+    fn length(&self, header_length: usize) -> usize {
+        header_length + self.body.len()
+    }
+}
+
+/// Reads `length` bytes at `position`, moving past them.
+fn take<'a>(bytes: &'a [u8], position: &mut usize, length: usize) -> Result<&'a [u8], String> {
+    let end = position
+        .checked_add(length)
+        .filter(|end| *end <= bytes.len())
+        .ok_or("entry runs past the end of the file")?;
+    let taken = &bytes[*position..end];
+    *position = end;
+    Ok(taken)
+}
+
+fn take_u16_prefixed<'a>(bytes: &'a [u8], position: &mut usize) -> Result<&'a [u8], String> {
+    let length = BigEndian::read_u16(take(bytes, position, 2)?) as usize;
+    take(bytes, position, length)
+}
+
+/// Reads the entry at `offset`, checking its bounds and, if the version has
+/// them, its checksum.
+fn read_entry(bytes: &[u8], offset: usize, version: u16) -> Result<RawEntry<'_>, String> {
+    let header_length = entry_header_length(version);
+    let mut position = offset;
+    let header = take(bytes, &mut position, header_length)?;
+    let entry_type = header[0];
+    // entries from before version 3 belong to the default namespace
+    let namespace = header
+        .get(1..3)
+        .map_or(DEFAULT_NAMESPACE_ID, BigEndian::read_u16);
+
+    // data entries flag how their value is compressed in the type
+    let fields = match entry_type & !ENTRY_FLAGS {
+        DATA_ENTRY_TYPE => {
+            let key = take_u16_prefixed(bytes, &mut position)?;
+            let value_length = BigEndian::read_u32(take(bytes, &mut position, 4)?) as usize;
+            take(bytes, &mut position, value_length)?;
+            Fields::Data { key }
+        }
+        SORTED_SET_ENTRY_TYPE => {
+            let key = take_u16_prefixed(bytes, &mut position)?;
+            let member = take_u16_prefixed(bytes, &mut position)?;
+            take(bytes, &mut position, 8)?;
+            Fields::SortedSet { key, member }
+        }
+        SET_ENTRY_TYPE => {
+            let key = take_u16_prefixed(bytes, &mut position)?;
+            let member = take_u16_prefixed(bytes, &mut position)?;
+            Fields::Set { key, member }
+        }
+        NAMESPACE_ENTRY_TYPE => Fields::Namespace {
+            name: take_u16_prefixed(bytes, &mut position)?,
+        },
+        _ => return Err(format!("unknown entry type {}", entry_type)),
+    };
+
+    if version >= 4 {
+        let stored = BigEndian::read_u32(&header[3..7]);
+        // version 4 entries end their header at the checksum
+        let covered_from = if version >= 5 {
+            CREATED_OFFSET as usize
+        } else {
+            V4_ENTRY_HEADER_LENGTH as usize
+        };
+        if checksum(&bytes[offset..position], covered_from) != stored {
+            return Err(String::from("checksum mismatch"));
+        }
+    }
+
+    let (deleted, created) = if version >= 5 {
+        let deleted = DELETED_OFFSET as usize;
+        let created = CREATED_OFFSET as usize;
+        (
+            BigEndian::read_u64(&header[deleted..created]),
+            BigEndian::read_u64(&header[created..]),
+        )
+    } else {
+        (0, 0)
+    };
+
+    Ok(RawEntry {
+        offset,
+        entry_type,
+        namespace,
+        created,
+        deleted,
+        body: &bytes[offset + header_length..position],
+        fields,
+    })
+}
+
+fn entry_header_length(version: u16) -> usize {
+    match version {
+        0..=2 => V2_ENTRY_HEADER_LENGTH as usize,
+        3 => V3_ENTRY_HEADER_LENGTH as usize,
+        4 => V4_ENTRY_HEADER_LENGTH as usize,
+        _ => ENTRY_HEADER_LENGTH as usize,
+    }
+}
+
+/// Walks every entry in a file, returning what was found along with the
+/// entries a repair should keep.
+fn scan(bytes: &[u8]) -> (CheckReport, Vec<RawEntry<'_>>) {
+    let mut problems = vec![];
+    let version_length = V6_HEADER_LENGTH as usize;
+
+    // a damaged header doesn't stop us looking for entries after it
+    let (version, assumed) = if bytes.is_empty() {
+        (None, CURRENT_VERSION)
+    } else if bytes.len() < version_length || bytes[..6] != MAGIC_BYTES {
+        problems.push(Problem {
+            offset: 0,
+            length: bytes.len().min(HEADER_LENGTH as usize) as u64,
+            kind: ProblemKind::BadHeader,
+        });
+        (None, CURRENT_VERSION)
+    } else {
+        match BigEndian::read_u16(&bytes[6..version_length]) {
+            version @ (0..=6 | CURRENT_VERSION) => (Some(version), version),
+            version => {
+                problems.push(Problem {
+                    offset: 6,
+                    length: 2,
+                    kind: ProblemKind::UnsupportedVersion(version),
+                });
+                (Some(version), CURRENT_VERSION)
+            }
+        }
+    };
+
+    // before version 7 the header was only the identifier and version
+    let file_header_length = match assumed {
+        CURRENT_VERSION => HEADER_LENGTH as usize,
+        _ => version_length,
+    };
+    if version == Some(CURRENT_VERSION) && bytes.len() < file_header_length {
+        problems.push(Problem {
+            offset: version_length as u64,
+            length: (bytes.len() - version_length) as u64,
+            kind: ProblemKind::BadHeader,
+        });
+    }
+
+    // find every readable entry, skipping over the bytes in between
+    let mut entries = vec![];
+    let mut position = file_header_length;
+    let mut unreadable: Option<(usize, String)> = None;
+    while position < bytes.len() {
+        match read_entry(bytes, position, assumed) {
+            Ok(entry) => {
+                if let Some((start, reason)) = unreadable.take() {
+                    problems.push(Problem {
+                        offset: start as u64,
+                        length: (position - start) as u64,
+                        kind: ProblemKind::Unreadable { reason },
+                    });
+                }
+                position += entry.length(entry_header_length(assumed));
+                if entry.deleted == 0 {
+                    entries.push(entry);
+                }
+            }
+            Err(reason) => {
+                unreadable.get_or_insert((position, reason));
+                position += 1;
+            }
+        }
+    }
+    if let Some((start, reason)) = unreadable {
+        problems.push(Problem {
+            offset: start as u64,
+            length: (bytes.len() - start) as u64,
+            kind: ProblemKind::TrailingGarbage { reason },
+        });
+    }
+
+    // then look at what the readable entries hold
+    let mut namespace_ids = HashSet::from([DEFAULT_NAMESPACE_ID]);
+    let mut namespace_names = HashSet::new();
+    let mut kept = vec![];
+    let mut dropped = vec![];
+    for entry in &entries {
+        if let Fields::Namespace { name } = entry.fields {
+            let kind = match std::str::from_utf8(name) {
+                Err(_) => Some(ProblemKind::InvalidUtf8),
+                Ok(name)
+                    if entry.namespace == DEFAULT_NAMESPACE_ID
+                        || namespace_ids.contains(&entry.namespace)
+                        || namespace_names.contains(name) =>
+                {
+                    Some(ProblemKind::DuplicateNamespace {
+                        id: entry.namespace,
+                        name: name.to_string(),
+                    })
+                }
+                Ok(name) => {
+                    namespace_ids.insert(entry.namespace);
+                    namespace_names.insert(name);
+                    None
+                }
+            };
+            if let Some(kind) = kind {
+                dropped.push((entry.offset, kind));
+            }
+        }
+    }
+
+    let mut keys = HashSet::new();
+    let mut members = HashSet::new();
+    for entry in entries {
+        let kind = match entry.fields {
+            Fields::Namespace { .. } => {
+                if dropped.iter().any(|(offset, _)| *offset == entry.offset) {
+                    continue;
+                }
+                None
+            }
+            _ if !namespace_ids.contains(&entry.namespace) => {
+                Some(ProblemKind::UnknownNamespace(entry.namespace))
+            }
+            Fields::Data { key } => {
+                if keys.insert((entry.namespace, key)) {
+                    None
+                } else {
+                    Some(ProblemKind::DuplicateKey {
+                        namespace: entry.namespace,
+                        key: key.to_vec(),
+                    })
+                }
+            }
+            Fields::SortedSet { key, member } | Fields::Set { key, member } => {
+                match (std::str::from_utf8(key), std::str::from_utf8(member)) {
+                    (Ok(key), Ok(member)) => {
+                        if members.insert((entry.entry_type, entry.namespace, key, member)) {
+                            None
+                        } else {
+                            Some(ProblemKind::DuplicateMember {
+                                namespace: entry.namespace,
+                                key: key.to_string(),
+                                member: member.to_string(),
+                            })
+                        }
+                    }
+                    _ => Some(ProblemKind::InvalidUtf8),
+                }
+            }
+        };
+
+        match kind {
+            Some(kind) => dropped.push((entry.offset, kind)),
+            None => kept.push(entry),
+        }
+    }
+
+    for (offset, kind) in dropped {
+        let length = read_entry(bytes, offset, assumed)
+            .map(|entry| entry.length(entry_header_length(assumed)))
+            .unwrap_or(0);
+        problems.push(Problem {
+            offset: offset as u64,
+            length: length as u64,
+            kind,
+        });
+    }
+    problems.sort_by_key(|problem| problem.offset);
+
+    let report = CheckReport {
+        version,
+        entries: kept.len() as u64,
+        problems,
+    };
+
+    (report, kept)
+}
+
+/// Checks a file without changing it.
+pub fn check(path: impl AsRef<Path>) -> std::io::Result<CheckReport> {
+    let bytes = std::fs::read(path)?;
+    let (report, _) = scan(&bytes);
+
+    Ok(report)
+}
+
+/// Copies every entry that can be read from `path` into a new file at
+/// `output`, in the current version. The report lists everything that was
+/// left out. `output` must not exist yet.
+pub fn repair(path: impl AsRef<Path>, output: impl AsRef<Path>) -> std::io::Result<CheckReport> {
+    let bytes = std::fs::read(path)?;
+    let (report, entries) = scan(&bytes);
+
+    let mut repaired = BytesMut::new();
+    repaired.extend_from_slice(&MAGIC_BYTES);
+    repaired.put_u16(CURRENT_VERSION);
+    // values stay encrypted with the key the file was, if it was
+    match bytes.get(V6_HEADER_LENGTH as usize..HEADER_LENGTH as usize) {
+        Some(key_block) if report.version == Some(CURRENT_VERSION) => {
+            repaired.extend_from_slice(key_block)
+        }
+        _ => repaired.extend_from_slice(&encrypt::key_block(None)),
+    }
+    for entry in entries {
+        let mut rebuilt = entry_header(entry.entry_type, entry.namespace, entry.created);
+        rebuilt.put(entry.body);
+        // older entries get a checksum and sequence numbers for the first
+        // time
+        repaired.put(with_checksum(rebuilt));
+    }
+
+    let mut file: File = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)?;
+    file.write_all(&repaired)?;
+    file.sync_all()?;
+
+    Ok(report)
+}
@@ -0,0 +1,184 @@
+// compressing the values of data entries
+//
+// values at least `threshold` bytes long are compressed with the codec the
+// file was opened with, and kept that way if that makes them smaller. the
+// codec is flagged in the top two bits of the entry type, and a compressed
+// value starts with the length it decompresses to. a file can hold values
+// written with any mix of codecs, each is read with the codec it was
+// written with. zstd dictionaries aren't kept in the file, so values
+// written with one can only be read by opening the file with it again
+
+use std::{borrow::Cow, io, sync::Arc};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BufMut;
+use zstd::{
+    bulk::{Compressor, Decompressor},
+    dict::{DecoderDictionary, EncoderDictionary},
+};
+
+use crate::invalid_data;
+
+/// The bits of an entry type that flag how its value is compressed.
+pub(crate) const CODEC_MASK: u8 = 0xC0;
+const LZ4: u8 = 0x40;
+const ZSTD: u8 = 0x80;
+const ZSTD_DICTIONARY: u8 = 0xC0;
+/// How many times bigger than what is stored a value can decompress to. An
+/// lz4 block never gets past 255 to 1, and a zstd block of 128KiB takes at
+/// least 4 bytes, so anything bigger was corrupted and isn't allocated.
+const MAX_RATIO: usize = 32 * 1024;
+
+/// How values are compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Codec {
+    /// Values are stored as they are.
+    #[default]
+    None,
+    /// Fast, with a modest ratio.
+    Lz4,
+    /// Slower, with a better ratio, especially with a dictionary.
+    Zstd,
+}
+
+/// How [`Storage`](crate::Storage) compresses the values of data entries.
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    pub codec: Codec,
+    /// Values shorter than this many bytes are stored as they are.
+    pub threshold: usize,
+    /// The zstd compression level, from 1 to 22.
+    pub level: i32,
+    /// A zstd dictionary, e.g. from [`train_dictionary`]. Values written
+    /// with it can only be read by opening the file with it.
+    pub dictionary: Option<Arc<Vec<u8>>>,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            codec: Codec::None,
+            threshold: 256,
+            level: 3,
+            dictionary: None,
+        }
+    }
+}
+
+/// Trains a zstd dictionary of at most `max_size` bytes on sample values.
+/// It works best with many samples that share structure, like values of
+/// the same shape of JSON.
+pub fn train_dictionary(samples: &[impl AsRef<[u8]>], max_size: usize) -> io::Result<Vec<u8>> {
+    let samples: Vec<&[u8]> = samples.iter().map(AsRef::as_ref).collect();
+    zstd::dict::from_samples(&samples, max_size)
+}
+
+/// The options a file was opened with, with their dictionary prepared.
+/// Shared by a `Storage` and its views.
+pub(crate) struct Compression {
+    options: CompressionOptions,
+    encoder: Option<EncoderDictionary<'static>>,
+    decoder: Option<DecoderDictionary<'static>>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new(CompressionOptions::default())
+    }
+}
+
+impl Compression {
+    pub(crate) fn new(options: CompressionOptions) -> Self {
+        let dictionary = options.dictionary.as_deref();
+        Self {
+            encoder: dictionary
+                .map(|dictionary| EncoderDictionary::copy(dictionary, options.level)),
+            decoder: dictionary.map(|dictionary| DecoderDictionary::copy(dictionary)),
+            options,
+        }
+    }
+
+    /// Compresses a value if it is long enough and comes out shorter,
+    /// returning the codec bits to flag its entry with and what to store.
+    pub(crate) fn compress<'a>(&self, value: &'a [u8]) -> (u8, Cow<'a, [u8]>) {
+        if value.len() < self.options.threshold {
+            return (0, Cow::Borrowed(value));
+        }
+
+        let (codec, compressed) = match (self.options.codec, &self.encoder) {
+            (Codec::None, _) => return (0, Cow::Borrowed(value)),
+            (Codec::Lz4, _) => (LZ4, Ok(lz4_flex::block::compress(value))),
+            (Codec::Zstd, None) => (ZSTD, zstd::bulk::compress(value, self.options.level)),
+            (Codec::Zstd, Some(dictionary)) => (
+                ZSTD_DICTIONARY,
+                Compressor::with_prepared_dictionary(dictionary)
+                    .and_then(|mut compressor| compressor.compress(value)),
+            ),
+        };
+
+        match compressed {
+            Ok(compressed)
+                if 4 + compressed.len() < value.len()
+                    && value.len() <= (4 + compressed.len()) * MAX_RATIO =>
+            {
+                let mut stored = Vec::with_capacity(4 + compressed.len());
+                stored.put_u32(value.len() as u32);
+                stored.extend_from_slice(&compressed);
+                (codec, Cow::Owned(stored))
+            }
+            // not worth it, or zstd failed, so it's stored as it is
+            _ => (0, Cow::Borrowed(value)),
+        }
+    }
+
+    /// Decompresses a value stored with the codec bits `codec`.
+    pub(crate) fn decompress(&self, codec: u8, stored: Vec<u8>) -> io::Result<Vec<u8>> {
+        if codec == 0 {
+            return Ok(stored);
+        }
+
+        let Some((length, compressed)) = stored.split_first_chunk::<4>() else {
+            return Err(invalid_data("compressed value is cut short"));
+        };
+        let length = BigEndian::read_u32(length) as usize;
+        if length
+            > stored
+                .len()
+                .saturating_mul(MAX_RATIO)
+                .min(u32::MAX as usize)
+        {
+            return Err(invalid_data(format!(
+                "compressed value claims to be {} bytes, more than {} bytes could decompress to",
+                length,
+                stored.len()
+            )));
+        }
+        let failed = |err: &dyn std::fmt::Display| {
+            invalid_data(format!("value could not be decompressed: {}", err))
+        };
+
+        let value = match codec {
+            LZ4 => lz4_flex::block::decompress(compressed, length).map_err(|err| failed(&err))?,
+            ZSTD => zstd::bulk::decompress(compressed, length).map_err(|err| failed(&err))?,
+            _ => {
+                let Some(dictionary) = &self.decoder else {
+                    return Err(invalid_data(
+                        "value was compressed with a zstd dictionary the file wasn't opened with",
+                    ));
+                };
+                Decompressor::with_prepared_dictionary(dictionary)
+                    .and_then(|mut decompressor| decompressor.decompress(compressed, length))
+                    .map_err(|err| failed(&err))?
+            }
+        };
+        if value.len() != length {
+            return Err(invalid_data(format!(
+                "value decompressed to {} bytes, not {}",
+                value.len(),
+                length
+            )));
+        }
+
+        Ok(value)
+    }
+}
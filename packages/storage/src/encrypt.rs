@@ -0,0 +1,413 @@
+// encrypting the values of data entries
+//
+// whether a file is encrypted is kept in a block of its header, which names
+// the cipher and holds a few bytes sealed with the key. opening that block is
+// how a key is checked before anything else in the file is read. values are
+// sealed once they are compressed, with a random nonce in front of them and
+// their namespace and key as associated data, so a value can't be moved to
+// another key unnoticed. keys, set members and namespace names are left in
+// the clear, as lookups compare them while scanning the file.
+//
+// compaction can re-encrypt every value with a new key, into a new file
+// whose header is sealed with the new key, which then replaces the old one
+
+use std::{
+    borrow::Cow,
+    fmt, io,
+    path::Path,
+    sync::{PoisonError, RwLock},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::invalid_data;
+
+/// The bit of an entry type that flags its value as encrypted.
+pub(crate) const ENCRYPTED: u8 = 0x20;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+/// What is sealed in the header to check keys against.
+const KEY_CHECK: [u8; 16] = [0; 16];
+/// The cipher, then the sealed check, zeroed in files that aren't
+/// encrypted.
+pub(crate) const KEY_BLOCK_LENGTH: usize = 1 + NONCE_LENGTH + KEY_CHECK.len() + TAG_LENGTH;
+
+/// The authenticated cipher values are encrypted with.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Cipher {
+    /// Fastest where the CPU has AES instructions.
+    #[default]
+    Aes256Gcm,
+    /// Fastest where it doesn't.
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Cipher::Aes256Gcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// A 256-bit key for either cipher.
+#[derive(Clone, PartialEq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Reads a key from a file holding either its 32 bytes or 64 hex
+    /// digits.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// Reads a key written as 64 hex digits from the environment variable
+    /// `name`.
+    pub fn from_env(name: &str) -> io::Result<Self> {
+        match std::env::var(name) {
+            Ok(key) => Self::parse(key.as_bytes()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("environment variable {} isn't set to a key", name),
+            )),
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> io::Result<Self> {
+        if let Ok(key) = <[u8; 32]>::try_from(bytes) {
+            return Ok(Self(key));
+        }
+
+        let digits = bytes.trim_ascii();
+        let mut key = [0u8; 32];
+        if digits.len() == 64 {
+            let decoded = digits.chunks(2).zip(&mut key).all(|(pair, byte)| {
+                let pair = std::str::from_utf8(pair).unwrap_or("");
+                u8::from_str_radix(pair, 16)
+                    .map(|value| *byte = value)
+                    .is_ok()
+            });
+            if decoded {
+                return Ok(Self(key));
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "an encryption key has to be 32 bytes, or 64 hex digits",
+        ))
+    }
+}
+
+/// How [`Storage`](crate::Storage) encrypts the values of data entries.
+#[derive(Debug, Clone, Default)]
+pub struct EncryptionOptions {
+    /// The cipher new files are encrypted with, and that compaction
+    /// re-encrypts with. Files are always read with the cipher they name.
+    pub cipher: Cipher,
+    /// The key the file is encrypted with. New files are encrypted with it.
+    pub key: Option<EncryptionKey>,
+    /// A key for the next compaction to re-encrypt the file with, after
+    /// which the file only opens with this one. It encrypts files that
+    /// aren't yet, and new files are encrypted with it from the start. The
+    /// file still opens with it if it was rotated to already.
+    pub rotate_to: Option<EncryptionKey>,
+}
+
+/// Why a file couldn't be opened with the keys it was given. Reported as
+/// an error of kind `PermissionDenied`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyError {
+    /// The file is encrypted, and no key was given.
+    Missing,
+    /// The file is encrypted with another key.
+    Wrong,
+    /// A key was given for a file that isn't encrypted.
+    NotEncrypted,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyError::Missing => "the file is encrypted, and no key was given",
+            KeyError::Wrong => "the file is encrypted with another key",
+            KeyError::NotEncrypted => {
+                "the file isn't encrypted, rotate to a key to encrypt it when it is compacted"
+            }
+        })
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl From<KeyError> for io::Error {
+    fn from(err: KeyError) -> Self {
+        io::Error::new(io::ErrorKind::PermissionDenied, err)
+    }
+}
+
+#[derive(Clone)]
+enum Sealer {
+    // its key schedule takes up most of a kilobyte
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+/// A cipher along with the key it uses.
+#[derive(Clone)]
+pub(crate) struct FileKey {
+    cipher: Cipher,
+    key: EncryptionKey,
+    sealer: Sealer,
+}
+
+impl FileKey {
+    fn new(cipher: Cipher, key: EncryptionKey) -> Self {
+        let sealer = match cipher {
+            Cipher::Aes256Gcm => Sealer::Aes256Gcm(Box::new(Aes256Gcm::new(&key.0.into()))),
+            Cipher::ChaCha20Poly1305 => {
+                Sealer::ChaCha20Poly1305(ChaCha20Poly1305::new(&key.0.into()))
+            }
+        };
+
+        Self {
+            cipher,
+            key,
+            sealer,
+        }
+    }
+
+    fn is(&self, other: &FileKey) -> bool {
+        self.cipher == other.cipher && self.key == other.key
+    }
+
+    /// Encrypts `plaintext`, returning it after the nonce it was sealed
+    /// with.
+    fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let sealed = match &self.sealer {
+            Sealer::Aes256Gcm(cipher) => cipher.encrypt(&nonce, payload),
+            Sealer::ChaCha20Poly1305(cipher) => cipher.encrypt(&nonce, payload),
+        }
+        // only fails for gigabytes more than a value can hold
+        .expect("failed to encrypt value");
+
+        let mut bytes = Vec::with_capacity(NONCE_LENGTH + sealed.len());
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&sealed);
+        bytes
+    }
+
+    /// Decrypts what [`FileKey::seal`] returned, or `None` if it wasn't
+    /// sealed with this key and `aad`.
+    fn open(&self, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let (nonce, ciphertext) = sealed.split_at_checked(NONCE_LENGTH)?;
+        let nonce = Nonce::from_slice(nonce);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        match &self.sealer {
+            Sealer::Aes256Gcm(cipher) => cipher.decrypt(nonce, payload),
+            Sealer::ChaCha20Poly1305(cipher) => cipher.decrypt(nonce, payload),
+        }
+        .ok()
+    }
+
+    /// Whether this is the key the header's key block was sealed with.
+    fn opens(&self, block: &[u8]) -> bool {
+        block[0] == self.cipher.id()
+            && self.open(&block[..1], &block[1..]).as_deref() == Some(&KEY_CHECK[..])
+    }
+}
+
+/// The associated data a value is sealed with.
+fn value_aad(namespace: u16, key: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(2 + key.len());
+    aad.extend_from_slice(&namespace.to_be_bytes());
+    aad.extend_from_slice(key);
+    aad
+}
+
+/// The key a file is encrypted with, if it is. Shared by a `Storage` and
+/// its views, so they all see a rotation.
+#[derive(Default)]
+pub(crate) struct Encryption {
+    current: RwLock<Option<FileKey>>,
+    rotate_to: Option<FileKey>,
+}
+
+impl Encryption {
+    /// The encryption for a new file.
+    pub(crate) fn create(options: &EncryptionOptions) -> Self {
+        let key = options.rotate_to.as_ref().or(options.key.as_ref());
+        let key = key.map(|key| FileKey::new(options.cipher, key.clone()));
+
+        Self {
+            current: RwLock::new(key.clone()),
+            rotate_to: key,
+        }
+    }
+
+    /// The encryption for a file with the key block `block`, checking the
+    /// keys in `options` against it.
+    pub(crate) fn open(options: &EncryptionOptions, block: &[u8]) -> io::Result<Self> {
+        let rotate_to = options
+            .rotate_to
+            .as_ref()
+            .map(|key| FileKey::new(options.cipher, key.clone()));
+
+        let current = match Cipher::from_id(block[0]) {
+            None if block[0] != 0 => {
+                return Err(invalid_data(format!("unknown cipher {}", block[0])))
+            }
+            None if options.key.is_some() => return Err(KeyError::NotEncrypted.into()),
+            None => None,
+            Some(cipher) => {
+                let candidates = [options.key.as_ref(), options.rotate_to.as_ref()];
+                let mut keys = candidates.into_iter().flatten().peekable();
+                if keys.peek().is_none() {
+                    return Err(KeyError::Missing.into());
+                }
+                let key = keys
+                    .map(|key| FileKey::new(cipher, key.clone()))
+                    .find(|key| key.opens(block))
+                    .ok_or(KeyError::Wrong)?;
+                Some(key)
+            }
+        };
+
+        Ok(Self {
+            current: RwLock::new(current),
+            rotate_to,
+        })
+    }
+
+    fn current(&self) -> Option<FileKey> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some()
+    }
+
+    /// The key block for the file's header.
+    pub(crate) fn key_block(&self) -> [u8; KEY_BLOCK_LENGTH] {
+        key_block(self.current().as_ref())
+    }
+
+    /// The key compaction should re-encrypt the file with, if it isn't
+    /// encrypted with it already.
+    pub(crate) fn rotation(&self) -> Option<FileKey> {
+        let rotate_to = self.rotate_to.as_ref()?;
+        match self.current() {
+            Some(current) if current.is(rotate_to) => None,
+            _ => Some(rotate_to.clone()),
+        }
+    }
+
+    /// Switches to `key` once everything in the file is encrypted with it.
+    pub(crate) fn rotated(&self, key: FileKey) {
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Some(key);
+    }
+
+    /// Encrypts a value if the file is encrypted, returning the bits to
+    /// flag its entry with and what to store.
+    pub(crate) fn seal<'a>(
+        &self,
+        namespace: u16,
+        key: &[u8],
+        value: Cow<'a, [u8]>,
+    ) -> (u8, Cow<'a, [u8]>) {
+        match self.current() {
+            Some(file_key) => (
+                ENCRYPTED,
+                Cow::Owned(file_key.seal(&value_aad(namespace, key), &value)),
+            ),
+            None => (0, value),
+        }
+    }
+
+    /// Decrypts a value stored with the flags `flags`.
+    pub(crate) fn open_value(
+        &self,
+        flags: u8,
+        namespace: u16,
+        key: &[u8],
+        stored: Vec<u8>,
+    ) -> io::Result<Vec<u8>> {
+        if flags & ENCRYPTED == 0 {
+            return Ok(stored);
+        }
+
+        let Some(file_key) = self.current() else {
+            return Err(invalid_data("value is encrypted, but the file isn't"));
+        };
+        file_key
+            .open(&value_aad(namespace, key), &stored)
+            .ok_or_else(|| invalid_data("value could not be decrypted"))
+    }
+
+    /// Decrypts a value stored with the flags `flags` and encrypts it again
+    /// with `to`, returning the new flags and value.
+    pub(crate) fn reseal(
+        &self,
+        to: &FileKey,
+        flags: u8,
+        namespace: u16,
+        key: &[u8],
+        stored: Vec<u8>,
+    ) -> io::Result<(u8, Vec<u8>)> {
+        let value = self.open_value(flags, namespace, key, stored)?;
+
+        Ok((
+            flags | ENCRYPTED,
+            to.seal(&value_aad(namespace, key), &value),
+        ))
+    }
+}
+
+/// The key block for a file encrypted with `key`, or one that isn't.
+pub(crate) fn key_block(key: Option<&FileKey>) -> [u8; KEY_BLOCK_LENGTH] {
+    let mut block = [0u8; KEY_BLOCK_LENGTH];
+    if let Some(key) = key {
+        let cipher = [key.cipher.id()];
+        block[0] = cipher[0];
+        block[1..].copy_from_slice(&key.seal(&cipher, &KEY_CHECK));
+    }
+
+    block
+}
@@ -0,0 +1,335 @@
+// the interface every storage engine offers, so `Kiv` doesn't depend on how
+// or where entries are kept
+//
+// keys and members are always read and written in the engine's current
+// namespace. the operations after the collections have defaults, for
+// engines that can't or don't need to offer them
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::{
+    compress::Compression,
+    encode_entry,
+    encrypt::{self, Encryption},
+    Entry, NamespaceStats, Storage, StorageStats, MAGIC_BYTES,
+};
+
+/// Runs a part of a long read, such as a backup, while nothing writes to the
+/// engine. Writes can be made between parts.
+pub type Locked<'a> = &'a dyn Fn(&mut dyn FnMut() -> io::Result<()>) -> io::Result<()>;
+
+pub trait StorageEngine: Send + Sync {
+    fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>>;
+
+    /// Stores a value, replacing the one already at `key`.
+    fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()>;
+
+    fn delete(&mut self, key: &[u8]) -> io::Result<()>;
+
+    /// Returns every key and value whose key starts with `prefix`, ordered
+    /// by key.
+    fn scan(&mut self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Removes every entry and namespace.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Adds a member to a sorted set, or updates its score.
+    fn zadd(&mut self, key: &str, member: &str, score: f64) -> io::Result<()>;
+
+    fn zrem(&mut self, key: &str, member: &str) -> io::Result<()>;
+
+    fn zscore(&mut self, key: &str, member: &str) -> io::Result<Option<f64>>;
+
+    /// Returns the members with a score between `min` and `max`
+    /// (inclusive), ordered by score, then by member.
+    fn zrange(&mut self, key: &str, min: f64, max: f64) -> io::Result<Vec<(String, f64)>>;
+
+    /// Returns the zero-based position of a member in that order.
+    fn zrank(&mut self, key: &str, member: &str) -> io::Result<Option<u64>>;
+
+    /// Adds a member to a set. Returns `false` if it was already present.
+    fn sadd(&mut self, key: &str, member: &str) -> io::Result<bool>;
+
+    /// Removes a member from a set. Returns `false` if it was not present.
+    fn srem(&mut self, key: &str, member: &str) -> io::Result<bool>;
+
+    fn sismember(&mut self, key: &str, member: &str) -> io::Result<bool>;
+
+    /// Returns every member of a set, in sorted order.
+    fn smembers(&mut self, key: &str) -> io::Result<Vec<String>>;
+
+    fn scard(&mut self, key: &str) -> io::Result<u64>;
+
+    /// Registers a namespace, returning its id. Returns `None` if the name
+    /// is taken.
+    fn create_namespace(&mut self, name: &str) -> io::Result<Option<u16>>;
+
+    /// Removes a namespace and everything in it. Returns `false` if it
+    /// doesn't exist or is the default namespace.
+    fn drop_namespace(&mut self, name: &str) -> io::Result<bool>;
+
+    /// Returns every namespace as `(id, name)` pairs, starting with the
+    /// default namespace.
+    fn namespaces(&mut self) -> io::Result<Vec<(u16, String)>>;
+
+    fn namespace_id(&mut self, name: &str) -> io::Result<Option<u16>> {
+        Ok(self
+            .namespaces()?
+            .into_iter()
+            .find(|(_, n)| n == name)
+            .map(|(id, _)| id))
+    }
+
+    fn namespace_stats(&mut self) -> io::Result<Vec<NamespaceStats>>;
+
+    /// Switches the namespace that reads and writes operate on.
+    fn use_namespace(&mut self, id: u16);
+
+    fn current_namespace(&self) -> u16;
+
+    /// Removes every entry in the current namespace.
+    fn truncate(&mut self) -> io::Result<()>;
+
+    fn stats(&mut self) -> io::Result<StorageStats>;
+
+    /// How many entries a full scan reads, and how many bytes.
+    fn size(&mut self) -> io::Result<(u64, u64)>;
+
+    /// Streams through every entry in every namespace. A namespace's entry
+    /// always comes before the entries stored in it.
+    fn for_each_entry(&mut self, f: &mut dyn FnMut(Entry) -> io::Result<()>) -> io::Result<()>;
+
+    /// Writes entries as they are, keeping their namespace ids. Only safe
+    /// when the engine can't already hold them.
+    fn append_entries(&mut self, entries: &[Entry]) -> io::Result<()>;
+
+    /// A read-only view that reads independently of this engine, starting
+    /// in the same namespace. Views can read from several threads at once
+    /// while nothing writes.
+    fn reader(&self) -> Box<dyn StorageEngine>;
+
+    /// A read-only view of the data as it is now, which goes on reading it
+    /// that way while later writes are made.
+    fn snapshot_reader(&self) -> Box<dyn StorageEngine>;
+
+    /// Copies everything to `out` as a kiv file, returning its size. The
+    /// copy is made in parts, each inside `locked`, so a view made by
+    /// [`StorageEngine::snapshot_reader`] can be copied while writes go on.
+    fn backup_to(&mut self, out: &mut dyn Write, locked: Locked) -> io::Result<u64> {
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&MAGIC_BYTES);
+        bytes.put_u16(crate::CURRENT_VERSION);
+        bytes.extend_from_slice(&encrypt::key_block(None));
+
+        // values are copied out as they are, for any engine to load
+        let (compression, encryption) = (Compression::default(), Encryption::default());
+        locked(&mut || {
+            self.for_each_entry(&mut |entry| {
+                bytes.put(encode_entry(&entry, 0, &compression, &encryption)?);
+                Ok(())
+            })
+        })?;
+
+        out.write_all(&bytes)?;
+        Ok(bytes.len() as u64)
+    }
+
+    /// Writes a kiv file to `path` like [`StorageEngine::backup_to`],
+    /// returning its size. `path` never holds a partial copy.
+    fn backup(&mut self, path: &Path, locked: Locked) -> io::Result<u64> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        let result = (|| {
+            let mut temp = File::create(&temp_path)?;
+            let written = self.backup_to(&mut temp, locked)?;
+            temp.sync_all()?;
+            std::fs::rename(&temp_path, path)?;
+            Ok(written)
+        })();
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+
+        result
+    }
+
+    /// Starts keeping a change log for incremental backups, returning the
+    /// current sequence number.
+    fn enable_change_log(&mut self) -> io::Result<u64> {
+        Err(unsupported("change logs"))
+    }
+
+    /// The sequence number of the latest change, if a change log is kept.
+    fn sequence(&self) -> Option<u64> {
+        None
+    }
+
+    /// Frees space taken up by data nothing can read any more, returning
+    /// how many bytes that freed.
+    fn compact(&mut self) -> io::Result<u64> {
+        Ok(0)
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+pub(crate) fn unsupported(feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} aren't supported by this storage engine", feature),
+    )
+}
+
+/// The file format.
+impl StorageEngine for Storage {
+    fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.get_data_entry(key)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        if self.get_data_entry(key)?.is_some() {
+            self.update_data_entry(key, value)
+        } else {
+            self.write_data_entry(key, value)
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> io::Result<()> {
+        self.delete_data_entry(key)
+    }
+
+    fn scan(&mut self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_data_entries(prefix)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Storage::flush(self)
+    }
+
+    fn zadd(&mut self, key: &str, member: &str, score: f64) -> io::Result<()> {
+        self.write_sorted_set_entry(key, member, score)
+    }
+
+    fn zrem(&mut self, key: &str, member: &str) -> io::Result<()> {
+        self.delete_sorted_set_entry(key, member)
+    }
+
+    fn zscore(&mut self, key: &str, member: &str) -> io::Result<Option<f64>> {
+        self.get_sorted_set_score(key, member)
+    }
+
+    fn zrange(&mut self, key: &str, min: f64, max: f64) -> io::Result<Vec<(String, f64)>> {
+        self.get_sorted_set_range(key, min, max)
+    }
+
+    fn zrank(&mut self, key: &str, member: &str) -> io::Result<Option<u64>> {
+        self.get_sorted_set_rank(key, member)
+    }
+
+    fn sadd(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        self.write_set_entry(key, member)
+    }
+
+    fn srem(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        self.delete_set_entry(key, member)
+    }
+
+    fn sismember(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        self.is_set_member(key, member)
+    }
+
+    fn smembers(&mut self, key: &str) -> io::Result<Vec<String>> {
+        self.get_set_members(key)
+    }
+
+    fn scard(&mut self, key: &str) -> io::Result<u64> {
+        self.get_set_cardinality(key)
+    }
+
+    fn create_namespace(&mut self, name: &str) -> io::Result<Option<u16>> {
+        Storage::create_namespace(self, name)
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> io::Result<bool> {
+        Storage::drop_namespace(self, name)
+    }
+
+    fn namespaces(&mut self) -> io::Result<Vec<(u16, String)>> {
+        self.get_namespaces()
+    }
+
+    fn namespace_stats(&mut self) -> io::Result<Vec<NamespaceStats>> {
+        self.get_namespace_stats()
+    }
+
+    fn use_namespace(&mut self, id: u16) {
+        Storage::use_namespace(self, id)
+    }
+
+    fn current_namespace(&self) -> u16 {
+        Storage::current_namespace(self)
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        self.truncate_namespace()
+    }
+
+    fn stats(&mut self) -> io::Result<StorageStats> {
+        self.get_stats()
+    }
+
+    fn size(&mut self) -> io::Result<(u64, u64)> {
+        // counted as entries are written, rather than by reading the file
+        Ok((self.entries, self.get_entries_size()?))
+    }
+
+    fn for_each_entry(&mut self, f: &mut dyn FnMut(Entry) -> io::Result<()>) -> io::Result<()> {
+        Storage::for_each_entry(self, f)
+    }
+
+    fn append_entries(&mut self, entries: &[Entry]) -> io::Result<()> {
+        Storage::append_entries(self, entries)
+    }
+
+    fn reader(&self) -> Box<dyn StorageEngine> {
+        Box::new(Storage::reader(self))
+    }
+
+    fn snapshot_reader(&self) -> Box<dyn StorageEngine> {
+        Box::new(Storage::snapshot_reader(self))
+    }
+
+    fn backup_to(&mut self, mut out: &mut dyn Write, locked: Locked) -> io::Result<u64> {
+        self.snapshot_to_with(&mut out, locked)
+    }
+
+    fn backup(&mut self, path: &Path, locked: Locked) -> io::Result<u64> {
+        self.snapshot_with(path, locked)
+    }
+
+    fn enable_change_log(&mut self) -> io::Result<u64> {
+        Storage::enable_change_log(self)
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        Storage::sequence(self)
+    }
+
+    fn compact(&mut self) -> io::Result<u64> {
+        Storage::compact(self)
+    }
+
+    fn is_read_only(&self) -> bool {
+        Storage::is_read_only(self)
+    }
+}
@@ -0,0 +1,170 @@
+// a file handle that keeps its own position
+//
+// reads and writes go through positional I/O (`pread`/`pwrite` on unix)
+// rather than the shared OS file offset, so handles made with `reader` can
+// read the same file from several threads at once without getting in each
+// other's way. reads can also go through a memory map of the file, see
+// `mmap`
+//
+// every handle on a file shares one slot holding it, so when the file is
+// rewritten into a new one and renamed over it, `replace` moves them all
+// over at once
+
+use std::{
+    fs::{File, Metadata},
+    io::{Read, Seek, SeekFrom, Write},
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use bytes::Bytes;
+
+use crate::mmap::FileMap;
+
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+
+pub(crate) struct PositionedFile {
+    file: Arc<RwLock<Arc<File>>>,
+    position: u64,
+    /// Set when reads go through a memory map, shared by every handle.
+    map: Option<Arc<FileMap>>,
+}
+
+impl PositionedFile {
+    pub(crate) fn new(file: File) -> Self {
+        Self {
+            file: Arc::new(RwLock::new(Arc::new(file))),
+            position: 0,
+            map: None,
+        }
+    }
+
+    /// A handle that reads through a memory map of the file. Only a file
+    /// that can't be written hands out slices of the map, see
+    /// [`PositionedFile::read_shared`].
+    pub(crate) fn mapped(file: File, writable: bool) -> Self {
+        Self {
+            map: Some(Arc::new(FileMap::new(writable))),
+            ..Self::new(file)
+        }
+    }
+
+    /// Another handle on the same file, starting at the beginning.
+    pub(crate) fn reader(&self) -> Self {
+        Self {
+            file: Arc::clone(&self.file),
+            position: 0,
+            map: self.map.clone(),
+        }
+    }
+
+    /// A handle on the file as it is now, which a later `replace` doesn't
+    /// move to the new file. Once the file is replaced the handle keeps
+    /// reading the old one, which nothing writes to any more.
+    pub(crate) fn pinned(&self) -> Self {
+        Self {
+            file: Arc::new(RwLock::new(self.file())),
+            position: 0,
+            // the file can still be written until it is replaced
+            map: self.map.as_ref().map(|_| Arc::new(FileMap::new(true))),
+        }
+    }
+
+    /// The file as it is now, which a later `replace` doesn't change.
+    fn file(&self) -> Arc<File> {
+        Arc::clone(&self.file.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Swaps the file every handle reads and writes for `file`, once it has
+    /// taken the old one's place on disk.
+    pub(crate) fn replace(&self, file: File) {
+        if let Some(map) = &self.map {
+            map.unmap();
+        }
+        *self.file.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(file);
+    }
+
+    pub(crate) fn metadata(&self) -> std::io::Result<Metadata> {
+        self.file().metadata()
+    }
+
+    pub(crate) fn set_len(&self, size: u64) -> std::io::Result<()> {
+        if let Some(map) = &self.map {
+            map.unmap();
+        }
+        self.file().set_len(size)
+    }
+
+    pub(crate) fn sync_all(&self) -> std::io::Result<()> {
+        self.file().sync_all()
+    }
+
+    /// Reads `length` bytes as a slice of the memory map, without copying
+    /// them. Returns `None`, without moving, when they can't be read that
+    /// way and have to be read as usual.
+    pub(crate) fn read_shared(&mut self, length: usize) -> std::io::Result<Option<Bytes>> {
+        let Some(map) = &self.map else {
+            return Ok(None);
+        };
+
+        let shared = map.slice(&self.file(), self.position, length)?;
+        if shared.is_some() {
+            self.position += length as u64;
+        }
+        Ok(shared)
+    }
+}
+
+impl Read for PositionedFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(map) = &self.map {
+            let read = map.read_at(&self.file(), buf, self.position)?;
+            self.position += read as u64;
+            return Ok(read);
+        }
+
+        #[cfg(unix)]
+        let read = self.file().read_at(buf, self.position)?;
+        #[cfg(windows)]
+        let read = self.file().seek_read(buf, self.position)?;
+
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for PositionedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        let written = self.file().write_at(buf, self.position)?;
+        #[cfg(windows)]
+        let written = self.file().seek_write(buf, self.position)?;
+
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PositionedFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.metadata()?.len().checked_add_signed(offset),
+        };
+
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
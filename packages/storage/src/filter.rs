@@ -0,0 +1,214 @@
+// the bloom filter of a kiv file's data keys, kept next to it in
+// `<file>.bloom`
+//
+// the filter only ever gains keys. deleting one leaves its bits set, which
+// lets more lookups through but never turns one away wrongly, until
+// compaction builds the filter again from the entries left. once more keys
+// have been added than it was sized for, it is built again twice as big.
+//
+// a writer removes the saved filter when it opens the file and saves it
+// again when it is dropped, so a filter on disk is always one a writer
+// finished with. after a crash there is none, and the next open builds it
+// by reading the file. the saved filter records how long the file was, and
+// is ignored if the file is a different length, e.g. after it was replaced
+
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::{PoisonError, RwLock},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+
+use crate::bloom::{self, BloomCounters, BloomFilter};
+
+const MAGIC_BYTES: [u8; 6] = [0, 107, 105, 118, 98, 102];
+const VERSION: u16 = 1;
+// magic, version, file length, keys, capacity and filter length
+const HEADER_LENGTH: usize = 6 + 2 + 8 + 8 + 8 + 4;
+/// The fewest keys a filter is sized for, so small files don't rebuild
+/// theirs every few writes.
+const MIN_CAPACITY: u64 = 1024;
+
+/// Where the bloom filter for the file at `path` is kept.
+pub fn bloom_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut bloom_path = OsString::from(path.as_ref().as_os_str());
+    bloom_path.push(".bloom");
+    PathBuf::from(bloom_path)
+}
+
+/// A file's filter, shared by the `Storage` that owns it and its views.
+pub(crate) struct FileBloom {
+    false_positive_rate: f64,
+    state: RwLock<State>,
+    counters: BloomCounters,
+}
+
+struct State {
+    /// `None` if the file couldn't be read through to build the filter, in
+    /// which case every lookup is let through.
+    filter: Option<BloomFilter>,
+    /// Keys added since the filter was built, counting ones added twice.
+    keys: u64,
+    /// How many keys the filter was sized for.
+    capacity: u64,
+}
+
+/// Hashes a key along with its namespace.
+fn key_hash(namespace: u16, key: &[u8]) -> (u64, u64) {
+    let mut bytes = Vec::with_capacity(2 + key.len());
+    bytes.put_u16(namespace);
+    bytes.extend_from_slice(key);
+
+    bloom::hash(&bytes)
+}
+
+impl FileBloom {
+    /// A filter that wrongly lets through about `false_positive_rate` of
+    /// lookups for missing keys. Until it is loaded or built, it lets every
+    /// lookup through and isn't saved, so a file that fails to open doesn't
+    /// leave behind an empty one.
+    pub(crate) fn new(false_positive_rate: f64) -> Self {
+        Self {
+            false_positive_rate,
+            state: RwLock::new(State {
+                filter: None,
+                keys: 0,
+                capacity: MIN_CAPACITY,
+            }),
+            counters: BloomCounters::default(),
+        }
+    }
+
+    fn state(&self) -> std::sync::RwLockReadGuard<'_, State> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn state_mut(&self) -> std::sync::RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether the file may hold `key` in `namespace`. `false` means it
+    /// certainly doesn't.
+    pub(crate) fn may_contain(&self, namespace: u16, key: &[u8]) -> bool {
+        let may_contain = match &self.state().filter {
+            Some(filter) => filter.may_contain_hash(key_hash(namespace, key)),
+            None => true,
+        };
+
+        self.counters.record(may_contain)
+    }
+
+    /// Adds a key, returning whether the filter now holds more keys than it
+    /// was sized for and should be built again.
+    pub(crate) fn insert(&self, namespace: u16, key: &[u8]) -> bool {
+        let mut state = self.state_mut();
+        let Some(filter) = &mut state.filter else {
+            return false;
+        };
+        filter.insert_hash(key_hash(namespace, key));
+        state.keys += 1;
+
+        state.keys > state.capacity
+    }
+
+    /// Replaces the filter with one of just these keys, with room for as
+    /// many again.
+    pub(crate) fn rebuild(&self, keys: &[(u16, Vec<u8>)]) {
+        let capacity = (keys.len() as u64 * 2).max(MIN_CAPACITY);
+        let mut filter = BloomFilter::new(capacity as usize, self.false_positive_rate);
+        for (namespace, key) in keys {
+            filter.insert_hash(key_hash(*namespace, key));
+        }
+
+        *self.state_mut() = State {
+            filter: Some(filter),
+            keys: keys.len() as u64,
+            capacity,
+        };
+    }
+
+    /// Lets every lookup through until the filter is next built.
+    pub(crate) fn disable(&self) {
+        self.state_mut().filter = None;
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.counters.hits()
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.counters.misses()
+    }
+
+    /// Loads the filter saved at `path`, returning whether there was one
+    /// for a file `file_length` bytes long. Anything else there is left
+    /// for the caller to build over.
+    pub(crate) fn load(&self, path: &Path, file_length: u64) -> io::Result<bool> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        let Some((body, crc)) = bytes.split_last_chunk::<4>() else {
+            return Ok(false);
+        };
+        if body.len() < HEADER_LENGTH
+            || body[..6] != MAGIC_BYTES
+            || BigEndian::read_u16(&body[6..8]) != VERSION
+            || crc32fast::hash(body) != BigEndian::read_u32(crc)
+            || BigEndian::read_u64(&body[8..16]) != file_length
+        {
+            return Ok(false);
+        }
+
+        let filter = &body[HEADER_LENGTH..];
+        if filter.len() != BigEndian::read_u32(&body[32..36]) as usize {
+            return Ok(false);
+        }
+        let Ok(filter) = BloomFilter::from_bytes(filter) else {
+            return Ok(false);
+        };
+
+        *self.state_mut() = State {
+            filter: Some(filter),
+            keys: BigEndian::read_u64(&body[16..24]),
+            capacity: BigEndian::read_u64(&body[24..32]),
+        };
+
+        Ok(true)
+    }
+
+    /// Saves the filter to `path`, for a file `file_length` bytes long. A
+    /// disabled filter isn't saved, so the next open builds it again.
+    pub(crate) fn save(&self, path: &Path, file_length: u64) -> io::Result<()> {
+        let state = self.state();
+        let Some(filter) = &state.filter else {
+            return remove(path);
+        };
+        let filter = filter.to_bytes();
+
+        let mut bytes = BytesMut::with_capacity(HEADER_LENGTH + filter.len() + 4);
+        bytes.extend_from_slice(&MAGIC_BYTES);
+        bytes.put_u16(VERSION);
+        bytes.put_u64(file_length);
+        bytes.put_u64(state.keys);
+        bytes.put_u64(state.capacity);
+        bytes.put_u32(filter.len() as u32);
+        bytes.extend_from_slice(&filter);
+        bytes.put_u32(crc32fast::hash(&bytes));
+
+        std::fs::write(path, &bytes)
+    }
+}
+
+/// Removes the filter saved at `path`, if there is one.
+pub(crate) fn remove(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
@@ -1,291 +1,2488 @@
+mod bloom;
+mod btree;
+pub mod changes;
+pub mod check;
+mod compress;
+mod encrypt;
+pub mod engine;
+mod file;
+mod filter;
+mod lock;
+mod lsm;
+mod memory;
+mod mmap;
+mod scores;
+mod sorted;
+
 use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
     fs::{File, OpenOptions},
-    io::{Read, Seek, Write},
+    io::{BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
 };
 
+pub use btree::{BTreeEngine, BTreeOptions};
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, Bytes, BytesMut};
+use changes::{changes_path, Change, ChangeLog, ChangeRecord};
+pub use compress::{train_dictionary, Codec, CompressionOptions};
+use compress::{Compression, CODEC_MASK};
+pub use encrypt::{Cipher, EncryptionKey, EncryptionOptions, KeyError};
+use encrypt::{Encryption, FileKey, ENCRYPTED, KEY_BLOCK_LENGTH};
+use engine::Locked;
+use file::PositionedFile;
+pub use filter::bloom_path;
+use filter::FileBloom;
+use lock::try_lock;
+pub use lock::LockError;
+pub use lsm::{LsmEngine, LsmOptions};
+pub use memory::MemoryEngine;
+use scores::{ScoreIndex, Version};
 
 const MAGIC_BYTES: [u8; 6] = [0, 104, 105, 107, 105, 118];
-const CURRENT_VERSION: u16 = 0;
+// version 1 added sorted set entries, version 2 set entries, version 3 the
+// namespace every entry belongs to, version 4 entry checksums, version 5
+// sequence numbers, version 6 the compression codec and version 7 the key
+// block
+const CURRENT_VERSION: u16 = 7;
+// the file identifier and version, then the block that says whether the file
+// is encrypted, see `encrypt`. files before version 7 had no key block
+const HEADER_LENGTH: u64 = 6 + 2 + KEY_BLOCK_LENGTH as u64;
+const V6_HEADER_LENGTH: u64 = 6 + 2;
+// every entry starts with its type, the id of the namespace it belongs to, a
+// CRC32 of the rest of the entry and the sequence numbers of the writes that
+// deleted and created it. the top two bits of a data entry's type flag how
+// its value is compressed, see `compress`, and the next one whether it is
+// encrypted. version 5 entries were never compressed, version 4 entries had
+// no sequence numbers, version 3 entries had no checksum either and earlier
+// ones were only their type
+const ENTRY_HEADER_LENGTH: u64 = 1 + 2 + 4 + 8 + 8;
+const V4_ENTRY_HEADER_LENGTH: u64 = 1 + 2 + 4;
+const V3_ENTRY_HEADER_LENGTH: u64 = 1 + 2;
+const V2_ENTRY_HEADER_LENGTH: u64 = 1;
+// the deleted sequence number is left out of the checksum, so an entry can be
+// marked deleted with a single write in place
+const DELETED_OFFSET: u64 = 7;
+const CREATED_OFFSET: u64 = 15;
 const DATA_ENTRY_TYPE: u8 = 0;
+const SORTED_SET_ENTRY_TYPE: u8 = 1;
+const SET_ENTRY_TYPE: u8 = 2;
+const NAMESPACE_ENTRY_TYPE: u8 = 3;
+/// The bits of an entry type that say how its value is stored.
+const ENTRY_FLAGS: u8 = CODEC_MASK | ENCRYPTED;
+
+/// The namespace every file starts with. It is never stored in the file and
+/// can't be dropped.
+pub const DEFAULT_NAMESPACE: &str = "default";
+const DEFAULT_NAMESPACE_ID: u16 = 0;
+
+/// How much of the file a snapshot copies at a time.
+const SNAPSHOT_PART_LENGTH: u64 = 1 << 20;
 
 pub struct Storage {
-    file: File,
+    file: PositionedFile,
+    /// Where the file is, so a rewrite can be put in its place.
+    path: PathBuf,
+    namespace: u16,
+    read_only: bool,
+    changes_path: PathBuf,
+    changes: Option<ChangeLog>,
+    /// Sequence number of the latest write. Every write gets the next one,
+    /// which the entries it creates and deletes are stamped with.
+    write_sequence: u64,
+    /// How many entries the file holds, deleted or not. Counted when the
+    /// file is opened and kept up to date by writes, so the size of a full
+    /// scan is known without reading the file.
+    entries: u64,
+    snapshots: OpenSnapshots,
+    /// Set on views made by [`Storage::snapshot_reader`].
+    pin: Option<Arc<SnapshotPin>>,
+    /// Shared with every view, saved to `bloom_path` when a `Storage` that
+    /// can write is dropped.
+    bloom: Arc<FileBloom>,
+    bloom_path: PathBuf,
+    /// Shared with every view, built the first time a sorted set is read.
+    scores: Arc<ScoreIndex>,
+    compression: Arc<Compression>,
+    encryption: Arc<Encryption>,
+}
+
+/// Tuning for [`Storage`].
+#[derive(Debug, Clone)]
+pub struct StorageOptions {
+    /// The share of lookups for missing keys the file's bloom filter lets
+    /// through to read the file anyway. Only used when the filter is
+    /// built, when the file is opened without one or compacted.
+    pub bloom_false_positive_rate: f64,
+    /// How values written from now on are compressed. Values already in
+    /// the file are read however they were written.
+    pub compression: CompressionOptions,
+    /// How values are encrypted, and with which key. A file is encrypted or
+    /// not from when it is created, until a compaction rotates it to a key.
+    /// Files that have a change log can't be encrypted, as the log isn't.
+    pub encryption: EncryptionOptions,
+    /// Reads the file through a memory map instead of a read call for every
+    /// field. The values of a file opened for reading only are then slices
+    /// of the map, see [`Storage::get_data_entry_bytes`].
+    pub mmap: bool,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            bloom_false_positive_rate: 0.01,
+            compression: CompressionOptions::default(),
+            encryption: EncryptionOptions::default(),
+            mmap: false,
+        }
+    }
+}
+
+/// The sequence numbers open snapshots read at, with how many are open at
+/// each. Shared by a `Storage` and every view made from it.
+type OpenSnapshots = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+/// Keeps the versions a snapshot reads from being removed, for as long as
+/// the snapshot or any view made from it is around.
+struct SnapshotPin {
+    sequence: u64,
+    /// The file as it was when the snapshot was taken, which
+    /// [`Storage::snapshot_to_with`] copies from even once a rewrite has
+    /// replaced it. Reads go to the file that replaced it, whose values the
+    /// current key can decrypt.
+    file: PositionedFile,
+    /// How long the file was, and the change log's latest sequence number
+    /// if there was one, when the snapshot was taken.
+    length: u64,
+    logged: Option<u64>,
+    snapshots: OpenSnapshots,
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        let mut snapshots = self
+            .snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(open) = snapshots.get_mut(&self.sequence) {
+            *open -= 1;
+            if *open == 0 {
+                snapshots.remove(&self.sequence);
+            }
+        }
+    }
+}
+
+/// The fixed-size start of an entry, including its sequence numbers.
+struct EntryHeader {
+    offset: u64,
+    entry_type: u8,
+    /// How the value of a data entry is compressed and whether it is
+    /// encrypted, 0 if it is stored as it was written.
+    flags: u8,
+    namespace: u16,
+    created: u64,
+    /// 0 for entries that haven't been deleted.
+    deleted: u64,
+}
+
+/// What [`Storage::retain_entries`] does with an entry.
+enum Retain {
+    /// Copies it as it is.
+    Keep,
+    /// Copies it, marked deleted by the write with this sequence number.
+    Delete(u64),
+    /// Leaves it out.
+    Drop,
+}
+
+/// Size of a namespace's contents.
+#[derive(Debug, PartialEq)]
+pub struct NamespaceStats {
+    pub name: String,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Size and layout information for a whole file.
+#[derive(Debug, PartialEq)]
+pub struct StorageStats {
+    /// Distinct keys across every namespace and value type.
+    pub keys: u64,
+    pub file_size: u64,
+    /// Bytes taken up by the file header and readable entries.
+    pub live_bytes: u64,
+    /// Bytes that don't belong to any entry.
+    pub dead_bytes: u64,
+    pub version: u16,
+    /// Lookups a bloom filter showed were for a missing key, without
+    /// reading from disk.
+    pub bloom_hits: u64,
+    /// Lookups a bloom filter let through to be read from disk.
+    pub bloom_misses: u64,
+    /// How many times bigger the values of data entries would be without
+    /// compression.
+    pub compression_ratio: f64,
+}
+
+/// An entry as it is stored in the file, read by [`Storage::for_each_entry`].
+/// Sorted sets and sets are stored as one entry per member.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Data {
+        namespace: u16,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SortedSetMember {
+        namespace: u16,
+        key: String,
+        member: String,
+        score: f64,
+    },
+    SetMember {
+        namespace: u16,
+        key: String,
+        member: String,
+    },
+    Namespace {
+        id: u16,
+        name: String,
+    },
 }
 
 struct DataEntry {
-    key: String,
-    value: String,
+    namespace: u16,
+    key: Vec<u8>,
+    value: Vec<u8>,
 }
 
 impl DataEntry {
-    fn from(key: impl Into<String>, value: impl Into<String>) -> Self {
+    fn from(namespace: u16, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Self {
+        Self {
+            namespace,
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+        }
+    }
+
+    fn to_bytes(&self, created: u64, compression: &Compression, encryption: &Encryption) -> Bytes {
+        let (codec, value) = compression.compress(&self.value);
+        let (encrypted, value) = encryption.seal(self.namespace, &self.key, value);
+        let flags = codec | encrypted;
+        let mut bytes = entry_header(DATA_ENTRY_TYPE | flags, self.namespace, created);
+        bytes.put_u16(self.key.len() as u16);
+        bytes.put(&self.key[..]);
+        bytes.put_u32(value.len() as u32);
+        bytes.put(&value[..]);
+
+        with_checksum(bytes)
+    }
+}
+
+/// A single member of a sorted set.
+///
+/// Every member is stored as its own entry so that range queries can stream
+/// through the file without loading the whole set. The score is written last
+/// and has a fixed width, which lets score updates happen in place.
+struct SortedSetEntry {
+    namespace: u16,
+    key: String,
+    member: String,
+    score: f64,
+}
+
+impl SortedSetEntry {
+    fn from(namespace: u16, key: impl Into<String>, member: impl Into<String>, score: f64) -> Self {
         Self {
+            namespace,
             key: key.into(),
-            value: value.into(),
+            member: member.into(),
+            score,
         }
     }
 
-    fn to_bytes(&self) -> Bytes {
-        let mut bytes = BytesMut::new();
+    fn to_bytes(&self, created: u64) -> Bytes {
+        let mut bytes = entry_header(SORTED_SET_ENTRY_TYPE, self.namespace, created);
+        bytes.put_u16(self.key.len() as u16);
+        bytes.put(self.key.as_bytes());
+        bytes.put_u16(self.member.len() as u16);
+        bytes.put(self.member.as_bytes());
+        bytes.put_f64(self.score);
+
+        with_checksum(bytes)
+    }
+}
+
+/// A single member of an unordered set, stored as its own entry.
+struct SetEntry {
+    namespace: u16,
+    key: String,
+    member: String,
+}
+
+impl SetEntry {
+    fn from(namespace: u16, key: impl Into<String>, member: impl Into<String>) -> Self {
+        Self {
+            namespace,
+            key: key.into(),
+            member: member.into(),
+        }
+    }
 
-        bytes.put_u8(DATA_ENTRY_TYPE);
+    fn to_bytes(&self, created: u64) -> Bytes {
+        let mut bytes = entry_header(SET_ENTRY_TYPE, self.namespace, created);
         bytes.put_u16(self.key.len() as u16);
         bytes.put(self.key.as_bytes());
-        bytes.put_u32(self.value.len() as u32);
-        bytes.put(self.value.as_bytes());
+        bytes.put_u16(self.member.len() as u16);
+        bytes.put(self.member.as_bytes());
+
+        with_checksum(bytes)
+    }
+}
+
+/// Registers a namespace name. The namespace slot of the entry holds the id
+/// being registered.
+struct NamespaceEntry {
+    id: u16,
+    name: String,
+}
+
+impl NamespaceEntry {
+    fn from(id: u16, name: impl Into<String>) -> Self {
+        Self {
+            id,
+            name: name.into(),
+        }
+    }
 
-        return Bytes::from(bytes);
+    fn to_bytes(&self, created: u64) -> Bytes {
+        let mut bytes = entry_header(NAMESPACE_ENTRY_TYPE, self.id, created);
+        bytes.put_u16(self.name.len() as u16);
+        bytes.put(self.name.as_bytes());
+
+        with_checksum(bytes)
+    }
+}
+
+/// Starts an entry created by the write with sequence number `created`.
+fn entry_header(entry_type: u8, namespace: u16, created: u64) -> BytesMut {
+    let mut bytes = BytesMut::new();
+
+    bytes.put_u8(entry_type);
+    bytes.put_u16(namespace);
+    // checksum, filled in once the entry is complete
+    bytes.put_u32(0);
+    // not deleted yet
+    bytes.put_u64(0);
+    bytes.put_u64(created);
+
+    bytes
+}
+
+/// Computes an entry's checksum, which covers everything but the checksum
+/// itself and the deleted sequence number.
+fn entry_checksum(entry: &[u8]) -> u32 {
+    checksum(entry, CREATED_OFFSET as usize)
+}
+
+/// Checksums the entry type and namespace, then everything from `from` on.
+fn checksum(entry: &[u8], from: usize) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&entry[..3]);
+    hasher.update(&entry[from..]);
+    hasher.finalize()
+}
+
+fn with_checksum(mut entry: BytesMut) -> Bytes {
+    let checksum = entry_checksum(&entry);
+    BigEndian::write_u32(&mut entry[3..7], checksum);
+    Bytes::from(entry)
+}
+
+/// Makes sure a field fits in the length prefix it is written with.
+fn check_length(field: &str, length: usize, max: usize) -> std::io::Result<()> {
+    if length > max {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is {} bytes long, the maximum is {}", field, length, max),
+        ));
     }
+
+    Ok(())
+}
+
+fn unencrypted_change_log() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "encrypted files can't have a change log, as it isn't encrypted",
+    )
 }
 
-impl Into<Bytes> for DataEntry {
-    fn into(self) -> Bytes {
-        return self.to_bytes();
+/// Where `path` leads once links and relative parts are resolved, whether or
+/// not there is a file there yet.
+fn resolve(path: &Path) -> std::io::Result<PathBuf> {
+    match path.canonicalize() {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let Some(name) = path.file_name() else {
+                return Err(err);
+            };
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            Ok(parent.canonicalize()?.join(name))
+        }
+        result => result,
     }
 }
 
+fn invalid_data(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+/// Orders sorted set members by score, then by member.
+fn compare_scored(a: &(String, f64), b: &(String, f64)) -> Ordering {
+    a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0))
+}
+
+/// Encodes an entry as it is stored in the file, created by the write with
+/// sequence number `sequence`.
+fn encode_entry(
+    entry: &Entry,
+    sequence: u64,
+    compression: &Compression,
+    encryption: &Encryption,
+) -> std::io::Result<Bytes> {
+    let bytes = match entry {
+        Entry::Data {
+            namespace,
+            key,
+            value,
+        } => {
+            check_length("key", key.len(), u16::MAX as usize)?;
+            check_length("value", value.len(), u32::MAX as usize)?;
+            DataEntry::from(*namespace, key, value).to_bytes(sequence, compression, encryption)
+        }
+        Entry::SortedSetMember {
+            namespace,
+            key,
+            member,
+            score,
+        } => {
+            check_length("key", key.len(), u16::MAX as usize)?;
+            check_length("member", member.len(), u16::MAX as usize)?;
+            SortedSetEntry::from(*namespace, key, member, *score).to_bytes(sequence)
+        }
+        Entry::SetMember {
+            namespace,
+            key,
+            member,
+        } => {
+            check_length("key", key.len(), u16::MAX as usize)?;
+            check_length("member", member.len(), u16::MAX as usize)?;
+            SetEntry::from(*namespace, key, member).to_bytes(sequence)
+        }
+        Entry::Namespace { id, name } => {
+            check_length("namespace name", name.len(), u16::MAX as usize)?;
+            NamespaceEntry::from(*id, name).to_bytes(sequence)
+        }
+    };
+
+    Ok(bytes)
+}
+
 impl Storage {
-    fn initialize_file(file: &mut File) {
+    fn initialize_file(
+        file: &mut impl Write,
+        key_block: &[u8; KEY_BLOCK_LENGTH],
+    ) -> std::io::Result<()> {
         let mut bytes = BytesMut::new();
 
         // write file identifier
         bytes.extend_from_slice(&MAGIC_BYTES);
         // write version number
         bytes.put_u16(CURRENT_VERSION);
+        // write how the file is encrypted
+        bytes.extend_from_slice(key_block);
 
-        file.write_all(&bytes).expect("failed to write to file");
+        file.write_all(&bytes)
     }
 
+    /// Opens the file for reading and writing, creating it if it doesn't
+    /// exist. The file is locked until the `Storage` is dropped, so no other
+    /// process can open it at the same time.
     pub fn open(path: impl Into<String>) -> std::io::Result<Self> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
+        Self::open_with(path.into(), StorageOptions::default(), false)
+    }
+
+    /// Like [`Storage::open`], tuned by `options`.
+    pub fn open_with_options(
+        path: impl Into<String>,
+        options: StorageOptions,
+    ) -> std::io::Result<Self> {
+        Self::open_with(path.into(), options, false)
+    }
+
+    /// Opens an existing file for reading only. Any number of processes can
+    /// do this at once, but not while the file is open for writing. Every
+    /// write fails, and the change log is left alone.
+    pub fn open_read_only(path: impl Into<String>) -> std::io::Result<Self> {
+        Self::open_with(path.into(), StorageOptions::default(), true)
+    }
+
+    /// Like [`Storage::open_read_only`], tuned by `options`.
+    pub fn open_read_only_with_options(
+        path: impl Into<String>,
+        options: StorageOptions,
+    ) -> std::io::Result<Self> {
+        Self::open_with(path.into(), options, true)
+    }
+
+    fn open_with(path: String, options: StorageOptions, read_only: bool) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(!read_only)
+            .truncate(false)
+            .write(!read_only)
             .read(true)
-            .open(path.into())?;
+            .open(&path)?;
+
+        // the lock has to be held before anything is read, as another
+        // process could be halfway through rewriting the file
+        try_lock(&file, read_only)?;
+        let mut file = if options.mmap {
+            PositionedFile::mapped(file, !read_only)
+        } else {
+            PositionedFile::new(file)
+        };
+
+        // keep the change log going if the file has one
+        let changes_path = changes_path(&path);
+        let changes = if changes_path.exists() && !read_only {
+            Some(ChangeLog::open(&changes_path)?)
+        } else {
+            None
+        };
+
+        let storage = |file| Self {
+            file,
+            path: PathBuf::from(&path),
+            namespace: DEFAULT_NAMESPACE_ID,
+            read_only,
+            changes_path,
+            changes,
+            write_sequence: 0,
+            entries: 0,
+            snapshots: OpenSnapshots::default(),
+            pin: None,
+            bloom: Arc::new(FileBloom::new(options.bloom_false_positive_rate)),
+            bloom_path: bloom_path(&path),
+            scores: Arc::default(),
+            compression: Arc::new(Compression::new(options.compression)),
+            encryption: Arc::default(),
+        };
 
         // see if file needs to be initialized
-        let mut buf = [0];
-        if file.read(&mut buf)? == 0 {
-            Self::initialize_file(&mut file);
+        let mut header = vec![];
+        (&mut file).take(HEADER_LENGTH).read_to_end(&mut header)?;
+        if header.is_empty() && !read_only {
+            let encryption = Encryption::create(&options.encryption);
+            Self::initialize_file(&mut file, &encryption.key_block())?;
+            let mut storage = storage(file);
+            storage.encryption = Arc::new(encryption);
+            storage.check_change_log()?;
+            storage.bloom.rebuild(&[]);
+            // left behind by a file that was at the same path
+            filter::remove(&storage.bloom_path)?;
+
+            return Ok(storage);
+        }
+
+        // validate file, leaving anything that isn't ours alone
+        if header.len() < V6_HEADER_LENGTH as usize || header[..6] != MAGIC_BYTES {
+            return Err(invalid_data("not a kiv file"));
+        }
+
+        let version = BigEndian::read_u16(&header[6..8]);
+        let mut storage = storage(file);
+        match version {
+            CURRENT_VERSION if header.len() < HEADER_LENGTH as usize => {
+                return Err(invalid_data("file header is cut short"))
+            }
+            CURRENT_VERSION => {
+                storage.encryption = Arc::new(Encryption::open(
+                    &options.encryption,
+                    &header[V6_HEADER_LENGTH as usize..],
+                )?);
+            }
+            0..=6 if read_only => {
+                return Err(invalid_data(format!(
+                    "file version {} has to be upgraded, open it for writing first",
+                    version
+                )))
+            }
+            0..=6 => {
+                // files were never encrypted before
+                storage.encryption = Arc::new(Encryption::open(
+                    &options.encryption,
+                    &encrypt::key_block(None),
+                )?);
+                storage.upgrade(version)?;
+            }
+            _ => {
+                return Err(invalid_data(format!(
+                    "unsupported file version {}",
+                    version
+                )))
+            }
+        }
+        storage.check_change_log()?;
+        let last = storage.read_counters()?;
+        if let Some(last) = last.filter(|_| !read_only) {
+            storage.finish_update(&last)?;
+        }
 
-            return Ok(Self { file });
+        // upgrading changes the file's length, so the filter is built again
+        let length = storage.file.metadata()?.len();
+        if !storage.bloom.load(&storage.bloom_path, length)? {
+            storage.build_bloom();
+        }
+        if !read_only {
+            // it is saved again once the file is closed, so a crash before
+            // then leaves none to go out of date
+            filter::remove(&storage.bloom_path)?;
         }
 
-        // validate file
-        let check_mb = [0u8; 6];
-        if file.read(&mut buf)? != 6 || check_mb != MAGIC_BYTES {
-            // reinitialize file
-            file.set_len(0)?;
-            Self::initialize_file(&mut file);
+        Ok(storage)
+    }
+
+    /// Whether the file is encrypted, or will be once it is compacted.
+    fn encrypts(&self) -> bool {
+        self.encryption.is_encrypted() || self.encryption.rotation().is_some()
+    }
+
+    /// Makes sure a file that is or will be encrypted has no change log,
+    /// which would keep its values in the clear.
+    fn check_change_log(&self) -> std::io::Result<()> {
+        if self.encrypts() && self.changes_path.exists() {
+            return Err(unencrypted_change_log());
         }
 
-        return Ok(Self { file });
+        Ok(())
+    }
+
+    /// Rewrites a file from before version 7 in the current version, making
+    /// room for the key block in its header. Entries from before version 5
+    /// get a checksum and sequence numbers, and count as created before any
+    /// write. Those from before version 3 belong to the default namespace.
+    /// Later entries are copied as they are.
+    fn upgrade(&mut self, version: u16) -> std::io::Result<()> {
+        let header_length = match version {
+            0..=2 => V2_ENTRY_HEADER_LENGTH,
+            3 => V3_ENTRY_HEADER_LENGTH,
+            _ => V4_ENTRY_HEADER_LENGTH,
+        };
+
+        let key_block = self.encryption.key_block();
+        self.rewrite(&key_block, |storage, out| {
+            storage
+                .file
+                .seek(std::io::SeekFrom::Start(V6_HEADER_LENGTH))?;
+            if version >= 5 {
+                std::io::copy(&mut storage.file, out)?;
+                return Ok(());
+            }
+
+            loop {
+                let start = storage.file.stream_position()?;
+                let mut header = [0u8; V4_ENTRY_HEADER_LENGTH as usize];
+                if storage.file.read(&mut header[..1])? == 0 {
+                    break;
+                }
+                storage
+                    .file
+                    .read_exact(&mut header[1..header_length as usize])?;
+                // the rest of the entry is laid out the same in every version
+                storage.skip_entry(header[0])?;
+                let end = storage.file.stream_position()?;
+
+                let namespace = if version >= 3 {
+                    BigEndian::read_u16(&header[1..3])
+                } else {
+                    DEFAULT_NAMESPACE_ID
+                };
+                let mut entry = entry_header(header[0], namespace, 0);
+                let mut body = vec![0u8; (end - start - header_length) as usize];
+                storage
+                    .file
+                    .seek(std::io::SeekFrom::Start(start + header_length))?;
+                storage.file.read_exact(&mut body)?;
+                entry.put(&body[..]);
+                out.write_all(&with_checksum(entry))?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Replaces the file with one headed by `key_block` and holding what
+    /// `write` writes after that. The new file is written next to this one,
+    /// synced and renamed over it, so a crash leaves one file or the other,
+    /// never part of each. If anything fails the file is left as it was.
+    fn rewrite<F>(&mut self, key_block: &[u8; KEY_BLOCK_LENGTH], write: F) -> std::io::Result<()>
+    where
+        F: FnOnce(&mut Self, &mut BufWriter<&File>) -> std::io::Result<()>,
+    {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+
+        let result = (|| {
+            let temp = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .read(true)
+                .open(&temp_path)?;
+            // locked before it takes the file's place, so no other process
+            // can open it in between
+            temp.lock()?;
+            temp.set_permissions(self.file.metadata()?.permissions())?;
+
+            let mut out = BufWriter::new(&temp);
+            Self::initialize_file(&mut out, key_block)?;
+            write(self, &mut out)?;
+            out.flush()?;
+            drop(out);
+            temp.sync_all()?;
+            std::fs::rename(&temp_path, &self.path)?;
+            Ok(temp)
+        })();
+
+        match result {
+            Ok(temp) => {
+                self.file.replace(temp);
+                self.scores.clear();
+                Ok(())
+            }
+            Err(err) => {
+                let _ = std::fs::remove_file(&temp_path);
+                Err(err)
+            }
+        }
     }
 
     pub fn write_data_entry(
         &mut self,
-        key: impl Into<String>,
-        value: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
     ) -> std::io::Result<()> {
-        let entry = DataEntry::from(key, value);
+        self.check_writable()?;
+        let entry = DataEntry::from(self.namespace, key, value);
+        check_length("key", entry.key.len(), u16::MAX as usize)?;
+        check_length("value", entry.value.len(), u32::MAX as usize)?;
 
+        let sequence = self.next_sequence();
         self.file.seek(std::io::SeekFrom::End(0))?;
-        self.file.write_all(&entry.to_bytes())?;
+        self.file
+            .write_all(&entry.to_bytes(sequence, &self.compression, &self.encryption))?;
+        self.entries += 1;
+        if self.bloom.insert(entry.namespace, &entry.key) {
+            self.build_bloom();
+        }
 
-        return Ok(());
+        self.log(|| {
+            Change::Put(Entry::Data {
+                namespace: entry.namespace,
+                key: entry.key,
+                value: entry.value,
+            })
+        })
     }
 
-    pub fn get_data_entry(&mut self, search_key: &String) -> std::io::Result<Option<String>> {
-        let entry_offset = if let Some(offset) = self.get_data_entry_offset(search_key)? {
+    pub fn get_data_entry(
+        &mut self,
+        search_key: impl AsRef<[u8]>,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self.get_data_entry_bytes(search_key)?.map(Vec::from))
+    }
+
+    /// Like [`Storage::get_data_entry`], returning the value as [`Bytes`].
+    /// When the file is memory mapped and opened for reading only, values
+    /// stored as they were written are slices of the map, which keep it
+    /// mapped for as long as they are around.
+    pub fn get_data_entry_bytes(
+        &mut self,
+        search_key: impl AsRef<[u8]>,
+    ) -> std::io::Result<Option<Bytes>> {
+        let entry_offset = if let Some(offset) = self.get_data_entry_offset(search_key.as_ref())? {
             offset
         } else {
             return Ok(None);
         };
-        // adding 1 to offset to skip entry type
-        self.file.seek(std::io::SeekFrom::Start(entry_offset + 1))?;
+        // the entry type says how the value is stored
+        self.file.seek(std::io::SeekFrom::Start(entry_offset))?;
+        let mut entry_type = [0];
+        self.file.read_exact(&mut entry_type)?;
+        let flags = entry_type[0] & ENTRY_FLAGS;
 
-        // read key length
-        let mut key_len = [0, 0];
-        if self.file.read(&mut key_len)? != 2 {
-            panic!("unknown error");
-        }
-        let key_len = BigEndian::read_u16(&key_len) as usize;
+        // skip the rest of the header
+        self.file
+            .seek(std::io::SeekFrom::Start(entry_offset + ENTRY_HEADER_LENGTH))?;
 
-        // skip key, we don't need it
-        self.file.seek(std::io::SeekFrom::Current(key_len as i64))?;
+        // skip key, unless the value was encrypted along with it
+        let key_len = self.read_u16()?;
+        let key = if flags & ENCRYPTED != 0 {
+            self.read_bytes(key_len as usize)?
+        } else {
+            self.file.seek(std::io::SeekFrom::Current(key_len as i64))?;
+            vec![]
+        };
 
         // read value length
-        let mut value_len = [0, 0, 0, 0];
-        if self.file.read(&mut value_len)? != 4 {
-            panic!("unknown error");
-        }
-        let value_len = BigEndian::read_u32(&value_len) as usize;
+        let value_len = self.read_u32()? as usize;
 
         // read value
-        let mut value = vec![0u8; value_len];
-        if self.file.read(&mut value)? != value_len {
-            panic!("unknown error");
+        let value = self.read_value(value_len)?;
+
+        Ok(Some(self.decode_value(
+            flags,
+            self.namespace,
+            &key,
+            value,
+        )?))
+    }
+
+    /// Turns the value of a data entry as it is stored back into the value
+    /// that was written.
+    fn decode_value(
+        &self,
+        flags: u8,
+        namespace: u16,
+        key: &[u8],
+        stored: Bytes,
+    ) -> std::io::Result<Bytes> {
+        if flags == 0 {
+            return Ok(stored);
         }
-        // TODO: proper error handling
-        let value = String::from_utf8(value).expect("failure");
 
-        return Ok(Some(value));
+        let value = self
+            .encryption
+            .open_value(flags, namespace, key, stored.into())?;
+        Ok(self
+            .compression
+            .decompress(flags & CODEC_MASK, value)?
+            .into())
     }
 
-    fn get_data_entry_offset(&mut self, search_key: &String) -> std::io::Result<Option<u64>> {
-        // skip file header
-        self.file.seek(std::io::SeekFrom::Start(8))?;
+    /// Reads the header of the next entry this view can see, starting at the
+    /// current position, returning the entry's offset, type and namespace.
+    /// Returns `None` at the end of the file.
+    fn read_entry_header(&mut self) -> std::io::Result<Option<(u64, u8, u16)>> {
+        Ok(self
+            .read_visible_header()?
+            .map(|header| (header.offset, header.entry_type, header.namespace)))
+    }
+
+    /// Like [`Storage::read_entry_header`], returning the whole header.
+    fn read_visible_header(&mut self) -> std::io::Result<Option<EntryHeader>> {
+        while let Some(header) = self.read_versioned_header()? {
+            if self.can_see(&header) {
+                return Ok(Some(header));
+            }
+            self.skip_entry(header.entry_type)?;
+        }
+
+        Ok(None)
+    }
+
+    /// Reads the header of the entry at the current position, whether or not
+    /// this view can see it. Returns `None` at the end of the file.
+    fn read_versioned_header(&mut self) -> std::io::Result<Option<EntryHeader>> {
         let mut buf = [0];
-        loop {
-            match self.file.read(&mut buf) {
-                Ok(0) => {
-                    return Ok(None);
-                }
-                Ok(_) => {
-                    match &buf[0] {
-                        // data entry
-                        &DATA_ENTRY_TYPE => {
-                            // store current offset incase it's the one we need
-                            // subtracting 1 to make up for entry type
-                            let offset =
-                                (self.file.seek(std::io::SeekFrom::Current(0))? - 1) as u64;
-
-                            // read key length
-                            let mut key_len = [0, 0];
-                            if self.file.read(&mut key_len)? != 2 {
-                                panic!("unknown error");
-                            }
-                            let key_len = BigEndian::read_u16(&key_len) as usize;
-
-                            // read key
-                            let mut key = vec![0u8; key_len];
-                            if self.file.read(&mut key)? != key_len {
-                                panic!("unknown error");
-                            }
-                            // TODO: proper error handling
-                            let key = String::from_utf8(key).expect("failure");
-
-                            // read value length
-                            let mut value_len = [0, 0, 0, 0];
-                            if self.file.read(&mut value_len)? != 4 {
-                                panic!("unknown error");
-                            }
-                            let value_len = BigEndian::read_u32(&value_len) as usize;
-
-                            // if read key matches search key, return offset
-                            if &key == search_key {
-                                return Ok(Some(offset));
-                            }
-
-                            // else, skip the value
-                            self.file
-                                .seek(std::io::SeekFrom::Current(value_len as i64))?;
-                        }
-                        _ => panic!("unknown data entry"),
-                    }
-                }
-                e => panic!("{:#?}", e),
+        match self.file.read(&mut buf) {
+            Ok(0) => Ok(None),
+            Ok(_) => {
+                // subtracting 1 to make up for entry type
+                let offset = self.file.stream_position()? - 1;
+                let namespace = self.read_u16()?;
+                // checksums are only verified by `check`
+                self.read_u32()?;
+                let deleted = self.read_u64()?;
+                let created = self.read_u64()?;
+                Ok(Some(EntryHeader {
+                    offset,
+                    entry_type: buf[0] & !ENTRY_FLAGS,
+                    flags: buf[0] & ENTRY_FLAGS,
+                    namespace,
+                    created,
+                    deleted,
+                }))
             }
+            Err(e) => Err(e),
         }
     }
 
-    fn get_data_entry_length(&mut self, search_key: &String) -> std::io::Result<Option<u64>> {
-        let entry_offset = if let Some(offset) = self.get_data_entry_offset(search_key)? {
-            offset
-        } else {
-            return Ok(None);
-        };
-        // adding 1 to offset to skip entry type
-        self.file.seek(std::io::SeekFrom::Start(entry_offset + 1))?;
+    /// Returns whether an entry existed at the sequence number this view
+    /// reads at. Views that aren't snapshots only see entries that haven't
+    /// been deleted.
+    fn can_see(&self, header: &EntryHeader) -> bool {
+        self.sees(header.created, header.deleted)
+    }
 
-        // read key length
-        let mut key_len = [0, 0];
-        if self.file.read(&mut key_len)? != 2 {
-            panic!("unknown error");
+    /// Like [`Storage::can_see`], for an entry created and deleted by the
+    /// writes with those sequence numbers.
+    fn sees(&self, created: u64, deleted: u64) -> bool {
+        match &self.pin {
+            Some(pin) => created <= pin.sequence && (deleted == 0 || deleted > pin.sequence),
+            None => deleted == 0,
         }
-        let key_len = BigEndian::read_u16(&key_len) as usize;
+    }
 
-        // skip key, we don't need it
-        self.file.seek(std::io::SeekFrom::Current(key_len as i64))?;
+    /// Finds the highest sequence number in the file and counts its
+    /// entries, returning the header of the last one. A damaged entry ends
+    /// the search early; it is left for the reads that reach it to report.
+    fn read_counters(&mut self) -> std::io::Result<Option<EntryHeader>> {
+        let (mut sequence, mut entries, mut last) = (0, 0, None);
 
-        // read value length
-        let mut value_len = [0, 0, 0, 0];
-        if self.file.read(&mut value_len)? != 4 {
-            panic!("unknown error");
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Ok(Some(header)) = self.read_versioned_header() {
+            sequence = sequence.max(header.created).max(header.deleted);
+            if self.skip_entry(header.entry_type).is_err() {
+                break;
+            }
+            entries += 1;
+            last = Some(header);
         }
-        let value_len = BigEndian::read_u32(&value_len) as usize;
-
-        // entry type, key length length, key length, value length length, value length
-        let entry_length = 1 + 2 + key_len + 4 + value_len;
 
-        return Ok(Some(entry_length as u64));
+        self.write_sequence = sequence;
+        self.entries = entries;
+        Ok(last)
     }
 
-    pub fn delete_data_entry(&mut self, search_key: &String) -> std::io::Result<()> {
-        let entry_offset = if let Some(offset) = self.get_data_entry_offset(search_key)? {
-            offset
-        } else {
+    /// Updates append the new version of an entry before marking the old
+    /// one deleted, so one cut short leaves both versions readable, with the
+    /// new one last in the file. This marks the old one deleted, as the
+    /// update would have.
+    fn finish_update(&mut self, last: &EntryHeader) -> std::io::Result<()> {
+        if last.deleted != 0 || !matches!(last.entry_type, DATA_ENTRY_TYPE | SORTED_SET_ENTRY_TYPE)
+        {
             return Ok(());
-        };
+        }
 
-        let entry_length = if let Some(length) = self.get_data_entry_length(search_key)? {
-            length
-        } else {
-            return Ok(());
+        // data entries are told apart by key, sorted set members by key
+        // and member
+        let read_name = |storage: &mut Self, entry_type| -> std::io::Result<Vec<u8>> {
+            let key_len = storage.read_u16()? as usize;
+            let mut name = storage.read_bytes(key_len)?;
+            if entry_type == SORTED_SET_ENTRY_TYPE {
+                let member_len = storage.read_u16()? as usize;
+                name.push(0);
+                name.extend(storage.read_bytes(member_len)?);
+            }
+            Ok(name)
         };
-
         self.file
-            .seek(std::io::SeekFrom::Start(entry_offset + entry_length))?;
-        // read the data we are shifting
-        let mut data_to_shift = vec![];
-        self.file.read_to_end(&mut data_to_shift)?;
-        // truncate file
-        self.file.set_len(entry_offset)?;
-        // seek back to correct location for data to shift
-        self.file.seek(std::io::SeekFrom::Start(entry_offset))?;
-        // write back the data we needed to shift
-        self.file.write_all(&data_to_shift)?;
+            .seek(std::io::SeekFrom::Start(last.offset + ENTRY_HEADER_LENGTH))?;
+        let updated = read_name(self, last.entry_type)?;
+
+        let mut position = HEADER_LENGTH;
+        while position < last.offset {
+            self.file.seek(std::io::SeekFrom::Start(position))?;
+            let Some(header) = self.read_versioned_header()? else {
+                break;
+            };
+            if header.deleted == 0
+                && header.entry_type == last.entry_type
+                && header.namespace == last.namespace
+                && read_name(self, header.entry_type)? == updated
+            {
+                return self.mark_deleted(header.offset, last.created);
+            }
+
+            self.file.seek(std::io::SeekFrom::Start(
+                header.offset + ENTRY_HEADER_LENGTH,
+            ))?;
+            self.skip_entry(header.entry_type)?;
+            position = self.file.stream_position()?;
+        }
 
-        return Ok(());
+        Ok(())
     }
 
-    pub fn update_data_entry(
-        &mut self,
-        search_key: &String,
-        new_value: &String,
-    ) -> std::io::Result<()> {
-        let entry_offset = if let Some(offset) = self.get_data_entry_offset(search_key)? {
-            offset
-        } else {
-            return Ok(());
-        };
+    /// Returns whether an entry is one the current namespace should see.
+    fn is_visible(&self, entry_type: u8, namespace: u16, wanted_type: u8) -> bool {
+        entry_type == wanted_type && namespace == self.namespace
+    }
 
-        let entry_length = if let Some(length) = self.get_data_entry_length(search_key)? {
-            length
-        } else {
-            return Ok(());
-        };
+    /// Skips the rest of an entry whose header has already been read.
+    fn skip_entry(&mut self, entry_type: u8) -> std::io::Result<()> {
+        match entry_type {
+            DATA_ENTRY_TYPE => {
+                let key_len = self.read_u16()?;
+                self.file.seek(std::io::SeekFrom::Current(key_len as i64))?;
+                let value_len = self.read_u32()?;
+                self.file
+                    .seek(std::io::SeekFrom::Current(value_len as i64))?;
+            }
+            SORTED_SET_ENTRY_TYPE => {
+                let key_len = self.read_u16()?;
+                self.file.seek(std::io::SeekFrom::Current(key_len as i64))?;
+                let member_len = self.read_u16()?;
+                // member, then the score
+                self.file
+                    .seek(std::io::SeekFrom::Current(member_len as i64 + 8))?;
+            }
+            SET_ENTRY_TYPE => {
+                let key_len = self.read_u16()?;
+                self.file.seek(std::io::SeekFrom::Current(key_len as i64))?;
+                let member_len = self.read_u16()?;
+                self.file
+                    .seek(std::io::SeekFrom::Current(member_len as i64))?;
+            }
+            NAMESPACE_ENTRY_TYPE => {
+                let name_len = self.read_u16()?;
+                self.file
+                    .seek(std::io::SeekFrom::Current(name_len as i64))?;
+            }
+            _ => {
+                return Err(invalid_data(format!("unknown entry type {}", entry_type)));
+            }
+        }
 
-        let new_entry = DataEntry::from(search_key.clone(), new_value.clone());
+        Ok(())
+    }
 
-        self.file
-            .seek(std::io::SeekFrom::Start(entry_offset + entry_length))?;
-        // read the data we are shifting
-        let mut data_to_shift = vec![];
-        self.file.read_to_end(&mut data_to_shift)?;
-        // truncate file
-        self.file.set_len(entry_offset)?;
-        // seek back to correct location for new entry
-        self.file.seek(std::io::SeekFrom::End(0))?;
-        // write new record
-        self.file.write_all(&new_entry.to_bytes())?;
-        // seek back to correct location for data to shift
-        self.file.seek(std::io::SeekFrom::End(0))?;
-        // write back the data we needed to shift
-        self.file.write_all(&data_to_shift)?;
+    // the read helpers fail with `UnexpectedEof` when an entry is cut short
+    // and `InvalidData` when its contents don't decode
+
+    fn read_u16(&mut self) -> std::io::Result<u16> {
+        let mut buf = [0, 0];
+        self.file.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u16(&buf))
+    }
+
+    fn read_u32(&mut self) -> std::io::Result<u32> {
+        let mut buf = [0, 0, 0, 0];
+        self.file.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u32(&buf))
+    }
+
+    fn read_u64(&mut self) -> std::io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.file.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u64(&buf))
+    }
+
+    fn read_f64(&mut self) -> std::io::Result<f64> {
+        let mut buf = [0u8; 8];
+        self.file.read_exact(&mut buf)?;
+        Ok(BigEndian::read_f64(&buf))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> std::io::Result<Vec<u8>> {
+        // don't trust the length enough to allocate it up front
+        let mut buf = vec![];
+        (&mut self.file).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf)
+    }
+
+    /// Reads a value, as a slice of the memory map where it can be.
+    fn read_value(&mut self, len: usize) -> std::io::Result<Bytes> {
+        match self.file.read_shared(len)? {
+            Some(value) => Ok(value),
+            None => Ok(self.read_bytes(len)?.into()),
+        }
+    }
+
+    fn read_string(&mut self, len: usize) -> std::io::Result<String> {
+        let buf = self.read_bytes(len)?;
+        String::from_utf8(buf).map_err(|_| invalid_data("string is not valid UTF-8"))
+    }
+
+    /// Builds the bloom filter again from the keys of every data entry in
+    /// the file, including deleted ones a snapshot may still read. A file
+    /// that can't be read to the end gets a filter that lets every lookup
+    /// through, leaving the damage for the lookups to report.
+    fn build_bloom(&mut self) {
+        let keys = (|| {
+            let mut keys = vec![];
+            self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+            while let Some(header) = self.read_versioned_header()? {
+                if header.entry_type != DATA_ENTRY_TYPE {
+                    self.skip_entry(header.entry_type)?;
+                    continue;
+                }
+
+                let key_len = self.read_u16()? as usize;
+                let key = self.read_bytes(key_len)?;
+                let value_len = self.read_u32()?;
+                self.file
+                    .seek(std::io::SeekFrom::Current(value_len as i64))?;
+                keys.push((header.namespace, key));
+            }
+
+            Ok::<_, std::io::Error>(keys)
+        })();
+
+        match keys {
+            Ok(keys) => self.bloom.rebuild(&keys),
+            Err(_) => self.bloom.disable(),
+        }
+    }
+
+    fn get_data_entry_offset(&mut self, search_key: &[u8]) -> std::io::Result<Option<u64>> {
+        if !self.bloom.may_contain(self.namespace, search_key) {
+            return Ok(None);
+        }
+
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some((offset, entry_type, namespace)) = self.read_entry_header()? {
+            if !self.is_visible(entry_type, namespace, DATA_ENTRY_TYPE) {
+                self.skip_entry(entry_type)?;
+                continue;
+            }
+
+            // read key
+            let key_len = self.read_u16()? as usize;
+            let key = self.read_bytes(key_len)?;
+
+            // read value length
+            let value_len = self.read_u32()?;
+
+            // if read key matches search key, return offset
+            if key == search_key {
+                return Ok(Some(offset));
+            }
 
-        return Ok(());
+            // else, skip the value
+            self.file
+                .seek(std::io::SeekFrom::Current(value_len as i64))?;
+        }
+
+        Ok(None)
+    }
+
+    /// Removes the entry at `offset` for the write with sequence number
+    /// `sequence`. The entry is marked deleted, then the file is rewritten
+    /// without it. While a snapshot is open it is only marked, as the
+    /// snapshot may still need to read it, and is left for
+    /// [`Storage::compact`] to remove.
+    fn remove_entry(&mut self, offset: u64, sequence: u64) -> std::io::Result<()> {
+        self.mark_deleted(offset, sequence)?;
+        self.drop_deleted();
+        Ok(())
+    }
+
+    fn mark_deleted(&mut self, offset: u64, sequence: u64) -> std::io::Result<()> {
+        let mut deleted = [0u8; 8];
+        BigEndian::write_u64(&mut deleted, sequence);
+        self.file
+            .seek(std::io::SeekFrom::Start(offset + DELETED_OFFSET))?;
+        self.file.write_all(&deleted)
+    }
+
+    /// Rewrites the file without the entries marked deleted, unless a
+    /// snapshot is open that may still read them. The write that marked
+    /// them is already made, so if the file can't be rewritten they stay
+    /// marked, as they would with a snapshot open, for
+    /// [`Storage::compact`] to remove.
+    fn drop_deleted(&mut self) {
+        if self.oldest_snapshot().is_some() {
+            return;
+        }
+
+        let _ = self.retain_entries(
+            |header| match header.deleted {
+                0 => Retain::Keep,
+                _ => Retain::Drop,
+            },
+            None,
+        );
+    }
+
+    pub fn delete_data_entry(&mut self, search_key: impl AsRef<[u8]>) -> std::io::Result<()> {
+        self.check_writable()?;
+        let search_key = search_key.as_ref();
+        let entry_offset = if let Some(offset) = self.get_data_entry_offset(search_key)? {
+            offset
+        } else {
+            return Ok(());
+        };
+
+        let sequence = self.next_sequence();
+        self.remove_entry(entry_offset, sequence)?;
+
+        let namespace = self.namespace;
+        self.log(|| Change::DeleteData {
+            namespace,
+            key: search_key.to_vec(),
+        })
+    }
+
+    pub fn update_data_entry(
+        &mut self,
+        search_key: impl AsRef<[u8]>,
+        new_value: impl AsRef<[u8]>,
+    ) -> std::io::Result<()> {
+        self.check_writable()?;
+        let search_key = search_key.as_ref();
+        check_length("value", new_value.as_ref().len(), u32::MAX as usize)?;
+        let entry_offset = if let Some(offset) = self.get_data_entry_offset(search_key)? {
+            offset
+        } else {
+            return Ok(());
+        };
+
+        let new_entry = DataEntry::from(self.namespace, search_key, new_value);
+        let sequence = self.next_sequence();
+
+        // the new version goes in before the old one is marked deleted, so
+        // an update cut short leaves the key readable either way
+        self.file.seek(std::io::SeekFrom::End(0))?;
+        self.file
+            .write_all(&new_entry.to_bytes(sequence, &self.compression, &self.encryption))?;
+        self.entries += 1;
+        self.mark_deleted(entry_offset, sequence)?;
+        self.drop_deleted();
+
+        self.log(|| {
+            Change::Put(Entry::Data {
+                namespace: new_entry.namespace,
+                key: new_entry.key,
+                value: new_entry.value,
+            })
+        })
+    }
+
+    /// Returns every data entry in the current namespace whose key starts
+    /// with `prefix`, ordered by key.
+    pub fn scan_data_entries(
+        &mut self,
+        prefix: impl AsRef<[u8]>,
+    ) -> std::io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = prefix.as_ref();
+        let mut entries = vec![];
+
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some(header) = self.read_visible_header()? {
+            if !self.is_visible(header.entry_type, header.namespace, DATA_ENTRY_TYPE) {
+                self.skip_entry(header.entry_type)?;
+                continue;
+            }
+
+            let key_len = self.read_u16()? as usize;
+            let key = self.read_bytes(key_len)?;
+            let value_len = self.read_u32()? as usize;
+
+            if !key.starts_with(prefix) {
+                self.file
+                    .seek(std::io::SeekFrom::Current(value_len as i64))?;
+                continue;
+            }
+
+            let value = self.read_value(value_len)?;
+            let value = self.decode_value(header.flags, header.namespace, &key, value)?;
+            entries.push((key, value.into()));
+        }
+
+        entries.sort();
+
+        Ok(entries)
+    }
+
+    /// Finds a sorted set member, returning the entry's offset, its length
+    /// and the member's score.
+    fn get_sorted_set_entry(
+        &mut self,
+        search_key: &str,
+        search_member: &str,
+    ) -> std::io::Result<Option<(u64, u64, f64)>> {
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some((offset, entry_type, namespace)) = self.read_entry_header()? {
+            if !self.is_visible(entry_type, namespace, SORTED_SET_ENTRY_TYPE) {
+                self.skip_entry(entry_type)?;
+                continue;
+            }
+
+            let key_len = self.read_u16()? as usize;
+            let key = self.read_string(key_len)?;
+            let member_len = self.read_u16()? as usize;
+
+            if key != search_key {
+                self.file
+                    .seek(std::io::SeekFrom::Current(member_len as i64 + 8))?;
+                continue;
+            }
+
+            let member = self.read_string(member_len)?;
+            let score = self.read_f64()?;
+
+            if member == search_member {
+                // entry header, key length, key, member length, member, score
+                let entry_length =
+                    ENTRY_HEADER_LENGTH + 2 + key_len as u64 + 2 + member_len as u64 + 8;
+                return Ok(Some((offset, entry_length, score)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Reads every version of every sorted set member in the file, deleted
+    /// or not, to build the score index from.
+    fn sorted_set_versions(&mut self) -> std::io::Result<Vec<Version>> {
+        let mut versions = vec![];
+
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some(header) = self.read_versioned_header()? {
+            if header.entry_type != SORTED_SET_ENTRY_TYPE {
+                self.skip_entry(header.entry_type)?;
+                continue;
+            }
+
+            let key_len = self.read_u16()? as usize;
+            let key = self.read_string(key_len)?;
+            let member_len = self.read_u16()? as usize;
+            let member = self.read_string(member_len)?;
+            let score = self.read_f64()?;
+            versions.push(Version {
+                namespace: header.namespace,
+                key,
+                member,
+                score,
+                created: header.created,
+                deleted: header.deleted,
+            });
+        }
+
+        Ok(versions)
+    }
+
+    /// Adds a member to a sorted set, or updates its score if it is already
+    /// present.
+    pub fn write_sorted_set_entry(
+        &mut self,
+        key: impl Into<String>,
+        member: impl Into<String>,
+        score: f64,
+    ) -> std::io::Result<()> {
+        self.check_writable()?;
+        let entry = SortedSetEntry::from(self.namespace, key, member, score);
+        check_length("key", entry.key.len(), u16::MAX as usize)?;
+        check_length("member", entry.member.len(), u16::MAX as usize)?;
+
+        let existing = self.get_sorted_set_entry(&entry.key, &entry.member)?;
+        let sequence = self.next_sequence();
+        if let Some((offset, length, _)) = existing.filter(|_| self.oldest_snapshot().is_none()) {
+            // the score is the last 8 bytes of the entry, so it can be
+            // overwritten in place
+            self.file
+                .seek(std::io::SeekFrom::Start(offset + length - 8))?;
+            let mut score = [0u8; 8];
+            BigEndian::write_f64(&mut score, entry.score);
+            self.file.write_all(&score)?;
+
+            // the checksum covers the score
+            let mut bytes = vec![0u8; length as usize];
+            self.file.seek(std::io::SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut bytes)?;
+            let mut checksum = [0u8; 4];
+            BigEndian::write_u32(&mut checksum, entry_checksum(&bytes));
+            self.file.seek(std::io::SeekFrom::Start(offset + 3))?;
+            self.file.write_all(&checksum)?;
+            self.scores
+                .rescore(entry.namespace, &entry.key, &entry.member, entry.score);
+        } else {
+            self.file.seek(std::io::SeekFrom::End(0))?;
+            self.file.write_all(&entry.to_bytes(sequence))?;
+            self.entries += 1;
+            if let Some((offset, _, _)) = existing {
+                // keep the old score for the snapshots that can see it
+                self.mark_deleted(offset, sequence)?;
+                self.scores
+                    .delete(entry.namespace, &entry.key, &entry.member, sequence);
+            }
+            self.scores.insert(
+                entry.namespace,
+                &entry.key,
+                &entry.member,
+                entry.score,
+                sequence,
+            );
+        }
+
+        self.log(|| {
+            Change::Put(Entry::SortedSetMember {
+                namespace: entry.namespace,
+                key: entry.key,
+                member: entry.member,
+                score: entry.score,
+            })
+        })
+    }
+
+    pub fn get_sorted_set_score(
+        &mut self,
+        search_key: &str,
+        search_member: &str,
+    ) -> std::io::Result<Option<f64>> {
+        Ok(self
+            .get_sorted_set_entry(search_key, search_member)?
+            .map(|(_, _, score)| score))
+    }
+
+    pub fn delete_sorted_set_entry(
+        &mut self,
+        search_key: &str,
+        search_member: &str,
+    ) -> std::io::Result<()> {
+        self.check_writable()?;
+        let (offset, _, _) =
+            if let Some(entry) = self.get_sorted_set_entry(search_key, search_member)? {
+                entry
+            } else {
+                return Ok(());
+            };
+
+        let sequence = self.next_sequence();
+        self.remove_entry(offset, sequence)?;
+        self.scores
+            .delete(self.namespace, search_key, search_member, sequence);
+
+        let namespace = self.namespace;
+        self.log(|| Change::DeleteSortedSetMember {
+            namespace,
+            key: search_key.to_string(),
+            member: search_member.to_string(),
+        })
+    }
+
+    /// Returns every member of the sorted set with a score between `min` and
+    /// `max` (inclusive), ordered by score.
+    ///
+    /// Members are looked up in the score index, so only the ones within
+    /// the range are read.
+    pub fn get_sorted_set_range(
+        &mut self,
+        search_key: &str,
+        min: f64,
+        max: f64,
+    ) -> std::io::Result<Vec<(String, f64)>> {
+        let scores = Arc::clone(&self.scores);
+        let namespace = self.namespace;
+        let mut file = self.reader();
+        scores.range(
+            namespace,
+            search_key,
+            min,
+            max,
+            || file.sorted_set_versions(),
+            &|created, deleted| self.sees(created, deleted),
+        )
+    }
+
+    /// Returns the zero-based position of a member when the sorted set is
+    /// ordered by score. Only the members ordered before it are read.
+    pub fn get_sorted_set_rank(
+        &mut self,
+        search_key: &str,
+        search_member: &str,
+    ) -> std::io::Result<Option<u64>> {
+        let scores = Arc::clone(&self.scores);
+        let namespace = self.namespace;
+        let mut file = self.reader();
+        scores.rank(
+            namespace,
+            search_key,
+            search_member,
+            || file.sorted_set_versions(),
+            &|created, deleted| self.sees(created, deleted),
+        )
+    }
+
+    /// Finds a set member, returning the entry's offset and length.
+    fn get_set_entry(
+        &mut self,
+        search_key: &str,
+        search_member: &str,
+    ) -> std::io::Result<Option<(u64, u64)>> {
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some((offset, entry_type, namespace)) = self.read_entry_header()? {
+            if !self.is_visible(entry_type, namespace, SET_ENTRY_TYPE) {
+                self.skip_entry(entry_type)?;
+                continue;
+            }
+
+            let key_len = self.read_u16()? as usize;
+            let key = self.read_string(key_len)?;
+            let member_len = self.read_u16()? as usize;
+
+            if key != search_key {
+                self.file
+                    .seek(std::io::SeekFrom::Current(member_len as i64))?;
+                continue;
+            }
+
+            let member = self.read_string(member_len)?;
+
+            if member == search_member {
+                // entry header, key length, key, member length, member
+                let entry_length = ENTRY_HEADER_LENGTH + 2 + key_len as u64 + 2 + member_len as u64;
+                return Ok(Some((offset, entry_length)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Streams through every member of the set at `search_key`, calling `f`
+    /// with each member.
+    fn for_each_set_member<F>(&mut self, search_key: &str, mut f: F) -> std::io::Result<()>
+    where
+        F: FnMut(String),
+    {
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some((_, entry_type, namespace)) = self.read_entry_header()? {
+            if !self.is_visible(entry_type, namespace, SET_ENTRY_TYPE) {
+                self.skip_entry(entry_type)?;
+                continue;
+            }
+
+            let key_len = self.read_u16()? as usize;
+            let key = self.read_string(key_len)?;
+            let member_len = self.read_u16()? as usize;
+
+            if key != search_key {
+                self.file
+                    .seek(std::io::SeekFrom::Current(member_len as i64))?;
+                continue;
+            }
+
+            f(self.read_string(member_len)?);
+        }
+
+        Ok(())
+    }
+
+    /// Adds a member to a set. Returns `false` if it was already present.
+    pub fn write_set_entry(
+        &mut self,
+        key: impl Into<String>,
+        member: impl Into<String>,
+    ) -> std::io::Result<bool> {
+        self.check_writable()?;
+        let entry = SetEntry::from(self.namespace, key, member);
+        check_length("key", entry.key.len(), u16::MAX as usize)?;
+        check_length("member", entry.member.len(), u16::MAX as usize)?;
+
+        if self.get_set_entry(&entry.key, &entry.member)?.is_some() {
+            return Ok(false);
+        }
+
+        let sequence = self.next_sequence();
+        self.file.seek(std::io::SeekFrom::End(0))?;
+        self.file.write_all(&entry.to_bytes(sequence))?;
+        self.entries += 1;
+
+        self.log(|| {
+            Change::Put(Entry::SetMember {
+                namespace: entry.namespace,
+                key: entry.key,
+                member: entry.member,
+            })
+        })?;
+
+        Ok(true)
+    }
+
+    /// Removes a member from a set. Returns `false` if it was not present.
+    pub fn delete_set_entry(
+        &mut self,
+        search_key: &str,
+        search_member: &str,
+    ) -> std::io::Result<bool> {
+        self.check_writable()?;
+        let (offset, _) = if let Some(entry) = self.get_set_entry(search_key, search_member)? {
+            entry
+        } else {
+            return Ok(false);
+        };
+
+        let sequence = self.next_sequence();
+        self.remove_entry(offset, sequence)?;
+
+        let namespace = self.namespace;
+        self.log(|| Change::DeleteSetMember {
+            namespace,
+            key: search_key.to_string(),
+            member: search_member.to_string(),
+        })?;
+
+        Ok(true)
+    }
+
+    pub fn is_set_member(
+        &mut self,
+        search_key: &str,
+        search_member: &str,
+    ) -> std::io::Result<bool> {
+        Ok(self.get_set_entry(search_key, search_member)?.is_some())
+    }
+
+    /// Returns every member of the set at `search_key`, in sorted order.
+    pub fn get_set_members(&mut self, search_key: &str) -> std::io::Result<Vec<String>> {
+        let mut members = vec![];
+        self.for_each_set_member(search_key, |member| members.push(member))?;
+
+        members.sort();
+
+        Ok(members)
+    }
+
+    pub fn get_set_cardinality(&mut self, search_key: &str) -> std::io::Result<u64> {
+        let mut cardinality = 0;
+        self.for_each_set_member(search_key, |_| cardinality += 1)?;
+
+        Ok(cardinality)
+    }
+
+    /// Returns every registered namespace as `(id, name)` pairs, starting
+    /// with the default namespace.
+    pub fn get_namespaces(&mut self) -> std::io::Result<Vec<(u16, String)>> {
+        let mut namespaces = vec![(DEFAULT_NAMESPACE_ID, DEFAULT_NAMESPACE.to_string())];
+
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some((_, entry_type, id)) = self.read_entry_header()? {
+            if entry_type != NAMESPACE_ENTRY_TYPE {
+                self.skip_entry(entry_type)?;
+                continue;
+            }
+
+            let name_len = self.read_u16()? as usize;
+            namespaces.push((id, self.read_string(name_len)?));
+        }
+
+        Ok(namespaces)
+    }
+
+    pub fn get_namespace_id(&mut self, name: &str) -> std::io::Result<Option<u16>> {
+        Ok(self
+            .get_namespaces()?
+            .into_iter()
+            .find(|(_, n)| n == name)
+            .map(|(id, _)| id))
+    }
+
+    /// Registers a new namespace, returning its id. Returns `None` if a
+    /// namespace with the same name already exists.
+    pub fn create_namespace(&mut self, name: impl Into<String>) -> std::io::Result<Option<u16>> {
+        self.check_writable()?;
+        let name = name.into();
+        check_length("namespace name", name.len(), u16::MAX as usize)?;
+        let namespaces = self.get_namespaces()?;
+        if namespaces.iter().any(|(_, n)| n == &name) {
+            return Ok(None);
+        }
+
+        let id = namespaces.iter().map(|(id, _)| *id).max().unwrap_or(0) + 1;
+        self.write_namespace_entry(id, name)?;
+
+        Ok(Some(id))
+    }
+
+    fn write_namespace_entry(&mut self, id: u16, name: String) -> std::io::Result<()> {
+        let entry = NamespaceEntry::from(id, name);
+
+        let sequence = self.next_sequence();
+        self.file.seek(std::io::SeekFrom::End(0))?;
+        self.file.write_all(&entry.to_bytes(sequence))?;
+        self.entries += 1;
+
+        self.log(|| {
+            Change::Put(Entry::Namespace {
+                id: entry.id,
+                name: entry.name,
+            })
+        })
+    }
+
+    /// Removes a namespace along with everything stored in it. Returns
+    /// `false` if it doesn't exist. The default namespace can't be dropped.
+    pub fn drop_namespace(&mut self, name: &str) -> std::io::Result<bool> {
+        self.check_writable()?;
+        let id = match self.get_namespace_id(name)? {
+            Some(DEFAULT_NAMESPACE_ID) | None => return Ok(false),
+            Some(id) => id,
+        };
+
+        self.drop_namespace_id(id)?;
+
+        Ok(true)
+    }
+
+    fn drop_namespace_id(&mut self, id: u16) -> std::io::Result<()> {
+        self.remove_entries(|_, namespace| namespace == id)?;
+
+        if self.namespace == id {
+            self.namespace = DEFAULT_NAMESPACE_ID;
+        }
+
+        self.log(|| Change::DropNamespace { id })
+    }
+
+    /// Switches the namespace that reads and writes operate on.
+    pub fn use_namespace(&mut self, id: u16) {
+        self.namespace = id;
+    }
+
+    pub fn current_namespace(&self) -> u16 {
+        self.namespace
+    }
+
+    /// Counts the entries and bytes stored in every namespace. Namespace
+    /// registrations aren't counted towards any namespace.
+    pub fn get_namespace_stats(&mut self) -> std::io::Result<Vec<NamespaceStats>> {
+        let mut stats: Vec<NamespaceStats> = vec![];
+        let mut ids = vec![];
+        for (id, name) in self.get_namespaces()? {
+            ids.push(id);
+            stats.push(NamespaceStats {
+                name,
+                entries: 0,
+                bytes: 0,
+            });
+        }
+
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some((offset, entry_type, namespace)) = self.read_entry_header()? {
+            self.skip_entry(entry_type)?;
+            if entry_type == NAMESPACE_ENTRY_TYPE {
+                continue;
+            }
+
+            if let Some(index) = ids.iter().position(|id| *id == namespace) {
+                stats[index].entries += 1;
+                stats[index].bytes += self.file.stream_position()? - offset;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Removes every entry `remove` returns `true` for, as a single write.
+    /// `remove` is called with each entry's type and namespace. Like
+    /// [`Storage::remove_entry`], entries are only marked deleted while a
+    /// snapshot is open.
+    fn remove_entries<F>(&mut self, remove: F) -> std::io::Result<()>
+    where
+        F: Fn(u8, u16) -> bool,
+    {
+        let sequence = self.next_sequence();
+        let snapshot_open = self.oldest_snapshot().is_some();
+        self.retain_entries(
+            |header| {
+                if header.deleted == 0 && remove(header.entry_type, header.namespace) {
+                    if snapshot_open {
+                        Retain::Delete(sequence)
+                    } else {
+                        Retain::Drop
+                    }
+                } else if header.deleted == 0 || snapshot_open {
+                    Retain::Keep
+                } else {
+                    // nothing can read deleted entries either, so they go too
+                    Retain::Drop
+                }
+            },
+            None,
+        )
+    }
+
+    /// Rewrites the file with what `retain` says to do with each entry,
+    /// whether or not it has been deleted, re-encrypting the values of the
+    /// entries it keeps with `rotate_to` if it is set.
+    fn retain_entries<F>(&mut self, retain: F, rotate_to: Option<&FileKey>) -> std::io::Result<()>
+    where
+        F: Fn(&EntryHeader) -> Retain,
+    {
+        // a rotated file is headed by the new key before it takes the old
+        // one's place
+        let key_block = match rotate_to {
+            Some(to) => encrypt::key_block(Some(to)),
+            None => self.encryption.key_block(),
+        };
+        let mut kept = 0;
+        self.rewrite(&key_block, |storage, out| {
+            let mut position = HEADER_LENGTH;
+            loop {
+                storage.file.seek(std::io::SeekFrom::Start(position))?;
+                let Some(header) = storage.read_versioned_header()? else {
+                    break;
+                };
+                storage.skip_entry(header.entry_type)?;
+                position = storage.file.stream_position()?;
+                let deleted = match retain(&header) {
+                    Retain::Keep => None,
+                    Retain::Delete(sequence) => Some(sequence),
+                    Retain::Drop => continue,
+                };
+
+                let mut entry = vec![0u8; (position - header.offset) as usize];
+                storage.file.seek(std::io::SeekFrom::Start(header.offset))?;
+                storage.file.read_exact(&mut entry)?;
+                if let Some(sequence) = deleted {
+                    let at = DELETED_OFFSET as usize;
+                    BigEndian::write_u64(&mut entry[at..at + 8], sequence);
+                }
+                match rotate_to {
+                    Some(to) if header.entry_type == DATA_ENTRY_TYPE => {
+                        out.write_all(&storage.reseal_entry(&entry, to)?)?
+                    }
+                    _ => out.write_all(&entry)?,
+                }
+                kept += 1;
+            }
+
+            Ok(())
+        })?;
+        self.entries = kept;
+        if let Some(to) = rotate_to {
+            self.encryption.rotated(to.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts the value of a whole data entry again with `to`, keeping
+    /// its sequence numbers.
+    fn reseal_entry(&self, entry: &[u8], to: &FileKey) -> std::io::Result<Bytes> {
+        let namespace = BigEndian::read_u16(&entry[1..3]);
+        let (header, body) = entry.split_at(ENTRY_HEADER_LENGTH as usize);
+        let (key_len, body) = body.split_at(2);
+        let (key, body) = body.split_at(BigEndian::read_u16(key_len) as usize);
+        let value = body[4..].to_vec();
+
+        let (flags, value) =
+            self.encryption
+                .reseal(to, entry[0] & ENTRY_FLAGS, namespace, key, value)?;
+        let mut bytes = BytesMut::from(header);
+        bytes[0] = DATA_ENTRY_TYPE | flags;
+        bytes.put_u16(key.len() as u16);
+        bytes.put(key);
+        bytes.put_u32(value.len() as u32);
+        bytes.put(&value[..]);
+
+        Ok(with_checksum(bytes))
+    }
+
+    /// Removes every entry in the current namespace.
+    pub fn truncate_namespace(&mut self) -> std::io::Result<()> {
+        self.check_writable()?;
+        let current = self.namespace;
+        self.remove_entries(|entry_type, namespace| {
+            entry_type != NAMESPACE_ENTRY_TYPE && namespace == current
+        })?;
+
+        self.log(|| Change::Truncate { namespace: current })
+    }
+
+    /// Removes every entry and namespace, leaving only the file header.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.check_writable()?;
+        self.remove_entries(|_, _| true)?;
+        self.namespace = DEFAULT_NAMESPACE_ID;
+
+        self.log(|| Change::Flush)
+    }
+
+    /// Rewrites the file without the deleted entries no open snapshot can
+    /// read any more, returning how many bytes that freed.
+    pub fn compact(&mut self) -> std::io::Result<u64> {
+        self.check_writable()?;
+        let size = self.file.metadata()?.len();
+
+        // a snapshot can read entries deleted after its sequence number
+        let oldest = self.oldest_snapshot();
+        let rotate_to = self.encryption.rotation();
+        self.retain_entries(
+            |header| {
+                if header.deleted == 0 || oldest.is_some_and(|oldest| header.deleted > oldest) {
+                    Retain::Keep
+                } else {
+                    Retain::Drop
+                }
+            },
+            rotate_to.as_ref(),
+        )?;
+        self.build_bloom();
+
+        Ok(size.saturating_sub(self.file.metadata()?.len()))
+    }
+
+    pub fn get_stats(&mut self) -> std::io::Result<StorageStats> {
+        let file_size = self.file.metadata()?.len();
+        let mut keys = HashSet::new();
+        let mut live_bytes = HEADER_LENGTH;
+        // the values of data entries as stored, and once decompressed
+        let (mut stored_values, mut values) = (0, 0);
+
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some(header) = self.read_visible_header()? {
+            let (offset, entry_type, namespace) =
+                (header.offset, header.entry_type, header.namespace);
+            if entry_type == NAMESPACE_ENTRY_TYPE {
+                self.skip_entry(entry_type)?;
+            } else {
+                // every other entry type starts with its key
+                let key_len = self.read_u16()? as usize;
+                let key = self.read_bytes(key_len)?;
+                if entry_type == DATA_ENTRY_TYPE {
+                    let value_len = self.read_u32()? as u64;
+                    stored_values += value_len;
+                    // compressed values start with their length, once
+                    // they are decrypted
+                    let prefix = if header.flags & ENCRYPTED != 0 {
+                        let stored = self.read_bytes(value_len as usize)?;
+                        let opened =
+                            self.encryption
+                                .open_value(header.flags, namespace, &key, stored)?;
+                        opened.get(..4).map(BigEndian::read_u32)
+                    } else if header.flags != 0 {
+                        Some(self.read_u32()?)
+                    } else {
+                        None
+                    };
+                    values += match (header.flags & CODEC_MASK, prefix) {
+                        (0, _) | (_, None) => value_len,
+                        (_, Some(length)) => length as u64,
+                    };
+                }
+                self.file
+                    .seek(std::io::SeekFrom::Start(offset + ENTRY_HEADER_LENGTH))?;
+                self.skip_entry(entry_type)?;
+                keys.insert((namespace, key));
+            }
+
+            live_bytes += self.file.stream_position()? - offset;
+        }
+
+        Ok(StorageStats {
+            keys: keys.len() as u64,
+            file_size,
+            live_bytes,
+            dead_bytes: file_size.saturating_sub(live_bytes),
+            version: CURRENT_VERSION,
+            bloom_hits: self.bloom.hits(),
+            bloom_misses: self.bloom.misses(),
+            compression_ratio: match stored_values {
+                0 => 1.0,
+                _ => values as f64 / stored_values as f64,
+            },
+        })
+    }
+
+    /// Counts every entry in the file by reading only entry headers and
+    /// lengths.
+    pub fn get_entry_count(&mut self) -> std::io::Result<u64> {
+        let mut entries = 0;
+
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some((_, entry_type, _)) = self.read_entry_header()? {
+            self.skip_entry(entry_type)?;
+            entries += 1;
+        }
+
+        Ok(entries)
+    }
+
+    /// Streams through every entry in the file, in every namespace, in file
+    /// order.
+    pub fn for_each_entry<F>(&mut self, mut f: F) -> std::io::Result<()>
+    where
+        F: FnMut(Entry) -> std::io::Result<()>,
+    {
+        // skip file header
+        self.file.seek(std::io::SeekFrom::Start(HEADER_LENGTH))?;
+        while let Some(header) = self.read_visible_header()? {
+            let (entry_type, namespace) = (header.entry_type, header.namespace);
+            let entry = match entry_type {
+                DATA_ENTRY_TYPE => {
+                    let key_len = self.read_u16()? as usize;
+                    let key = self.read_bytes(key_len)?;
+                    let value_len = self.read_u32()? as usize;
+                    let value = self.read_value(value_len)?;
+                    let value = self.decode_value(header.flags, namespace, &key, value)?;
+                    Entry::Data {
+                        namespace,
+                        key,
+                        value: value.into(),
+                    }
+                }
+                SORTED_SET_ENTRY_TYPE => {
+                    let key_len = self.read_u16()? as usize;
+                    let key = self.read_string(key_len)?;
+                    let member_len = self.read_u16()? as usize;
+                    let member = self.read_string(member_len)?;
+                    let score = self.read_f64()?;
+                    Entry::SortedSetMember {
+                        namespace,
+                        key,
+                        member,
+                        score,
+                    }
+                }
+                SET_ENTRY_TYPE => {
+                    let key_len = self.read_u16()? as usize;
+                    let key = self.read_string(key_len)?;
+                    let member_len = self.read_u16()? as usize;
+                    let member = self.read_string(member_len)?;
+                    Entry::SetMember {
+                        namespace,
+                        key,
+                        member,
+                    }
+                }
+                NAMESPACE_ENTRY_TYPE => {
+                    let name_len = self.read_u16()? as usize;
+                    let name = self.read_string(name_len)?;
+                    // the namespace slot holds the namespace's own id
+                    Entry::Namespace {
+                        id: namespace,
+                        name,
+                    }
+                }
+                _ => {
+                    return Err(invalid_data(format!("unknown entry type {}", entry_type)));
+                }
+            };
+
+            f(entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes entries to the end of the file in one go, keeping the ids
+    /// they were given. Unlike the other writes this doesn't look for
+    /// existing keys, members or namespaces first, so it is only safe when
+    /// the file can't already hold them, e.g. when loading into an empty
+    /// file.
+    pub fn append_entries(&mut self, entries: &[Entry]) -> std::io::Result<()> {
+        self.check_writable()?;
+        let mut bytes = BytesMut::new();
+        let sequence = self.next_sequence();
+
+        for entry in entries {
+            bytes.put(encode_entry(
+                entry,
+                sequence,
+                &self.compression,
+                &self.encryption,
+            )?);
+        }
+
+        self.file.seek(std::io::SeekFrom::End(0))?;
+        self.file.write_all(&bytes)?;
+        self.entries += entries.len() as u64;
+
+        let mut outgrown = false;
+        for entry in entries {
+            match entry {
+                Entry::Data { namespace, key, .. } => {
+                    outgrown |= self.bloom.insert(*namespace, key);
+                }
+                Entry::SortedSetMember {
+                    namespace,
+                    key,
+                    member,
+                    score,
+                } => self
+                    .scores
+                    .insert(*namespace, key, member, *score, sequence),
+                _ => {}
+            }
+        }
+        if outgrown {
+            self.build_bloom();
+        }
+
+        if let Some(changes) = &mut self.changes {
+            for entry in entries {
+                changes.append(Change::Put(entry.clone()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Size of the file without its header.
+    pub fn get_entries_size(&mut self) -> std::io::Result<u64> {
+        Ok(self.file.metadata()?.len().saturating_sub(HEADER_LENGTH))
+    }
+
+    /// Copies the file as it is now to `out` and returns how many bytes
+    /// were written.
+    pub fn snapshot_to(&mut self, out: &mut impl Write) -> std::io::Result<u64> {
+        self.snapshot_to_with(out, &|part| part())
+    }
+
+    /// Like [`Storage::snapshot_to`], copying a part of the file at a time
+    /// inside `locked`. The copy is taken from a view made by
+    /// [`Storage::snapshot_reader`], or from one made now, so it holds the
+    /// file as it was when the view was made however much is written
+    /// between the parts. Nothing is written to `out` inside `locked`.
+    pub fn snapshot_to_with(
+        &mut self,
+        out: &mut impl Write,
+        locked: Locked,
+    ) -> std::io::Result<u64> {
+        if self.pin.is_none() {
+            return self.snapshot_reader().snapshot_to_with(out, locked);
+        }
+
+        let mut copied = 0;
+        loop {
+            let mut part = vec![];
+            locked(&mut || self.snapshot_part(&mut part, copied))?;
+            if part.is_empty() {
+                return Ok(copied);
+            }
+            out.write_all(&part)?;
+            copied += part.len() as u64;
+        }
+    }
+
+    /// Copies the entries of a snapshot view from `offset` on to `out`,
+    /// about [`SNAPSHOT_PART_LENGTH`] bytes of them, starting with the file
+    /// header if `offset` is 0. Entries are copied as they are, except that
+    /// deletions made after the view was taken are left out.
+    fn snapshot_part(&self, out: &mut Vec<u8>, offset: u64) -> std::io::Result<()> {
+        let Some(pin) = self.pin.clone() else {
+            return Ok(());
+        };
+        let mut source = self.reader();
+        source.file = pin.file.reader();
+
+        let mut position = offset;
+        if position == 0 {
+            source.file.seek(std::io::SeekFrom::Start(0))?;
+            out.resize(HEADER_LENGTH as usize, 0);
+            source.file.read_exact(out)?;
+            position = HEADER_LENGTH;
+        }
+        // entries written since are all past the end the file had, and the
+        // ones before it never move while the view is open, or at all once
+        // the file has been replaced
+        while position < pin.length && position - offset < SNAPSHOT_PART_LENGTH {
+            source.file.seek(std::io::SeekFrom::Start(position))?;
+            let Some(header) = source.read_versioned_header()? else {
+                break;
+            };
+            source.skip_entry(header.entry_type)?;
+            position = source.file.stream_position()?;
+
+            let start = out.len();
+            out.resize(start + (position - header.offset) as usize, 0);
+            source.file.seek(std::io::SeekFrom::Start(header.offset))?;
+            source.file.read_exact(&mut out[start..])?;
+            if header.deleted > pin.sequence {
+                let at = start + DELETED_OFFSET as usize;
+                BigEndian::write_u64(&mut out[at..at + 8], 0);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a point-in-time copy of the file to `path` and returns its
+    /// size. The copy is written next to `path` first and renamed into place
+    /// once it is on disk, so `path` never holds a partial snapshot. If the
+    /// file has a change log, the snapshot gets an empty one starting at the
+    /// current sequence number. `path` can't be the file itself or one of
+    /// the files kept next to it.
+    pub fn snapshot(&mut self, path: impl AsRef<Path>) -> std::io::Result<u64> {
+        self.snapshot_with(path, &|part| part())
+    }
+
+    /// Like [`Storage::snapshot`], copying a part of the file at a time
+    /// inside `locked`, see [`Storage::snapshot_to_with`].
+    pub fn snapshot_with(
+        &mut self,
+        path: impl AsRef<Path>,
+        locked: Locked,
+    ) -> std::io::Result<u64> {
+        if self.pin.is_none() {
+            return self.snapshot_reader().snapshot_with(path, locked);
+        }
+
+        let path = path.as_ref();
+        let target = resolve(path)?;
+        for own in [&self.path, &self.changes_path, &self.bloom_path] {
+            if resolve(own)? == target {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "a snapshot can't be written over the file it is taken of",
+                ));
+            }
+        }
+
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        let result = (|| {
+            let mut temp = File::create(&temp_path)?;
+            let copied = self.snapshot_to_with(&mut temp, locked)?;
+            temp.sync_all()?;
+            locked(&mut || self.bloom.save(&bloom_path(path), copied))?;
+            // an empty log records which change the snapshot was taken at
+            match self.sequence() {
+                Some(sequence) => drop(ChangeLog::create(changes_path(path), sequence)?),
+                // don't leave behind the log of a file this one replaces
+                None => match std::fs::remove_file(changes_path(path)) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                },
+            }
+            std::fs::rename(&temp_path, path)?;
+            Ok(copied)
+        })();
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+
+        result
+    }
+
+    /// Starts keeping a change log next to the file, if it doesn't have one
+    /// yet, and returns the current sequence number.
+    pub fn enable_change_log(&mut self) -> std::io::Result<u64> {
+        self.check_writable()?;
+        if self.encrypts() {
+            return Err(unencrypted_change_log());
+        }
+        if self.changes.is_none() {
+            self.changes = Some(ChangeLog::create(&self.changes_path, 0)?);
+        }
+
+        Ok(self.sequence().unwrap_or(0))
+    }
+
+    /// The sequence number of the latest change, if the file has a change
+    /// log. Views made by [`Storage::snapshot_reader`] return the one they
+    /// were made at.
+    pub fn sequence(&self) -> Option<u64> {
+        match &self.pin {
+            Some(pin) => pin.logged,
+            None => self.changes.as_ref().map(ChangeLog::last_sequence),
+        }
+    }
+
+    /// Where the change log is, or would be, kept.
+    pub fn changes_path(&self) -> &Path {
+        &self.changes_path
+    }
+
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ReadOnlyFilesystem,
+                "the file is open read-only",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// A read-only view of the file that reads independently of this one,
+    /// starting in the same namespace. Any number of views can read at
+    /// once, from different threads, as long as nothing writes to the file
+    /// while they do; keeping writes out is up to the caller.
+    pub fn reader(&self) -> Storage {
+        Storage {
+            file: self.file.reader(),
+            path: self.path.clone(),
+            namespace: self.namespace,
+            read_only: true,
+            changes_path: self.changes_path.clone(),
+            changes: None,
+            write_sequence: self.write_sequence,
+            entries: self.entries,
+            snapshots: Arc::clone(&self.snapshots),
+            pin: self.pin.clone(),
+            bloom: Arc::clone(&self.bloom),
+            bloom_path: self.bloom_path.clone(),
+            scores: Arc::clone(&self.scores),
+            compression: Arc::clone(&self.compression),
+            encryption: Arc::clone(&self.encryption),
+        }
+    }
+
+    /// A read-only view of the file as it is now, which goes on reading it
+    /// that way while later writes are made. Unlike [`Storage::snapshot`]
+    /// nothing is copied: writes keep the versions the view can read until
+    /// it, and every view made from it with [`Storage::reader`], is dropped.
+    /// Views still can't read while a write is being made.
+    pub fn snapshot_reader(&self) -> Storage {
+        let sequence = self.write_sequence;
+        *self
+            .snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(sequence)
+            .or_default() += 1;
+
+        let mut view = self.reader();
+        view.pin = Some(Arc::new(SnapshotPin {
+            sequence,
+            file: self.file.pinned(),
+            length: self.file.metadata().map_or(0, |metadata| metadata.len()),
+            logged: self.sequence(),
+            snapshots: Arc::clone(&self.snapshots),
+        }));
+
+        view
+    }
+
+    /// The sequence number the oldest open snapshot reads at.
+    fn oldest_snapshot(&self) -> Option<u64> {
+        self.snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .next()
+            .copied()
+    }
+
+    /// Gives the next write its sequence number.
+    fn next_sequence(&mut self) -> u64 {
+        self.write_sequence += 1;
+        self.write_sequence
+    }
+
+    /// Whether the file was opened with [`Storage::open_read_only`].
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Records a change in the change log, if there is one. Changes are
+    /// logged after they have been made to the file.
+    fn log(&mut self, change: impl FnOnce() -> Change) -> std::io::Result<()> {
+        if let Some(changes) = &mut self.changes {
+            changes.append(change())?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes a change read from a change log, keeping its sequence number
+    /// and timestamp in this file's log.
+    pub fn apply_change(&mut self, record: &ChangeRecord) -> std::io::Result<()> {
+        self.check_writable()?;
+        let changes = self.changes.take();
+        let current = self.namespace;
+        let result = self.apply(record.change.clone());
+        self.namespace = current;
+        self.changes = changes;
+        result?;
+
+        if let Some(changes) = &mut self.changes {
+            changes.append_record(record)?;
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, change: Change) -> std::io::Result<()> {
+        match change {
+            Change::Put(Entry::Data {
+                namespace,
+                key,
+                value,
+            }) => {
+                self.namespace = namespace;
+                if self.get_data_entry_offset(&key)?.is_some() {
+                    self.update_data_entry(key, value)
+                } else {
+                    self.write_data_entry(key, value)
+                }
+            }
+            Change::Put(Entry::SortedSetMember {
+                namespace,
+                key,
+                member,
+                score,
+            }) => {
+                self.namespace = namespace;
+                self.write_sorted_set_entry(key, member, score)
+            }
+            Change::Put(Entry::SetMember {
+                namespace,
+                key,
+                member,
+            }) => {
+                self.namespace = namespace;
+                self.write_set_entry(key, member).map(|_| ())
+            }
+            Change::Put(Entry::Namespace { id, name }) => {
+                if self.get_namespaces()?.iter().any(|(_, n)| n == &name) {
+                    return Ok(());
+                }
+                self.write_namespace_entry(id, name)
+            }
+            Change::DeleteData { namespace, key } => {
+                self.namespace = namespace;
+                self.delete_data_entry(key)
+            }
+            Change::DeleteSortedSetMember {
+                namespace,
+                key,
+                member,
+            } => {
+                self.namespace = namespace;
+                self.delete_sorted_set_entry(&key, &member)
+            }
+            Change::DeleteSetMember {
+                namespace,
+                key,
+                member,
+            } => {
+                self.namespace = namespace;
+                self.delete_set_entry(&key, &member).map(|_| ())
+            }
+            Change::DropNamespace { id } => self.drop_namespace_id(id),
+            Change::Truncate { namespace } => {
+                self.namespace = namespace;
+                self.truncate_namespace()
+            }
+            Change::Flush => self.flush(),
+        }
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        // views share the filter of the `Storage` they were made from
+        if !self.read_only {
+            if let Ok(metadata) = self.file.metadata() {
+                let _ = self.bloom.save(&self.bloom_path, metadata.len());
+            }
+        }
     }
 }
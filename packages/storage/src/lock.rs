@@ -0,0 +1,43 @@
+// locks the files engines open, so one process can write to a database while
+// any others wait, and read-only opens can share it
+
+use std::{
+    fmt,
+    fs::{File, TryLockError},
+    io,
+};
+
+/// Why a file couldn't be opened: another process holds a lock on it that
+/// conflicts with the one asked for. Reported as an error of kind
+/// `WouldBlock`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LockError;
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the file is locked by another process")
+    }
+}
+
+impl std::error::Error for LockError {}
+
+impl From<LockError> for io::Error {
+    fn from(err: LockError) -> Self {
+        io::Error::new(io::ErrorKind::WouldBlock, err)
+    }
+}
+
+/// Takes a shared lock on `file` when it is only read, and an exclusive one
+/// otherwise, without waiting for one held elsewhere.
+pub(crate) fn try_lock(file: &File, read_only: bool) -> io::Result<()> {
+    let locked = if read_only {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(LockError.into()),
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
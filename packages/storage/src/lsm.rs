@@ -0,0 +1,969 @@
+// a storage engine built as a log-structured merge tree, for write-heavy use
+//
+// writes go to a write-ahead log and a sorted memtable. once the memtable
+// grows past `memtable_size` it is written out as an immutable table at
+// level 0, so everything written to disk is written sequentially.
+// compaction merges tables down through the levels: level 0 once it has
+// `level0_tables` tables, and every level after that once it holds more
+// than its share of bytes, each level `level_size_multiplier` times bigger
+// than the last. tables below level 0 never overlap, so a lookup reads at
+// most one table per level, and usually none thanks to their bloom filters
+//
+// entries are laid out in a single sorted key space, see `sorted`
+
+mod manifest;
+mod table;
+mod wal;
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread::JoinHandle,
+};
+
+use manifest::Manifest;
+use table::{Table, TableWriter};
+use wal::Wal;
+
+use crate::{
+    bloom::BloomCounters,
+    engine::StorageEngine,
+    invalid_data,
+    lock::try_lock,
+    sorted::{self, Batch, SortedStore, Value, Visit},
+    Entry, NamespaceStats, StorageStats, DEFAULT_NAMESPACE_ID,
+};
+
+/// Reported as the version in stats.
+const FORMAT_VERSION: u16 = 1;
+
+/// Tuning for [`LsmEngine`].
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// How many bytes of keys and values the memtable holds before it is
+    /// written out as a table.
+    pub memtable_size: usize,
+    /// Roughly how many bytes of entries go in each block of a table.
+    pub block_size: usize,
+    /// The share of missing keys each table's bloom filter lets through.
+    pub bloom_false_positive_rate: f64,
+    /// How many tables level 0 collects before they are merged into
+    /// level 1.
+    pub level0_tables: usize,
+    /// How many bytes level 1 holds before it is merged into level 2.
+    pub level_size: u64,
+    /// How much more each level after level 1 holds than the one above.
+    pub level_size_multiplier: u64,
+    /// Roughly how big the tables written by compaction are.
+    pub table_size: u64,
+    /// How many levels there are, counting level 0. At least 2.
+    pub levels: usize,
+    /// Compacts on a background thread. Otherwise the write that fills the
+    /// memtable compacts before it returns.
+    pub background_compaction: bool,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 << 20,
+            block_size: 4 << 10,
+            bloom_false_positive_rate: 0.01,
+            level0_tables: 4,
+            level_size: 10 << 20,
+            level_size_multiplier: 10,
+            table_size: 2 << 20,
+            levels: 7,
+            background_compaction: true,
+        }
+    }
+}
+
+/// Keeps entries in a directory of sorted tables, see [`LsmOptions`].
+///
+/// The directory is locked while it is open, like a kiv file. Views made
+/// with [`StorageEngine::reader`] and [`StorageEngine::snapshot_reader`]
+/// keep the tables they read from around, even once compaction has
+/// replaced them.
+pub struct LsmEngine {
+    shared: Arc<Shared>,
+    namespace: u16,
+    read_only: bool,
+    /// What views made by `snapshot_reader` read.
+    frozen: Option<(Arc<Memtable>, Arc<Version>)>,
+    /// Set on the engine that writes, if it compacts in the background.
+    compactor: Option<Compactor>,
+}
+
+#[derive(Clone, Default)]
+struct Memtable {
+    entries: BTreeMap<Vec<u8>, Value>,
+    /// Bytes of keys and values.
+    size: usize,
+}
+
+impl Memtable {
+    fn insert(&mut self, key: Vec<u8>, value: Value) {
+        let size = key.len() + value.as_ref().map_or(0, Vec::len);
+        if let Some(old) = self.entries.get(&key) {
+            self.size -= key.len() + old.as_ref().map_or(0, Vec::len);
+        }
+        self.entries.insert(key, value);
+        self.size += size;
+    }
+}
+
+/// The tables that make up the database at some point. Level 0 goes newest
+/// first, and the levels after it go in key order.
+#[derive(Clone)]
+struct Version {
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl Version {
+    fn empty(levels: usize) -> Self {
+        Self {
+            levels: vec![vec![]; levels],
+        }
+    }
+
+    /// The newest entry for `key` in any table.
+    fn get(&self, key: &[u8], counters: &BloomCounters) -> io::Result<Option<Value>> {
+        for table in &self.levels[0] {
+            if table.overlaps(key, key) {
+                if let Some(value) = table.get(key, counters)? {
+                    return Ok(Some(value));
+                }
+            }
+        }
+
+        for level in &self.levels[1..] {
+            let index = level.partition_point(|table| table.last_key() < key);
+            if let Some(table) = level.get(index) {
+                if table.first_key.as_slice() <= key {
+                    if let Some(value) = table.get(key, counters)? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn tables(&self) -> impl Iterator<Item = &Arc<Table>> {
+        self.levels.iter().flatten()
+    }
+
+    fn level_size(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|table| table.size).sum()
+    }
+}
+
+struct Shared {
+    dir: PathBuf,
+    options: LsmOptions,
+    state: Mutex<State>,
+    /// Wakes the compaction thread.
+    wake: Condvar,
+    /// Held by whatever is compacting, so compactions don't overlap.
+    compacting: Mutex<()>,
+    /// How the tables' bloom filters have answered lookups.
+    bloom: BloomCounters,
+    _lock: File,
+}
+
+struct State {
+    memtable: Arc<Memtable>,
+    version: Arc<Version>,
+    /// `None` when the directory is open read-only.
+    wal: Option<Wal>,
+    next_table_id: u64,
+    closed: bool,
+    /// Why background compaction last failed, returned by the next write.
+    error: Option<io::Error>,
+}
+
+/// A merge of some tables into the level below the one they come from.
+struct Job {
+    /// Tables from the upper level, newest first.
+    upper: Vec<Arc<Table>>,
+    /// Tables in the output level they overlap.
+    lower: Vec<Arc<Table>>,
+    output_level: usize,
+    /// Whether no older table can hold the keys being merged, so tombstones
+    /// have nothing left to shadow.
+    bottom: bool,
+}
+
+impl Shared {
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_compacting(&self) -> MutexGuard<'_, ()> {
+        self.compacting
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Makes `version` current, once the manifest says so.
+    fn install(&self, state: &mut State, version: Version) -> io::Result<()> {
+        let mut tables = vec![];
+        for (level, level_tables) in version.levels.iter().enumerate() {
+            tables.extend(level_tables.iter().map(|table| (level as u8, table.id)));
+        }
+        Manifest {
+            next_table_id: state.next_table_id,
+            tables,
+        }
+        .write(&self.dir)?;
+        state.version = Arc::new(version);
+
+        Ok(())
+    }
+
+    fn new_table(&self) -> io::Result<TableWriter> {
+        let id = {
+            let mut state = self.lock_state();
+            state.next_table_id += 1;
+            state.next_table_id - 1
+        };
+
+        TableWriter::create(
+            &self.dir,
+            id,
+            self.options.block_size,
+            self.options.bloom_false_positive_rate,
+        )
+    }
+
+    /// Writes the memtable out as a table at level 0 and empties it.
+    fn flush_memtable(&self, state: &mut State) -> io::Result<()> {
+        if state.memtable.entries.is_empty() {
+            return Ok(());
+        }
+
+        let id = state.next_table_id;
+        state.next_table_id += 1;
+        let mut writer = TableWriter::create(
+            &self.dir,
+            id,
+            self.options.block_size,
+            self.options.bloom_false_positive_rate,
+        )?;
+        let written = state
+            .memtable
+            .entries
+            .iter()
+            .try_for_each(|(key, value)| writer.add(key, value));
+        if let Err(err) = written {
+            writer.abandon();
+            return Err(err);
+        }
+        let table = Arc::new(writer.finish(&self.dir)?);
+
+        let mut version = (*state.version).clone();
+        version.levels[0].insert(0, Arc::clone(&table));
+        if let Err(err) = self.install(state, version) {
+            table.mark_obsolete();
+            return Err(err);
+        }
+
+        state.memtable = Arc::new(Memtable::default());
+        if let Some(wal) = &mut state.wal {
+            wal.reset()?;
+        }
+
+        Ok(())
+    }
+
+    fn level_limit(&self, level: usize) -> u64 {
+        let multiplier = self.options.level_size_multiplier.max(1);
+        (1..level).fold(self.options.level_size, |limit, _| {
+            limit.saturating_mul(multiplier)
+        })
+    }
+
+    /// The merge that is most overdue, if any level is over its limit.
+    fn pick_job(&self, version: &Version) -> Option<Job> {
+        if !version.levels[0].is_empty() && version.levels[0].len() >= self.options.level0_tables {
+            return Some(Self::job(version, 0, version.levels[0].clone()));
+        }
+
+        for level in 1..version.levels.len() - 1 {
+            if version.level_size(level) > self.level_limit(level) {
+                // the oldest table goes first, so every part of the key
+                // space gets its turn
+                let table = version.levels[level].iter().min_by_key(|table| table.id)?;
+                return Some(Self::job(version, level, vec![Arc::clone(table)]));
+            }
+        }
+
+        None
+    }
+
+    fn job(version: &Version, level: usize, upper: Vec<Arc<Table>>) -> Job {
+        let first = upper
+            .iter()
+            .map(|table| table.first_key.as_slice())
+            .min()
+            .unwrap_or_default();
+        let last = upper
+            .iter()
+            .map(|table| table.last_key())
+            .max()
+            .unwrap_or_default();
+        let lower = version.levels[level + 1]
+            .iter()
+            .filter(|table| table.overlaps(first, last))
+            .cloned()
+            .collect();
+        let bottom = version.levels[level + 2..]
+            .iter()
+            .flatten()
+            .all(|table| !table.overlaps(first, last));
+
+        Job {
+            upper,
+            lower,
+            output_level: level + 1,
+            bottom,
+        }
+    }
+
+    /// Merges a job's tables into new ones and swaps them in.
+    fn run_job(&self, job: Job) -> io::Result<()> {
+        let sources = job
+            .upper
+            .iter()
+            .chain(&job.lower)
+            .map(|table| Box::new(table.iter_from(&[])) as Source)
+            .collect();
+
+        let mut outputs: Vec<Arc<Table>> = vec![];
+        let mut writer: Option<TableWriter> = None;
+        let merged = (|| {
+            for entry in Merge::new(sources) {
+                let (key, value) = entry?;
+                if value.is_none() && job.bottom {
+                    continue;
+                }
+
+                let table = match &mut writer {
+                    Some(table) => table,
+                    None => writer.insert(self.new_table()?),
+                };
+                table.add(&key, &value)?;
+                if table.size() >= self.options.table_size {
+                    if let Some(table) = writer.take() {
+                        outputs.push(Arc::new(table.finish(&self.dir)?));
+                    }
+                }
+            }
+            if let Some(table) = writer.take() {
+                outputs.push(Arc::new(table.finish(&self.dir)?));
+            }
+
+            Ok(())
+        })();
+
+        let installed = merged.and_then(|()| {
+            let inputs: HashSet<u64> = job
+                .upper
+                .iter()
+                .chain(&job.lower)
+                .map(|table| table.id)
+                .collect();
+
+            let mut state = self.lock_state();
+            let mut version = (*state.version).clone();
+            for level in &mut version.levels {
+                level.retain(|table| !inputs.contains(&table.id));
+            }
+            let output = &mut version.levels[job.output_level];
+            output.extend(outputs.iter().cloned());
+            output.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+
+            self.install(&mut state, version)
+        });
+
+        match installed {
+            Ok(()) => {
+                for table in job.upper.iter().chain(&job.lower) {
+                    table.mark_obsolete();
+                }
+                Ok(())
+            }
+            Err(err) => {
+                if let Some(table) = writer {
+                    table.abandon();
+                }
+                for table in outputs {
+                    table.mark_obsolete();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Runs the most overdue merge, returning `false` if none was due.
+    fn compact_step(&self) -> io::Result<bool> {
+        let _compacting = self.lock_compacting();
+        let version = Arc::clone(&self.lock_state().version);
+        match self.pick_job(&version) {
+            Some(job) => self.run_job(job).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    fn compact_in_background(&self) {
+        loop {
+            {
+                let mut state = self.lock_state();
+                while !state.closed
+                    && (state.error.is_some() || self.pick_job(&state.version).is_none())
+                {
+                    state = self
+                        .wake
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                if state.closed {
+                    return;
+                }
+            }
+
+            if let Err(err) = self.compact_step() {
+                self.lock_state().error = Some(err);
+            }
+        }
+    }
+
+    /// Bytes taken up by every file in the directory.
+    fn disk_size(&self) -> io::Result<u64> {
+        let mut size = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            size += entry?.metadata()?.len();
+        }
+
+        Ok(size)
+    }
+}
+
+/// Stops the compaction thread when the engine that writes is dropped.
+struct Compactor {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    fn spawn(shared: &Arc<Shared>) -> io::Result<Self> {
+        let thread_shared = Arc::clone(shared);
+        let thread = std::thread::Builder::new()
+            .name("kiv-compaction".to_string())
+            .spawn(move || thread_shared.compact_in_background())?;
+
+        Ok(Self {
+            shared: Arc::clone(shared),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.shared.lock_state().closed = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+type Source<'a> = Box<dyn Iterator<Item = io::Result<(Vec<u8>, Value)>> + 'a>;
+
+/// Merges sources sorted by key into one. Where several sources have the
+/// same key, only the first one's entry is kept, so sources go newest
+/// first.
+struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    heads: Vec<Option<(Vec<u8>, Value)>>,
+    started: bool,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Source<'a>>) -> Self {
+        Self {
+            heads: sources.iter().map(|_| None).collect(),
+            sources,
+            started: false,
+        }
+    }
+
+    fn advance(&mut self, source: usize) -> io::Result<()> {
+        self.heads[source] = self.sources[source].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<(Vec<u8>, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            for source in 0..self.sources.len() {
+                if let Err(err) = self.advance(source) {
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        // ties go to the earlier source
+        let mut smallest: Option<usize> = None;
+        for (source, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                let smaller = smallest
+                    .and_then(|s| self.heads[s].as_ref())
+                    .is_none_or(|(smallest_key, _)| key < smallest_key);
+                if smaller {
+                    smallest = Some(source);
+                }
+            }
+        }
+
+        let (key, value) = self.heads[smallest?].take()?;
+        for source in 0..self.sources.len() {
+            let shadowed = self.heads[source]
+                .as_ref()
+                .is_some_and(|(other, _)| *other == key);
+            if source == smallest? || shadowed {
+                if let Err(err) = self.advance(source) {
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        Some(Ok((key, value)))
+    }
+}
+
+impl LsmEngine {
+    /// Opens the database in directory `dir` for reading and writing,
+    /// creating it if it doesn't exist.
+    pub fn open(dir: impl AsRef<Path>, options: LsmOptions) -> io::Result<Self> {
+        Self::open_with(dir.as_ref(), options, false)
+    }
+
+    /// Opens an existing database for reading only. Any number of processes
+    /// can do this at once, but not while it is open for writing.
+    pub fn open_read_only(dir: impl AsRef<Path>, options: LsmOptions) -> io::Result<Self> {
+        Self::open_with(dir.as_ref(), options, true)
+    }
+
+    fn open_with(dir: &Path, mut options: LsmOptions, read_only: bool) -> io::Result<Self> {
+        options.levels = options.levels.clamp(2, u8::MAX as usize);
+        if !read_only {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let lock = OpenOptions::new()
+            .create(!read_only)
+            .truncate(false)
+            .write(!read_only)
+            .read(true)
+            .open(dir.join("LOCK"))?;
+        try_lock(&lock, read_only)?;
+
+        let manifest = Manifest::read(dir)?.unwrap_or_default();
+        let mut version = Version::empty(options.levels);
+        for (level, id) in &manifest.tables {
+            let level = *level as usize;
+            if level >= options.levels {
+                return Err(invalid_data(format!(
+                    "table {} is at level {}, but there are only {} levels",
+                    id, level, options.levels
+                )));
+            }
+            version.levels[level].push(Arc::new(Table::open(dir, *id)?));
+        }
+        version.levels[0].sort_by_key(|table| Reverse(table.id));
+        for level in &mut version.levels[1..] {
+            level.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        }
+
+        if !read_only {
+            // tables the manifest doesn't name were being written when a
+            // flush or compaction was cut short
+            let named: HashSet<u64> = manifest.tables.iter().map(|(_, id)| *id).collect();
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let id = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_suffix(".sst"))
+                    .and_then(|id| id.parse::<u64>().ok());
+                if id.is_some_and(|id| !named.contains(&id)) {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+
+        let (wal, batches) = if read_only {
+            (None, Wal::read(dir)?)
+        } else {
+            let (wal, batches) = Wal::open(dir)?;
+            (Some(wal), batches)
+        };
+        let mut memtable = Memtable::default();
+        for (key, value) in batches.into_iter().flatten() {
+            memtable.insert(key, value);
+        }
+
+        let next_table_id = version
+            .tables()
+            .map(|table| table.id + 1)
+            .fold(manifest.next_table_id, u64::max);
+        let background = options.background_compaction && !read_only;
+        let shared = Arc::new(Shared {
+            dir: dir.to_path_buf(),
+            options,
+            state: Mutex::new(State {
+                memtable: Arc::new(memtable),
+                version: Arc::new(version),
+                wal,
+                next_table_id,
+                closed: false,
+                error: None,
+            }),
+            wake: Condvar::new(),
+            compacting: Mutex::new(()),
+            bloom: BloomCounters::default(),
+            _lock: lock,
+        });
+        let compactor = if background {
+            Some(Compactor::spawn(&shared)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            shared,
+            namespace: DEFAULT_NAMESPACE_ID,
+            read_only,
+            frozen: None,
+            compactor,
+        })
+    }
+
+    /// How many tables each level holds, starting with level 0.
+    pub fn levels(&self) -> Vec<usize> {
+        let (_, version) = self.current();
+        version.levels.iter().map(Vec::len).collect()
+    }
+
+    /// A read-only engine reading what this one does.
+    fn view(&self) -> LsmEngine {
+        LsmEngine {
+            shared: Arc::clone(&self.shared),
+            namespace: self.namespace,
+            read_only: true,
+            frozen: self.frozen.clone(),
+            compactor: None,
+        }
+    }
+
+    fn current(&self) -> (Arc<Memtable>, Arc<Version>) {
+        match &self.frozen {
+            Some((memtable, version)) => (Arc::clone(memtable), Arc::clone(version)),
+            None => {
+                let state = self.shared.lock_state();
+                (Arc::clone(&state.memtable), Arc::clone(&state.version))
+            }
+        }
+    }
+}
+
+impl SortedStore for LsmEngine {
+    fn lookup(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let (memtable, version) = self.current();
+        if let Some(value) = memtable.entries.get(key) {
+            return Ok(value.clone());
+        }
+
+        Ok(version.get(key, &self.shared.bloom)?.flatten())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8], f: &mut Visit) -> io::Result<()> {
+        let (memtable, version) = self.current();
+
+        let mut sources: Vec<Source> = vec![Box::new(
+            memtable
+                .entries
+                .range(prefix.to_vec()..)
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        )];
+        for table in &version.levels[0] {
+            sources.push(Box::new(table.iter_from(prefix)));
+        }
+        for level in &version.levels[1..] {
+            let first = level.partition_point(|table| table.last_key() < prefix);
+            sources.push(Box::new(
+                level[first..]
+                    .iter()
+                    .flat_map(move |table| table.iter_from(prefix)),
+            ));
+        }
+
+        for entry in Merge::new(sources) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            if let Some(value) = value {
+                f(&key, value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::ReadOnlyFilesystem,
+                "the database is open read-only",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, batch: Batch) -> io::Result<()> {
+        self.check_writable()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let flushed = {
+            let mut state = self.shared.lock_state();
+            if let Some(err) = state.error.take() {
+                return Err(err);
+            }
+
+            if let Some(wal) = &mut state.wal {
+                wal.append(&batch)?;
+            }
+            let memtable = Arc::make_mut(&mut state.memtable);
+            for (key, value) in batch {
+                memtable.insert(key, value);
+            }
+
+            if state.memtable.size >= self.shared.options.memtable_size {
+                self.shared.flush_memtable(&mut state)?;
+                true
+            } else {
+                false
+            }
+        };
+
+        if flushed {
+            if self.compactor.is_some() {
+                self.shared.wake.notify_all();
+            } else {
+                while self.shared.compact_step()? {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn namespace(&self) -> u16 {
+        self.namespace
+    }
+
+    fn set_namespace(&mut self, id: u16) {
+        self.namespace = id;
+    }
+}
+
+impl StorageEngine for LsmEngine {
+    fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        sorted::get(self, key)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        sorted::put(self, key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> io::Result<()> {
+        sorted::delete(self, key)
+    }
+
+    fn scan(&mut self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        sorted::scan(self, prefix)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_writable()?;
+        let _compacting = self.shared.lock_compacting();
+        let mut state = self.shared.lock_state();
+
+        let old = Arc::clone(&state.version);
+        self.shared
+            .install(&mut state, Version::empty(self.shared.options.levels))?;
+        for table in old.tables() {
+            table.mark_obsolete();
+        }
+        state.memtable = Arc::new(Memtable::default());
+        if let Some(wal) = &mut state.wal {
+            wal.reset()?;
+        }
+        self.namespace = DEFAULT_NAMESPACE_ID;
+
+        Ok(())
+    }
+
+    fn zadd(&mut self, key: &str, member: &str, score: f64) -> io::Result<()> {
+        sorted::zadd(self, key, member, score)
+    }
+
+    fn zrem(&mut self, key: &str, member: &str) -> io::Result<()> {
+        sorted::zrem(self, key, member)
+    }
+
+    fn zscore(&mut self, key: &str, member: &str) -> io::Result<Option<f64>> {
+        sorted::zscore(self, key, member)
+    }
+
+    fn zrange(&mut self, key: &str, min: f64, max: f64) -> io::Result<Vec<(String, f64)>> {
+        sorted::zrange(self, key, min, max)
+    }
+
+    fn zrank(&mut self, key: &str, member: &str) -> io::Result<Option<u64>> {
+        sorted::zrank(self, key, member)
+    }
+
+    fn sadd(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        sorted::sadd(self, key, member)
+    }
+
+    fn srem(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        sorted::srem(self, key, member)
+    }
+
+    fn sismember(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        sorted::sismember(self, key, member)
+    }
+
+    fn smembers(&mut self, key: &str) -> io::Result<Vec<String>> {
+        sorted::smembers(self, key)
+    }
+
+    fn scard(&mut self, key: &str) -> io::Result<u64> {
+        sorted::scard(self, key)
+    }
+
+    fn create_namespace(&mut self, name: &str) -> io::Result<Option<u16>> {
+        sorted::create_namespace(self, name)
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> io::Result<bool> {
+        sorted::drop_namespace(self, name)
+    }
+
+    fn namespaces(&mut self) -> io::Result<Vec<(u16, String)>> {
+        sorted::namespaces(self)
+    }
+
+    fn namespace_stats(&mut self) -> io::Result<Vec<NamespaceStats>> {
+        sorted::namespace_stats(self)
+    }
+
+    fn use_namespace(&mut self, id: u16) {
+        self.namespace = id;
+    }
+
+    fn current_namespace(&self) -> u16 {
+        self.namespace
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        sorted::truncate(self)
+    }
+
+    /// Everything in the directory that isn't a live key or value,
+    /// including table indexes and filters, counts as dead.
+    fn stats(&mut self) -> io::Result<StorageStats> {
+        Ok(StorageStats {
+            bloom_hits: self.shared.bloom.hits(),
+            bloom_misses: self.shared.bloom.misses(),
+            ..sorted::stats(self, self.shared.disk_size()?, FORMAT_VERSION)?
+        })
+    }
+
+    fn size(&mut self) -> io::Result<(u64, u64)> {
+        let (memtable, version) = self.current();
+        let entries = memtable.entries.len() as u64
+            + version.tables().map(|table| table.entries).sum::<u64>();
+        let bytes = memtable.size as u64 + version.tables().map(|table| table.size).sum::<u64>();
+
+        Ok((entries, bytes))
+    }
+
+    fn for_each_entry(&mut self, f: &mut dyn FnMut(Entry) -> io::Result<()>) -> io::Result<()> {
+        sorted::for_each_entry(self, f)
+    }
+
+    fn append_entries(&mut self, entries: &[Entry]) -> io::Result<()> {
+        sorted::append_entries(self, entries)
+    }
+
+    fn reader(&self) -> Box<dyn StorageEngine> {
+        Box::new(self.view())
+    }
+
+    fn snapshot_reader(&self) -> Box<dyn StorageEngine> {
+        Box::new(LsmEngine {
+            frozen: Some(self.current()),
+            ..self.view()
+        })
+    }
+
+    /// Writes out the memtable and merges every table into the last level,
+    /// dropping tombstones and replaced values. Tables a view is still
+    /// reading from are only removed once it is dropped.
+    fn compact(&mut self) -> io::Result<u64> {
+        self.check_writable()?;
+        let shared = Arc::clone(&self.shared);
+        let _compacting = shared.lock_compacting();
+        let before = shared.disk_size()?;
+
+        let version = {
+            let mut state = shared.lock_state();
+            shared.flush_memtable(&mut state)?;
+            Arc::clone(&state.version)
+        };
+        let upper: Vec<Arc<Table>> = version.tables().cloned().collect();
+        drop(version);
+        if !upper.is_empty() {
+            shared.run_job(Job {
+                upper,
+                lower: vec![],
+                output_level: shared.options.levels - 1,
+                bottom: true,
+            })?;
+        }
+
+        Ok(before.saturating_sub(shared.disk_size()?))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
@@ -0,0 +1,91 @@
+// the manifest: which tables make up the database, and at which level
+//
+// it is rewritten in full whenever the set of tables changes, next to the
+// old one and then renamed over it, so it always names a complete set of
+// tables. tables it doesn't name are leftovers from an interrupted flush
+// or compaction
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+
+use crate::invalid_data;
+
+const MAGIC_BYTES: [u8; 6] = [0, 107, 105, 118, 109, 102];
+const VERSION: u16 = 1;
+// magic, version, next table id and table count
+const HEADER_LENGTH: usize = 6 + 2 + 8 + 4;
+
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join("MANIFEST")
+}
+
+#[derive(Default)]
+pub(super) struct Manifest {
+    pub(super) next_table_id: u64,
+    /// `(level, table id)` pairs.
+    pub(super) tables: Vec<(u8, u64)>,
+}
+
+impl Manifest {
+    /// Reads the manifest in `dir`, if there is one.
+    pub(super) fn read(dir: &Path) -> io::Result<Option<Self>> {
+        let bytes = match std::fs::read(manifest_path(dir)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        if bytes.len() < HEADER_LENGTH + 4
+            || bytes[..6] != MAGIC_BYTES
+            || BigEndian::read_u16(&bytes[6..8]) != VERSION
+        {
+            return Err(invalid_data("the manifest has an unknown format"));
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(body) != BigEndian::read_u32(crc) {
+            return Err(invalid_data("the manifest has a bad checksum"));
+        }
+
+        let next_table_id = BigEndian::read_u64(&body[8..16]);
+        let count = BigEndian::read_u32(&body[16..20]) as usize;
+        let tables = &body[HEADER_LENGTH..];
+        if tables.len() != count * 9 {
+            return Err(invalid_data("the manifest has the wrong number of tables"));
+        }
+
+        Ok(Some(Self {
+            next_table_id,
+            tables: tables
+                .chunks(9)
+                .map(|table| (table[0], BigEndian::read_u64(&table[1..])))
+                .collect(),
+        }))
+    }
+
+    pub(super) fn write(&self, dir: &Path) -> io::Result<()> {
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&MAGIC_BYTES);
+        bytes.put_u16(VERSION);
+        bytes.put_u64(self.next_table_id);
+        bytes.put_u32(self.tables.len() as u32);
+        for (level, id) in &self.tables {
+            bytes.put_u8(*level);
+            bytes.put_u64(*id);
+        }
+        let crc = crc32fast::hash(&bytes);
+        bytes.put_u32(crc);
+
+        let path = manifest_path(dir);
+        let temp_path = dir.join("MANIFEST.tmp");
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(&bytes)?;
+        temp.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    }
+}
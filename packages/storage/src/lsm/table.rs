@@ -0,0 +1,411 @@
+// sorted string tables: immutable files of entries in key order
+//
+// a table is a header, then blocks of entries, then an index holding the
+// last key of every block, then a bloom filter of every key, then a fixed
+// size footer saying where the index and filter are. a lookup checks the
+// filter, binary searches the index and reads a single block
+//
+// entries are a flag, the key and, for puts, the value, with 4-byte length
+// prefixes. a tombstone shadows the key in older tables until compaction
+// drops it
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+
+use crate::{
+    bloom::{self, BloomCounters, BloomFilter},
+    file::PositionedFile,
+    invalid_data,
+};
+
+use crate::sorted::Value;
+
+const MAGIC_BYTES: [u8; 6] = [0, 107, 105, 118, 115, 116];
+const VERSION: u16 = 1;
+const HEADER_LENGTH: u64 = 6 + 2;
+// index offset and length, filter offset and length, entry count and a
+// CRC32 of the index and filter
+const FOOTER_LENGTH: u64 = 8 + 4 + 8 + 4 + 8 + 4;
+
+const PUT: u8 = 0;
+const TOMBSTONE: u8 = 1;
+
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:08}.sst", id))
+}
+
+/// Where a block is, and the last key in it.
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    length: u32,
+    crc: u32,
+}
+
+pub(super) struct Table {
+    pub(super) id: u64,
+    path: PathBuf,
+    file: PositionedFile,
+    pub(super) size: u64,
+    pub(super) entries: u64,
+    pub(super) first_key: Vec<u8>,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    /// Set once compaction has replaced the table. The file is removed when
+    /// nothing can read it any more.
+    obsolete: AtomicBool,
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::Relaxed) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Table {
+    pub(super) fn open(dir: &Path, id: u64) -> io::Result<Self> {
+        let path = table_path(dir, id);
+        let mut file = PositionedFile::new(File::open(&path)?);
+        let size = file.metadata()?.len();
+        if size < HEADER_LENGTH + FOOTER_LENGTH {
+            return Err(invalid_data(format!("table {} is truncated", id)));
+        }
+
+        let mut header = [0u8; HEADER_LENGTH as usize];
+        file.read_exact(&mut header)?;
+        if header[..6] != MAGIC_BYTES || BigEndian::read_u16(&header[6..]) != VERSION {
+            return Err(invalid_data(format!("table {} has an unknown format", id)));
+        }
+
+        let mut footer = [0u8; FOOTER_LENGTH as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_LENGTH))?;
+        file.read_exact(&mut footer)?;
+        let index_offset = BigEndian::read_u64(&footer[0..8]);
+        let index_length = BigEndian::read_u32(&footer[8..12]) as u64;
+        let bloom_offset = BigEndian::read_u64(&footer[12..20]);
+        let bloom_length = BigEndian::read_u32(&footer[20..24]) as u64;
+        let entries = BigEndian::read_u64(&footer[24..32]);
+        let crc = BigEndian::read_u32(&footer[32..36]);
+        if index_offset + index_length != bloom_offset
+            || bloom_offset + bloom_length != size - FOOTER_LENGTH
+        {
+            return Err(invalid_data(format!("table {} has a malformed footer", id)));
+        }
+
+        let mut meta = vec![0u8; (index_length + bloom_length) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut meta)?;
+        if crc32fast::hash(&meta) != crc {
+            return Err(invalid_data(format!("table {} has a bad checksum", id)));
+        }
+        let (mut index_bytes, bloom_bytes) = meta.split_at(index_length as usize);
+
+        let first_key = take_prefixed(&mut index_bytes)?.to_vec();
+        let mut index = vec![];
+        while !index_bytes.is_empty() {
+            let last_key = take_prefixed(&mut index_bytes)?.to_vec();
+            let fields = take(&mut index_bytes, 8 + 4 + 4)?;
+            index.push(BlockHandle {
+                last_key,
+                offset: BigEndian::read_u64(&fields[0..8]),
+                length: BigEndian::read_u32(&fields[8..12]),
+                crc: BigEndian::read_u32(&fields[12..16]),
+            });
+        }
+
+        Ok(Self {
+            id,
+            path,
+            file,
+            size,
+            entries,
+            first_key,
+            index,
+            bloom: BloomFilter::from_bytes(bloom_bytes)?,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    pub(super) fn last_key(&self) -> &[u8] {
+        self.index
+            .last()
+            .map(|block| block.last_key.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Whether the table's keys overlap `first..=last`.
+    pub(super) fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        self.first_key.as_slice() <= last && self.last_key() >= first
+    }
+
+    pub(super) fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::Relaxed);
+    }
+
+    /// Looks a key up, returning `None` if the table doesn't mention it.
+    /// How the bloom filter answered is counted in `counters`.
+    pub(super) fn get(&self, key: &[u8], counters: &BloomCounters) -> io::Result<Option<Value>> {
+        if !counters.record(self.bloom.may_contain(key)) {
+            return Ok(None);
+        }
+
+        let block = self
+            .index
+            .partition_point(|block| block.last_key.as_slice() < key);
+        if block == self.index.len() {
+            return Ok(None);
+        }
+
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value))
+    }
+
+    fn read_block(&self, block: usize) -> io::Result<Vec<(Vec<u8>, Value)>> {
+        let handle = &self.index[block];
+        let mut bytes = vec![0u8; handle.length as usize];
+        let mut file = self.file.reader();
+        file.seek(SeekFrom::Start(handle.offset))?;
+        file.read_exact(&mut bytes)?;
+        if crc32fast::hash(&bytes) != handle.crc {
+            return Err(invalid_data(format!(
+                "block at {} in table {} has a bad checksum",
+                handle.offset, self.id
+            )));
+        }
+
+        let mut entries = vec![];
+        let mut rest = bytes.as_slice();
+        while !rest.is_empty() {
+            let flag = take(&mut rest, 1)?[0];
+            let key = take_prefixed(&mut rest)?.to_vec();
+            let value = match flag {
+                PUT => Some(take_prefixed(&mut rest)?.to_vec()),
+                TOMBSTONE => None,
+                _ => return Err(invalid_data(format!("unknown table entry flag {}", flag))),
+            };
+            entries.push((key, value));
+        }
+
+        Ok(entries)
+    }
+
+    /// Every entry from the first key at or after `start`, in key order.
+    pub(super) fn iter_from(self: &Arc<Self>, start: &[u8]) -> TableIter {
+        let block = self
+            .index
+            .partition_point(|block| block.last_key.as_slice() < start);
+
+        TableIter {
+            table: Arc::clone(self),
+            block,
+            start: start.to_vec(),
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    /// The next block to read.
+    block: usize,
+    start: Vec<u8>,
+    entries: std::vec::IntoIter<(Vec<u8>, Value)>,
+}
+
+impl Iterator for TableIter {
+    type Item = io::Result<(Vec<u8>, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                if entry.0 >= self.start {
+                    return Some(Ok(entry));
+                }
+                continue;
+            }
+
+            if self.block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(err) => {
+                    // don't keep failing on the same block
+                    self.block = self.table.index.len();
+                    return Some(Err(err));
+                }
+            }
+            self.block += 1;
+        }
+    }
+}
+
+/// Writes a table, one entry at a time in key order.
+pub(super) struct TableWriter {
+    id: u64,
+    path: PathBuf,
+    file: BufWriter<File>,
+    block_size: usize,
+    false_positive_rate: f64,
+    block: BytesMut,
+    last_key: Vec<u8>,
+    first_key: Option<Vec<u8>>,
+    index: BytesMut,
+    hashes: Vec<(u64, u64)>,
+    offset: u64,
+}
+
+impl TableWriter {
+    pub(super) fn create(
+        dir: &Path,
+        id: u64,
+        block_size: usize,
+        false_positive_rate: f64,
+    ) -> io::Result<Self> {
+        let path = table_path(dir, id);
+        let mut file = BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?,
+        );
+        file.write_all(&MAGIC_BYTES)?;
+        file.write_all(&VERSION.to_be_bytes())?;
+
+        Ok(Self {
+            id,
+            path,
+            file,
+            block_size,
+            false_positive_rate,
+            block: BytesMut::new(),
+            last_key: vec![],
+            first_key: None,
+            index: BytesMut::new(),
+            hashes: vec![],
+            offset: HEADER_LENGTH,
+        })
+    }
+
+    /// Adds an entry. Keys have to be added in order, without repeats.
+    pub(super) fn add(&mut self, key: &[u8], value: &Value) -> io::Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+
+        match value {
+            Some(value) => {
+                self.block.put_u8(PUT);
+                put_prefixed(&mut self.block, key);
+                put_prefixed(&mut self.block, value);
+            }
+            None => {
+                self.block.put_u8(TOMBSTONE);
+                put_prefixed(&mut self.block, key);
+            }
+        }
+        self.hashes.push(bloom::hash(key));
+        self.last_key = key.to_vec();
+
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+
+        Ok(())
+    }
+
+    /// Roughly how big the table is so far.
+    pub(super) fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        put_prefixed(&mut self.index, &self.last_key);
+        self.index.put_u64(self.offset);
+        self.index.put_u32(self.block.len() as u32);
+        self.index.put_u32(crc32fast::hash(&self.block));
+
+        self.file.write_all(&self.block)?;
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+
+        Ok(())
+    }
+
+    /// Writes the index, filter and footer, and syncs the table to disk.
+    pub(super) fn finish(mut self, dir: &Path) -> io::Result<Table> {
+        self.finish_block()?;
+
+        let mut meta = BytesMut::new();
+        put_prefixed(&mut meta, self.first_key.as_deref().unwrap_or_default());
+        meta.put(self.index.split());
+        let index_length = meta.len();
+
+        let mut bloom = BloomFilter::new(self.hashes.len(), self.false_positive_rate);
+        for hash in &self.hashes {
+            bloom.insert_hash(*hash);
+        }
+        meta.extend_from_slice(&bloom.to_bytes());
+
+        let mut footer = BytesMut::new();
+        footer.put_u64(self.offset);
+        footer.put_u32(index_length as u32);
+        footer.put_u64(self.offset + index_length as u64);
+        footer.put_u32((meta.len() - index_length) as u32);
+        footer.put_u64(self.hashes.len() as u64);
+        footer.put_u32(crc32fast::hash(&meta));
+
+        self.file.write_all(&meta)?;
+        self.file.write_all(&footer)?;
+        let file = self.file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        drop(file);
+
+        Table::open(dir, self.id)
+    }
+
+    /// Removes the partly written table.
+    pub(super) fn abandon(self) {
+        drop(self.file);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn put_prefixed(bytes: &mut BytesMut, field: &[u8]) {
+    bytes.put_u32(field.len() as u32);
+    bytes.extend_from_slice(field);
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> io::Result<&'a [u8]> {
+    if bytes.len() < length {
+        return Err(invalid_data("table entry runs past the end of its block"));
+    }
+    let (field, rest) = bytes.split_at(length);
+    *bytes = rest;
+
+    Ok(field)
+}
+
+fn take_prefixed<'a>(bytes: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let length = BigEndian::read_u32(take(bytes, 4)?) as usize;
+    take(bytes, length)
+}
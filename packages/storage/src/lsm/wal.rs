@@ -0,0 +1,171 @@
+// the write-ahead log: every write not yet in a table, so the memtable can
+// be rebuilt after a restart
+//
+// each record is a batch of puts and tombstones applied together, with its
+// length and a CRC32 in front. a record cut short by a crash is dropped
+// when the log is read back, along with anything after it. the log is
+// emptied once the memtable it describes has been written out as a table
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+
+use crate::invalid_data;
+
+use crate::sorted::{Batch, Value};
+
+const MAGIC_BYTES: [u8; 6] = [0, 107, 105, 118, 119, 108];
+const VERSION: u16 = 1;
+const HEADER_LENGTH: u64 = 6 + 2;
+// payload length and a CRC32 of the payload
+const RECORD_HEADER_LENGTH: usize = 4 + 4;
+
+const PUT: u8 = 0;
+const TOMBSTONE: u8 = 1;
+
+fn wal_path(dir: &Path) -> PathBuf {
+    dir.join("wal.log")
+}
+
+pub(super) struct Wal {
+    file: File,
+}
+
+impl Wal {
+    /// Opens the log in `dir`, creating it if needed, and returns it with
+    /// every batch it holds.
+    pub(super) fn open(dir: &Path) -> io::Result<(Self, Vec<Batch>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(wal_path(dir))?;
+
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        let batches = if bytes.is_empty() {
+            file.write_all(&MAGIC_BYTES)?;
+            file.write_all(&VERSION.to_be_bytes())?;
+            vec![]
+        } else {
+            let (batches, length) = parse(&bytes)?;
+            // drop a torn record, so later ones aren't written after it
+            file.set_len(length)?;
+            batches
+        };
+        file.seek(SeekFrom::End(0))?;
+
+        Ok((Self { file }, batches))
+    }
+
+    /// Reads the batches in the log in `dir` without changing it, for
+    /// read-only opens.
+    pub(super) fn read(dir: &Path) -> io::Result<Vec<Batch>> {
+        match std::fs::read(wal_path(dir)) {
+            Ok(bytes) if bytes.is_empty() => Ok(vec![]),
+            Ok(bytes) => Ok(parse(&bytes)?.0),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(super) fn append(&mut self, batch: &[(Vec<u8>, Value)]) -> io::Result<()> {
+        let mut payload = BytesMut::new();
+        payload.put_u32(batch.len() as u32);
+        for (key, value) in batch {
+            match value {
+                Some(value) => {
+                    payload.put_u8(PUT);
+                    payload.put_u32(key.len() as u32);
+                    payload.extend_from_slice(key);
+                    payload.put_u32(value.len() as u32);
+                    payload.extend_from_slice(value);
+                }
+                None => {
+                    payload.put_u8(TOMBSTONE);
+                    payload.put_u32(key.len() as u32);
+                    payload.extend_from_slice(key);
+                }
+            }
+        }
+
+        let mut record = BytesMut::with_capacity(RECORD_HEADER_LENGTH + payload.len());
+        record.put_u32(payload.len() as u32);
+        record.put_u32(crc32fast::hash(&payload));
+        record.put(payload);
+
+        self.file.write_all(&record)
+    }
+
+    /// Empties the log, once everything in it is in a table.
+    pub(super) fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(HEADER_LENGTH)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.sync_all()
+    }
+}
+
+/// Returns the complete batches in a log, and how far they reach.
+fn parse(bytes: &[u8]) -> io::Result<(Vec<Batch>, u64)> {
+    if bytes.len() < HEADER_LENGTH as usize
+        || bytes[..6] != MAGIC_BYTES
+        || BigEndian::read_u16(&bytes[6..8]) != VERSION
+    {
+        return Err(invalid_data("the write-ahead log has an unknown format"));
+    }
+
+    let mut batches = vec![];
+    let mut offset = HEADER_LENGTH as usize;
+    while let Some(header) = bytes.get(offset..offset + RECORD_HEADER_LENGTH) {
+        let length = BigEndian::read_u32(&header[0..4]) as usize;
+        let crc = BigEndian::read_u32(&header[4..8]);
+        let start = offset + RECORD_HEADER_LENGTH;
+        let Some(payload) = bytes.get(start..start + length) else {
+            break;
+        };
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        let Some(batch) = parse_batch(payload) else {
+            break;
+        };
+
+        batches.push(batch);
+        offset = start + length;
+    }
+
+    Ok((batches, offset as u64))
+}
+
+fn parse_batch(mut payload: &[u8]) -> Option<Batch> {
+    fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+        let field = bytes.get(..length)?;
+        *bytes = &bytes[length..];
+        Some(field)
+    }
+    fn take_prefixed<'a>(bytes: &mut &'a [u8]) -> Option<&'a [u8]> {
+        let length = BigEndian::read_u32(take(bytes, 4)?) as usize;
+        take(bytes, length)
+    }
+
+    let count = BigEndian::read_u32(take(&mut payload, 4)?);
+    let mut batch = vec![];
+    for _ in 0..count {
+        let flag = take(&mut payload, 1)?[0];
+        let key = take_prefixed(&mut payload)?.to_vec();
+        let value = match flag {
+            PUT => Some(take_prefixed(&mut payload)?.to_vec()),
+            TOMBSTONE => None,
+            _ => return None,
+        };
+        batch.push((key, value));
+    }
+
+    Some(batch)
+}
@@ -1,37 +1,135 @@
-use storage::Storage;
+use storage::{Entry, NamespaceStats, Storage};
 
 fn main() {
+    // start from an empty file, now that files persist between opens
+    let _ = std::fs::remove_file("test.kiv");
     let mut storage = Storage::open("test.kiv").expect("failed");
 
     storage
         .write_data_entry("test key", "test value hello")
         .unwrap();
     assert_eq!(
-        storage.get_data_entry(&"test key".to_string()).unwrap(),
-        Some("test value hello".to_string())
+        storage.get_data_entry("test key").unwrap(),
+        Some(b"test value hello".to_vec())
     );
     storage
         .write_data_entry("test2", "test value hello2")
         .unwrap();
     assert_eq!(
-        storage.get_data_entry(&"test2".to_string()).unwrap(),
-        Some("test value hello2".to_string())
+        storage.get_data_entry("test2").unwrap(),
+        Some(b"test value hello2".to_vec())
     );
-    storage.delete_data_entry(&"test key".to_string()).unwrap();
+    storage.delete_data_entry("test key").unwrap();
+    assert_eq!(storage.get_data_entry("test key").unwrap(), None);
     assert_eq!(
-        storage.get_data_entry(&"test key".to_string()).unwrap(),
-        None
+        storage.get_data_entry("test2").unwrap(),
+        Some(b"test value hello2".to_vec())
+    );
+    storage.update_data_entry("test2", "updated value").unwrap();
+
+    assert_eq!(
+        storage.get_data_entry("test2").unwrap(),
+        Some(b"updated value".to_vec())
+    );
+
+    storage
+        .write_sorted_set_entry("board", "alice", 30.0)
+        .unwrap();
+    storage
+        .write_sorted_set_entry("board", "bob", 10.0)
+        .unwrap();
+    storage
+        .write_sorted_set_entry("board", "carol", 20.0)
+        .unwrap();
+    assert_eq!(
+        storage.get_sorted_set_score("board", "bob").unwrap(),
+        Some(10.0)
     );
     assert_eq!(
-        storage.get_data_entry(&"test2".to_string()).unwrap(),
-        Some("test value hello2".to_string())
+        storage.get_sorted_set_rank("board", "alice").unwrap(),
+        Some(2)
     );
     storage
-        .update_data_entry(&"test2".to_string(), &"updated value".to_string())
+        .write_sorted_set_entry("board", "bob", 40.0)
         .unwrap();
+    assert_eq!(
+        storage.get_sorted_set_range("board", 15.0, 40.0).unwrap(),
+        vec![
+            ("carol".to_string(), 20.0),
+            ("alice".to_string(), 30.0),
+            ("bob".to_string(), 40.0)
+        ]
+    );
+    storage.delete_sorted_set_entry("board", "carol").unwrap();
+    assert_eq!(
+        storage.get_sorted_set_score("board", "carol").unwrap(),
+        None
+    );
+    assert_eq!(
+        storage.get_data_entry("test2").unwrap(),
+        Some(b"updated value".to_vec())
+    );
+    assert!(storage.write_set_entry("flags", "beta").unwrap());
+    assert!(storage.write_set_entry("flags", "alpha").unwrap());
+    assert!(!storage.write_set_entry("flags", "beta").unwrap());
+    assert!(storage.is_set_member("flags", "alpha").unwrap());
+    assert_eq!(storage.get_set_cardinality("flags").unwrap(), 2);
+    assert_eq!(
+        storage.get_set_members("flags").unwrap(),
+        vec!["alpha".to_string(), "beta".to_string()]
+    );
+    assert!(storage.delete_set_entry("flags", "alpha").unwrap());
+    assert!(!storage.is_set_member("flags", "alpha").unwrap());
+    assert_eq!(
+        storage.get_sorted_set_score("board", "alice").unwrap(),
+        Some(30.0)
+    );
 
+    let team = storage.create_namespace("team").unwrap().unwrap();
+    assert_eq!(storage.create_namespace("team").unwrap(), None);
+    storage.use_namespace(team);
+    assert_eq!(storage.get_data_entry("test2").unwrap(), None);
+    storage.write_data_entry("test2", "team value").unwrap();
     assert_eq!(
-        storage.get_data_entry(&"test2".to_string()).unwrap(),
-        Some("updated value".to_string())
+        storage.get_data_entry("test2").unwrap(),
+        Some(b"team value".to_vec())
     );
+
+    // reopening keeps entries and namespaces
+    drop(storage);
+    let mut storage = Storage::open("test.kiv").expect("failed");
+    assert_eq!(storage.get_namespace_id("team").unwrap(), Some(team));
+    assert_eq!(
+        storage.get_data_entry("test2").unwrap(),
+        Some(b"updated value".to_vec())
+    );
+    assert_eq!(
+        storage.get_namespace_stats().unwrap()[1],
+        NamespaceStats {
+            name: "team".to_string(),
+            entries: 1,
+            bytes: 44,
+        }
+    );
+    assert!(storage.drop_namespace("team").unwrap());
+    assert_eq!(storage.get_namespace_id("team").unwrap(), None);
+    assert!(!storage.drop_namespace("default").unwrap());
+    assert_eq!(
+        storage.get_data_entry("test2").unwrap(),
+        Some(b"updated value".to_vec())
+    );
+
+    let mut entries = vec![];
+    storage
+        .for_each_entry(|entry| {
+            entries.push(entry);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(entries.len(), 4);
+    assert!(entries.contains(&Entry::SetMember {
+        namespace: 0,
+        key: "flags".to_string(),
+        member: "beta".to_string(),
+    }));
 }
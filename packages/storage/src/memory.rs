@@ -0,0 +1,521 @@
+// a storage engine that keeps everything in memory, for tests and caches
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use crate::{
+    check_length, compare_scored, engine::StorageEngine, Entry, NamespaceStats, StorageStats,
+    DEFAULT_NAMESPACE, DEFAULT_NAMESPACE_ID,
+};
+
+/// Keeps entries in memory, losing them when the last handle is dropped.
+///
+/// Entries are held to the same length limits as in a file, so they can
+/// always be backed up to one. Stats report a `file_size` and `version` of
+/// 0, and count the bytes of keys, members, scores and values as live.
+/// Snapshots copy everything, so they are only cheap for small databases.
+pub struct MemoryEngine {
+    data: Data,
+    namespace: u16,
+    read_only: bool,
+}
+
+enum Data {
+    Live(Arc<RwLock<Contents>>),
+    /// Read by snapshots, which never change.
+    Frozen(Arc<Contents>),
+}
+
+#[derive(Clone, Default)]
+struct Contents {
+    /// Every namespace besides the default one.
+    namespaces: BTreeMap<u16, String>,
+    values: BTreeMap<(u16, Vec<u8>), Vec<u8>>,
+    sorted_sets: BTreeMap<(u16, String), BTreeMap<String, f64>>,
+    sets: BTreeMap<(u16, String), BTreeSet<String>>,
+}
+
+impl Contents {
+    fn remove_namespace_entries(&mut self, id: u16) {
+        self.values.retain(|(namespace, _), _| *namespace != id);
+        self.sorted_sets
+            .retain(|(namespace, _), _| *namespace != id);
+        self.sets.retain(|(namespace, _), _| *namespace != id);
+    }
+
+    fn sorted_set(&self, namespace: u16, key: &str) -> Vec<(String, f64)> {
+        let mut members: Vec<(String, f64)> = self
+            .sorted_sets
+            .get(&(namespace, key.to_string()))
+            .map(|members| members.iter().map(|(m, s)| (m.clone(), *s)).collect())
+            .unwrap_or_default();
+        members.sort_by(compare_scored);
+
+        members
+    }
+}
+
+impl Default for MemoryEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryEngine {
+    pub fn new() -> Self {
+        Self {
+            data: Data::Live(Arc::new(RwLock::new(Contents::default()))),
+            namespace: DEFAULT_NAMESPACE_ID,
+            read_only: false,
+        }
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&Contents) -> T) -> T {
+        match &self.data {
+            Data::Live(contents) => f(&contents.read().unwrap_or_else(PoisonError::into_inner)),
+            Data::Frozen(contents) => f(contents),
+        }
+    }
+
+    fn write<T>(&mut self, f: impl FnOnce(&mut Contents) -> T) -> io::Result<T> {
+        match &self.data {
+            Data::Live(contents) if !self.read_only => Ok(f(&mut contents
+                .write()
+                .unwrap_or_else(PoisonError::into_inner))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::ReadOnlyFilesystem,
+                "the engine is read-only",
+            )),
+        }
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let namespace = self.namespace;
+        Ok(self.read(|contents| contents.values.get(&(namespace, key.to_vec())).cloned()))
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        check_length("key", key.len(), u16::MAX as usize)?;
+        check_length("value", value.len(), u32::MAX as usize)?;
+        let namespace = self.namespace;
+        self.write(|contents| {
+            contents
+                .values
+                .insert((namespace, key.to_vec()), value.to_vec());
+        })
+    }
+
+    fn delete(&mut self, key: &[u8]) -> io::Result<()> {
+        let namespace = self.namespace;
+        self.write(|contents| {
+            contents.values.remove(&(namespace, key.to_vec()));
+        })
+    }
+
+    fn scan(&mut self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let namespace = self.namespace;
+        Ok(self.read(|contents| {
+            contents
+                .values
+                .range((namespace, prefix.to_vec())..)
+                .take_while(|((n, key), _)| *n == namespace && key.starts_with(prefix))
+                .map(|((_, key), value)| (key.clone(), value.clone()))
+                .collect()
+        }))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write(|contents| *contents = Contents::default())?;
+        self.namespace = DEFAULT_NAMESPACE_ID;
+
+        Ok(())
+    }
+
+    fn zadd(&mut self, key: &str, member: &str, score: f64) -> io::Result<()> {
+        check_length("key", key.len(), u16::MAX as usize)?;
+        check_length("member", member.len(), u16::MAX as usize)?;
+        let namespace = self.namespace;
+        self.write(|contents| {
+            contents
+                .sorted_sets
+                .entry((namespace, key.to_string()))
+                .or_default()
+                .insert(member.to_string(), score);
+        })
+    }
+
+    fn zrem(&mut self, key: &str, member: &str) -> io::Result<()> {
+        let namespace = self.namespace;
+        self.write(|contents| {
+            let key = (namespace, key.to_string());
+            if let Some(members) = contents.sorted_sets.get_mut(&key) {
+                members.remove(member);
+                if members.is_empty() {
+                    contents.sorted_sets.remove(&key);
+                }
+            }
+        })
+    }
+
+    fn zscore(&mut self, key: &str, member: &str) -> io::Result<Option<f64>> {
+        let namespace = self.namespace;
+        Ok(self.read(|contents| {
+            contents
+                .sorted_sets
+                .get(&(namespace, key.to_string()))
+                .and_then(|members| members.get(member).copied())
+        }))
+    }
+
+    fn zrange(&mut self, key: &str, min: f64, max: f64) -> io::Result<Vec<(String, f64)>> {
+        let namespace = self.namespace;
+        Ok(self.read(|contents| {
+            contents
+                .sorted_set(namespace, key)
+                .into_iter()
+                .filter(|(_, score)| *score >= min && *score <= max)
+                .collect()
+        }))
+    }
+
+    fn zrank(&mut self, key: &str, member: &str) -> io::Result<Option<u64>> {
+        let namespace = self.namespace;
+        Ok(self.read(|contents| {
+            contents
+                .sorted_set(namespace, key)
+                .iter()
+                .position(|(m, _)| m == member)
+                .map(|rank| rank as u64)
+        }))
+    }
+
+    fn sadd(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        check_length("key", key.len(), u16::MAX as usize)?;
+        check_length("member", member.len(), u16::MAX as usize)?;
+        let namespace = self.namespace;
+        self.write(|contents| {
+            contents
+                .sets
+                .entry((namespace, key.to_string()))
+                .or_default()
+                .insert(member.to_string())
+        })
+    }
+
+    fn srem(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        let namespace = self.namespace;
+        self.write(|contents| {
+            let key = (namespace, key.to_string());
+            let Some(members) = contents.sets.get_mut(&key) else {
+                return false;
+            };
+            let removed = members.remove(member);
+            if members.is_empty() {
+                contents.sets.remove(&key);
+            }
+
+            removed
+        })
+    }
+
+    fn sismember(&mut self, key: &str, member: &str) -> io::Result<bool> {
+        let namespace = self.namespace;
+        Ok(self.read(|contents| {
+            contents
+                .sets
+                .get(&(namespace, key.to_string()))
+                .is_some_and(|members| members.contains(member))
+        }))
+    }
+
+    fn smembers(&mut self, key: &str) -> io::Result<Vec<String>> {
+        let namespace = self.namespace;
+        Ok(self.read(|contents| {
+            contents
+                .sets
+                .get(&(namespace, key.to_string()))
+                .map(|members| members.iter().cloned().collect())
+                .unwrap_or_default()
+        }))
+    }
+
+    fn scard(&mut self, key: &str) -> io::Result<u64> {
+        let namespace = self.namespace;
+        Ok(self.read(|contents| {
+            contents
+                .sets
+                .get(&(namespace, key.to_string()))
+                .map_or(0, |members| members.len() as u64)
+        }))
+    }
+
+    fn create_namespace(&mut self, name: &str) -> io::Result<Option<u16>> {
+        check_length("namespace name", name.len(), u16::MAX as usize)?;
+        self.write(|contents| {
+            if name == DEFAULT_NAMESPACE || contents.namespaces.values().any(|n| n == name) {
+                return None;
+            }
+
+            let id = contents.namespaces.keys().max().copied().unwrap_or(0) + 1;
+            contents.namespaces.insert(id, name.to_string());
+
+            Some(id)
+        })
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> io::Result<bool> {
+        let dropped = self.write(|contents| {
+            let id = contents
+                .namespaces
+                .iter()
+                .find(|(_, n)| *n == name)
+                .map(|(id, _)| *id)?;
+            contents.namespaces.remove(&id);
+            contents.remove_namespace_entries(id);
+
+            Some(id)
+        })?;
+
+        if dropped == Some(self.namespace) {
+            self.namespace = DEFAULT_NAMESPACE_ID;
+        }
+
+        Ok(dropped.is_some())
+    }
+
+    fn namespaces(&mut self) -> io::Result<Vec<(u16, String)>> {
+        let mut namespaces = vec![(DEFAULT_NAMESPACE_ID, DEFAULT_NAMESPACE.to_string())];
+        self.read(|contents| {
+            namespaces.extend(
+                contents
+                    .namespaces
+                    .iter()
+                    .map(|(id, name)| (*id, name.clone())),
+            )
+        });
+
+        Ok(namespaces)
+    }
+
+    fn namespace_stats(&mut self) -> io::Result<Vec<NamespaceStats>> {
+        let namespaces = self.namespaces()?;
+        Ok(self.read(|contents| {
+            namespaces
+                .into_iter()
+                .map(|(id, name)| {
+                    let mut stats = NamespaceStats {
+                        name,
+                        entries: 0,
+                        bytes: 0,
+                    };
+                    for_each_size(contents, |namespace, _, bytes| {
+                        if namespace == id {
+                            stats.entries += 1;
+                            stats.bytes += bytes;
+                        }
+                    });
+
+                    stats
+                })
+                .collect()
+        }))
+    }
+
+    fn use_namespace(&mut self, id: u16) {
+        self.namespace = id;
+    }
+
+    fn current_namespace(&self) -> u16 {
+        self.namespace
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        let namespace = self.namespace;
+        self.write(|contents| contents.remove_namespace_entries(namespace))
+    }
+
+    fn stats(&mut self) -> io::Result<StorageStats> {
+        Ok(self.read(|contents| {
+            let mut keys = HashSet::new();
+            let mut live_bytes = 0;
+            for_each_size(contents, |namespace, key, bytes| {
+                keys.insert((namespace, key.to_vec()));
+                live_bytes += bytes;
+            });
+
+            StorageStats {
+                keys: keys.len() as u64,
+                file_size: 0,
+                live_bytes,
+                dead_bytes: 0,
+                version: 0,
+                bloom_hits: 0,
+                bloom_misses: 0,
+                compression_ratio: 1.0,
+            }
+        }))
+    }
+
+    fn size(&mut self) -> io::Result<(u64, u64)> {
+        Ok(self.read(|contents| {
+            let (mut entries, mut size) = (contents.namespaces.len() as u64, 0);
+            for_each_size(contents, |_, _, bytes| {
+                entries += 1;
+                size += bytes;
+            });
+
+            (entries, size)
+        }))
+    }
+
+    fn for_each_entry(&mut self, f: &mut dyn FnMut(Entry) -> io::Result<()>) -> io::Result<()> {
+        // collected first, so `f` can take as long as it likes without
+        // holding up writes
+        let entries = self.read(|contents| {
+            let mut entries = vec![];
+            for (id, name) in &contents.namespaces {
+                entries.push(Entry::Namespace {
+                    id: *id,
+                    name: name.clone(),
+                });
+            }
+            for ((namespace, key), value) in &contents.values {
+                entries.push(Entry::Data {
+                    namespace: *namespace,
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+            }
+            for ((namespace, key), members) in &contents.sorted_sets {
+                for (member, score) in members {
+                    entries.push(Entry::SortedSetMember {
+                        namespace: *namespace,
+                        key: key.clone(),
+                        member: member.clone(),
+                        score: *score,
+                    });
+                }
+            }
+            for ((namespace, key), members) in &contents.sets {
+                for member in members {
+                    entries.push(Entry::SetMember {
+                        namespace: *namespace,
+                        key: key.clone(),
+                        member: member.clone(),
+                    });
+                }
+            }
+
+            entries
+        });
+
+        entries.into_iter().try_for_each(f)
+    }
+
+    fn append_entries(&mut self, entries: &[Entry]) -> io::Result<()> {
+        self.write(|contents| {
+            for entry in entries.iter().cloned() {
+                match entry {
+                    Entry::Data {
+                        namespace,
+                        key,
+                        value,
+                    } => {
+                        contents.values.insert((namespace, key), value);
+                    }
+                    Entry::SortedSetMember {
+                        namespace,
+                        key,
+                        member,
+                        score,
+                    } => {
+                        contents
+                            .sorted_sets
+                            .entry((namespace, key))
+                            .or_default()
+                            .insert(member, score);
+                    }
+                    Entry::SetMember {
+                        namespace,
+                        key,
+                        member,
+                    } => {
+                        contents
+                            .sets
+                            .entry((namespace, key))
+                            .or_default()
+                            .insert(member);
+                    }
+                    Entry::Namespace { id, name } => {
+                        contents.namespaces.insert(id, name);
+                    }
+                }
+            }
+        })
+    }
+
+    fn reader(&self) -> Box<dyn StorageEngine> {
+        let data = match &self.data {
+            Data::Live(contents) => Data::Live(Arc::clone(contents)),
+            Data::Frozen(contents) => Data::Frozen(Arc::clone(contents)),
+        };
+
+        Box::new(MemoryEngine {
+            data,
+            namespace: self.namespace,
+            read_only: true,
+        })
+    }
+
+    fn snapshot_reader(&self) -> Box<dyn StorageEngine> {
+        let data = match &self.data {
+            Data::Live(contents) => Data::Frozen(Arc::new(
+                contents
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone(),
+            )),
+            Data::Frozen(contents) => Data::Frozen(Arc::clone(contents)),
+        };
+
+        Box::new(MemoryEngine {
+            data,
+            namespace: self.namespace,
+            read_only: true,
+        })
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+/// Calls `f` with the namespace, key and size of every value and member.
+fn for_each_size(contents: &Contents, mut f: impl FnMut(u16, &[u8], u64)) {
+    for ((namespace, key), value) in &contents.values {
+        f(*namespace, key, (key.len() + value.len()) as u64);
+    }
+    for ((namespace, key), members) in &contents.sorted_sets {
+        for member in members.keys() {
+            f(
+                *namespace,
+                key.as_bytes(),
+                (key.len() + member.len() + 8) as u64,
+            );
+        }
+    }
+    for ((namespace, key), members) in &contents.sets {
+        for member in members {
+            f(
+                *namespace,
+                key.as_bytes(),
+                (key.len() + member.len()) as u64,
+            );
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// a memory map of a kiv file, shared by a `Storage` and its views
+//
+// reads copy straight out of the mapped pages instead of making a syscall
+// for every field. a map only covers the file as it was when it was made: a
+// read past its end maps the file again if it has grown since, and the file
+// is unmapped before its length changes, as touching mapped pages past the
+// end of a file faults, and before a rewrite replaces it
+//
+// a file opened for reading only is held with a shared lock that keeps
+// every writer out, so it can't change while it's mapped, and its values are
+// handed out as slices of the map instead of being copied
+
+use std::{
+    fs::File,
+    io,
+    ops::Range,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use bytes::Bytes;
+use memmap2::Mmap;
+
+pub(crate) struct FileMap {
+    map: RwLock<Option<Arc<Mmap>>>,
+    /// Whether the file can be written while it's mapped, which rules out
+    /// handing out slices of the map.
+    writable: bool,
+}
+
+impl FileMap {
+    pub(crate) fn new(writable: bool) -> Self {
+        Self {
+            map: RwLock::new(None),
+            writable,
+        }
+    }
+
+    /// The map, covering the file up to `end` if it is that long. Returns
+    /// `None` while the file is empty.
+    fn covering(&self, file: &File, end: u64) -> io::Result<Option<Arc<Mmap>>> {
+        let covers = |map: &Option<Arc<Mmap>>| {
+            map.as_ref()
+                .filter(|map| map.len() as u64 >= end)
+                .map(Arc::clone)
+        };
+        if let Some(map) = covers(&self.map.read().unwrap_or_else(PoisonError::into_inner)) {
+            return Ok(Some(map));
+        }
+
+        let mut map = self.map.write().unwrap_or_else(PoisonError::into_inner);
+        // another view may have mapped it again in the meantime
+        if let Some(map) = covers(&map) {
+            return Ok(Some(map));
+        }
+        let length = file.metadata()?.len();
+        if length == 0 || map.as_ref().is_some_and(|map| map.len() as u64 == length) {
+            return Ok(map.clone());
+        }
+
+        // SAFETY: no other process writes the file while it's locked, and
+        // this one unmaps it before changing its length. a writable file is
+        // only written while nothing reads it, and its map is never handed
+        // out, so no slice of it changes while it's held
+        let mapped = Arc::new(unsafe { Mmap::map(file)? });
+        *map = Some(Arc::clone(&mapped));
+        Ok(Some(mapped))
+    }
+
+    /// Reads from the map at `position`, like a positional read from the
+    /// file would.
+    pub(crate) fn read_at(&self, file: &File, buf: &mut [u8], position: u64) -> io::Result<usize> {
+        let end = position.saturating_add(buf.len() as u64);
+        let Some(map) = self.covering(file, end)? else {
+            return Ok(0);
+        };
+
+        let start = usize::try_from(position).map_or(map.len(), |start| start.min(map.len()));
+        let read = buf.len().min(map.len() - start);
+        buf[..read].copy_from_slice(&map[start..start + read]);
+        Ok(read)
+    }
+
+    /// `length` bytes at `position` as a slice of the map. Returns `None`
+    /// when they have to be copied, as the file can be written, or when
+    /// the file ends before them.
+    pub(crate) fn slice(
+        &self,
+        file: &File,
+        position: u64,
+        length: usize,
+    ) -> io::Result<Option<Bytes>> {
+        if self.writable {
+            return Ok(None);
+        }
+
+        let end = position.saturating_add(length as u64);
+        Ok(self
+            .covering(file, end)?
+            .filter(|map| map.len() as u64 >= end)
+            .map(|map| {
+                let start = position as usize;
+                Bytes::from_owner(MappedRange {
+                    map,
+                    range: start..start + length,
+                })
+            }))
+    }
+
+    /// Drops the map, before the file's length is changed.
+    pub(crate) fn unmap(&self) {
+        *self.map.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+/// Keeps the map a slice was taken from alive for as long as the slice is.
+struct MappedRange {
+    map: Arc<Mmap>,
+    range: Range<usize>,
+}
+
+impl AsRef<[u8]> for MappedRange {
+    fn as_ref(&self) -> &[u8] {
+        &self.map[self.range.clone()]
+    }
+}
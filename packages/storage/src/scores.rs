@@ -0,0 +1,262 @@
+// an index of the members of every sorted set in a kiv file, ordered by
+// score, so range and rank reads only look at the members they return or
+// count instead of reading through the whole file
+//
+// the index is built the first time a sorted set is read, from every sorted
+// set entry in the file, deleted or not, as snapshots may still read deleted
+// ones. it is kept up to date by writes from then on, and dropped whenever
+// the file is rewritten, to be built again from the new file. like the file,
+// it holds each version of a member along with the sequence numbers of the
+// writes that created and deleted it
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{PoisonError, RwLock},
+};
+
+/// A version of a sorted set member, as it is read from the file.
+pub(crate) struct Version {
+    pub(crate) namespace: u16,
+    pub(crate) key: String,
+    pub(crate) member: String,
+    pub(crate) score: f64,
+    pub(crate) created: u64,
+    pub(crate) deleted: u64,
+}
+
+/// Orders versions by score, then member, like sorted sets are read.
+struct Scored {
+    score: f64,
+    member: String,
+    created: u64,
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| self.member.cmp(&other.member))
+            .then_with(|| self.created.cmp(&other.created))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+#[derive(Default)]
+struct SortedSet {
+    /// Every version, mapped to the sequence number of the write that
+    /// deleted it, or 0 if it hasn't been.
+    by_score: BTreeMap<Scored, u64>,
+    /// The score and creating write of each member's versions.
+    members: HashMap<String, Vec<(f64, u64)>>,
+}
+
+impl SortedSet {
+    fn insert(&mut self, member: String, score: f64, created: u64, deleted: u64) {
+        self.members
+            .entry(member.clone())
+            .or_default()
+            .push((score, created));
+        self.by_score.insert(
+            Scored {
+                score,
+                member,
+                created,
+            },
+            deleted,
+        );
+    }
+
+    /// The version of `member` that hasn't been deleted.
+    fn live(&self, member: &str) -> Option<Scored> {
+        self.members
+            .get(member)?
+            .iter()
+            .find_map(|&(score, created)| {
+                let scored = Scored {
+                    score,
+                    member: member.to_string(),
+                    created,
+                };
+                (self.by_score.get(&scored) == Some(&0)).then_some(scored)
+            })
+    }
+
+    /// The version of `member` a reader that sees `visible` versions reads.
+    fn visible(&self, member: &str, visible: &dyn Fn(u64, u64) -> bool) -> Option<Scored> {
+        self.members
+            .get(member)?
+            .iter()
+            .find_map(|&(score, created)| {
+                let scored = Scored {
+                    score,
+                    member: member.to_string(),
+                    created,
+                };
+                let deleted = *self.by_score.get(&scored)?;
+                visible(created, deleted).then_some(scored)
+            })
+    }
+}
+
+type Sets = HashMap<(u16, String), SortedSet>;
+
+/// A file's index, shared by the `Storage` that owns it and its views.
+#[derive(Default)]
+pub(crate) struct ScoreIndex {
+    /// `None` until the index is built.
+    sets: RwLock<Option<Sets>>,
+}
+
+impl ScoreIndex {
+    /// Runs `f` on the index, building it from the versions `build` reads
+    /// first if it hasn't been built yet.
+    fn read<T>(
+        &self,
+        build: impl FnOnce() -> io::Result<Vec<Version>>,
+        f: impl FnOnce(&Sets) -> T,
+    ) -> io::Result<T> {
+        {
+            let sets = self.sets.read().unwrap_or_else(PoisonError::into_inner);
+            if let Some(sets) = sets.as_ref() {
+                return Ok(f(sets));
+            }
+        }
+
+        let versions = build()?;
+        let mut sets = self.sets.write().unwrap_or_else(PoisonError::into_inner);
+        let sets = sets.get_or_insert_with(|| {
+            let mut sets = Sets::new();
+            for version in versions {
+                sets.entry((version.namespace, version.key))
+                    .or_default()
+                    .insert(
+                        version.member,
+                        version.score,
+                        version.created,
+                        version.deleted,
+                    );
+            }
+            sets
+        });
+
+        Ok(f(sets))
+    }
+
+    /// Changes the index if it has been built.
+    fn update(&self, namespace: u16, key: &str, f: impl FnOnce(&mut SortedSet)) {
+        let mut sets = self.sets.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(sets) = sets.as_mut() {
+            f(sets.entry((namespace, key.to_string())).or_default());
+        }
+    }
+
+    /// Drops the index, to be built again from the file the next time it is
+    /// read.
+    pub(crate) fn clear(&self) {
+        *self.sets.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// Adds a version of a member created by the write with sequence number
+    /// `created`.
+    pub(crate) fn insert(&self, namespace: u16, key: &str, member: &str, score: f64, created: u64) {
+        self.update(namespace, key, |set| {
+            set.insert(member.to_string(), score, created, 0)
+        });
+    }
+
+    /// Marks the member's live version deleted by the write with sequence
+    /// number `deleted`. The version stays in the index, like the entry
+    /// stays in the file, until the file is rewritten.
+    pub(crate) fn delete(&self, namespace: u16, key: &str, member: &str, deleted: u64) {
+        self.update(namespace, key, |set| {
+            if let Some(live) = set.live(member) {
+                set.by_score.insert(live, deleted);
+            }
+        });
+    }
+
+    /// Changes the score of the member's live version, which is changed in
+    /// place in the file.
+    pub(crate) fn rescore(&self, namespace: u16, key: &str, member: &str, score: f64) {
+        self.update(namespace, key, |set| {
+            let Some(live) = set.live(member) else {
+                return;
+            };
+            set.by_score.remove(&live);
+            if let Some(versions) = set.members.get_mut(member) {
+                versions.retain(|&(_, created)| created != live.created);
+            }
+            set.insert(member.to_string(), score, live.created, 0);
+        });
+    }
+
+    /// Returns the members of the sorted set at `key` with a score between
+    /// `min` and `max` (inclusive), ordered by score, then by member, as a
+    /// reader that sees the versions `visible` returns `true` for reads them.
+    pub(crate) fn range(
+        &self,
+        namespace: u16,
+        key: &str,
+        min: f64,
+        max: f64,
+        build: impl FnOnce() -> io::Result<Vec<Version>>,
+        visible: &dyn Fn(u64, u64) -> bool,
+    ) -> io::Result<Vec<(String, f64)>> {
+        self.read(build, |sets| {
+            let Some(set) = sets.get(&(namespace, key.to_string())) else {
+                return vec![];
+            };
+            // the index orders -0.0 before 0.0, which compare equal
+            let from = Scored {
+                score: if min == 0.0 { -0.0 } else { min },
+                member: String::new(),
+                created: 0,
+            };
+            set.by_score
+                .range(from..)
+                .take_while(|(scored, _)| scored.score <= max)
+                .filter(|(scored, &deleted)| {
+                    scored.score >= min && visible(scored.created, deleted)
+                })
+                .map(|(scored, _)| (scored.member.clone(), scored.score))
+                .collect()
+        })
+    }
+
+    /// Returns the zero-based position of a member in the order
+    /// [`ScoreIndex::range`] returns members in.
+    pub(crate) fn rank(
+        &self,
+        namespace: u16,
+        key: &str,
+        member: &str,
+        build: impl FnOnce() -> io::Result<Vec<Version>>,
+        visible: &dyn Fn(u64, u64) -> bool,
+    ) -> io::Result<Option<u64>> {
+        self.read(build, |sets| {
+            let set = sets.get(&(namespace, key.to_string()))?;
+            let target = set.visible(member, visible)?;
+            let before = set
+                .by_score
+                .range(..target)
+                .filter(|(scored, &deleted)| visible(scored.created, deleted))
+                .count();
+            Some(before as u64)
+        })
+    }
+}
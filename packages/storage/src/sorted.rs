@@ -0,0 +1,493 @@
+// entries laid out in a single sorted key space, for engines built on an
+// ordered map of byte strings
+//
+// every entry is stored under a flat key that starts with its kind and its
+// namespace, so values, collection members and namespaces all live in the
+// same map. data keys are followed by the key itself, and collection
+// members by the collection's key, with a length in front, and the member.
+// sorted set members hold their score as a big-endian f64, set members hold
+// nothing and namespaces hold their name
+//
+// the functions here build every engine operation out of the handful of
+// map operations in `SortedStore`
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{
+    check_length, compare_scored, invalid_data, Entry, NamespaceStats, StorageStats,
+    DEFAULT_NAMESPACE, DEFAULT_NAMESPACE_ID,
+};
+
+/// A value, or `None` for a tombstone left by a delete.
+pub(crate) type Value = Option<Vec<u8>>;
+
+/// Puts and tombstones written together.
+pub(crate) type Batch = Vec<(Vec<u8>, Value)>;
+
+// the kinds of key, in the order they sort in. namespaces come first, so
+// they are read before the entries stored in them
+const NAMESPACE: u8 = 0;
+const DATA: u8 = 1;
+const SORTED_SET_MEMBER: u8 = 2;
+const SET_MEMBER: u8 = 3;
+
+/// Called with each key and value a scan finds.
+pub(crate) type Visit<'a> = dyn FnMut(&[u8], Vec<u8>) -> io::Result<()> + 'a;
+
+/// An ordered map the engine operations below are built on.
+pub(crate) trait SortedStore {
+    /// The value stored under `key`.
+    fn lookup(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>>;
+
+    /// Calls `f` with every key starting with `prefix` and its value, in key
+    /// order.
+    fn scan_prefix(&self, prefix: &[u8], f: &mut Visit) -> io::Result<()>;
+
+    /// Applies a batch of puts and tombstones as a single write.
+    fn write(&mut self, batch: Batch) -> io::Result<()>;
+
+    fn check_writable(&self) -> io::Result<()>;
+
+    fn namespace(&self) -> u16;
+
+    fn set_namespace(&mut self, id: u16);
+}
+
+fn data_key(namespace: u16, key: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(3 + key.len());
+    encoded.push(DATA);
+    encoded.extend_from_slice(&namespace.to_be_bytes());
+    encoded.extend_from_slice(key);
+
+    encoded
+}
+
+fn member_prefix(kind: u8, namespace: u16, key: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(5 + key.len());
+    encoded.push(kind);
+    encoded.extend_from_slice(&namespace.to_be_bytes());
+    encoded.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    encoded.extend_from_slice(key.as_bytes());
+
+    encoded
+}
+
+fn member_key(kind: u8, namespace: u16, key: &str, member: &str) -> Vec<u8> {
+    let mut encoded = member_prefix(kind, namespace, key);
+    encoded.extend_from_slice(member.as_bytes());
+
+    encoded
+}
+
+fn namespace_key(id: u16) -> Vec<u8> {
+    let mut encoded = vec![NAMESPACE];
+    encoded.extend_from_slice(&id.to_be_bytes());
+
+    encoded
+}
+
+fn encode_entry(entry: &Entry) -> io::Result<(Vec<u8>, Value)> {
+    Ok(match entry {
+        Entry::Data {
+            namespace,
+            key,
+            value,
+        } => {
+            check_length("key", key.len(), u16::MAX as usize)?;
+            check_length("value", value.len(), u32::MAX as usize)?;
+            (data_key(*namespace, key), Some(value.clone()))
+        }
+        Entry::SortedSetMember {
+            namespace,
+            key,
+            member,
+            score,
+        } => {
+            check_length("key", key.len(), u16::MAX as usize)?;
+            check_length("member", member.len(), u16::MAX as usize)?;
+            (
+                member_key(SORTED_SET_MEMBER, *namespace, key, member),
+                Some(score.to_be_bytes().to_vec()),
+            )
+        }
+        Entry::SetMember {
+            namespace,
+            key,
+            member,
+        } => {
+            check_length("key", key.len(), u16::MAX as usize)?;
+            check_length("member", member.len(), u16::MAX as usize)?;
+            (
+                member_key(SET_MEMBER, *namespace, key, member),
+                Some(vec![]),
+            )
+        }
+        Entry::Namespace { id, name } => {
+            check_length("namespace name", name.len(), u16::MAX as usize)?;
+            (namespace_key(*id), Some(name.as_bytes().to_vec()))
+        }
+    })
+}
+
+fn decode_entry(key: &[u8], value: Vec<u8>) -> io::Result<Entry> {
+    if key.len() < 3 {
+        return Err(invalid_data("sorted key is too short"));
+    }
+    let namespace = BigEndian::read_u16(&key[1..3]);
+    let rest = &key[3..];
+
+    let member = || -> io::Result<(String, String)> {
+        if rest.len() < 2 {
+            return Err(invalid_data("member key is too short"));
+        }
+        let key_len = BigEndian::read_u16(rest) as usize;
+        let (key, member) = rest[2..]
+            .split_at_checked(key_len)
+            .ok_or_else(|| invalid_data("member key is too short"))?;
+        Ok((string(key)?, string(member)?))
+    };
+
+    Ok(match key[0] {
+        NAMESPACE => Entry::Namespace {
+            id: namespace,
+            name: string(&value)?,
+        },
+        DATA => Entry::Data {
+            namespace,
+            key: rest.to_vec(),
+            value,
+        },
+        SORTED_SET_MEMBER => {
+            let (key, member) = member()?;
+            Entry::SortedSetMember {
+                namespace,
+                key,
+                member,
+                score: read_score(&value)?,
+            }
+        }
+        SET_MEMBER => {
+            let (key, member) = member()?;
+            Entry::SetMember {
+                namespace,
+                key,
+                member,
+            }
+        }
+        kind => return Err(invalid_data(format!("unknown key kind {}", kind))),
+    })
+}
+
+fn string(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("string is not valid UTF-8"))
+}
+
+fn read_score(bytes: &[u8]) -> io::Result<f64> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| invalid_data("sorted set score isn't 8 bytes long"))?;
+    Ok(f64::from_be_bytes(bytes))
+}
+
+/// Which namespace an entry belongs to, and the key it's stored under
+/// there. `None` for namespaces themselves.
+fn entry_key(entry: Entry) -> Option<(u16, Vec<u8>)> {
+    match entry {
+        Entry::Data { namespace, key, .. } => Some((namespace, key)),
+        Entry::SortedSetMember { namespace, key, .. } | Entry::SetMember { namespace, key, .. } => {
+            Some((namespace, key.into_bytes()))
+        }
+        Entry::Namespace { .. } => None,
+    }
+}
+
+/// Tombstones for every value and collection member in a namespace.
+fn tombstones(store: &impl SortedStore, namespace: u16) -> io::Result<Batch> {
+    let mut batch = vec![];
+    for kind in [DATA, SORTED_SET_MEMBER, SET_MEMBER] {
+        let mut prefix = vec![kind];
+        prefix.extend_from_slice(&namespace.to_be_bytes());
+        store.scan_prefix(&prefix, &mut |key, _| {
+            batch.push((key.to_vec(), None));
+            Ok(())
+        })?;
+    }
+
+    Ok(batch)
+}
+
+fn sorted_set(store: &impl SortedStore, key: &str) -> io::Result<Vec<(String, f64)>> {
+    let prefix = member_prefix(SORTED_SET_MEMBER, store.namespace(), key);
+    let mut members = vec![];
+    store.scan_prefix(&prefix, &mut |member, score| {
+        members.push((string(&member[prefix.len()..])?, read_score(&score)?));
+        Ok(())
+    })?;
+    members.sort_by(compare_scored);
+
+    Ok(members)
+}
+
+/// Every entry, decoded, in key order, with the bytes of its flat key and
+/// value.
+fn entries(
+    store: &impl SortedStore,
+    mut f: impl FnMut(Entry, u64) -> io::Result<()>,
+) -> io::Result<()> {
+    store.scan_prefix(&[], &mut |key, value| {
+        let size = (key.len() + value.len()) as u64;
+        f(decode_entry(key, value)?, size)
+    })
+}
+
+pub(crate) fn get(store: &impl SortedStore, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    store.lookup(&data_key(store.namespace(), key))
+}
+
+pub(crate) fn put(store: &mut impl SortedStore, key: &[u8], value: &[u8]) -> io::Result<()> {
+    check_length("key", key.len(), u16::MAX as usize)?;
+    check_length("value", value.len(), u32::MAX as usize)?;
+    let key = data_key(store.namespace(), key);
+    store.write(vec![(key, Some(value.to_vec()))])
+}
+
+pub(crate) fn delete(store: &mut impl SortedStore, key: &[u8]) -> io::Result<()> {
+    let key = data_key(store.namespace(), key);
+    store.write(vec![(key, None)])
+}
+
+pub(crate) fn scan(store: &impl SortedStore, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut found = vec![];
+    store.scan_prefix(&data_key(store.namespace(), prefix), &mut |key, value| {
+        found.push((key[3..].to_vec(), value));
+        Ok(())
+    })?;
+
+    Ok(found)
+}
+
+pub(crate) fn zadd(
+    store: &mut impl SortedStore,
+    key: &str,
+    member: &str,
+    score: f64,
+) -> io::Result<()> {
+    check_length("key", key.len(), u16::MAX as usize)?;
+    check_length("member", member.len(), u16::MAX as usize)?;
+    let encoded = member_key(SORTED_SET_MEMBER, store.namespace(), key, member);
+    store.write(vec![(encoded, Some(score.to_be_bytes().to_vec()))])
+}
+
+pub(crate) fn zrem(store: &mut impl SortedStore, key: &str, member: &str) -> io::Result<()> {
+    let encoded = member_key(SORTED_SET_MEMBER, store.namespace(), key, member);
+    store.write(vec![(encoded, None)])
+}
+
+pub(crate) fn zscore(store: &impl SortedStore, key: &str, member: &str) -> io::Result<Option<f64>> {
+    store
+        .lookup(&member_key(
+            SORTED_SET_MEMBER,
+            store.namespace(),
+            key,
+            member,
+        ))?
+        .map(|score| read_score(&score))
+        .transpose()
+}
+
+pub(crate) fn zrange(
+    store: &impl SortedStore,
+    key: &str,
+    min: f64,
+    max: f64,
+) -> io::Result<Vec<(String, f64)>> {
+    Ok(sorted_set(store, key)?
+        .into_iter()
+        .filter(|(_, score)| *score >= min && *score <= max)
+        .collect())
+}
+
+pub(crate) fn zrank(store: &impl SortedStore, key: &str, member: &str) -> io::Result<Option<u64>> {
+    Ok(sorted_set(store, key)?
+        .iter()
+        .position(|(m, _)| m == member)
+        .map(|rank| rank as u64))
+}
+
+pub(crate) fn sadd(store: &mut impl SortedStore, key: &str, member: &str) -> io::Result<bool> {
+    check_length("key", key.len(), u16::MAX as usize)?;
+    check_length("member", member.len(), u16::MAX as usize)?;
+    let encoded = member_key(SET_MEMBER, store.namespace(), key, member);
+    if store.lookup(&encoded)?.is_some() {
+        return Ok(false);
+    }
+
+    store.write(vec![(encoded, Some(vec![]))])?;
+    Ok(true)
+}
+
+pub(crate) fn srem(store: &mut impl SortedStore, key: &str, member: &str) -> io::Result<bool> {
+    let encoded = member_key(SET_MEMBER, store.namespace(), key, member);
+    if store.lookup(&encoded)?.is_none() {
+        return Ok(false);
+    }
+
+    store.write(vec![(encoded, None)])?;
+    Ok(true)
+}
+
+pub(crate) fn sismember(store: &impl SortedStore, key: &str, member: &str) -> io::Result<bool> {
+    Ok(store
+        .lookup(&member_key(SET_MEMBER, store.namespace(), key, member))?
+        .is_some())
+}
+
+pub(crate) fn smembers(store: &impl SortedStore, key: &str) -> io::Result<Vec<String>> {
+    let prefix = member_prefix(SET_MEMBER, store.namespace(), key);
+    let mut members = vec![];
+    store.scan_prefix(&prefix, &mut |member, _| {
+        members.push(string(&member[prefix.len()..])?);
+        Ok(())
+    })?;
+
+    Ok(members)
+}
+
+pub(crate) fn scard(store: &impl SortedStore, key: &str) -> io::Result<u64> {
+    let mut cardinality = 0;
+    let prefix = member_prefix(SET_MEMBER, store.namespace(), key);
+    store.scan_prefix(&prefix, &mut |_, _| {
+        cardinality += 1;
+        Ok(())
+    })?;
+
+    Ok(cardinality)
+}
+
+pub(crate) fn create_namespace(
+    store: &mut impl SortedStore,
+    name: &str,
+) -> io::Result<Option<u16>> {
+    store.check_writable()?;
+    check_length("namespace name", name.len(), u16::MAX as usize)?;
+    let namespaces = namespaces(store)?;
+    if namespaces.iter().any(|(_, n)| n == name) {
+        return Ok(None);
+    }
+
+    let id = namespaces.iter().map(|(id, _)| *id).max().unwrap_or(0) + 1;
+    store.write(vec![(namespace_key(id), Some(name.as_bytes().to_vec()))])?;
+
+    Ok(Some(id))
+}
+
+pub(crate) fn drop_namespace(store: &mut impl SortedStore, name: &str) -> io::Result<bool> {
+    store.check_writable()?;
+    let id = namespaces(store)?
+        .into_iter()
+        .find(|(_, n)| n == name)
+        .map(|(id, _)| id);
+    let id = match id {
+        Some(DEFAULT_NAMESPACE_ID) | None => return Ok(false),
+        Some(id) => id,
+    };
+
+    let mut batch = vec![(namespace_key(id), None)];
+    batch.extend(tombstones(store, id)?);
+    store.write(batch)?;
+    if store.namespace() == id {
+        store.set_namespace(DEFAULT_NAMESPACE_ID);
+    }
+
+    Ok(true)
+}
+
+pub(crate) fn namespaces(store: &impl SortedStore) -> io::Result<Vec<(u16, String)>> {
+    let mut namespaces = vec![(DEFAULT_NAMESPACE_ID, DEFAULT_NAMESPACE.to_string())];
+    store.scan_prefix(&[NAMESPACE], &mut |key, name| {
+        namespaces.push((BigEndian::read_u16(&key[1..3]), string(&name)?));
+        Ok(())
+    })?;
+
+    Ok(namespaces)
+}
+
+pub(crate) fn namespace_stats(store: &impl SortedStore) -> io::Result<Vec<NamespaceStats>> {
+    let mut stats: Vec<NamespaceStats> = vec![];
+    let mut ids = HashMap::new();
+    for (id, name) in namespaces(store)? {
+        ids.insert(id, stats.len());
+        stats.push(NamespaceStats {
+            name,
+            entries: 0,
+            bytes: 0,
+        });
+    }
+
+    entries(store, |entry, size| {
+        if let Some((namespace, _)) = entry_key(entry) {
+            if let Some(index) = ids.get(&namespace) {
+                stats[*index].entries += 1;
+                stats[*index].bytes += size;
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(stats)
+}
+
+pub(crate) fn truncate(store: &mut impl SortedStore) -> io::Result<()> {
+    store.check_writable()?;
+    let batch = tombstones(store, store.namespace())?;
+    store.write(batch)
+}
+
+/// Counts the bytes of live flat keys and values as live, and the rest of
+/// the `file_size` bytes the engine takes up on disk as dead.
+pub(crate) fn stats(
+    store: &impl SortedStore,
+    file_size: u64,
+    version: u16,
+) -> io::Result<StorageStats> {
+    let mut keys = HashSet::new();
+    let mut live_bytes = 0;
+    entries(store, |entry, size| {
+        if let Some(key) = entry_key(entry) {
+            keys.insert(key);
+        }
+        live_bytes += size;
+        Ok(())
+    })?;
+
+    Ok(StorageStats {
+        keys: keys.len() as u64,
+        file_size,
+        live_bytes,
+        dead_bytes: file_size.saturating_sub(live_bytes),
+        version,
+        bloom_hits: 0,
+        bloom_misses: 0,
+        compression_ratio: 1.0,
+    })
+}
+
+pub(crate) fn for_each_entry(
+    store: &impl SortedStore,
+    f: &mut dyn FnMut(Entry) -> io::Result<()>,
+) -> io::Result<()> {
+    entries(store, |entry, _| f(entry))
+}
+
+pub(crate) fn append_entries(store: &mut impl SortedStore, entries: &[Entry]) -> io::Result<()> {
+    let batch = entries
+        .iter()
+        .map(encode_entry)
+        .collect::<io::Result<Vec<_>>>()?;
+    store.write(batch)
+}
@@ -0,0 +1,184 @@
+// looks up keys that were never written, and checks the bloom filters
+// answer them without ever turning away a key that is there
+
+use storage::{bloom_path, engine::StorageEngine, LsmEngine, LsmOptions, Storage, StorageOptions};
+
+fn missing(storage: &mut Storage, keys: u32) {
+    for i in 0..keys {
+        let key = format!("missing-{}", i);
+        assert_eq!(storage.get_data_entry(key).unwrap(), None);
+    }
+}
+
+#[test]
+fn lookups_for_missing_keys_skip_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bloom.kiv");
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    // more keys than a new filter is sized for
+    for i in 0..1500 {
+        storage
+            .write_data_entry(format!("key-{}", i), "value")
+            .unwrap();
+    }
+    let other = storage.create_namespace("other").unwrap().unwrap();
+
+    missing(&mut storage, 1000);
+    storage.use_namespace(other);
+    assert_eq!(storage.get_data_entry("key-1").unwrap(), None);
+    let stats = storage.get_stats().unwrap();
+    assert!(stats.bloom_hits > 980, "{:?}", stats);
+    assert_eq!(stats.bloom_hits + stats.bloom_misses, 1001);
+
+    storage.use_namespace(0);
+    for i in (0..1500).step_by(10) {
+        let key = format!("key-{}", i);
+        assert_eq!(
+            storage.get_data_entry(key).unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+    let after = storage.get_stats().unwrap();
+    assert_eq!(after.bloom_hits, stats.bloom_hits);
+    assert_eq!(after.bloom_misses, stats.bloom_misses + 150);
+}
+
+#[test]
+fn false_positive_rates_can_be_tuned() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bloom.kiv");
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    for i in 0..500 {
+        storage
+            .write_data_entry(format!("key-{}", i), "value")
+            .unwrap();
+    }
+    drop(storage);
+    std::fs::remove_file(bloom_path(&path)).unwrap();
+
+    let options = StorageOptions {
+        bloom_false_positive_rate: 0.5,
+        ..StorageOptions::default()
+    };
+    let mut storage = Storage::open_with_options(path.to_string_lossy(), options).unwrap();
+    missing(&mut storage, 1000);
+    let loose = storage.get_stats().unwrap().bloom_misses;
+    drop(storage);
+    std::fs::remove_file(bloom_path(&path)).unwrap();
+
+    let options = StorageOptions {
+        bloom_false_positive_rate: 0.0001,
+        ..StorageOptions::default()
+    };
+    let mut storage = Storage::open_with_options(path.to_string_lossy(), options).unwrap();
+    missing(&mut storage, 1000);
+    let tight = storage.get_stats().unwrap().bloom_misses;
+
+    assert!(tight < 5 && loose > tight, "{} and {}", loose, tight);
+}
+
+#[test]
+fn filters_are_only_saved_once_the_file_is_closed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bloom.kiv");
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    // a crash now would leave no filter to go out of date
+    assert!(!bloom_path(&path).exists());
+    drop(storage);
+    assert!(bloom_path(&path).exists());
+    let saved = std::fs::read(bloom_path(&path)).unwrap();
+
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    assert!(!bloom_path(&path).exists());
+    storage.write_data_entry("b", "two").unwrap();
+    drop(storage);
+
+    // a filter from before "b" was written is ignored
+    std::fs::write(bloom_path(&path), saved).unwrap();
+    let mut storage = Storage::open_read_only(path.to_string_lossy()).unwrap();
+    assert_eq!(storage.get_data_entry("b").unwrap(), Some(b"two".to_vec()));
+    drop(storage);
+
+    // and so is one that isn't a filter at all
+    std::fs::write(bloom_path(&path), b"not a filter").unwrap();
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(storage.get_data_entry("b").unwrap(), Some(b"two".to_vec()));
+}
+
+#[test]
+fn compaction_forgets_deleted_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bloom.kiv");
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    for i in 0..100 {
+        storage
+            .write_data_entry(format!("key-{}", i), "value")
+            .unwrap();
+    }
+    let snapshot = storage.snapshot_reader();
+    for i in 0..100 {
+        storage.delete_data_entry(format!("key-{}", i)).unwrap();
+    }
+
+    // the snapshot can still read them, so they stay in the filter
+    storage.compact().unwrap();
+    let mut view = snapshot.reader();
+    assert_eq!(
+        view.get_data_entry("key-7").unwrap(),
+        Some(b"value".to_vec())
+    );
+    drop((view, snapshot));
+
+    let before = storage.get_stats().unwrap();
+    storage.compact().unwrap();
+    for i in 0..100 {
+        assert_eq!(storage.get_data_entry(format!("key-{}", i)).unwrap(), None);
+    }
+    let after = storage.get_stats().unwrap();
+    assert!(after.bloom_hits - before.bloom_hits > 95, "{:?}", after);
+}
+
+#[test]
+fn snapshots_keep_their_own_filter() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bloom.kiv");
+    let copy = dir.path().join("copy.kiv");
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    storage.snapshot(&copy).unwrap();
+    assert!(bloom_path(&copy).exists());
+
+    let mut copy = Storage::open_read_only(copy.to_string_lossy()).unwrap();
+    assert_eq!(copy.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(copy.get_data_entry("b").unwrap(), None);
+}
+
+#[test]
+fn lsm_tables_count_their_filters_answers() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut engine = LsmEngine::open(
+        dir.path().join("lsm"),
+        LsmOptions {
+            memtable_size: 16,
+            background_compaction: false,
+            ..LsmOptions::default()
+        },
+    )
+    .unwrap();
+    for i in 0..100 {
+        engine
+            .put(format!("key-{}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+
+    for i in 0..100 {
+        assert_eq!(
+            engine.get(format!("key-{}-missing", i).as_bytes()).unwrap(),
+            None
+        );
+    }
+    let stats = engine.stats().unwrap();
+    assert!(stats.bloom_hits > stats.bloom_misses, "{:?}", stats);
+}
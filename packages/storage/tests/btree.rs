@@ -0,0 +1,205 @@
+// writes enough through the b+tree engine to split and merge its pages, and
+// damages the file to check what survives a crash
+
+use std::{collections::BTreeMap, io::ErrorKind, path::Path};
+
+use proptest::prelude::*;
+use storage::{engine::StorageEngine, BTreeEngine, BTreeOptions};
+
+fn small() -> BTreeOptions {
+    BTreeOptions {
+        page_size: 1024,
+        cache_pages: 8,
+    }
+}
+
+fn open(path: &Path) -> BTreeEngine {
+    BTreeEngine::open(path, small()).unwrap()
+}
+
+fn fill(engine: &mut BTreeEngine, keys: u32) {
+    for i in 0..keys {
+        engine
+            .put(
+                format!("key-{:05}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+    }
+}
+
+fn check(engine: &mut BTreeEngine, keys: u32) {
+    for i in 0..keys {
+        assert_eq!(
+            engine.get(format!("key-{:05}", i).as_bytes()).unwrap(),
+            Some(format!("value-{}", i).into_bytes())
+        );
+    }
+    assert_eq!(engine.get(b"key-missing").unwrap(), None);
+}
+
+#[test]
+fn trees_grow_and_survive_reopening() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tree.kvb");
+    let mut engine = open(&path);
+    fill(&mut engine, 2000);
+    // too big for a leaf, so it goes on overflow pages
+    let big = vec![7u8; 5000];
+    engine.put(b"big", &big).unwrap();
+    check(&mut engine, 2000);
+    assert_eq!(engine.scan(b"key-0001").unwrap().len(), 10);
+    drop(engine);
+
+    let mut engine = open(&path);
+    check(&mut engine, 2000);
+    assert_eq!(engine.get(b"big").unwrap(), Some(big));
+    assert_eq!(engine.size().unwrap().0, 2001);
+    assert_eq!(engine.stats().unwrap().keys, 2001);
+}
+
+#[test]
+fn freed_pages_are_reused_and_compaction_shrinks_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tree.kvb");
+    let mut engine = open(&path);
+    fill(&mut engine, 1000);
+    let full = std::fs::metadata(&path).unwrap().len();
+
+    // rewriting the same keys reuses the pages the old versions were on
+    fill(&mut engine, 1000);
+    let rewritten = std::fs::metadata(&path).unwrap().len();
+    assert!(rewritten < full * 2, "{} grew to {}", full, rewritten);
+
+    for i in 0..1000 {
+        engine.delete(format!("key-{:05}", i).as_bytes()).unwrap();
+    }
+    engine.put(b"kept", b"yes").unwrap();
+    let freed = engine.compact().unwrap();
+    assert!(freed > 0);
+    assert!(std::fs::metadata(&path).unwrap().len() < full / 4);
+    assert_eq!(
+        engine.scan(b"").unwrap(),
+        vec![(b"kept".to_vec(), b"yes".to_vec())]
+    );
+    drop(engine);
+
+    let mut engine = open(&path);
+    assert_eq!(engine.get(b"kept").unwrap(), Some(b"yes".to_vec()));
+}
+
+#[test]
+fn snapshots_keep_their_pages_until_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut engine = open(&dir.path().join("tree.kvb"));
+    fill(&mut engine, 500);
+    let mut snapshot = engine.snapshot_reader();
+
+    engine.truncate().unwrap();
+    fill(&mut engine, 100);
+    engine.compact().unwrap();
+    assert_eq!(engine.get(b"key-00400").unwrap(), None);
+    assert_eq!(snapshot.size().unwrap().0, 500);
+    assert_eq!(
+        snapshot.get(b"key-00400").unwrap(),
+        Some(b"value-400".to_vec())
+    );
+
+    drop(snapshot);
+    let freed = engine.compact().unwrap();
+    assert!(freed > 0);
+    check(&mut engine, 100);
+}
+
+#[test]
+fn crashes_fall_back_to_the_last_complete_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tree.kvb");
+    let mut engine = open(&path);
+    engine.put(b"a", b"one").unwrap();
+    engine.put(b"b", b"two").unwrap();
+    drop(engine);
+
+    // pages written by a write that never committed
+    let mut bytes = std::fs::read(&path).unwrap();
+    let committed = bytes.len();
+    bytes.extend_from_slice(&[0xAB; 3000]);
+    std::fs::write(&path, &bytes).unwrap();
+    let mut engine = open(&path);
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"two".to_vec()));
+    drop(engine);
+    assert_eq!(std::fs::metadata(&path).unwrap().len() as usize, committed);
+
+    // a torn header for the last commit, the second write, in the first copy
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[8] ^= 0xFF;
+    std::fs::write(&path, &bytes).unwrap();
+    let mut engine = open(&path);
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(engine.get(b"b").unwrap(), None);
+    engine.put(b"c", b"three").unwrap();
+    drop(engine);
+
+    let mut engine = open(&path);
+    assert_eq!(engine.get(b"c").unwrap(), Some(b"three".to_vec()));
+}
+
+#[test]
+fn files_are_locked_while_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tree.kvb");
+    let mut engine = open(&path);
+    engine.put(b"a", b"one").unwrap();
+
+    let err = BTreeEngine::open(&path, small()).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    drop(engine);
+
+    let mut reader = BTreeEngine::open_read_only(&path, BTreeOptions::default()).unwrap();
+    assert_eq!(reader.page_size(), 1024);
+    assert_eq!(reader.get(b"a").unwrap(), Some(b"one".to_vec()));
+    assert!(reader.put(b"b", b"two").is_err());
+    let err = BTreeEngine::open(&path, small()).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+}
+
+proptest! {
+    #[test]
+    fn btree_engines_behave_like_a_sorted_map(
+        ops in prop::collection::vec(
+            (
+                prop::collection::vec(0u8..8, 1..4),
+                prop::option::of(prop::collection::vec(any::<u8>(), 0..400)),
+            ),
+            1..200,
+        ),
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.kvb");
+        let mut engine = open(&path);
+        let mut model = BTreeMap::new();
+        for (i, (key, value)) in ops.into_iter().enumerate() {
+            match value {
+                Some(value) => {
+                    engine.put(&key, &value).unwrap();
+                    model.insert(key, value);
+                }
+                None => {
+                    engine.delete(&key).unwrap();
+                    model.remove(&key);
+                }
+            }
+            if i % 50 == 49 {
+                drop(engine);
+                engine = open(&path);
+            }
+        }
+
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = model.into_iter().collect();
+        prop_assert_eq!(engine.scan(b"").unwrap(), expected.clone());
+        prop_assert_eq!(engine.size().unwrap().0, expected.len() as u64);
+        for (key, value) in expected {
+            prop_assert_eq!(engine.get(&key).unwrap(), Some(value));
+        }
+    }
+}
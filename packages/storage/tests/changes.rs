@@ -0,0 +1,189 @@
+// replays change logs onto snapshots and checks they end up where the live
+// file did
+
+use std::io::Write;
+use std::path::Path;
+
+use storage::changes::{changes_path, export, Change, ChangeLog, ChangeReader};
+use storage::{Entry, Storage};
+
+fn open(path: &Path) -> Storage {
+    Storage::open(path.to_string_lossy()).unwrap()
+}
+
+/// Every entry in the file, in an order that doesn't depend on where they
+/// are stored.
+fn contents(storage: &mut Storage) -> Vec<String> {
+    let mut entries = vec![];
+    storage
+        .for_each_entry(|entry| {
+            entries.push(format!("{:?}", entry));
+            Ok(())
+        })
+        .unwrap();
+    entries.sort();
+    entries
+}
+
+fn replay(snapshot: &Path, log: &Path, until: u64) -> Storage {
+    let mut storage = open(snapshot);
+    for record in ChangeReader::open(log).unwrap() {
+        let record = record.unwrap();
+        if record.sequence > storage.sequence().unwrap() && record.sequence <= until {
+            storage.apply_change(&record).unwrap();
+        }
+    }
+    storage
+}
+
+#[test]
+fn changes_get_sequence_numbers() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("live.kiv");
+
+    let mut storage = open(&path);
+    storage.write_data_entry("before", "log").unwrap();
+    assert_eq!(storage.sequence(), None);
+
+    assert_eq!(storage.enable_change_log().unwrap(), 0);
+    storage.write_data_entry("a", "one").unwrap();
+    storage.update_data_entry("a", "two").unwrap();
+    // deleting something that isn't there changes nothing
+    storage.delete_data_entry("missing").unwrap();
+    storage.write_set_entry("s", "x").unwrap();
+    storage.write_set_entry("s", "x").unwrap();
+    assert_eq!(storage.sequence(), Some(3));
+
+    let records: Vec<_> = ChangeReader::open(changes_path(&path))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(
+        records.iter().map(|r| r.sequence).collect::<Vec<_>>(),
+        [1, 2, 3]
+    );
+    assert_eq!(
+        records[1].change,
+        Change::Put(Entry::Data {
+            namespace: 0,
+            key: b"a".to_vec(),
+            value: b"two".to_vec(),
+        })
+    );
+
+    // the log carries on where it left off
+    drop(storage);
+    let mut storage = open(&path);
+    assert_eq!(storage.sequence(), Some(3));
+    storage.delete_data_entry("a").unwrap();
+    assert_eq!(storage.sequence(), Some(4));
+}
+
+#[test]
+fn snapshots_and_changes_give_back_the_live_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("live.kiv");
+    let snapshot = dir.path().join("snapshot.kiv");
+    let incremental = dir.path().join("incremental.changes");
+
+    let mut storage = open(&path);
+    storage.enable_change_log().unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    storage.write_sorted_set_entry("z", "m", 1.0).unwrap();
+    storage.snapshot(&snapshot).unwrap();
+    let since = storage.sequence().unwrap();
+
+    let id = storage.create_namespace("other").unwrap().unwrap();
+    storage.use_namespace(id);
+    storage.write_data_entry("b", "two").unwrap();
+    storage.write_set_entry("s", "x").unwrap();
+    storage.use_namespace(0);
+    storage.write_sorted_set_entry("z", "m", 2.0).unwrap();
+    storage.delete_data_entry("a").unwrap();
+    let halfway = storage.sequence().unwrap();
+    let halfway_contents = contents(&mut storage);
+    storage.use_namespace(id);
+    storage.truncate_namespace().unwrap();
+    storage.delete_set_entry("s", "x").unwrap();
+    storage.use_namespace(0);
+    storage.drop_namespace("other").unwrap();
+    storage.write_data_entry("c", "three").unwrap();
+
+    assert_eq!(
+        export(changes_path(&path), since, &incremental).unwrap(),
+        storage.sequence().unwrap() - since
+    );
+    assert_eq!(
+        ChangeLog::open(changes_path(&snapshot))
+            .unwrap()
+            .last_sequence(),
+        since
+    );
+
+    // replay a copy of the snapshot all the way
+    let restored = dir.path().join("restored.kiv");
+    std::fs::copy(&snapshot, &restored).unwrap();
+    std::fs::copy(changes_path(&snapshot), changes_path(&restored)).unwrap();
+    let mut replayed = replay(&restored, &incremental, u64::MAX);
+    assert_eq!(contents(&mut replayed), contents(&mut storage));
+    assert_eq!(replayed.sequence(), storage.sequence());
+
+    // and only part of the way
+    let restored = dir.path().join("halfway.kiv");
+    std::fs::copy(&snapshot, &restored).unwrap();
+    std::fs::copy(changes_path(&snapshot), changes_path(&restored)).unwrap();
+    let mut replayed = replay(&restored, &incremental, halfway);
+    assert_eq!(contents(&mut replayed), halfway_contents);
+}
+
+#[test]
+fn changes_that_are_gone_cant_be_exported() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("live.kiv");
+
+    let mut storage = open(&path);
+    storage.enable_change_log().unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+
+    // a newer snapshot's log starts later, so it can't go back
+    let snapshot = dir.path().join("snapshot.kiv");
+    storage.snapshot(&snapshot).unwrap();
+    assert!(export(changes_path(&snapshot), 0, dir.path().join("old")).is_err());
+    // and nothing can come from the future
+    assert!(export(changes_path(&path), 5, dir.path().join("new")).is_err());
+}
+
+#[test]
+fn partly_written_changes_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("live.kiv");
+
+    let mut storage = open(&path);
+    storage.enable_change_log().unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    storage.write_data_entry("b", "two").unwrap();
+    drop(storage);
+
+    // cut the last record short
+    let log = changes_path(&path);
+    let length = std::fs::metadata(&log).unwrap().len();
+    let file = std::fs::OpenOptions::new().write(true).open(&log).unwrap();
+    file.set_len(length - 3).unwrap();
+    drop(file);
+
+    assert_eq!(ChangeReader::open(&log).unwrap().count(), 1);
+
+    let mut storage = open(&path);
+    assert_eq!(storage.sequence(), Some(1));
+    storage.write_data_entry("c", "three").unwrap();
+    assert_eq!(ChangeReader::open(&log).unwrap().count(), 2);
+
+    // damage anywhere else is an error
+    let mut bytes = std::fs::read(&log).unwrap();
+    bytes[20] ^= 0xff;
+    std::fs::File::create(&log)
+        .unwrap()
+        .write_all(&bytes)
+        .unwrap();
+    assert!(Storage::open(path.to_string_lossy()).is_err());
+}
@@ -0,0 +1,102 @@
+// damages files in known ways and checks what `check` and `repair` make of
+// them
+
+use storage::check::{check, repair, ProblemKind};
+use storage::Storage;
+
+fn write_file(dir: &tempfile::TempDir) -> std::path::PathBuf {
+    let path = dir.path().join("check.kiv");
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    storage.write_data_entry("b", "two").unwrap();
+    storage.write_set_entry("s", "x").unwrap();
+    path
+}
+
+#[test]
+fn intact_files_have_no_problems() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_file(&dir);
+
+    let report = check(&path).unwrap();
+
+    assert!(report.is_ok());
+    assert_eq!(report.version, Some(7));
+    assert_eq!(report.entries, 3);
+}
+
+#[test]
+fn damaged_entries_are_skipped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_file(&dir);
+
+    // flip a byte in the first entry's value, which only the checksum notices
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[53 + 23 + 2 + 1 + 4] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+
+    let report = check(&path).unwrap();
+    assert_eq!(report.entries, 2);
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(report.problems[0].offset, 53);
+    assert_eq!(
+        report.problems[0].kind,
+        ProblemKind::Unreadable {
+            reason: String::from("checksum mismatch")
+        }
+    );
+
+    let repaired = dir.path().join("repaired.kiv");
+    repair(&path, &repaired).unwrap();
+    let mut storage = Storage::open(repaired.to_string_lossy()).unwrap();
+    assert_eq!(storage.get_data_entry("a").unwrap(), None);
+    assert_eq!(storage.get_data_entry("b").unwrap(), Some(b"two".to_vec()));
+    assert!(storage.is_set_member("s", "x").unwrap());
+}
+
+#[test]
+fn trailing_garbage_and_duplicates_are_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_file(&dir);
+
+    // a second "a" written straight to the end, plus half an entry
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    let length = std::fs::metadata(&path).unwrap().len();
+    storage.write_data_entry("a", "shadowed").unwrap();
+    drop(storage);
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes.extend_from_slice(&[0, 0, 0, 1]);
+    std::fs::write(&path, bytes).unwrap();
+
+    let report = check(&path).unwrap();
+    let kinds: Vec<&ProblemKind> = report.problems.iter().map(|p| &p.kind).collect();
+    assert_eq!(report.problems[0].offset, length);
+    assert!(matches!(kinds[0], ProblemKind::DuplicateKey { key, .. } if key == b"a"));
+    assert!(matches!(kinds[1], ProblemKind::TrailingGarbage { .. }));
+
+    let repaired = dir.path().join("repaired.kiv");
+    repair(&path, &repaired).unwrap();
+    assert!(check(&repaired).unwrap().is_ok());
+}
+
+#[test]
+fn old_versions_are_not_duplicates() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_file(&dir);
+
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    let snapshot = storage.snapshot_reader();
+    storage.update_data_entry("a", "uno").unwrap();
+    drop(snapshot);
+    drop(storage);
+
+    let report = check(&path).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.entries, 3);
+
+    let repaired = dir.path().join("repaired.kiv");
+    repair(&path, &repaired).unwrap();
+    let mut storage = Storage::open(repaired.to_string_lossy()).unwrap();
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"uno".to_vec()));
+    assert_eq!(storage.get_stats().unwrap().dead_bytes, 0);
+}
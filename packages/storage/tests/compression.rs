@@ -0,0 +1,230 @@
+// writes values with each codec and checks they read back the same, however
+// the file is opened later
+
+use storage::check::{check, repair};
+use storage::{train_dictionary, Codec, CompressionOptions, Storage, StorageOptions};
+
+fn options(codec: Codec) -> StorageOptions {
+    StorageOptions {
+        compression: CompressionOptions {
+            codec,
+            ..CompressionOptions::default()
+        },
+        ..StorageOptions::default()
+    }
+}
+
+fn value(i: usize) -> String {
+    format!(
+        "{{\"id\":{},\"name\":\"user {}\",\"tags\":[\"a\",\"b\",\"c\"],\"bio\":\"{}\"}}",
+        i,
+        i,
+        "lorem ipsum dolor sit amet ".repeat(20)
+    )
+}
+
+#[test]
+fn values_read_back_with_each_codec() {
+    for codec in [Codec::None, Codec::Lz4, Codec::Zstd] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compression.kiv");
+        let mut storage =
+            Storage::open_with_options(path.to_string_lossy(), options(codec)).unwrap();
+        for i in 0..20 {
+            storage
+                .write_data_entry(format!("key-{}", i), value(i))
+                .unwrap();
+        }
+        storage.update_data_entry("key-0", value(100)).unwrap();
+
+        assert_eq!(
+            storage.get_data_entry("key-0").unwrap(),
+            Some(value(100).into_bytes())
+        );
+        let entries = storage.scan_data_entries("key-1").unwrap();
+        assert_eq!(entries.len(), 11);
+        for (key, stored) in entries {
+            let i = String::from_utf8(key[4..].to_vec())
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert_eq!(stored, value(i).into_bytes());
+        }
+
+        let ratio = storage.get_stats().unwrap().compression_ratio;
+        if codec == Codec::None {
+            assert_eq!(ratio, 1.0);
+        } else {
+            assert!(ratio > 3.0, "{:?} {}", codec, ratio);
+        }
+    }
+}
+
+#[test]
+fn short_values_are_stored_as_they_are() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compression.kiv");
+    let mut storage =
+        Storage::open_with_options(path.to_string_lossy(), options(Codec::Zstd)).unwrap();
+    // long enough to compress well, but under the threshold
+    storage.write_data_entry("short", "a".repeat(200)).unwrap();
+    // over it, but random enough to come out longer
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let noise: Vec<u8> = (0..1000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    storage.write_data_entry("noise", &noise).unwrap();
+
+    assert_eq!(storage.get_stats().unwrap().compression_ratio, 1.0);
+    assert_eq!(storage.get_data_entry("noise").unwrap(), Some(noise));
+}
+
+#[test]
+fn files_can_mix_codecs() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compression.kiv");
+    for (i, codec) in [Codec::Lz4, Codec::None, Codec::Zstd]
+        .into_iter()
+        .enumerate()
+    {
+        let mut storage =
+            Storage::open_with_options(path.to_string_lossy(), options(codec)).unwrap();
+        storage
+            .write_data_entry(format!("key-{}", i), value(i))
+            .unwrap();
+    }
+
+    let mut storage = Storage::open_read_only(path.to_string_lossy()).unwrap();
+    for i in 0..3 {
+        assert_eq!(
+            storage.get_data_entry(format!("key-{}", i)).unwrap(),
+            Some(value(i).into_bytes())
+        );
+    }
+
+    // compaction copies values over without changing how they're stored
+    drop(storage);
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    storage.compact().unwrap();
+    assert_eq!(
+        storage.get_data_entry("key-2").unwrap(),
+        Some(value(2).into_bytes())
+    );
+    assert!(storage.get_stats().unwrap().compression_ratio > 1.0);
+}
+
+#[test]
+fn dictionaries_are_needed_to_read_what_they_wrote() {
+    let samples: Vec<String> = (0..200).map(value).collect();
+    let dictionary = train_dictionary(&samples, 4096).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compression.kiv");
+    let with_dictionary = StorageOptions {
+        compression: CompressionOptions {
+            codec: Codec::Zstd,
+            dictionary: Some(dictionary.into()),
+            ..CompressionOptions::default()
+        },
+        ..StorageOptions::default()
+    };
+    let mut storage =
+        Storage::open_with_options(path.to_string_lossy(), with_dictionary.clone()).unwrap();
+    storage.write_data_entry("a", value(1000)).unwrap();
+    let with = storage.get_stats().unwrap().compression_ratio;
+    drop(storage);
+
+    let mut storage = Storage::open_read_only(path.to_string_lossy()).unwrap();
+    let err = storage.get_data_entry("a").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    drop(storage);
+
+    let mut storage =
+        Storage::open_read_only_with_options(path.to_string_lossy(), with_dictionary).unwrap();
+    assert_eq!(
+        storage.get_data_entry("a").unwrap(),
+        Some(value(1000).into_bytes())
+    );
+
+    let other = dir.path().join("other.kiv");
+    let mut storage =
+        Storage::open_with_options(other.to_string_lossy(), options(Codec::Zstd)).unwrap();
+    storage.write_data_entry("a", value(1000)).unwrap();
+    let without = storage.get_stats().unwrap().compression_ratio;
+    assert!(with > without, "{} and {}", with, without);
+}
+
+#[test]
+fn version_5_files_are_upgraded() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compression.kiv");
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    drop(storage);
+    // nothing written before compression has its flags set, and the header
+    // had no key block
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes.drain(8..53);
+    bytes[7] = 5;
+    std::fs::write(&path, bytes).unwrap();
+    assert_eq!(check(&path).unwrap().version, Some(5));
+    assert!(Storage::open_read_only(path.to_string_lossy()).is_err());
+
+    let mut storage =
+        Storage::open_with_options(path.to_string_lossy(), options(Codec::Lz4)).unwrap();
+    assert_eq!(check(&path).unwrap().version, Some(7));
+    storage.write_data_entry("b", value(0)).unwrap();
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+}
+
+#[test]
+fn repairs_keep_values_compressed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compression.kiv");
+    let mut storage =
+        Storage::open_with_options(path.to_string_lossy(), options(Codec::Lz4)).unwrap();
+    storage.write_data_entry("a", value(0)).unwrap();
+    storage.write_data_entry("b", "two").unwrap();
+    drop(storage);
+    assert!(check(&path).unwrap().is_ok());
+
+    let repaired = dir.path().join("repaired.kiv");
+    assert!(repair(&path, &repaired).unwrap().is_ok());
+    let mut storage = Storage::open(repaired.to_string_lossy()).unwrap();
+    assert_eq!(
+        storage.get_data_entry("a").unwrap(),
+        Some(value(0).into_bytes())
+    );
+    assert_eq!(storage.get_data_entry("b").unwrap(), Some(b"two".to_vec()));
+    assert!(storage.get_stats().unwrap().compression_ratio > 1.0);
+}
+
+#[test]
+fn implausible_lengths_are_rejected_before_decompressing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compression.kiv");
+    let mut storage =
+        Storage::open_with_options(path.to_string_lossy(), options(Codec::Lz4)).unwrap();
+    storage.write_data_entry("a", value(0)).unwrap();
+    drop(storage);
+
+    // the length the value decompresses to comes right before it
+    let mut bytes = std::fs::read(&path).unwrap();
+    let length = (value(0).len() as u32).to_be_bytes();
+    let at = bytes
+        .windows(4)
+        .rposition(|window| window == length)
+        .unwrap();
+    bytes[at..at + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    std::fs::write(&path, bytes).unwrap();
+
+    let mut storage = Storage::open_read_only(path.to_string_lossy()).unwrap();
+    let err = storage.get_data_entry("a").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("more than"));
+}
@@ -0,0 +1,32 @@
+// replays the storage fuzz corpus, so inputs that once broke decoding keep
+// being checked without a nightly toolchain
+
+use std::path::PathBuf;
+use storage::Storage;
+
+const HEADER: [u8; 8] = [0, 104, 105, 107, 105, 118, 0, 7];
+const KEY_BLOCK: [u8; 45] = [0; 45];
+
+#[test]
+fn corpus_decodes_without_panicking() {
+    let corpus = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../fuzz/corpus/storage");
+    let dir = tempfile::tempdir().unwrap();
+
+    for input in std::fs::read_dir(corpus).unwrap() {
+        let input = input.unwrap().path();
+        let path = dir.path().join(input.file_name().unwrap());
+
+        let mut bytes = HEADER.to_vec();
+        bytes.extend(KEY_BLOCK);
+        bytes.extend(std::fs::read(&input).unwrap());
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+        let _ = storage.get_stats();
+        let _ = storage.get_entry_count();
+        let _ = storage.get_namespace_stats();
+        let _ = storage.scan_data_entries(b"");
+        let _ = storage.get_sorted_set_range("key", f64::MIN, f64::MAX);
+        let _ = storage.get_set_members("key");
+    }
+}
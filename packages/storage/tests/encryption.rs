@@ -0,0 +1,292 @@
+// writes encrypted values and checks they only read back with the right key,
+// across reopening, compaction and key rotation
+
+use std::io::ErrorKind;
+
+use storage::check::{check, repair};
+use storage::{
+    Cipher, Codec, CompressionOptions, EncryptionKey, EncryptionOptions, KeyError, Storage,
+    StorageOptions,
+};
+
+const SECRET: &str = "tok_4f9a1c2b7d3e";
+
+fn key(byte: u8) -> EncryptionKey {
+    EncryptionKey::new([byte; 32])
+}
+
+fn options(key: Option<EncryptionKey>, rotate_to: Option<EncryptionKey>) -> StorageOptions {
+    StorageOptions {
+        encryption: EncryptionOptions {
+            key,
+            rotate_to,
+            ..EncryptionOptions::default()
+        },
+        ..StorageOptions::default()
+    }
+}
+
+fn open(path: &std::path::Path, options: StorageOptions) -> std::io::Result<Storage> {
+    Storage::open_with_options(path.to_string_lossy(), options)
+}
+
+fn key_error(result: std::io::Result<Storage>) -> KeyError {
+    let err = result.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    *err.get_ref().unwrap().downcast_ref::<KeyError>().unwrap()
+}
+
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+#[test]
+fn values_are_only_stored_encrypted() {
+    for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("encryption.kiv");
+        let mut options = options(Some(key(1)), None);
+        options.encryption.cipher = cipher;
+        let mut storage = open(&path, options.clone()).unwrap();
+        storage.write_data_entry("customer-1", SECRET).unwrap();
+        storage.write_data_entry("customer-2", "other").unwrap();
+        storage.update_data_entry("customer-2", SECRET).unwrap();
+        drop(storage);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!contains(&bytes, SECRET));
+        // keys are left in the clear for lookups to compare
+        assert!(contains(&bytes, "customer-1"));
+
+        let mut storage =
+            Storage::open_read_only_with_options(path.to_string_lossy(), options).unwrap();
+        assert_eq!(
+            storage.get_data_entry("customer-1").unwrap(),
+            Some(SECRET.as_bytes().to_vec())
+        );
+        assert_eq!(
+            storage.scan_data_entries("customer").unwrap(),
+            vec![
+                (b"customer-1".to_vec(), SECRET.as_bytes().to_vec()),
+                (b"customer-2".to_vec(), SECRET.as_bytes().to_vec()),
+            ]
+        );
+    }
+}
+
+#[test]
+fn files_only_open_with_their_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("encryption.kiv");
+    let mut storage = open(&path, options(Some(key(1)), None)).unwrap();
+    storage.write_data_entry("a", SECRET).unwrap();
+    drop(storage);
+
+    assert_eq!(
+        key_error(open(&path, StorageOptions::default())),
+        KeyError::Missing
+    );
+    assert_eq!(
+        key_error(open(&path, options(Some(key(2)), None))),
+        KeyError::Wrong
+    );
+    let mut wrong_cipher = options(Some(key(1)), None);
+    wrong_cipher.encryption.cipher = Cipher::ChaCha20Poly1305;
+    // files are read with the cipher they name
+    assert!(open(&path, wrong_cipher).is_ok());
+
+    let plain = dir.path().join("plain.kiv");
+    drop(open(&plain, StorageOptions::default()).unwrap());
+    assert_eq!(
+        key_error(open(&plain, options(Some(key(1)), None))),
+        KeyError::NotEncrypted
+    );
+}
+
+#[test]
+fn tampered_values_fail_to_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("encryption.kiv");
+    let mut storage = open(&path, options(Some(key(1)), None)).unwrap();
+    storage.write_data_entry("a", SECRET).unwrap();
+    drop(storage);
+
+    let mut bytes = std::fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+
+    let mut storage = open(&path, options(Some(key(1)), None)).unwrap();
+    let err = storage.get_data_entry("a").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn keys_can_be_read_from_files_and_the_environment() {
+    let dir = tempfile::tempdir().unwrap();
+    let raw = dir.path().join("raw.key");
+    std::fs::write(&raw, [7u8; 32]).unwrap();
+    let hex = dir.path().join("hex.key");
+    std::fs::write(&hex, format!("{}\n", "07".repeat(32))).unwrap();
+    std::env::set_var("KIV_TEST_ENCRYPTION_KEY", "07".repeat(32));
+
+    let path = dir.path().join("encryption.kiv");
+    let key = EncryptionKey::from_file(&raw).unwrap();
+    let mut storage = open(&path, options(Some(key), None)).unwrap();
+    storage.write_data_entry("a", SECRET).unwrap();
+    drop(storage);
+
+    for key in [
+        EncryptionKey::from_file(&hex).unwrap(),
+        EncryptionKey::from_env("KIV_TEST_ENCRYPTION_KEY").unwrap(),
+    ] {
+        let mut storage = open(&path, options(Some(key), None)).unwrap();
+        assert_eq!(
+            storage.get_data_entry("a").unwrap(),
+            Some(SECRET.as_bytes().to_vec())
+        );
+    }
+
+    std::fs::write(&hex, "not a key").unwrap();
+    let err = EncryptionKey::from_file(&hex).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let err = EncryptionKey::from_env("KIV_TEST_MISSING_ENCRYPTION_KEY").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn compaction_rotates_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("encryption.kiv");
+    let mut storage = open(&path, options(Some(key(1)), None)).unwrap();
+    storage.write_data_entry("a", SECRET).unwrap();
+    storage.write_data_entry("b", "two").unwrap();
+    drop(storage);
+
+    let mut storage = open(&path, options(Some(key(1)), Some(key(2)))).unwrap();
+    // values are written with the old key until the file is compacted
+    storage.write_data_entry("c", "three").unwrap();
+    let mut snapshot = storage.snapshot_reader();
+    storage.update_data_entry("b", "changed").unwrap();
+    storage.compact().unwrap();
+    // the version only the snapshot reads is re-encrypted too
+    assert_eq!(snapshot.get_data_entry("b").unwrap(), Some(b"two".to_vec()));
+    drop(snapshot);
+    storage.write_data_entry("d", "four").unwrap();
+    drop(storage);
+
+    assert_eq!(
+        key_error(open(&path, options(Some(key(1)), None))),
+        KeyError::Wrong
+    );
+    // rotating again once it's done changes nothing
+    drop(open(&path, options(Some(key(1)), Some(key(2)))).unwrap());
+    let mut storage = open(&path, options(Some(key(2)), None)).unwrap();
+    for (key, value) in [
+        ("a", SECRET),
+        ("b", "changed"),
+        ("c", "three"),
+        ("d", "four"),
+    ] {
+        assert_eq!(
+            storage.get_data_entry(key).unwrap(),
+            Some(value.as_bytes().to_vec())
+        );
+    }
+}
+
+#[test]
+fn failed_rotations_leave_the_old_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("encryption.kiv");
+    let mut storage = open(&path, options(Some(key(1)), None)).unwrap();
+    storage.write_data_entry("a", SECRET).unwrap();
+    drop(storage);
+    let before = std::fs::read(&path).unwrap();
+
+    // the rotated file can't be written where it would be
+    std::fs::create_dir(dir.path().join("encryption.kiv.tmp")).unwrap();
+    let mut storage = open(&path, options(Some(key(1)), Some(key(2)))).unwrap();
+    assert!(storage.compact().is_err());
+    assert_eq!(
+        storage.get_data_entry("a").unwrap(),
+        Some(SECRET.as_bytes().to_vec())
+    );
+    drop(storage);
+    assert_eq!(std::fs::read(&path).unwrap(), before);
+
+    // and once it can, the file is rotated as usual
+    std::fs::remove_dir(dir.path().join("encryption.kiv.tmp")).unwrap();
+    let mut storage = open(&path, options(Some(key(1)), Some(key(2)))).unwrap();
+    storage.compact().unwrap();
+    drop(storage);
+    let mut storage = open(&path, options(Some(key(2)), None)).unwrap();
+    assert_eq!(
+        storage.get_data_entry("a").unwrap(),
+        Some(SECRET.as_bytes().to_vec())
+    );
+}
+
+#[test]
+fn plain_files_are_encrypted_by_rotating_to_a_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("encryption.kiv");
+    let mut storage = open(&path, StorageOptions::default()).unwrap();
+    storage.write_data_entry("a", SECRET).unwrap();
+    drop(storage);
+
+    let mut storage = open(&path, options(None, Some(key(1)))).unwrap();
+    storage.compact().unwrap();
+    drop(storage);
+    assert!(!contains(&std::fs::read(&path).unwrap(), SECRET));
+
+    let mut storage = open(&path, options(Some(key(1)), None)).unwrap();
+    assert_eq!(
+        storage.get_data_entry("a").unwrap(),
+        Some(SECRET.as_bytes().to_vec())
+    );
+}
+
+#[test]
+fn encrypted_files_have_no_change_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("encryption.kiv");
+    let mut storage = open(&path, options(Some(key(1)), None)).unwrap();
+    let err = storage.enable_change_log().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    drop(storage);
+
+    let plain = dir.path().join("plain.kiv");
+    let mut storage = open(&plain, StorageOptions::default()).unwrap();
+    storage.enable_change_log().unwrap();
+    drop(storage);
+    let err = open(&plain, options(None, Some(key(1)))).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+}
+
+#[test]
+fn values_are_compressed_before_they_are_encrypted() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("encryption.kiv");
+    let mut options = options(Some(key(1)), None);
+    options.compression = CompressionOptions {
+        codec: Codec::Zstd,
+        ..CompressionOptions::default()
+    };
+    let value = SECRET.repeat(100);
+    let mut storage = open(&path, options.clone()).unwrap();
+    storage.write_data_entry("a", &value).unwrap();
+    assert!(storage.get_stats().unwrap().compression_ratio > 10.0);
+    drop(storage);
+
+    assert!(check(&path).unwrap().is_ok());
+    let repaired = dir.path().join("repaired.kiv");
+    assert!(repair(&path, &repaired).unwrap().is_ok());
+    let mut storage = open(&repaired, options).unwrap();
+    assert_eq!(
+        storage.get_data_entry("a").unwrap(),
+        Some(value.into_bytes())
+    );
+}
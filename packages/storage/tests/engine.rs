@@ -0,0 +1,175 @@
+// runs the same operations through every storage engine, expecting the same
+// results from each
+
+use storage::{
+    engine::StorageEngine, BTreeEngine, BTreeOptions, Entry, LsmEngine, LsmOptions, MemoryEngine,
+    Storage,
+};
+
+fn engines(dir: &tempfile::TempDir) -> Vec<Box<dyn StorageEngine>> {
+    let path = dir.path().join("engine.kiv").to_string_lossy().into_owned();
+    vec![
+        Box::new(Storage::open(path).unwrap()),
+        Box::new(MemoryEngine::new()),
+        // small enough that most writes end up in tables
+        Box::new(
+            LsmEngine::open(
+                dir.path().join("lsm"),
+                LsmOptions {
+                    memtable_size: 16,
+                    level0_tables: 2,
+                    background_compaction: false,
+                    ..LsmOptions::default()
+                },
+            )
+            .unwrap(),
+        ),
+        Box::new(
+            BTreeEngine::open(
+                dir.path().join("engine.kvb"),
+                BTreeOptions {
+                    page_size: 1024,
+                    ..BTreeOptions::default()
+                },
+            )
+            .unwrap(),
+        ),
+    ]
+}
+
+#[test]
+fn engines_store_values_and_collections() {
+    let dir = tempfile::tempdir().unwrap();
+    for mut engine in engines(&dir) {
+        engine.put(b"a", b"one").unwrap();
+        engine.put(b"ab", b"two").unwrap();
+        engine.put(b"b", b"three").unwrap();
+        engine.put(b"a", b"uno").unwrap();
+        engine.delete(b"b").unwrap();
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"uno".to_vec()));
+        assert_eq!(engine.get(b"b").unwrap(), None);
+        assert_eq!(
+            engine.scan(b"a").unwrap(),
+            vec![
+                (b"a".to_vec(), b"uno".to_vec()),
+                (b"ab".to_vec(), b"two".to_vec())
+            ]
+        );
+
+        engine.zadd("board", "alice", 30.0).unwrap();
+        engine.zadd("board", "bob", 10.0).unwrap();
+        engine.zadd("board", "carol", 20.0).unwrap();
+        engine.zadd("board", "bob", 40.0).unwrap();
+        engine.zrem("board", "carol").unwrap();
+        assert_eq!(engine.zscore("board", "bob").unwrap(), Some(40.0));
+        assert_eq!(engine.zrank("board", "alice").unwrap(), Some(0));
+        assert_eq!(
+            engine.zrange("board", 0.0, 35.0).unwrap(),
+            vec![("alice".to_string(), 30.0)]
+        );
+
+        assert!(engine.sadd("flags", "beta").unwrap());
+        assert!(engine.sadd("flags", "alpha").unwrap());
+        assert!(!engine.sadd("flags", "beta").unwrap());
+        assert!(engine.srem("flags", "beta").unwrap());
+        assert!(!engine.srem("flags", "beta").unwrap());
+        assert!(engine.sismember("flags", "alpha").unwrap());
+        assert_eq!(engine.smembers("flags").unwrap(), vec!["alpha".to_string()]);
+        assert_eq!(engine.scard("flags").unwrap(), 1);
+
+        assert_eq!(engine.stats().unwrap().keys, 4);
+        assert!(engine.put(&[0; 1 << 16], b"").is_err());
+    }
+}
+
+#[test]
+fn engines_keep_namespaces_apart() {
+    let dir = tempfile::tempdir().unwrap();
+    for mut engine in engines(&dir) {
+        engine.put(b"a", b"default").unwrap();
+        let team = engine.create_namespace("team").unwrap().unwrap();
+        assert_eq!(engine.create_namespace("team").unwrap(), None);
+        assert_eq!(engine.namespace_id("team").unwrap(), Some(team));
+
+        engine.use_namespace(team);
+        assert_eq!(engine.get(b"a").unwrap(), None);
+        engine.put(b"a", b"team").unwrap();
+        engine.sadd("s", "x").unwrap();
+        let stats = engine.namespace_stats().unwrap();
+        assert_eq!((stats[1].name.as_str(), stats[1].entries), ("team", 2));
+
+        engine.truncate().unwrap();
+        assert_eq!(engine.get(b"a").unwrap(), None);
+        assert_eq!(engine.namespace_id("team").unwrap(), Some(team));
+
+        engine.put(b"a", b"team").unwrap();
+        assert!(engine.drop_namespace("team").unwrap());
+        assert!(!engine.drop_namespace("default").unwrap());
+        assert_eq!(engine.current_namespace(), 0);
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"default".to_vec()));
+
+        engine.flush().unwrap();
+        assert_eq!(engine.namespaces().unwrap().len(), 1);
+        assert_eq!(engine.stats().unwrap().keys, 0);
+    }
+}
+
+#[test]
+fn engine_views_read_but_dont_write() {
+    let dir = tempfile::tempdir().unwrap();
+    for mut engine in engines(&dir) {
+        engine.put(b"a", b"one").unwrap();
+        let mut reader = engine.reader();
+        let mut snapshot = engine.snapshot_reader();
+        assert!(reader.is_read_only());
+        assert!(reader.put(b"b", b"two").is_err());
+        assert!(snapshot.put(b"b", b"two").is_err());
+
+        engine.put(b"a", b"two").unwrap();
+        assert_eq!(reader.get(b"a").unwrap(), Some(b"two".to_vec()));
+        assert_eq!(snapshot.get(b"a").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(snapshot.reader().get(b"a").unwrap(), Some(b"one".to_vec()));
+    }
+}
+
+#[test]
+fn memory_engines_back_up_to_kiv_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("backup.kiv");
+    let mut engine = MemoryEngine::new();
+    let team = engine.create_namespace("team").unwrap().unwrap();
+    engine.use_namespace(team);
+    engine.put(b"a", b"one").unwrap();
+    engine.zadd("z", "x", 1.5).unwrap();
+    engine.sadd("s", "x").unwrap();
+
+    engine.backup(&path, &|part| part()).unwrap();
+    assert_eq!(engine.current_namespace(), team);
+    assert!(storage::check::check(&path).unwrap().is_ok());
+
+    let mut entries = vec![];
+    engine
+        .for_each_entry(&mut |entry| {
+            entries.push(entry);
+            Ok(())
+        })
+        .unwrap();
+    let mut restored = Storage::open(path.to_string_lossy()).unwrap();
+    let mut restored_entries = vec![];
+    restored
+        .for_each_entry(|entry| {
+            restored_entries.push(entry);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(restored_entries, entries);
+    assert!(matches!(entries[0], Entry::Namespace { .. }));
+
+    // and load back from them
+    let mut loaded = MemoryEngine::new();
+    loaded.append_entries(&entries).unwrap();
+    loaded.use_namespace(team);
+    assert_eq!(loaded.get(b"a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(loaded.zscore("z", "x").unwrap(), Some(1.5));
+    assert!(loaded.enable_change_log().is_err());
+}
@@ -0,0 +1,79 @@
+// opens the same file more than once and checks who gets in
+
+use std::io::ErrorKind;
+
+use storage::{LockError, Storage};
+
+#[test]
+fn writers_lock_everyone_else_out() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("locked.kiv");
+    let path = path.to_string_lossy();
+
+    let mut storage = Storage::open(path.clone()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+
+    let err = Storage::open(path.clone()).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    let err = Storage::open_read_only(path.clone()).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert!(err.get_ref().unwrap().is::<LockError>());
+
+    // the lock goes with the storage
+    drop(storage);
+    Storage::open(path).unwrap();
+}
+
+#[test]
+fn readers_share_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("shared.kiv");
+    let path = path.to_string_lossy();
+
+    let mut storage = Storage::open(path.clone()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    drop(storage);
+
+    let mut first = Storage::open_read_only(path.clone()).unwrap();
+    let mut second = Storage::open_read_only(path.clone()).unwrap();
+    assert_eq!(first.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(second.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+    assert!(first.is_read_only());
+
+    let err = first.write_data_entry("b", "two").err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::ReadOnlyFilesystem);
+    let err = Storage::open(path.clone()).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+}
+
+#[test]
+fn read_only_files_have_to_exist() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("missing.kiv");
+
+    let err = Storage::open_read_only(path.to_string_lossy())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(!path.exists());
+}
+
+#[test]
+fn rewritten_files_stay_locked() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("rewritten.kiv");
+    let path = path.to_string_lossy();
+
+    let mut storage = Storage::open(path.clone()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    storage.delete_data_entry("a").unwrap();
+    storage.write_data_entry("b", "two").unwrap();
+    let mut reader = storage.reader();
+    // the file is replaced by a new one without the deleted entry
+    storage.compact().unwrap();
+
+    let err = Storage::open(path.clone()).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert_eq!(reader.get_data_entry("b").unwrap(), Some(b"two".to_vec()));
+    assert!(!dir.path().join("rewritten.kiv.tmp").exists());
+}
@@ -0,0 +1,206 @@
+// writes enough through the lsm engine to flush and compact its tables, and
+// reopens it to check what survives
+
+use std::{collections::BTreeMap, io::ErrorKind, path::Path};
+
+use proptest::prelude::*;
+use storage::{engine::StorageEngine, LsmEngine, LsmOptions};
+
+fn small(background_compaction: bool) -> LsmOptions {
+    LsmOptions {
+        memtable_size: 256,
+        block_size: 64,
+        level0_tables: 2,
+        level_size: 1024,
+        level_size_multiplier: 2,
+        table_size: 512,
+        levels: 4,
+        background_compaction,
+        ..LsmOptions::default()
+    }
+}
+
+fn fill(engine: &mut LsmEngine, keys: u32) {
+    for i in 0..keys {
+        engine
+            .put(
+                format!("key-{:05}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+    }
+}
+
+fn check(engine: &mut LsmEngine, keys: u32) {
+    for i in 0..keys {
+        assert_eq!(
+            engine.get(format!("key-{:05}", i).as_bytes()).unwrap(),
+            Some(format!("value-{}", i).into_bytes())
+        );
+    }
+    assert_eq!(engine.get(b"key-missing").unwrap(), None);
+}
+
+fn tables(dir: &Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+        .count()
+}
+
+#[test]
+fn unflushed_writes_are_replayed_from_the_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut engine = LsmEngine::open(dir.path(), LsmOptions::default()).unwrap();
+    engine.put(b"a", b"one").unwrap();
+    engine.sadd("s", "x").unwrap();
+    engine.delete(b"a").unwrap();
+    engine.put(b"b", b"two").unwrap();
+    assert_eq!(tables(dir.path()), 0);
+    drop(engine);
+
+    let mut engine = LsmEngine::open(dir.path(), LsmOptions::default()).unwrap();
+    assert_eq!(engine.get(b"a").unwrap(), None);
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"two".to_vec()));
+    assert!(engine.sismember("s", "x").unwrap());
+}
+
+#[test]
+fn full_memtables_are_flushed_and_compacted_down() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut engine = LsmEngine::open(dir.path(), small(false)).unwrap();
+    fill(&mut engine, 500);
+
+    let levels = engine.levels();
+    assert!(levels[0] < 2, "level 0 wasn't compacted: {:?}", levels);
+    assert!(levels[1..].iter().sum::<usize>() > 1);
+    check(&mut engine, 500);
+    assert_eq!(engine.scan(b"key-0001").unwrap().len(), 10);
+
+    drop(engine);
+    let mut engine = LsmEngine::open(dir.path(), small(false)).unwrap();
+    assert_eq!(engine.levels(), levels);
+    check(&mut engine, 500);
+    assert_eq!(engine.stats().unwrap().keys, 500);
+}
+
+#[test]
+fn background_compaction_keeps_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut engine = LsmEngine::open(dir.path(), small(true)).unwrap();
+    fill(&mut engine, 500);
+    check(&mut engine, 500);
+
+    // dropping the engine waits for the compaction thread
+    drop(engine);
+    let mut engine = LsmEngine::open(dir.path(), small(false)).unwrap();
+    check(&mut engine, 500);
+}
+
+#[test]
+fn compaction_drops_overwritten_and_deleted_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut engine = LsmEngine::open(dir.path(), small(false)).unwrap();
+    fill(&mut engine, 200);
+    for i in 0..200 {
+        engine.delete(format!("key-{:05}", i).as_bytes()).unwrap();
+    }
+    engine.put(b"kept", b"yes").unwrap();
+
+    let freed = engine.compact().unwrap();
+    assert!(freed > 0);
+    assert_eq!(engine.levels(), vec![0, 0, 0, 1]);
+    assert_eq!(engine.size().unwrap().0, 1);
+    assert_eq!(engine.get(b"kept").unwrap(), Some(b"yes".to_vec()));
+    assert_eq!(engine.get(b"key-00000").unwrap(), None);
+}
+
+#[test]
+fn snapshots_keep_their_tables_through_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut engine = LsmEngine::open(dir.path(), small(false)).unwrap();
+    fill(&mut engine, 100);
+    let mut snapshot = engine.snapshot_reader();
+
+    engine.truncate().unwrap();
+    engine.compact().unwrap();
+    assert_eq!(engine.get(b"key-00000").unwrap(), None);
+    assert_eq!(
+        snapshot.get(b"key-00000").unwrap(),
+        Some(b"value-0".to_vec())
+    );
+    assert_eq!(snapshot.scan(b"key-").unwrap().len(), 100);
+
+    // the replaced tables go once nothing reads them
+    drop(snapshot);
+    assert_eq!(tables(dir.path()), 0);
+}
+
+#[test]
+fn leftover_tables_are_removed_on_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut engine = LsmEngine::open(dir.path(), small(false)).unwrap();
+    fill(&mut engine, 100);
+    drop(engine);
+
+    let stray = dir.path().join("99999999.sst");
+    std::fs::write(&stray, b"half written").unwrap();
+    let mut engine = LsmEngine::open(dir.path(), small(false)).unwrap();
+    assert!(!stray.exists());
+    check(&mut engine, 100);
+}
+
+#[test]
+fn directories_are_locked_while_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut engine = LsmEngine::open(dir.path(), LsmOptions::default()).unwrap();
+    engine.put(b"a", b"one").unwrap();
+
+    let err = LsmEngine::open(dir.path(), LsmOptions::default())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    let err = LsmEngine::open_read_only(dir.path(), LsmOptions::default())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+    drop(engine);
+    let mut reader = LsmEngine::open_read_only(dir.path(), LsmOptions::default()).unwrap();
+    assert_eq!(reader.get(b"a").unwrap(), Some(b"one".to_vec()));
+    assert!(reader.put(b"b", b"two").is_err());
+    assert!(reader.compact().is_err());
+}
+
+proptest! {
+    #[test]
+    fn lsm_engines_behave_like_a_sorted_map(
+        ops in prop::collection::vec((prop::collection::vec(0u8..8, 1..3), prop::option::of(prop::collection::vec(any::<u8>(), 0..48))), 1..200),
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path(), small(false)).unwrap();
+        let mut model = BTreeMap::new();
+        for (i, (key, value)) in ops.into_iter().enumerate() {
+            match value {
+                Some(value) => {
+                    engine.put(&key, &value).unwrap();
+                    model.insert(key, value);
+                }
+                None => {
+                    engine.delete(&key).unwrap();
+                    model.remove(&key);
+                }
+            }
+            if i % 50 == 49 {
+                drop(engine);
+                engine = LsmEngine::open(dir.path(), small(false)).unwrap();
+            }
+        }
+
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = model.into_iter().collect();
+        prop_assert_eq!(engine.scan(b"").unwrap(), expected.clone());
+        for (key, value) in expected {
+            prop_assert_eq!(engine.get(&key).unwrap(), Some(value));
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// reads files through a memory map and checks they read the same as they do
+// through read calls, as the file grows and shrinks under the map
+
+use std::io::ErrorKind;
+
+use storage::{Codec, CompressionOptions, Entry, Storage, StorageOptions};
+
+fn mapped() -> StorageOptions {
+    StorageOptions {
+        mmap: true,
+        ..StorageOptions::default()
+    }
+}
+
+fn open(path: &std::path::Path, options: StorageOptions) -> Storage {
+    Storage::open_with_options(path.to_string_lossy(), options).unwrap()
+}
+
+fn open_read_only(path: &std::path::Path, options: StorageOptions) -> Storage {
+    Storage::open_read_only_with_options(path.to_string_lossy(), options).unwrap()
+}
+
+/// Everything the file written by `mapped_reads_match_read_calls` holds,
+/// read each way there is.
+#[derive(Debug, PartialEq)]
+struct Reads {
+    values: Vec<Option<Vec<u8>>>,
+    scanned: Vec<(Vec<u8>, Vec<u8>)>,
+    entries: Vec<Entry>,
+    compression_ratio: f64,
+}
+
+fn reads(storage: &mut Storage) -> Reads {
+    let values = (0..50)
+        .map(|i| storage.get_data_entry(format!("key{}", i)).unwrap())
+        .collect();
+    assert_eq!(
+        storage.get_set_members("set").unwrap(),
+        vec!["member".to_string()]
+    );
+    assert_eq!(
+        storage.get_sorted_set_score("zset", "member").unwrap(),
+        Some(1.5)
+    );
+    let mut entries = vec![];
+    storage
+        .for_each_entry(|entry| {
+            entries.push(entry);
+            Ok(())
+        })
+        .unwrap();
+
+    Reads {
+        values,
+        scanned: storage.scan_data_entries("key1").unwrap(),
+        entries,
+        compression_ratio: storage.get_stats().unwrap().compression_ratio,
+    }
+}
+
+#[test]
+fn mapped_reads_match_read_calls() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mmap.kiv");
+    let mut storage = open(
+        &path,
+        StorageOptions {
+            compression: CompressionOptions {
+                codec: Codec::Lz4,
+                threshold: 64,
+                ..CompressionOptions::default()
+            },
+            ..StorageOptions::default()
+        },
+    );
+    for i in 0..50 {
+        storage
+            .write_data_entry(format!("key{}", i), "value ".repeat(i))
+            .unwrap();
+    }
+    storage.delete_data_entry("key7").unwrap();
+    storage.write_set_entry("set", "member").unwrap();
+    storage
+        .write_sorted_set_entry("zset", "member", 1.5)
+        .unwrap();
+    let id = storage.create_namespace("other").unwrap().unwrap();
+    storage.use_namespace(id);
+    storage.write_data_entry("key1", "other").unwrap();
+    drop(storage);
+
+    let expected = reads(&mut open_read_only(&path, StorageOptions::default()));
+    assert_eq!(reads(&mut open(&path, mapped())), expected);
+    assert_eq!(reads(&mut open_read_only(&path, mapped())), expected);
+}
+
+#[test]
+fn writes_are_read_back_through_the_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mmap.kiv");
+    let mut storage = open(&path, mapped());
+    let mut reader = storage.reader();
+    storage.write_data_entry("a", "one").unwrap();
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+
+    // the file grows past what was mapped
+    for i in 0..1000 {
+        storage
+            .write_data_entry(format!("key{}", i), "x".repeat(100))
+            .unwrap();
+    }
+    assert_eq!(
+        reader.get_data_entry("key999").unwrap(),
+        Some("x".repeat(100).into_bytes())
+    );
+
+    // and shrinks, shifting the entries after the one that changed
+    storage.update_data_entry("a", "two").unwrap();
+    storage.delete_data_entry("key0").unwrap();
+    assert_eq!(reader.get_data_entry("a").unwrap(), Some(b"two".to_vec()));
+    assert_eq!(reader.get_data_entry("key0").unwrap(), None);
+    assert_eq!(
+        storage.get_data_entry("key999").unwrap(),
+        Some("x".repeat(100).into_bytes())
+    );
+
+    storage.flush().unwrap();
+    assert_eq!(reader.get_data_entry("key999").unwrap(), None);
+    storage.write_data_entry("b", "three").unwrap();
+    assert_eq!(reader.get_data_entry("b").unwrap(), Some(b"three".to_vec()));
+}
+
+#[test]
+fn read_only_values_are_slices_of_the_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mmap.kiv");
+    let mut storage = open(&path, mapped());
+    storage.write_data_entry("a", "one").unwrap();
+    // a file that can be written copies its values out
+    let first = storage.get_data_entry_bytes("a").unwrap().unwrap();
+    let second = storage.get_data_entry_bytes("a").unwrap().unwrap();
+    assert_ne!(first.as_ptr(), second.as_ptr());
+    drop(storage);
+
+    let mut storage = open_read_only(&path, mapped());
+    let first = storage.get_data_entry_bytes("a").unwrap().unwrap();
+    let second = storage.get_data_entry_bytes("a").unwrap().unwrap();
+    assert_eq!(first.as_ptr(), second.as_ptr());
+    // the map outlives the storage for as long as its values are around
+    drop(storage);
+    assert_eq!(first, "one");
+}
+
+#[test]
+fn cut_short_files_fail_to_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mmap.kiv");
+    let mut storage = open(&path, StorageOptions::default());
+    storage.write_data_entry("a", "one").unwrap();
+    storage.write_data_entry("b", "two").unwrap();
+    drop(storage);
+    let length = std::fs::metadata(&path).unwrap().len();
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(length - 1).unwrap();
+    drop(file);
+
+    let mut storage = open_read_only(&path, mapped());
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+    let err = storage.get_data_entry("b").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,69 @@
+// runs random operations against storage and a HashMap, expecting the same
+// results from both
+
+use proptest::prelude::*;
+use std::collections::HashMap;
+use storage::Storage;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(Vec<u8>, Vec<u8>),
+    Get(Vec<u8>),
+    Delete(Vec<u8>),
+    Reopen,
+}
+
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    // a handful of short keys so operations land on the same entries
+    prop::collection::vec(0u8..4, 0..3)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (key(), prop::collection::vec(any::<u8>(), 0..32))
+            .prop_map(|(key, value)| Op::Set(key, value)),
+        3 => key().prop_map(Op::Get),
+        2 => key().prop_map(Op::Delete),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn data_entries_behave_like_a_hash_map(ops in prop::collection::vec(op(), 1..64)) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.kiv").to_string_lossy().into_owned();
+        let mut storage = Storage::open(path.clone()).unwrap();
+        let mut model: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+
+        for op in ops {
+            match op {
+                Op::Set(key, value) => {
+                    // the same write-or-update core does for SET
+                    if storage.get_data_entry(&key).unwrap().is_some() {
+                        storage.update_data_entry(&key, &value).unwrap();
+                    } else {
+                        storage.write_data_entry(&key, &value).unwrap();
+                    }
+                    model.insert(key, value);
+                }
+                Op::Get(key) => {
+                    prop_assert_eq!(storage.get_data_entry(&key).unwrap(), model.get(&key).cloned());
+                }
+                Op::Delete(key) => {
+                    storage.delete_data_entry(&key).unwrap();
+                    model.remove(&key);
+                }
+                Op::Reopen => {
+                    drop(storage);
+                    storage = Storage::open(path.clone()).unwrap();
+                }
+            }
+        }
+
+        let mut expected: Vec<(Vec<u8>, Vec<u8>)> = model.into_iter().collect();
+        expected.sort();
+        prop_assert_eq!(storage.scan_data_entries(b"").unwrap(), expected.clone());
+        prop_assert_eq!(storage.get_stats().unwrap().keys, expected.len() as u64);
+    }
+}
@@ -0,0 +1,277 @@
+// writes to a file while snapshots of it are open, checking what each one
+// reads and what compaction leaves behind
+
+use storage::Storage;
+
+fn open(dir: &tempfile::TempDir) -> Storage {
+    Storage::open(dir.path().join("mvcc.kiv").to_string_lossy()).unwrap()
+}
+
+#[test]
+fn snapshots_keep_reading_what_they_started_with() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = open(&dir);
+    storage.write_data_entry("a", "one").unwrap();
+    storage.write_data_entry("b", "two").unwrap();
+    storage.write_sorted_set_entry("z", "x", 1.0).unwrap();
+    storage.write_set_entry("s", "x").unwrap();
+
+    let mut snapshot = storage.snapshot_reader();
+
+    storage.update_data_entry("a", "uno").unwrap();
+    storage.delete_data_entry("b").unwrap();
+    storage.write_data_entry("c", "three").unwrap();
+    storage.write_sorted_set_entry("z", "x", 2.0).unwrap();
+    storage.delete_set_entry("s", "x").unwrap();
+
+    assert_eq!(snapshot.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(snapshot.get_data_entry("b").unwrap(), Some(b"two".to_vec()));
+    assert_eq!(snapshot.get_data_entry("c").unwrap(), None);
+    assert_eq!(snapshot.get_sorted_set_score("z", "x").unwrap(), Some(1.0));
+    assert!(snapshot.is_set_member("s", "x").unwrap());
+    assert_eq!(snapshot.scan_data_entries("").unwrap().len(), 2);
+
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"uno".to_vec()));
+    assert_eq!(storage.get_data_entry("b").unwrap(), None);
+    assert_eq!(storage.get_sorted_set_score("z", "x").unwrap(), Some(2.0));
+    assert!(!storage.is_set_member("s", "x").unwrap());
+    assert_eq!(storage.get_stats().unwrap().keys, 3);
+
+    // views made from a snapshot read at the same point
+    let mut view = snapshot.reader();
+    assert_eq!(view.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+}
+
+#[test]
+fn snapshots_see_dropped_namespaces_and_flushes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = open(&dir);
+    let id = storage.create_namespace("other").unwrap().unwrap();
+    storage.use_namespace(id);
+    storage.write_data_entry("a", "other").unwrap();
+
+    let mut snapshot = storage.snapshot_reader();
+    storage.drop_namespace("other").unwrap();
+    assert_eq!(storage.get_namespace_id("other").unwrap(), None);
+    assert_eq!(snapshot.get_namespace_id("other").unwrap(), Some(id));
+    assert_eq!(
+        snapshot.get_data_entry("a").unwrap(),
+        Some(b"other".to_vec())
+    );
+
+    storage.write_data_entry("b", "default").unwrap();
+    let mut later = storage.snapshot_reader();
+    storage.flush().unwrap();
+    assert_eq!(storage.get_stats().unwrap().keys, 0);
+    assert_eq!(
+        later.get_data_entry("b").unwrap(),
+        Some(b"default".to_vec())
+    );
+    assert_eq!(snapshot.get_data_entry("b").unwrap(), None);
+}
+
+#[test]
+fn compaction_keeps_versions_until_snapshots_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = open(&dir);
+    storage.write_data_entry("a", "one").unwrap();
+    let size = storage.get_stats().unwrap().file_size;
+
+    let snapshot = storage.snapshot_reader();
+    storage.update_data_entry("a", "two").unwrap();
+    let mut later = storage.snapshot_reader();
+    storage.update_data_entry("a", "three").unwrap();
+
+    let stats = storage.get_stats().unwrap();
+    assert_eq!(stats.keys, 1);
+    assert!(stats.dead_bytes > 0);
+
+    // nothing is freed while both snapshots can read the old versions
+    assert_eq!(storage.compact().unwrap(), 0);
+
+    drop(snapshot.reader());
+    drop(snapshot);
+    assert!(storage.compact().unwrap() > 0);
+    assert_eq!(later.get_data_entry("a").unwrap(), Some(b"two".to_vec()));
+
+    drop(later);
+    storage.compact().unwrap();
+    let stats = storage.get_stats().unwrap();
+    assert_eq!(stats.file_size, size + 2);
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(
+        storage.get_data_entry("a").unwrap(),
+        Some(b"three".to_vec())
+    );
+
+    // without snapshots, old versions aren't kept at all
+    storage.update_data_entry("a", "four").unwrap();
+    assert_eq!(storage.get_stats().unwrap().dead_bytes, 0);
+}
+
+#[test]
+fn deleted_versions_stay_hidden_after_reopening() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = open(&dir);
+    storage.write_data_entry("a", "one").unwrap();
+    let snapshot = storage.snapshot_reader();
+    storage.update_data_entry("a", "two").unwrap();
+    drop(snapshot);
+    drop(storage);
+
+    let mut storage = open(&dir);
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"two".to_vec()));
+    assert_eq!(storage.scan_data_entries("").unwrap().len(), 1);
+
+    // later writes carry on from the sequence numbers already in the file
+    let mut snapshot = storage.snapshot_reader();
+    storage.update_data_entry("a", "three").unwrap();
+    assert_eq!(snapshot.get_data_entry("a").unwrap(), Some(b"two".to_vec()));
+}
+
+#[test]
+fn updates_and_deletes_leave_no_old_versions_behind() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mvcc.kiv");
+    let mut storage = open(&dir);
+    storage.write_data_entry("a", "one").unwrap();
+    storage.write_sorted_set_entry("z", "x", 1.0).unwrap();
+    let before = std::fs::metadata(&path).unwrap().len();
+
+    storage.update_data_entry("a", "two").unwrap();
+    storage.write_data_entry("b", "one").unwrap();
+    storage.delete_data_entry("b").unwrap();
+    storage.write_set_entry("s", "x").unwrap();
+    storage.delete_set_entry("s", "x").unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), before);
+    assert!(!dir.path().join("mvcc.kiv.tmp").exists());
+    assert_eq!(storage.compact().unwrap(), 0);
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"two".to_vec()));
+    assert_eq!(storage.get_sorted_set_score("z", "x").unwrap(), Some(1.0));
+}
+
+#[test]
+fn updates_cut_short_are_finished_on_opening() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mvcc.kiv");
+    let mut storage = open(&dir);
+    storage.write_data_entry("a", "one").unwrap();
+    storage.write_sorted_set_entry("z", "x", 1.0).unwrap();
+    storage.write_data_entry("b", "other").unwrap();
+
+    for update in [
+        |storage: &mut Storage| storage.update_data_entry("a", "two").unwrap(),
+        |storage: &mut Storage| storage.write_sorted_set_entry("z", "x", 2.0).unwrap(),
+    ] {
+        let before = std::fs::read(&path).unwrap();
+        // with a snapshot open the old version is kept, only marked deleted
+        let snapshot = storage.snapshot_reader();
+        update(&mut storage);
+        drop(snapshot);
+        drop(storage);
+
+        // the new version was appended, but the old one never marked
+        let mut cut_short = before.clone();
+        cut_short.extend(&std::fs::read(&path).unwrap()[before.len()..]);
+        std::fs::write(&path, cut_short).unwrap();
+        std::fs::remove_file(storage::bloom_path(&path)).ok();
+        storage = open(&dir);
+    }
+
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"two".to_vec()));
+    assert_eq!(storage.get_sorted_set_score("z", "x").unwrap(), Some(2.0));
+    assert_eq!(
+        storage
+            .get_sorted_set_range("z", f64::NEG_INFINITY, f64::INFINITY)
+            .unwrap(),
+        vec![("x".to_string(), 2.0)]
+    );
+    assert_eq!(storage.scan_data_entries("").unwrap().len(), 2);
+
+    // and the old versions don't come back once the new ones are gone
+    storage.delete_data_entry("a").unwrap();
+    storage.delete_sorted_set_entry("z", "x").unwrap();
+    assert_eq!(storage.get_data_entry("a").unwrap(), None);
+    assert_eq!(storage.get_sorted_set_score("z", "x").unwrap(), None);
+    assert_eq!(
+        storage.get_data_entry("b").unwrap(),
+        Some(b"other".to_vec())
+    );
+}
+
+#[test]
+fn failed_rewrites_leave_the_file_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mvcc.kiv");
+    let mut storage = open(&dir);
+    storage.write_data_entry("a", "one").unwrap();
+    storage.update_data_entry("a", "two").unwrap();
+    storage.write_set_entry("s", "x").unwrap();
+    let before = std::fs::read(&path).unwrap();
+
+    // the rewritten file can't be written where it would be
+    std::fs::create_dir(dir.path().join("mvcc.kiv.tmp")).unwrap();
+    for open_snapshot in [false, true] {
+        let snapshot = open_snapshot.then(|| storage.snapshot_reader());
+        assert!(storage.compact().is_err());
+        assert!(storage.truncate_namespace().is_err());
+        assert!(storage.flush().is_err());
+        drop(snapshot);
+
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"two".to_vec()));
+        assert!(storage.is_set_member("s", "x").unwrap());
+    }
+
+    // updates and deletes are made all the same, leaving the old versions
+    // marked for compaction to remove
+    storage.update_data_entry("a", "three").unwrap();
+    storage.delete_set_entry("s", "x").unwrap();
+    assert_eq!(
+        storage.get_data_entry("a").unwrap(),
+        Some(b"three".to_vec())
+    );
+    assert!(!storage.is_set_member("s", "x").unwrap());
+    assert!(std::fs::read(&path).unwrap().len() > before.len());
+
+    std::fs::remove_dir(dir.path().join("mvcc.kiv.tmp")).unwrap();
+    let mut snapshot = storage.snapshot_reader();
+    storage.flush().unwrap();
+    assert_eq!(storage.get_data_entry("a").unwrap(), None);
+    assert_eq!(
+        snapshot.get_data_entry("a").unwrap(),
+        Some(b"three".to_vec())
+    );
+    drop(snapshot);
+    storage.compact().unwrap();
+    assert_eq!(storage.get_entries_size().unwrap(), 0);
+}
+
+#[test]
+fn version_4_files_are_upgraded() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mvcc.kiv");
+
+    // a version 4 data entry: type, namespace, checksum, then key and value
+    let mut entry = vec![0, 0, 0, 0, 0, 0, 0, 0, 1, b'a', 0, 0, 0, 3];
+    entry.extend_from_slice(b"one");
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&entry[..3]);
+    hasher.update(&entry[7..]);
+    entry[3..7].copy_from_slice(&hasher.finalize().to_be_bytes());
+    let mut bytes = vec![0, 104, 105, 107, 105, 118, 0, 4];
+    bytes.extend(entry);
+    std::fs::write(&path, bytes).unwrap();
+
+    let err = Storage::open_read_only(path.to_string_lossy())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut storage = open(&dir);
+    assert_eq!(storage.get_stats().unwrap().version, 7);
+    let mut snapshot = storage.snapshot_reader();
+    storage.update_data_entry("a", "two").unwrap();
+    assert_eq!(snapshot.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+    assert!(storage::check::check(&path).unwrap().is_ok());
+}
@@ -0,0 +1,49 @@
+// reads one file from several threads at once through reader views
+
+use std::thread;
+
+use storage::Storage;
+
+#[test]
+fn readers_read_in_parallel() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("readers.kiv");
+
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    for i in 0..100 {
+        storage
+            .write_data_entry(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    let id = storage.create_namespace("other").unwrap().unwrap();
+    storage.use_namespace(id);
+    storage.write_data_entry("key0", "other").unwrap();
+    storage.use_namespace(0);
+
+    thread::scope(|scope| {
+        for thread in 0..8 {
+            let mut reader = storage.reader();
+            scope.spawn(move || {
+                // each view keeps its own position, so these scans don't
+                // trip over each other
+                for i in (thread..100).step_by(8) {
+                    assert_eq!(
+                        reader.get_data_entry(format!("key{}", i)).unwrap(),
+                        Some(format!("value{}", i).into_bytes())
+                    );
+                }
+                assert_eq!(reader.scan_data_entries("key").unwrap().len(), 100);
+            });
+        }
+    });
+
+    // views start in the namespace they were made in
+    storage.use_namespace(id);
+    let mut reader = storage.reader();
+    assert_eq!(
+        reader.get_data_entry("key0").unwrap(),
+        Some(b"other".to_vec())
+    );
+    assert!(reader.is_read_only());
+    assert!(reader.write_data_entry("key1", "nope").is_err());
+}
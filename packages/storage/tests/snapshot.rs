@@ -0,0 +1,126 @@
+// takes snapshots of a file that keeps being written to and opens them
+
+use storage::check::check;
+use storage::Storage;
+
+#[test]
+fn snapshots_hold_the_state_they_were_taken_in() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("live.kiv");
+    let snapshot_path = dir.path().join("snapshot.kiv");
+
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    storage.write_set_entry("s", "x").unwrap();
+
+    let bytes = storage.snapshot(&snapshot_path).unwrap();
+
+    storage.write_data_entry("b", "two").unwrap();
+    storage.delete_data_entry("a").unwrap();
+
+    assert_eq!(bytes, std::fs::metadata(&snapshot_path).unwrap().len());
+    assert!(check(&snapshot_path).unwrap().is_ok());
+    assert!(!dir.path().join("snapshot.kiv.tmp").exists());
+
+    let mut snapshot = Storage::open(snapshot_path.to_string_lossy()).unwrap();
+    assert_eq!(snapshot.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(snapshot.get_data_entry("b").unwrap(), None);
+    assert!(snapshot.is_set_member("s", "x").unwrap());
+}
+
+#[test]
+fn snapshots_can_be_streamed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("live.kiv");
+
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+
+    let mut out = vec![];
+    let bytes = storage.snapshot_to(&mut out).unwrap();
+
+    assert_eq!(bytes, out.len() as u64);
+    assert_eq!(out, std::fs::read(&path).unwrap());
+
+    // the file is still usable afterwards
+    storage.write_data_entry("b", "two").unwrap();
+    assert_eq!(storage.get_data_entry("b").unwrap(), Some(b"two".to_vec()));
+}
+
+#[test]
+fn snapshots_refuse_to_overwrite_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("live.kiv");
+
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    storage.write_data_entry("a", "one").unwrap();
+    storage.enable_change_log().unwrap();
+
+    let changes = storage.changes_path().to_path_buf();
+    let bloom = storage::bloom_path(&path);
+    // the same files, reached another way
+    let roundabout = dir.path().join("..").join(dir.path().file_name().unwrap());
+    for target in [
+        path.clone(),
+        changes.clone(),
+        bloom,
+        roundabout.join("live.kiv"),
+        roundabout.join("live.kiv.changes"),
+    ] {
+        let err = storage.snapshot(&target).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{:?}", target);
+    }
+
+    // the file, its change log and its lock are left alone
+    assert!(Storage::open(path.to_string_lossy()).is_err());
+    storage.write_data_entry("b", "two").unwrap();
+    assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(storage.get_data_entry("b").unwrap(), Some(b"two".to_vec()));
+    assert!(std::fs::metadata(&changes).unwrap().len() > 0);
+    assert!(!dir.path().join("live.kiv.tmp").exists());
+}
+
+#[test]
+fn snapshots_taken_in_parts_ignore_writes_between_them() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("live.kiv");
+    let snapshot_path = dir.path().join("snapshot.kiv");
+
+    // big enough to be copied in several parts
+    let value = vec![7u8; 64 * 1024];
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    for i in 0..40 {
+        storage.write_data_entry(format!("k{}", i), &value).unwrap();
+    }
+
+    let mut view = storage.snapshot_reader();
+    let parts = std::cell::Cell::new(0);
+    let storage = std::sync::Mutex::new(storage);
+    // every part follows some writes
+    let locked = |part: &mut dyn FnMut() -> std::io::Result<()>| {
+        let mut storage = storage.lock().unwrap();
+        let i = parts.replace(parts.get() + 1);
+        storage
+            .update_data_entry(format!("k{}", 39 - i), "new")
+            .unwrap();
+        storage.delete_data_entry(format!("k{}", 38 - i)).unwrap();
+        storage
+            .write_data_entry(format!("new{}", i), "new")
+            .unwrap();
+        // the snapshot goes on reading the file it was taken of
+        if i == 1 {
+            storage.compact().unwrap();
+        }
+        part()
+    };
+    view.snapshot_with(&snapshot_path, &locked).unwrap();
+    assert!(parts.get() > 2);
+
+    assert!(check(&snapshot_path).unwrap().is_ok());
+    let mut snapshot = Storage::open(snapshot_path.to_string_lossy()).unwrap();
+    for i in 0..40 {
+        let key = format!("k{}", i);
+        assert_eq!(snapshot.get_data_entry(&key).unwrap(), Some(value.clone()));
+    }
+    assert!(snapshot.scan_data_entries("new").unwrap().is_empty());
+}
@@ -0,0 +1,155 @@
+// runs random sorted set operations against storage and a BTreeMap, reading
+// ranges and ranks through the score index, from the file as it is and from
+// a snapshot taken along the way
+
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+use storage::Storage;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Add(String, f64),
+    Remove(String),
+    Range(f64, f64),
+    Rank(String),
+    Snapshot,
+    Compact,
+    Reopen,
+}
+
+fn member() -> impl Strategy<Value = String> {
+    // a handful of members so operations land on the same entries
+    prop::sample::select(vec!["a", "b", "c", "d", "e"]).prop_map(String::from)
+}
+
+fn score() -> impl Strategy<Value = f64> {
+    prop::sample::select(vec![-1.0, -0.0, 0.0, 1.0, 1.5, 2.0, f64::INFINITY])
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (member(), score()).prop_map(|(member, score)| Op::Add(member, score)),
+        2 => member().prop_map(Op::Remove),
+        3 => (score(), score()).prop_map(|(min, max)| Op::Range(min, max)),
+        2 => member().prop_map(Op::Rank),
+        1 => Just(Op::Snapshot),
+        1 => Just(Op::Compact),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+fn range(model: &BTreeMap<String, f64>, min: f64, max: f64) -> Vec<(String, f64)> {
+    let mut members: Vec<_> = model
+        .iter()
+        .filter(|(_, &score)| score >= min && score <= max)
+        .map(|(member, &score)| (member.clone(), score))
+        .collect();
+    members.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    members
+}
+
+fn rank(model: &BTreeMap<String, f64>, member: &str) -> Option<u64> {
+    range(model, f64::NEG_INFINITY, f64::INFINITY)
+        .iter()
+        .position(|(m, _)| m == member)
+        .map(|rank| rank as u64)
+}
+
+proptest! {
+    #[test]
+    fn sorted_sets_behave_like_a_sorted_map(ops in prop::collection::vec(op(), 1..64)) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sorted.kiv").to_string_lossy().into_owned();
+        let mut storage = Storage::open(path.clone()).unwrap();
+        // another set in the same file, which reads never return
+        storage.write_sorted_set_entry("other", "a", 1.0).unwrap();
+        let mut model: BTreeMap<String, f64> = BTreeMap::new();
+        let mut snapshot: Option<(Storage, BTreeMap<String, f64>)> = None;
+
+        for op in ops {
+            match op {
+                Op::Add(member, score) => {
+                    storage.write_sorted_set_entry("z", &member, score).unwrap();
+                    model.insert(member, score);
+                }
+                Op::Remove(member) => {
+                    storage.delete_sorted_set_entry("z", &member).unwrap();
+                    model.remove(&member);
+                }
+                Op::Range(min, max) => {
+                    prop_assert_eq!(
+                        storage.get_sorted_set_range("z", min, max).unwrap(),
+                        range(&model, min, max)
+                    );
+                }
+                Op::Rank(member) => {
+                    prop_assert_eq!(
+                        storage.get_sorted_set_rank("z", &member).unwrap(),
+                        rank(&model, &member)
+                    );
+                }
+                Op::Snapshot => {
+                    snapshot = Some((storage.snapshot_reader(), model.clone()));
+                }
+                Op::Compact => {
+                    storage.compact().unwrap();
+                }
+                Op::Reopen => {
+                    snapshot = None;
+                    drop(storage);
+                    storage = Storage::open(path.clone()).unwrap();
+                }
+            }
+
+            if let Some((snapshot, model)) = &mut snapshot {
+                prop_assert_eq!(
+                    snapshot.get_sorted_set_range("z", f64::NEG_INFINITY, f64::INFINITY).unwrap(),
+                    range(model, f64::NEG_INFINITY, f64::INFINITY)
+                );
+                for member in model.keys() {
+                    prop_assert_eq!(
+                        snapshot.get_sorted_set_rank("z", member).unwrap(),
+                        rank(model, member)
+                    );
+                }
+            }
+        }
+
+        prop_assert_eq!(
+            storage.get_sorted_set_range("other", f64::NEG_INFINITY, f64::INFINITY).unwrap(),
+            vec![("a".to_string(), 1.0)]
+        );
+    }
+}
+
+#[test]
+fn ranges_of_equal_scores_include_both_zeros() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("zeros.kiv");
+    let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+    storage
+        .write_sorted_set_entry("z", "negative", -0.0)
+        .unwrap();
+    storage
+        .write_sorted_set_entry("z", "positive", 0.0)
+        .unwrap();
+    storage
+        .write_sorted_set_entry("z", "nan", f64::NAN)
+        .unwrap();
+
+    for (min, max) in [(0.0, 0.0), (-0.0, -0.0), (0.0, -0.0)] {
+        let members: Vec<_> = storage
+            .get_sorted_set_range("z", min, max)
+            .unwrap()
+            .into_iter()
+            .map(|(member, _)| member)
+            .collect();
+        assert_eq!(members, vec!["negative", "positive"]);
+    }
+    // NaN scores are never within a range, but still have a rank
+    assert!(storage
+        .get_sorted_set_range("z", f64::NEG_INFINITY, f64::NAN)
+        .unwrap()
+        .is_empty());
+    assert_eq!(storage.get_sorted_set_rank("z", "nan").unwrap(), Some(2));
+}
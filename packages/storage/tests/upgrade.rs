@@ -0,0 +1,77 @@
+// opens files written before entries had a namespace and checks they are
+// upgraded, and that files which aren't kiv files are left alone
+
+use std::io::ErrorKind;
+
+use storage::Storage;
+
+/// A file of `version` holding a value, and the sorted set and set members
+/// the version had entries for. Entries were only their type and fields.
+fn old_file(version: u8) -> Vec<u8> {
+    let mut bytes = vec![0, 104, 105, 107, 105, 118, 0, version];
+    bytes.extend_from_slice(&[0, 0, 1, b'a', 0, 0, 0, 3, b'o', b'n', b'e']);
+    if version >= 1 {
+        bytes.extend_from_slice(&[1, 0, 1, b'z', 0, 1, b'm']);
+        bytes.extend_from_slice(&1.5f64.to_be_bytes());
+    }
+    if version >= 2 {
+        bytes.extend_from_slice(&[2, 0, 1, b's', 0, 1, b'x']);
+    }
+    bytes
+}
+
+#[test]
+fn files_from_before_namespaces_are_upgraded() {
+    for version in 0..=2 {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.kiv");
+        std::fs::write(&path, old_file(version)).unwrap();
+        assert_eq!(
+            storage::check::check(&path).unwrap().version,
+            Some(version as u16)
+        );
+
+        let err = Storage::open_read_only(path.to_string_lossy())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut storage = Storage::open(path.to_string_lossy()).unwrap();
+        assert_eq!(storage.get_stats().unwrap().version, 7);
+        assert_eq!(storage.get_data_entry("a").unwrap(), Some(b"one".to_vec()));
+        if version >= 1 {
+            assert_eq!(storage.get_sorted_set_score("z", "m").unwrap(), Some(1.5));
+        }
+        if version >= 2 {
+            assert!(storage.is_set_member("s", "x").unwrap());
+        }
+
+        // everything was in the default namespace
+        let id = storage.create_namespace("other").unwrap().unwrap();
+        storage.use_namespace(id);
+        assert_eq!(storage.get_data_entry("a").unwrap(), None);
+        drop(storage);
+        assert!(storage::check::check(&path).unwrap().is_ok());
+    }
+}
+
+#[test]
+fn other_files_are_left_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("other.kiv");
+    for bytes in [&b"hi"[..], b"not a kiv file, just some text"] {
+        std::fs::write(&path, bytes).unwrap();
+
+        let err = Storage::open(path.to_string_lossy()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    }
+
+    // and so are files from a version this one doesn't know
+    let mut bytes = old_file(0);
+    bytes[7] = 200;
+    std::fs::write(&path, &bytes).unwrap();
+    let err = Storage::open(path.to_string_lossy()).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+}